@@ -0,0 +1,163 @@
+//! LoRa time-on-air, after the formula in the SX1276/77/78 datasheet (section 4.1.1.7).
+
+use core::fmt;
+
+use crate::compact::{Encoding, Precision};
+use crate::protocol::AUTH_LEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LoraParams {
+    /// 6..=12
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+    /// Denominator of the coding rate 4/5..4/8.
+    pub coding_rate: u8,
+    pub preamble_len: u16,
+    pub explicit_header: bool,
+    pub crc: bool,
+    /// Packets with another sync word are not received.
+    pub sync_word: u8,
+    /// Receive with inverted I and Q, as LoRaWAN downlinks are sent.
+    pub invert_iq: bool,
+}
+
+impl LoraParams {
+    /// Modem settings of the standard profile: SF9, 125 kHz, CR 4/8, 12 symbol preamble, CRC on,
+    /// private sync word.
+    pub const ARKAN: LoraParams = LoraParams {
+        spreading_factor: 9,
+        bandwidth_hz: 125_000,
+        coding_rate: 8,
+        preamble_len: 12,
+        explicit_header: true,
+        crc: true,
+        // the SX127x reset value, which the firmwares always ran with
+        sync_word: 0x12,
+        invert_iq: false,
+    };
+
+    pub const fn symbol_us(&self) -> u32 {
+        ((1u64 << self.spreading_factor) * 1_000_000 / self.bandwidth_hz as u64) as u32
+    }
+
+    /// The radio sets the low data rate optimization bit for symbols longer than 16 ms.
+    pub const fn low_data_rate_optimize(&self) -> bool {
+        self.symbol_us() > 16_000
+    }
+
+    /// Time on air of a packet with `payload_len` bytes.
+    pub const fn time_on_air_us(&self, payload_len: usize) -> u32 {
+        let sf = self.spreading_factor as i64;
+        let de = self.low_data_rate_optimize() as i64;
+        let ih = !self.explicit_header as i64;
+        let crc = self.crc as i64;
+        let num = 8 * payload_len as i64 - 4 * sf + 28 + 16 * crc - 20 * ih;
+        let den = 4 * (sf - 2 * de);
+        let blocks = if num > 0 { (num + den - 1) / den } else { 0 };
+        let payload_symbols = 8 + blocks * (self.coding_rate as i64);
+        // preamble takes n + 4.25 symbols; count in quarter symbols to stay in integers
+        let quarters = 4 * (self.preamble_len as i64 + payload_symbols) + 17;
+        ((quarters as u64 * (1u64 << sf) * 1_000_000) / (4 * self.bandwidth_hz as u64)) as u32
+    }
+}
+
+/// Encodings compared by `Report`.
+pub const ENCODINGS: [Encoding; 6] = [
+    Encoding::Full,
+    Encoding::Compact(Precision::E7),
+    Encoding::Compact(Precision::E6),
+    Encoding::Compact(Precision::E5),
+    Encoding::Compact(Precision::E4),
+    Encoding::Delta(Precision::E5),
+];
+
+/// Table of frame size and time on air per encoding for SF7..SF12, with the
+/// other settings taken from `params`.
+pub struct Report(pub LoraParams);
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = &self.0;
+        write!(
+            f,
+            "time on air in ms at {} kHz, CR 4/{}, preamble {}\r\n{:<12} bytes",
+            p.bandwidth_hz / 1000,
+            p.coding_rate,
+            p.preamble_len,
+            "encoding"
+        )?;
+        for sf in 7..=12 {
+            write!(f, "  {:>6}", SfLabel(sf))?;
+        }
+        f.write_str("\r\n")?;
+        for encoding in ENCODINGS {
+            let len = on_air_len(encoding);
+            write!(f, "{:<12} {:>5}", Label(encoding), len)?;
+            for sf in 7..=12 {
+                let us = LoraParams { spreading_factor: sf, ..*p }.time_on_air_us(len);
+                write!(f, "  {:>4}.{}", us / 1000, us % 1000 / 100)?;
+            }
+            f.write_str("\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Length of a frame in `encoding` with its MAC.
+pub const fn on_air_len(encoding: Encoding) -> usize {
+    encoding.frame_len() + AUTH_LEN
+}
+
+/// Width and alignment only apply to `pad`ded strings, so render into a small buffer first.
+struct Label(Encoding);
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = heapless::String::<16>::new();
+        let _ = fmt::write(&mut s, format_args!("{}", self.0));
+        f.pad(&s)
+    }
+}
+
+struct SfLabel(u8);
+
+impl fmt::Display for SfLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = heapless::String::<4>::new();
+        let _ = fmt::write(&mut s, format_args!("SF{}", self.0));
+        f.pad(&s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_semtech_calculator() {
+        let sf7 = LoraParams { spreading_factor: 7, coding_rate: 5, preamble_len: 8, ..LoraParams::ARKAN };
+        assert_eq!(sf7.time_on_air_us(10), 41_216);
+        let sf12 = LoraParams { spreading_factor: 12, ..sf7 };
+        assert!(sf12.low_data_rate_optimize());
+        assert_eq!(sf12.time_on_air_us(10), 991_232);
+    }
+
+    #[test]
+    fn compact_frames_cut_airtime() {
+        let p = LoraParams::ARKAN;
+        assert_eq!(p.time_on_air_us(on_air_len(Encoding::Full)), 295_936);
+        assert_eq!(p.time_on_air_us(on_air_len(Encoding::Compact(Precision::E5))), 230_400);
+        assert_eq!(p.time_on_air_us(on_air_len(Encoding::Delta(Precision::E5))), 197_632);
+    }
+
+    #[test]
+    fn report_lists_every_encoding() {
+        let mut s = heapless::String::<1024>::new();
+        fmt::write(&mut s, format_args!("{}", Report(LoraParams::ARKAN))).unwrap();
+        let mut lines = s.lines();
+        assert_eq!(lines.next(), Some("time on air in ms at 125 kHz, CR 4/8, preamble 12"));
+        assert_eq!(lines.next(), Some("encoding     bytes     SF7     SF8     SF9    SF10    SF11    SF12"));
+        assert_eq!(lines.next(), Some("full            25    90.3   164.3   295.9   591.8  1183.7  2105.3"));
+        assert_eq!(lines.count(), ENCODINGS.len() - 1);
+    }
+}
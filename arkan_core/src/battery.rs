@@ -0,0 +1,201 @@
+//! Battery state from the Pico's VSYS voltage.
+//!
+//! The cell feeds VSYS through the DW01A protection, and the Pico divides
+//! VSYS by three onto ADC3 (GPIO29). While the Pico's USB port is powered,
+//! VSYS follows VBUS instead and says nothing about the cell; GPIO24 tells.
+//! `Battery` averages the samples, turns the voltage into a charge along a
+//! discharge `Curve` and sorts it into a `Level`, with some hysteresis so a
+//! cell near a threshold does not flip between levels.
+
+use core::fmt;
+
+/// Charge over resting voltage, as (mV, %) points in rising order.
+pub struct Curve(&'static [(u16, u8)]);
+
+/// A single Li-ion cell at light load, 3.3 V being where the DW01A cuts it off.
+pub const LI_ION: Curve = Curve(&[
+    (3_300, 0),
+    (3_600, 5),
+    (3_700, 10),
+    (3_750, 20),
+    (3_790, 30),
+    (3_830, 40),
+    (3_870, 50),
+    (3_920, 60),
+    (3_970, 70),
+    (4_020, 80),
+    (4_080, 90),
+    (4_200, 100),
+]);
+
+impl Curve {
+    /// Charge at `mv`, interpolated between the points.
+    pub fn percent(&self, mv: u16) -> u8 {
+        let points = self.0;
+        let Some(upper) = points.iter().position(|&(p, _)| p >= mv) else {
+            return points.last().map_or(0, |&(_, pct)| pct);
+        };
+        if upper == 0 {
+            return points[0].1;
+        }
+        let ((v0, p0), (v1, p1)) = (points[upper - 1], points[upper]);
+        (p0 as u32 + (mv - v0) as u32 * (p1 - p0) as u32 / (v1 - v0) as u32) as u8
+    }
+}
+
+/// VSYS in mV from a 12-bit reading of ADC3 against the 3.3 V reference.
+pub fn vsys_mv(raw: u16) -> u16 {
+    (raw as u32 * 3 * 3_300 / 4_096) as u16
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Level {
+    Ok,
+    Low,
+    Critical,
+    /// Powered over USB, which also charges the cell.
+    External,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Ok => "ok",
+            Level::Low => "low",
+            Level::Critical => "critical",
+            Level::External => "external",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BatteryStatus {
+    /// VSYS, averaged.
+    pub mv: u16,
+    pub percent: u8,
+    pub level: Level,
+}
+
+impl fmt::Display for BatteryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Level::External => write!(f, "battery on USB power, VSYS {} mV", self.mv),
+            level => write!(f, "battery {} mV, {} %, {}", self.mv, self.percent, level.as_str()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct BatteryConfig {
+    pub curve: &'static Curve,
+    /// At or below this charge the battery is low.
+    pub low_percent: u8,
+    /// At or below this charge it is critical.
+    pub critical_percent: u8,
+    /// A level is left again only this far above its threshold.
+    pub hysteresis: u8,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        Self { curve: &LI_ION, low_percent: 20, critical_percent: 5, hysteresis: 5 }
+    }
+}
+
+pub struct Battery {
+    config: BatteryConfig,
+    /// Averaged voltage in 1/16 mV, once a sample on battery power came in.
+    avg: Option<u32>,
+    status: Option<BatteryStatus>,
+}
+
+impl Battery {
+    pub fn new(config: BatteryConfig) -> Self {
+        Self { config, avg: None, status: None }
+    }
+
+    pub fn status(&self) -> Option<BatteryStatus> {
+        self.status
+    }
+
+    /// Takes a VSYS reading, with `usb_power` when the Pico's USB port is
+    /// powered; returns the new state.
+    pub fn sample(&mut self, mv: u16, usb_power: bool) -> BatteryStatus {
+        if usb_power {
+            // VBUS says nothing about the cell
+            self.avg = None;
+            let status = BatteryStatus { mv, percent: 100, level: Level::External };
+            self.status = Some(status);
+            return status;
+        }
+        // exponential average over about 4 samples against ADC noise and load steps
+        let sample = (mv as u32) << 4;
+        let avg = self.avg.map_or(sample, |avg| avg - avg / 4 + sample / 4);
+        self.avg = Some(avg);
+        let mv = (avg >> 4) as u16;
+        let percent = self.config.curve.percent(mv);
+        let status = BatteryStatus { mv, percent, level: self.level(percent) };
+        self.status = Some(status);
+        status
+    }
+
+    fn level(&self, percent: u8) -> Level {
+        let c = &self.config;
+        let previous = self.status.map_or(Level::Ok, |s| s.level);
+        // a level is only left once the charge is clear of its threshold
+        let critical_margin = if previous == Level::Critical { c.hysteresis } else { 0 };
+        let low_margin = if matches!(previous, Level::Low | Level::Critical) { c.hysteresis } else { 0 };
+        if percent <= c.critical_percent + critical_margin {
+            Level::Critical
+        } else if percent <= c.low_percent + low_margin {
+            Level::Low
+        } else {
+            Level::Ok
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_voltage_to_charge() {
+        assert_eq!(vsys_mv(1_655), 4_000);
+        assert_eq!(LI_ION.percent(4_250), 100);
+        assert_eq!(LI_ION.percent(4_200), 100);
+        assert_eq!(LI_ION.percent(3_850), 45);
+        assert_eq!(LI_ION.percent(3_650), 7);
+        assert_eq!(LI_ION.percent(3_000), 0);
+    }
+
+    #[test]
+    fn levels_change_with_hysteresis() {
+        let mut battery = Battery::new(BatteryConfig::default());
+        assert_eq!(battery.sample(3_870, false).level, Level::Ok);
+        // a single dip under load is averaged out
+        assert_eq!(battery.sample(3_600, false), BatteryStatus { mv: 3_802, percent: 33, level: Level::Ok });
+        let mut status = battery.sample(3_740, false);
+        while status.level == Level::Ok {
+            status = battery.sample(3_740, false);
+        }
+        assert_eq!(status.level, Level::Low);
+        // back above 20 % but not above 25 %
+        assert_eq!(battery.sample(3_770, false).level, Level::Low);
+        for _ in 0..30 {
+            status = battery.sample(3_600, false);
+        }
+        assert_eq!((status.percent, status.level), (5, Level::Critical));
+        for _ in 0..30 {
+            status = battery.sample(3_720, false);
+        }
+        assert_eq!((status.percent, status.level), (14, Level::Low));
+
+        let status = battery.sample(4_700, true);
+        assert_eq!(status.level, Level::External);
+        assert_eq!(status.to_string(), "battery on USB power, VSYS 4700 mV");
+        // on battery again, the average starts over
+        let status = battery.sample(4_100, false);
+        assert_eq!(status.to_string(), "battery 4100 mV, 91 %, ok");
+    }
+}
@@ -0,0 +1,1262 @@
+//! Beacon main-loop logic: turns GPS bytes into LoRa packets and decides when to sleep.
+
+use core::ops::Range;
+
+use crate::battery::{BatteryStatus, Level};
+use crate::boot_count;
+use crate::breadcrumb::{Batch, BatchConfig};
+use crate::compact::{Encoder, Encoding, Precision};
+use crate::crash::PanicRecord;
+use crate::decryption::NONCE_LEN;
+use crate::duty_cycle::{DutyCycle, Exceeded};
+use crate::encryption::GpsCoord;
+use crate::gps_proccess::{parse_fix, NonceCounter};
+use crate::hal::{Clock, LogSink, Radio, Store};
+use crate::hopping::hop_channel;
+use crate::lbt::doubled;
+use crate::log::Logger;
+use crate::lorawan::{self, Event, Mac, JOIN_RETRY_MS};
+use crate::nmea::{days_since_epoch, field, is_sentence, time_of_day, LineAssembler};
+use crate::protocol::{
+    mark_stale, parse_ack, parse_relay, sign, wrap_relay, ACK_LEN, AUTH_LEN, FLAG_ACK_REQUEST, HEADER_LEN, MIC_LEN,
+};
+use crate::radio_config::RadioConfig;
+use crate::radio_health::HealthStats;
+use crate::relay::{Relay, RelayConfig};
+use crate::tdma::SlotPlan;
+use crate::telemetry::{Faults, GpsState, Telemetry, TxStats, Version};
+use crate::track_log::TrackLog;
+use crate::uart_rx::RxStats;
+use crate::watchdog::ResetReason;
+use crate::{debug, error, info, warn};
+
+const TAG: &str = "beacon";
+
+/// Sleep once packets have been going out for this long.
+pub const SEND_BEFORE_SLEEP_MS: u64 = 20_000;
+/// Sleep when there was neither a fix nor a sent packet for this long.
+pub const NO_GPS_SLEEP_MS: u64 = 30_000;
+/// Length of one sleep period.
+pub const SLEEP_MS: u32 = 30_000;
+/// Length of one sleep period while the battery is low.
+pub const LOW_BATTERY_SLEEP_MS: u32 = 120_000;
+/// Once the battery is critical and the receiver was told, the beacon only
+/// wakes this often to see whether it was charged.
+pub const CRITICAL_SLEEP_MS: u32 = 600_000;
+/// Interval of the telemetry frames.
+pub const TELEMETRY_MS: u64 = 600_000;
+/// Repeat the last fix this long after the previous transmission when no new one arrived.
+pub const HEARTBEAT_MS: u64 = 10_000;
+/// Stop resending the last fix once it is older than a day.
+pub const STALE_FIX_MS: u64 = 86_400_000;
+const DAY_MS: u64 = 86_400_000;
+/// A pause this long ends the GPS receiver's sentences for the second, so the
+/// track log can erase a block before the next ones arrive.
+const GPS_QUIET_MS: u64 = 50;
+/// Logged fixes are uploaded in track frames of this shape.
+const UPLOAD: BatchConfig = BatchConfig { points: 16, interval_ms: 0, precision: Precision::E5 };
+
+/// Acknowledged delivery: every fix is sent with `FLAG_ACK_REQUEST` and
+/// retried until the receiver acknowledges it.
+#[derive(Clone, Copy, Debug)]
+pub struct AckConfig {
+    /// How long to listen for the ACK once the transmission finished.
+    pub window_ms: u64,
+    /// Retransmissions after the first attempt before the fix is given up.
+    pub max_retries: u8,
+    /// Wait before the first retry; doubles with every further retry.
+    pub backoff_ms: u64,
+}
+
+impl Default for AckConfig {
+    fn default() -> Self {
+        // an ACK takes about 270 ms on air at SF9/125 kHz/CR 4/8
+        Self { window_ms: 1_000, max_retries: 3, backoff_ms: 2_000 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum AckState {
+    Idle,
+    /// Waiting for the transmission to finish before opening the receive window.
+    Sending,
+    Listening { until: u64 },
+    Backoff { until: u64 },
+}
+
+pub struct Beacon {
+    assembler: LineAssembler,
+    nonces: NonceCounter,
+    encoder: Encoder,
+    lora_buf: [u8; 255],
+    last_lora_packet_len: usize,
+    /// Nonce of the frame in `lora_buf`.
+    frame_nonce: [u8; NONCE_LEN],
+    /// When the frame in `lora_buf` was built, for the age of stale repeats.
+    frame_ms: u64,
+    heartbeat_ms: Option<u64>,
+    last_lora_success: u64,
+    first_lora_success: Option<u64>,
+    last_gps_success: Option<u64>,
+    ack: Option<AckConfig>,
+    /// Breadcrumbs collected for the next track frame.
+    batch: Option<Batch>,
+    ack_state: AckState,
+    /// Nonce of the position frame that is waiting for its ACK.
+    sent_nonce: [u8; NONCE_LEN],
+    /// Retries of the current fix so far.
+    attempt: u8,
+    /// Store-and-forward log of every fix.
+    track: Option<TrackLog>,
+    /// When the last GPS byte arrived.
+    gps_byte_ms: u64,
+    /// Logged fixes covered by the frame in `lora_buf` and by the one waiting for its ACK.
+    frame_seqs: Range<u32>,
+    sent_seqs: Range<u32>,
+    /// First logged fix not yet in a breadcrumb frame.
+    batch_from: u32,
+    /// Pending logged fixes older than this are uploaded; set from the last
+    /// acknowledged live frame and cleared when the receiver stops answering.
+    upload_until: u32,
+    /// Date of the last RMC sentence, in days since 1970.
+    date: Option<u32>,
+    /// UTC in ms since 1970 minus `Clock::now_ms`, once GPS time is known.
+    utc_offset_ms: Option<u64>,
+    /// Settings the radio was configured with, for time on air and sub-band.
+    radio_config: RadioConfig,
+    duty: DutyCycle,
+    /// Set while the frame in `lora_buf` waits for duty-cycle budget or its
+    /// time slot, until it may go out.
+    held_until: Option<u64>,
+    tdma: Option<SlotPlan>,
+    /// GPS time of day in ms minus `Clock::now_ms`, modulo a day, once a GGA had a time.
+    time_offset_ms: Option<u64>,
+    /// Every frame goes out on the channel its nonce picks.
+    hopping: bool,
+    /// Frames go out wrapped for relays.
+    mesh: bool,
+    relay: Option<Relay>,
+    /// The relay role put the radio into receive mode.
+    listening: bool,
+    /// Frames go out as LoRaWAN uplinks instead.
+    lorawan: Option<Mac>,
+    /// Store offset of the LoRaWAN count.
+    count_offset: Option<u32>,
+    /// Panic before the last reset, until it went out in a diagnostic frame.
+    panic_report: Option<PanicRecord>,
+    /// The radio is down; fixes only go into the track log.
+    log_only: bool,
+    /// Latest battery state, once measured.
+    battery: Option<BatteryStatus>,
+    telemetry_ms: Option<u64>,
+    /// When the next periodic telemetry frame is due.
+    next_telemetry: u64,
+    /// The receiver was told that the battery is critical.
+    critical_sent: bool,
+    version: Option<Version>,
+    reset: Option<ResetReason>,
+    temp_c: Option<i8>,
+    /// Satellites in the last GGA sentence.
+    satellites: Option<u8>,
+    /// Boot or the last wake-up, until the first fix after it.
+    searching_since: Option<u64>,
+    ttf_ms: Option<u64>,
+    tx: TxStats,
+    /// Faults the firmware counts, for telemetry.
+    faults: Faults,
+}
+
+/// A frame about the beacon itself, sent in between positions.
+#[derive(Clone, Copy)]
+enum Report {
+    Panic(PanicRecord),
+    Telemetry(Telemetry),
+}
+
+impl Report {
+    fn name(&self) -> &'static str {
+        match self {
+            Report::Panic(_) => "panic report",
+            Report::Telemetry(_) => "telemetry",
+        }
+    }
+}
+
+impl Beacon {
+    pub fn new(nonces: NonceCounter, now_ms: u64) -> Self {
+        Self {
+            assembler: LineAssembler::new(),
+            nonces,
+            encoder: Encoder::new(Encoding::Full),
+            lora_buf: [0u8; 255],
+            last_lora_packet_len: 0,
+            frame_nonce: [0; NONCE_LEN],
+            frame_ms: 0,
+            heartbeat_ms: Some(HEARTBEAT_MS),
+            last_lora_success: now_ms,
+            first_lora_success: None,
+            last_gps_success: None,
+            ack: None,
+            batch: None,
+            ack_state: AckState::Idle,
+            sent_nonce: [0; NONCE_LEN],
+            attempt: 0,
+            track: None,
+            gps_byte_ms: 0,
+            frame_seqs: 0..0,
+            sent_seqs: 0..0,
+            batch_from: 0,
+            upload_until: 0,
+            date: None,
+            utc_offset_ms: None,
+            radio_config: RadioConfig::DEFAULT,
+            duty: DutyCycle::new(),
+            held_until: None,
+            tdma: None,
+            time_offset_ms: None,
+            hopping: false,
+            mesh: false,
+            relay: None,
+            listening: false,
+            lorawan: None,
+            count_offset: None,
+            panic_report: None,
+            log_only: false,
+            battery: None,
+            telemetry_ms: None,
+            next_telemetry: 0,
+            critical_sent: false,
+            version: None,
+            reset: None,
+            temp_c: None,
+            satellites: None,
+            searching_since: Some(now_ms),
+            ttf_ms: None,
+            tx: TxStats::default(),
+            faults: Faults::default(),
+        }
+    }
+
+    /// Enables acknowledged delivery. Without it every fix is sent once and
+    /// then repeated at the heartbeat interval.
+    pub fn set_ack(&mut self, ack: Option<AckConfig>) {
+        self.ack = ack;
+        self.ack_state = AckState::Idle;
+        self.encoder.set_acked(ack.is_some());
+    }
+
+    /// Sets how long after the last transmission the last fix is repeated,
+    /// marked stale, while no new fix arrives; `None` never repeats it.
+    /// `HEARTBEAT_MS` by default. Only used without acknowledged delivery.
+    pub fn set_heartbeat(&mut self, interval_ms: Option<u64>) {
+        self.heartbeat_ms = interval_ms;
+    }
+
+    /// Enables breadcrumb mode: fixes are collected and sent several per frame
+    /// instead of one frame per fix, and frames are not repeated.
+    pub fn set_batch(&mut self, batch: Option<BatchConfig>) {
+        self.batch = batch.map(|config| self.new_batch(config));
+    }
+
+    /// Enables the store-and-forward log: every fix with a known GPS time is
+    /// appended to it, and with acknowledged delivery the fixes the receiver
+    /// missed are uploaded as track frames once it answers again.
+    pub fn set_track_log(&mut self, track: Option<TrackLog>) {
+        self.batch_from = track.as_ref().map_or(0, TrackLog::next_seq);
+        self.upload_until = 0;
+        self.track = track;
+    }
+
+    pub fn track_log(&self) -> Option<&TrackLog> {
+        self.track.as_ref()
+    }
+
+    /// Tells the beacon which settings the radio runs with; `RadioConfig::DEFAULT` until set.
+    pub fn set_radio_config(&mut self, config: RadioConfig) {
+        self.radio_config = config;
+        self.held_until = None;
+    }
+
+    /// Enables time slots: frames only start in the beacon's slot of `plan`,
+    /// once GPS time is known.
+    pub fn set_tdma(&mut self, plan: Option<SlotPlan>) {
+        self.tdma = plan;
+        self.held_until = None;
+    }
+
+    /// The beacon's time slot, with time slots enabled.
+    pub fn slot(&self) -> Option<u8> {
+        self.tdma.map(|plan| plan.slot_of(&self.nonces.device_id()))
+    }
+
+    /// Enables frequency hopping over the channels of the radio config's
+    /// region; the beacon then retunes the radio before every frame.
+    pub fn set_hopping(&mut self, hopping: bool) {
+        self.hopping = hopping;
+    }
+
+    /// Wraps every frame for relays, so that relays forward it. With
+    /// acknowledged delivery the ACK window has to cover the way back.
+    pub fn set_mesh(&mut self, mesh: bool) {
+        self.mesh = mesh;
+        self.update_overhead();
+    }
+
+    /// Enables the relay role: while the beacon does not use the radio it
+    /// listens and forwards the relay frames of other devices. A relaying
+    /// beacon never asks to sleep, so that it keeps listening.
+    pub fn set_relay(&mut self, config: Option<RelayConfig>) {
+        self.relay = config.map(Relay::new);
+        self.listening = false;
+    }
+
+    pub fn relay(&self) -> Option<&Relay> {
+        self.relay.as_ref()
+    }
+
+    /// Sends frames as LoRaWAN uplinks through `mac`; with OTAA they are held
+    /// until the device joined. Acknowledged delivery uses confirmed uplinks.
+    /// Not combined with time slots, hopping or relays.
+    pub fn set_lorawan(&mut self, mac: Option<Mac>) {
+        self.lorawan = mac;
+        self.held_until = None;
+        self.update_overhead();
+        self.keep_count();
+    }
+
+    /// Keeps the LoRaWAN count that must not repeat across resets in the
+    /// store at `offset`, as a `boot_count` counter; nothing goes out until it
+    /// is written. Start the `Mac` from `boot_count::current` there.
+    pub fn set_lorawan_store(&mut self, offset: Option<u32>) {
+        self.count_offset = offset;
+        self.keep_count();
+    }
+
+    pub fn lorawan(&self) -> Option<&Mac> {
+        self.lorawan.as_ref()
+    }
+
+    /// Selects how positions are encoded; `Encoding::Full` by default.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoder = Encoder::new(encoding);
+        self.encoder.set_acked(self.ack.is_some());
+    }
+
+    /// Sends `record` to the receiver once in a diagnostic frame, as soon as
+    /// the radio is free.
+    pub fn set_panic_report(&mut self, record: Option<PanicRecord>) {
+        self.panic_report = record;
+    }
+
+    /// While the radio is down, keeps fixes in the track log only and sends
+    /// nothing. With acknowledged delivery they are uploaded once it is back.
+    pub fn set_log_only(&mut self, log_only: bool) {
+        self.log_only = log_only;
+        self.held_until = None;
+        self.ack_state = AckState::Idle;
+        self.listening = false;
+    }
+
+    /// Sends a telemetry frame every `interval_ms`, the first once the battery
+    /// state is known; `None` sends one only when the battery turns critical.
+    pub fn set_telemetry(&mut self, interval_ms: Option<u64>) {
+        self.telemetry_ms = interval_ms;
+        self.next_telemetry = 0;
+    }
+
+    /// Takes the latest battery state. A low battery makes the beacon sleep
+    /// longer. A critical one is reported at once in a final telemetry frame,
+    /// after which the beacon stays asleep until the battery recovers.
+    pub fn update_battery<L: LogSink>(&mut self, status: BatteryStatus, log: &mut Logger<L>) {
+        let previous = self.battery.map_or(Level::Ok, |b| b.level);
+        self.battery = Some(status);
+        if status.level != Level::Critical {
+            self.critical_sent = false;
+        }
+        if status.level == previous {
+            return;
+        }
+        match status.level {
+            Level::Low => warn!(log, TAG, "{}, sleeping longer", status),
+            Level::Critical => error!(log, TAG, "{}, sending a final report", status),
+            Level::Ok | Level::External => info!(log, TAG, "{}", status),
+        }
+    }
+
+    pub fn battery(&self) -> Option<BatteryStatus> {
+        self.battery
+    }
+
+    /// Tells the beacon its firmware version and why the board last reset,
+    /// for telemetry.
+    pub fn set_firmware(&mut self, version: Option<Version>, reset: ResetReason) {
+        self.version = version;
+        self.reset = Some(reset);
+    }
+
+    /// Takes the latest temperature of the chip.
+    pub fn update_temperature(&mut self, temp_c: i8) {
+        self.temp_c = Some(temp_c);
+    }
+
+    /// Takes the fault counters of the radio and the GPS UART.
+    pub fn update_faults(&mut self, radio: HealthStats, uart: RxStats) {
+        self.faults = Faults {
+            radio_errors: radio.errors,
+            radio_hangs: radio.stuck,
+            radio_resets: radio.recoveries,
+            gps_bytes_lost: uart.overflows.saturating_add(uart.overruns).saturating_add(uart.errors),
+            gps_lines_dropped: 0,
+        };
+    }
+
+    /// What a telemetry frame sent at `now` tells.
+    pub fn telemetry(&self, now: u64) -> Telemetry {
+        let ttf_s = self.ttf_ms.map(|ms| (ms / 1000).min(u16::MAX as u64) as u16);
+        Telemetry {
+            battery: self.battery,
+            uptime_s: Some((now / 1000) as u32),
+            version: self.version,
+            reset: self.reset,
+            temp_c: self.temp_c,
+            gps: self.satellites.map(|satellites| GpsState { satellites, ttf_s }),
+            tx: Some(self.tx),
+            faults: Some(Faults { gps_lines_dropped: self.discarded_lines(), ..self.faults }),
+        }
+    }
+
+    /// NMEA lines dropped because they were too long or bytes of them were lost.
+    pub fn discarded_lines(&self) -> u32 {
+        self.assembler.discarded()
+    }
+
+    /// Feeds one byte from the GPS UART, `uart_rx::GAP` where bytes were lost.
+    /// Returns the number of milliseconds to sleep when the beacon should
+    /// power down; call `wake` afterwards.
+    pub fn handle_byte<R: Radio, S: Store, L: LogSink, C: Clock>(
+        &mut self,
+        b: u8,
+        radio: &mut R,
+        store: &mut S,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) -> Option<u32> {
+        if self.dormant() {
+            info!(log, TAG, "Battery critical, going to sleep...");
+            return Some(CRITICAL_SLEEP_MS);
+        }
+        self.store_count(store, log);
+        self.gps_byte_ms = clock.now_ms();
+        let flags = self.flags();
+        if let Some(line) = self.assembler.push(b) {
+            // the receiver outputs RMC before GGA, so the date is current when the fix arrives
+            if is_sentence(line, b"RMC")
+                && let Some(days) = field(line, 9).and_then(days_since_epoch)
+            {
+                self.date = Some(days);
+            }
+            // GGA carries the time and the satellites in use also without a fix
+            if is_sentence(line, b"GGA") {
+                if let Some(t) = field(line, 1).and_then(time_of_day) {
+                    let now = clock.now_ms() % DAY_MS;
+                    self.time_offset_ms = Some((t as u64 * 1000 + DAY_MS - now) % DAY_MS);
+                }
+                if let Some(n) = field(line, 7).and_then(|f| core::str::from_utf8(f).ok()?.parse().ok()) {
+                    self.satellites = Some(n);
+                }
+            }
+            // if we found GPS signals, process and send to LoRa
+            if let Some(coord) = parse_fix(line, log) {
+                let now = clock.now_ms();
+                self.last_gps_success = Some(now);
+                if let Some(since) = self.searching_since.take() {
+                    self.ttf_ms = Some(now.saturating_sub(since));
+                }
+                let utc = self.date.zip(field(line, 1).and_then(time_of_day)).map(|(d, t)| d * 86_400 + t);
+                if let Some(utc) = utc {
+                    self.utc_offset_ms = Some((utc as u64 * 1000).saturating_sub(now));
+                }
+                let seqs = self.record(store, log, utc, &coord);
+                if self.log_only {
+                    // left pending in the track log, outside the next breadcrumb frame
+                    self.batch_from = seqs.end;
+                } else if let Some(batch) = &mut self.batch {
+                    if batch.push(now, &coord) {
+                        debug!(log, TAG, "breadcrumb {} collected", batch.len());
+                    }
+                    self.send_batch(radio, log, clock);
+                } else if let Some((len, nonce)) =
+                    self.encoder.encode(&coord, &mut self.nonces, flags, log, &mut self.lora_buf)
+                {
+                    self.send_frame(len, nonce, seqs, radio, log, clock);
+                }
+
+            // if GPS fix failed now, but we have valid data from before, resend it
+            // once the heartbeat is due
+            } else if self.last_lora_packet_len > 0
+                && self.ack.is_none()
+                && !self.log_only
+                && self.repeat_due(clock.now_ms())
+            {
+                self.send(radio, log, clock);
+            }
+        }
+
+        let now = clock.now_ms();
+
+        // if we have been successfully sending data for 20 seconds, go to sleep,
+        // unless the receiver is still taking logged fixes or we relay
+        if let Some(first) = self.first_lora_success
+            && now.saturating_sub(first) > SEND_BEFORE_SLEEP_MS
+            && !self.uploading()
+            && self.relay.is_none()
+        {
+            info!(log, TAG, "Been sending data for 20 seconds, going to sleep...");
+            return Some(self.sleep_ms());
+        }
+
+        // if we don't have any valid GPS data and did not send any packets, go to sleep and retry after
+        let since_gps = self.last_gps_success.map_or(now, |t| now.saturating_sub(t));
+        if since_gps > NO_GPS_SLEEP_MS
+            && now.saturating_sub(self.last_lora_success) > NO_GPS_SLEEP_MS
+            && self.relay.is_none()
+        {
+            info!(log, TAG, "No valid GPS data for 30 sec, going to sleep...");
+            return Some(self.sleep_ms());
+        }
+
+        // if we did not have GPS fix for 1 day, prevent resending irrelevant data
+        if self.last_gps_success.is_some() && since_gps > STALE_FIX_MS {
+            self.last_lora_packet_len = 0;
+        }
+        None
+    }
+
+    /// Resets the sleep timers after the firmware woke up from a sleep requested by `handle_byte`.
+    pub fn wake<L: LogSink, C: Clock>(&mut self, log: &mut Logger<L>, clock: &C) {
+        info!(log, TAG, "Woke up from sleep, retrying GPS connection...");
+        self.first_lora_success = None;
+        // reset this to avoid immediate sleep
+        self.last_lora_success = clock.now_ms();
+        self.searching_since = Some(clock.now_ms());
+        self.ack_state = AckState::Idle;
+    }
+
+    /// Runs the ACK exchange and uploads logged fixes, or without acknowledged
+    /// delivery sends a held frame once it may go out, relays frames with the
+    /// relay role, runs the LoRaWAN receive windows and sends the panic
+    /// report and telemetry; call on every main loop iteration.
+    pub fn poll<R: Radio, S: Store, L: LogSink, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        store: &mut S,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        let now = clock.now_ms();
+        self.erase_ahead(store, log, now);
+        if self.log_only {
+            return;
+        }
+        self.store_count(store, log);
+        self.listen(radio, log, now);
+        let event = self.poll_lorawan(radio, log, now);
+        if self.send_report(radio, log, now) {
+            return;
+        }
+        let Some(ack) = self.ack else {
+            if self.held_until.is_some_and(|t| now >= t)
+                && self.send(radio, log, clock)
+                && self.first_lora_success.is_none()
+            {
+                self.first_lora_success = Some(self.last_lora_success);
+            }
+            return;
+        };
+        if self.ack_state == AckState::Idle {
+            self.send_batch(radio, log, clock);
+            self.upload(radio, store, log, clock);
+        }
+        match self.ack_state {
+            AckState::Idle => {}
+            // the MAC runs the receive windows of the confirmed uplink
+            AckState::Sending if self.lorawan.is_some() => match event {
+                Some(Event::Downlink { ack: true }) => self.acknowledged(radio, store, log, clock),
+                Some(Event::Downlink { ack: false } | Event::NoDownlink) => self.retry_later(&ack, log, now),
+                Some(Event::Joined) | None => {}
+            },
+            AckState::Sending => match radio.is_transmitting() {
+                Ok(true) => {}
+                Ok(false) if radio.start_receive().is_ok() => {
+                    self.ack_state = AckState::Listening { until: now + ack.window_ms };
+                }
+                _ => {
+                    error!(log, TAG, "LoRa receive failed");
+                    self.retry_later(&ack, log, now);
+                }
+            },
+            AckState::Listening { until } => {
+                let mut buf = [0u8; 255];
+                if let Ok(Some(size)) = radio.receive(&mut buf) {
+                    // ACKs come back wrapped when the frame went through relays
+                    let frame = parse_relay(&buf[..size]).map_or(&buf[..size], |(_, inner)| inner);
+                    match parse_ack(frame) {
+                        Some(nonce) if nonce == self.sent_nonce => {
+                            self.acknowledged(radio, store, log, clock);
+                            return;
+                        }
+                        _ if self.relay.as_mut().is_some_and(|r| r.offer(&buf[..size], now)) => {}
+                        _ => debug!(log, TAG, "ignoring frame while waiting for ACK"),
+                    }
+                }
+                if now >= until {
+                    self.retry_later(&ack, log, now);
+                }
+            }
+            AckState::Backoff { until } => {
+                if now >= until {
+                    self.send_for_ack(radio, log, clock);
+                }
+            }
+        }
+    }
+
+    /// Takes the ACK of the frame sent last, and sends the fix that arrived
+    /// while it waited for the ACK.
+    fn acknowledged<R: Radio, S: Store, L: LogSink, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        store: &mut S,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        let now = clock.now_ms();
+        info!(log, TAG, "LoRa delivery acknowledged");
+        self.tx.acked += 1;
+        self.encoder.acknowledged(&self.sent_nonce);
+        self.last_lora_success = now;
+        self.first_lora_success.get_or_insert(now);
+        self.ack_state = AckState::Idle;
+        self.delivered(store, log);
+        if self.frame_nonce != self.sent_nonce {
+            self.attempt = 0;
+            self.send_for_ack(radio, log, clock);
+        }
+    }
+
+    /// Runs the LoRaWAN receive windows and sends join requests; returns what
+    /// the windows that closed brought.
+    /// Has the LoRaWAN count held back until it is stored.
+    fn keep_count(&mut self) {
+        if let (Some(mac), Some(_)) = (&mut self.lorawan, self.count_offset) {
+            mac.keep_count();
+        }
+    }
+
+    /// Stores the LoRaWAN count once the next join request or uplink needs it.
+    fn store_count<S: Store, L: LogSink>(&mut self, store: &mut S, log: &mut Logger<L>) {
+        let (Some(mac), Some(offset)) = (&mut self.lorawan, self.count_offset) else { return };
+        let Some(count) = mac.count_to_store() else { return };
+        match boot_count::advance(store, offset, count) {
+            Ok(()) => mac.stored(count),
+            Err(_) => error!(log, TAG, "LoRaWAN counter write failed"),
+        }
+    }
+
+    fn poll_lorawan<R: Radio, L: LogSink>(&mut self, radio: &mut R, log: &mut Logger<L>, now: u64) -> Option<Event> {
+        let mac = self.lorawan.as_mut()?;
+        let event = mac.poll(radio, now).unwrap_or_else(|_| {
+            error!(log, TAG, "LoRa receive failed");
+            Some(Event::NoDownlink)
+        });
+        match event {
+            Some(Event::Joined) => info!(log, TAG, "joined the LoRaWAN network"),
+            Some(Event::Downlink { .. }) => debug!(log, TAG, "LoRaWAN downlink received"),
+            _ => {}
+        }
+        if mac.join_due(now) {
+            self.join(radio, log, now);
+        }
+        event
+    }
+
+    fn join<R: Radio, L: LogSink>(&mut self, radio: &mut R, log: &mut Logger<L>, now: u64) {
+        let Some(mac) = &mut self.lorawan else { return };
+        let mut frame = [0u8; 255];
+        let Some(len) = mac.join_request(&mut frame) else { return };
+        let config = mac.tx_config();
+        let airtime_ms = config.params().time_on_air_us(len).div_ceil(1000);
+        if let Err(e) = self.duty.reserve(config.frequency_hz(), airtime_ms, now) {
+            let at = match e {
+                Exceeded::Until(t) => t,
+                Exceeded::TooLong => now + JOIN_RETRY_MS,
+            };
+            info!(log, TAG, "duty cycle used up, join request in {} s", (at - now).div_ceil(1000));
+            mac.defer_join(at);
+            return;
+        }
+        self.listening = false;
+        match radio.configure(&config).and_then(|()| radio.transmit(&frame[..len])) {
+            Ok(()) => {
+                info!(log, TAG, "sent LoRaWAN join request");
+                mac.sent();
+                self.tx.sent += 1;
+            }
+            Err(_) => {
+                error!(log, TAG, "LoRa transmit failed");
+                self.duty.release(config.frequency_hz(), airtime_ms, now);
+                mac.defer_join(now + JOIN_RETRY_MS);
+                self.tx.failed += 1;
+            }
+        }
+    }
+
+    /// The battery is critical and the receiver knows, or cannot be told.
+    fn dormant(&self) -> bool {
+        self.battery.is_some_and(|b| b.level == Level::Critical) && (self.critical_sent || self.log_only)
+    }
+
+    fn sleep_ms(&self) -> u32 {
+        match self.battery.map(|b| b.level) {
+            Some(Level::Low | Level::Critical) => LOW_BATTERY_SLEEP_MS,
+            _ => SLEEP_MS,
+        }
+    }
+
+    /// The report due next, if any: a panic record first, then telemetry.
+    fn next_report(&self, now: u64) -> Option<Report> {
+        if let Some(record) = self.panic_report {
+            return Some(Report::Panic(record));
+        }
+        let critical = self.battery.is_some_and(|b| b.level == Level::Critical) && !self.critical_sent;
+        let periodic = self.telemetry_ms.is_some() && now >= self.next_telemetry;
+        (critical || periodic).then(|| Report::Telemetry(self.telemetry(now)))
+    }
+
+    /// The report went out, or never will.
+    fn report_done(&mut self, report: &Report, now: u64) {
+        match report {
+            Report::Panic(_) => self.panic_report = None,
+            Report::Telemetry(telemetry) => {
+                self.next_telemetry = self.telemetry_ms.map_or(u64::MAX, |ms| now + ms);
+                if telemetry.battery.is_some_and(|b| b.level == Level::Critical) {
+                    self.critical_sent = true;
+                }
+            }
+        }
+    }
+
+    fn flags(&self) -> u8 {
+        if self.ack.is_some() { FLAG_ACK_REQUEST } else { 0 }
+    }
+
+    fn repeat_due(&self, now: u64) -> bool {
+        // breadcrumbs are never repeated
+        let heartbeat = self.heartbeat_ms.filter(|_| self.batch.is_none());
+        heartbeat.is_some_and(|h| now.saturating_sub(self.last_lora_success) >= h)
+    }
+
+    fn uploading(&self) -> bool {
+        self.track.as_ref().is_some_and(|t| t.pending().start < self.upload_until)
+    }
+
+    /// Appends the fix to the track log; returns the logged fixes a frame sent
+    /// now covers, empty when the fix could not be logged.
+    fn record<S: Store, L: LogSink>(
+        &mut self,
+        store: &mut S,
+        log: &mut Logger<L>,
+        utc: Option<u32>,
+        coord: &GpsCoord,
+    ) -> Range<u32> {
+        let Some(track) = &mut self.track else { return 0..0 };
+        let next = track.next_seq();
+        let Some(utc) = utc else {
+            debug!(log, TAG, "no GPS date yet, fix not logged");
+            return next..next;
+        };
+        match track.append(store, utc, coord) {
+            Ok(seq) => seq..seq + 1,
+            Err(_) => {
+                error!(log, TAG, "track log write failed");
+                next..next
+            }
+        }
+    }
+
+    /// Has the track log erase its next block while the GPS is quiet.
+    fn erase_ahead<S: Store, L: LogSink>(&mut self, store: &mut S, log: &mut Logger<L>, now: u64) {
+        let Some(track) = &mut self.track else { return };
+        if track.erase_due() && now >= self.gps_byte_ms + GPS_QUIET_MS && track.erase_ahead(store).is_err() {
+            error!(log, TAG, "track log erase failed");
+        }
+    }
+
+    /// Marks the logged fixes of the acknowledged frame as delivered.
+    fn delivered<S: Store, L: LogSink>(&mut self, store: &mut S, log: &mut Logger<L>) {
+        let Some(track) = &mut self.track else { return };
+        // everything logged before an acknowledged live frame missed the receiver
+        self.upload_until = self.upload_until.max(self.sent_seqs.start);
+        if track.mark_delivered(store, self.sent_seqs.clone()).is_err() {
+            error!(log, TAG, "track log write failed");
+        }
+    }
+
+    /// Sends the oldest pending logged fixes once the receiver answers again.
+    fn upload<R: Radio, S: Store, L: LogSink, C: Clock>(
+        &mut self,
+        radio: &mut R,
+        store: &mut S,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        let (Some(track), Some(offset)) = (&self.track, self.utc_offset_ms) else { return };
+        let pending = track.pending();
+        if self.ack_state != AckState::Idle || pending.start >= self.upload_until {
+            return;
+        }
+        let mut batch = self.new_batch(UPLOAD);
+        let mut end = pending.start;
+        let mut last_ms = 0;
+        while end < pending.end.min(self.upload_until) && !batch.is_ready() {
+            match track.read(store, end) {
+                Ok(Some(r)) if !r.delivered => {
+                    // keep points in order should the GPS time have jumped back
+                    last_ms = (r.time_s as u64 * 1000).max(last_ms);
+                    batch.push(last_ms, &r.coord);
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    error!(log, TAG, "track log read failed");
+                    return;
+                }
+            }
+            end += 1;
+        }
+        if batch.is_empty() {
+            // everything in the range was delivered or overwritten since
+            if let Some(track) = &mut self.track
+                && track.mark_delivered(store, pending.start..end).is_err()
+            {
+                error!(log, TAG, "track log write failed");
+            }
+            return;
+        }
+        info!(log, TAG, "uploading {} logged fixes", batch.len());
+        let nonce = self.nonces.next_nonce();
+        let len = batch.seal(clock.now_ms() + offset, &nonce, self.flags(), &mut self.lora_buf);
+        self.send_frame(len, nonce, pending.start..end, radio, log, clock);
+    }
+
+    /// Sends the position frame just written to `lora_buf`, which covers the logged fixes `seqs`.
+    fn send_frame<R: Radio, L: LogSink, C: Clock>(
+        &mut self,
+        len: usize,
+        nonce: [u8; NONCE_LEN],
+        seqs: Range<u32>,
+        radio: &mut R,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        self.last_lora_packet_len = len;
+        self.frame_nonce = nonce;
+        self.frame_ms = clock.now_ms();
+        self.frame_seqs = seqs;
+        if self.ack.is_some() {
+            // a fix that arrives while the previous one is pending replaces it on
+            // the next retry, or goes out once the previous one is acknowledged
+            if self.ack_state == AckState::Idle {
+                self.attempt = 0;
+                self.send_for_ack(radio, log, clock);
+            }
+        } else if self.send(radio, log, clock) && self.first_lora_success.is_none() {
+            self.first_lora_success = Some(self.last_lora_success);
+        }
+    }
+
+    /// Sends the breadcrumbs once the batch is full and no earlier frame waits for its ACK.
+    fn send_batch<R: Radio, L: LogSink, C: Clock>(&mut self, radio: &mut R, log: &mut Logger<L>, clock: &C) {
+        let flags = self.flags();
+        let Some(batch) = &mut self.batch else { return };
+        if !batch.is_ready() || self.ack_state != AckState::Idle {
+            return;
+        }
+        info!(log, TAG, "sending {} breadcrumbs", batch.len());
+        let nonce = self.nonces.next_nonce();
+        let len = batch.seal(clock.now_ms(), &nonce, flags, &mut self.lora_buf);
+        let next = self.track.as_ref().map_or(0, TrackLog::next_seq);
+        let seqs = self.batch_from..next;
+        self.batch_from = next;
+        self.send_frame(len, nonce, seqs, radio, log, clock);
+    }
+
+    fn send_for_ack<R: Radio, L: LogSink, C: Clock>(&mut self, radio: &mut R, log: &mut Logger<L>, clock: &C) {
+        self.sent_nonce = self.frame_nonce;
+        self.sent_seqs = self.frame_seqs.clone();
+        let now = clock.now_ms();
+        if self.lorawan.as_ref().is_some_and(|mac| !mac.is_ready()) {
+            // sent once the device joined and the receive windows closed
+            self.ack_state = AckState::Backoff { until: now };
+            return;
+        }
+        let (frame, len) = self.outgoing(0, now);
+        // waiting for the slot does not count as a retry either
+        let wait = self.slot_wait(len, now);
+        if wait > 0 {
+            debug!(log, TAG, "waiting {} ms for time slot", wait);
+            self.ack_state = AckState::Backoff { until: now + wait };
+            return;
+        }
+        match self.reserve_airtime(len, log, now) {
+            Ok(()) => {}
+            // waiting for budget does not count as a retry
+            Err(Exceeded::Until(t)) => {
+                self.ack_state = AckState::Backoff { until: t };
+                return;
+            }
+            Err(Exceeded::TooLong) => {
+                self.ack_state = AckState::Idle;
+                return;
+            }
+        }
+        self.listening = false;
+        match self.tune(&self.frame_nonce, radio, log).and_then(|()| radio.transmit(&frame[..len])) {
+            Ok(()) => {
+                info!(log, TAG, "sent data to LoRa, waiting for ACK");
+                if let Some(mac) = &mut self.lorawan {
+                    mac.sent();
+                }
+                self.ack_state = AckState::Sending;
+                self.tx.sent += 1;
+            }
+            Err(_) => {
+                error!(log, TAG, "LoRa transmit failed");
+                self.release_airtime(len, now);
+                self.tx.failed += 1;
+                if let Some(ack) = self.ack {
+                    self.retry_later(&ack, log, clock.now_ms());
+                }
+            }
+        }
+    }
+
+    fn retry_later<L: LogSink>(&mut self, ack: &AckConfig, log: &mut Logger<L>, now: u64) {
+        if self.attempt >= ack.max_retries {
+            warn!(log, TAG, "No ACK after {} retries, giving up", self.attempt);
+            // the receiver may have lost track of this beacon
+            self.encoder.reset();
+            self.upload_until = 0;
+            self.ack_state = AckState::Idle;
+            return;
+        }
+        let wait = doubled(ack.backoff_ms, self.attempt);
+        self.attempt += 1;
+        info!(log, TAG, "No ACK, retry {} in {} ms", self.attempt, wait);
+        self.ack_state = AckState::Backoff { until: now.saturating_add(wait) };
+    }
+
+    /// Sends the frame in `lora_buf`; a single fix that is no longer fresh
+    /// goes out marked stale, with its age.
+    fn send<R: Radio, L: LogSink, C: Clock>(&mut self, radio: &mut R, log: &mut Logger<L>, clock: &C) -> bool {
+        let now = clock.now_ms();
+        let age_s = (now.saturating_sub(self.frame_ms) / 1000) as u32;
+        let (frame, len) = self.outgoing(if self.batch.is_none() { age_s } else { 0 }, now);
+        if self.held_until.is_some_and(|t| now < t) {
+            return false;
+        }
+        if self.lorawan.as_ref().is_some_and(|mac| !mac.is_ready()) {
+            // sent once the device joined and the receive windows closed
+            self.held_until = Some(now);
+            return false;
+        }
+        let wait = self.slot_wait(len, now);
+        if wait > 0 {
+            debug!(log, TAG, "waiting {} ms for time slot", wait);
+            self.held_until = Some(now + wait);
+            return false;
+        }
+        match self.reserve_airtime(len, log, now) {
+            Ok(()) => {}
+            Err(Exceeded::Until(t)) => {
+                self.held_until = Some(t);
+                return false;
+            }
+            Err(Exceeded::TooLong) => return false,
+        }
+        self.held_until = None;
+        self.listening = false;
+        match self.tune(&self.frame_nonce, radio, log).and_then(|()| radio.transmit(&frame[..len])) {
+            Ok(()) => {
+                // a frame already sent once was sent when or after it was built
+                if age_s > 0 && self.last_lora_success >= self.frame_ms {
+                    info!(log, TAG, "sent last fix again, {} s old", age_s);
+                } else if age_s > 0 {
+                    info!(log, TAG, "sent data to LoRa, {} s after the fix", age_s);
+                } else {
+                    info!(log, TAG, "sent data to LoRa");
+                }
+                if let Some(mac) = &mut self.lorawan {
+                    mac.sent();
+                }
+                self.last_lora_success = clock.now_ms();
+                self.tx.sent += 1;
+                true
+            }
+            Err(_) => {
+                error!(log, TAG, "LoRa transmit failed");
+                self.release_airtime(len, now);
+                self.tx.failed += 1;
+                false
+            }
+        }
+    }
+
+    /// Bytes `wrap` adds to a frame.
+    fn overhead(&self) -> usize {
+        let relay = if self.mesh { HEADER_LEN + MIC_LEN } else { 0 };
+        let lorawan = if self.lorawan.is_some() { lorawan::OVERHEAD } else { 0 };
+        AUTH_LEN + relay + lorawan
+    }
+
+    fn update_overhead(&mut self) {
+        let overhead = self.overhead();
+        if let Some(batch) = &mut self.batch {
+            batch.set_overhead(overhead);
+        }
+    }
+
+    /// An empty batch whose frames still fit once wrapped.
+    fn new_batch(&self, config: BatchConfig) -> Batch {
+        let mut batch = Batch::new(config);
+        batch.set_overhead(self.overhead());
+        batch
+    }
+
+    /// The frame in `lora_buf` as it goes on air: marked stale when `age_s`
+    /// is not 0, signed, and wrapped for relays in a mesh or in a LoRaWAN uplink.
+    fn outgoing(&mut self, age_s: u32, now: u64) -> ([u8; 255], usize) {
+        let mut frame = self.lora_buf;
+        let mut len = self.last_lora_packet_len;
+        if age_s > 0 {
+            len = mark_stale(&mut frame, len, age_s);
+        }
+        let len = self.wrap(&mut frame, len, self.ack.is_some(), now);
+        (frame, len)
+    }
+
+    /// Signs `frame[..len]` and wraps it for relays in a mesh or in a LoRaWAN
+    /// uplink, confirmed when `confirmed`; returns the new length.
+    fn wrap(&mut self, frame: &mut [u8; 255], mut len: usize, confirmed: bool, now: u64) -> usize {
+        len = sign(frame, len).unwrap_or(len);
+        if self.mesh {
+            // copies relays send back are not forwarded again
+            if let Some(relay) = &mut self.relay {
+                relay.remember(&frame[..len], now);
+            }
+            len = wrap_relay(frame, len).unwrap_or(len);
+        }
+        if let Some(mac) = &self.lorawan {
+            let mut uplink = [0u8; 255];
+            // 0 before the join, when nothing is sent
+            len = mac.uplink(&frame[..len], confirmed, &mut uplink).unwrap_or(0);
+            *frame = uplink;
+        }
+        len
+    }
+
+    /// Sends the report due next once the radio is free, it fits the duty
+    /// cycle and the time slot; returns true when it went on air.
+    fn send_report<R: Radio, L: LogSink>(&mut self, radio: &mut R, log: &mut Logger<L>, now: u64) -> bool {
+        let Some(report) = self.next_report(now) else { return false };
+        if self.ack_state != AckState::Idle
+            || self.held_until.is_some()
+            || self.lorawan.as_ref().is_some_and(|mac| !mac.is_ready())
+            || radio.is_transmitting().unwrap_or(true)
+        {
+            return false;
+        }
+        // the nonce is only taken once the frame goes out, so hopping
+        // receivers still find the next frame
+        let nonce = self.nonces.peek_nonce();
+        let mut frame = [0u8; 255];
+        let len = match &report {
+            Report::Panic(record) => record.seal(&nonce, &mut frame),
+            Report::Telemetry(telemetry) => telemetry.seal(&nonce, &mut frame),
+        };
+        let len = self.wrap(&mut frame, len, false, now);
+        if self.slot_wait(len, now) > 0 {
+            return false;
+        }
+        let config = self.config_for(&nonce);
+        let airtime_ms = config.params().time_on_air_us(len).div_ceil(1000);
+        match self.duty.reserve(config.frequency_hz(), airtime_ms, now) {
+            Ok(()) => {}
+            Err(Exceeded::Until(_)) => return false,
+            Err(Exceeded::TooLong) => {
+                warn!(log, TAG, "{} takes {} ms on air, more than the duty cycle allows", report.name(), airtime_ms);
+                self.report_done(&report, now);
+                return false;
+            }
+        }
+        self.nonces.next_nonce();
+        self.listening = false;
+        match self.tune(&nonce, radio, log).and_then(|()| radio.transmit(&frame[..len])) {
+            Ok(()) => {
+                info!(log, TAG, "sent {}", report.name());
+                if let Some(mac) = &mut self.lorawan {
+                    mac.sent();
+                }
+                self.report_done(&report, now);
+                self.tx.sent += 1;
+                true
+            }
+            Err(_) => {
+                error!(log, TAG, "LoRa transmit failed");
+                self.duty.release(config.frequency_hz(), airtime_ms, now);
+                self.tx.failed += 1;
+                false
+            }
+        }
+    }
+
+    /// With the relay role, keeps the radio listening while the ACK exchange
+    /// does not need it, and forwards the relay frames heard.
+    fn listen<R: Radio, L: LogSink>(&mut self, radio: &mut R, log: &mut Logger<L>, now: u64) {
+        if self.relay.is_none() || matches!(self.ack_state, AckState::Sending | AckState::Listening { .. }) {
+            return;
+        }
+        if radio.is_transmitting().unwrap_or(true) {
+            self.listening = false;
+            return;
+        }
+        if self.forward(radio, log, now) {
+            return;
+        }
+        if !self.listening {
+            if radio.start_receive().is_err() {
+                error!(log, TAG, "LoRa receive failed");
+                return;
+            }
+            self.listening = true;
+        }
+        let mut buf = [0u8; 255];
+        if let Ok(Some(size)) = radio.receive(&mut buf)
+            && let Some(relay) = &mut self.relay
+            && relay.offer(&buf[..size], now)
+        {
+            debug!(log, TAG, "frame queued for relaying");
+        }
+    }
+
+    /// Sends the frame the relay role queued; returns true when it went on air.
+    fn forward<R: Radio, L: LogSink>(&mut self, radio: &mut R, log: &mut Logger<L>, now: u64) -> bool {
+        // relays do not follow hopping: forwarded frames go out on the base
+        // channel, also when the beacon's own last frame hopped elsewhere
+        let config = self.radio_config;
+        let Some(relay) = &mut self.relay else { return false };
+        let Some(frame) = relay.pending() else { return false };
+        let airtime_ms = config.params().time_on_air_us(frame.len()).div_ceil(1000);
+        let sent = if self.duty.reserve(config.frequency_hz(), airtime_ms, now).is_err() {
+            warn!(log, TAG, "duty cycle used up, frame not relayed");
+            false
+        } else if self.hopping && radio.configure(&config).is_err() {
+            error!(log, TAG, "LoRa configuration failed");
+            self.duty.release(config.frequency_hz(), airtime_ms, now);
+            self.tx.failed += 1;
+            false
+        } else if radio.transmit(frame).is_err() {
+            error!(log, TAG, "relay transmit failed");
+            self.duty.release(config.frequency_hz(), airtime_ms, now);
+            self.tx.failed += 1;
+            false
+        } else {
+            debug!(log, TAG, "relayed frame");
+            self.tx.sent += 1;
+            true
+        };
+        relay.done(sent);
+        self.listening = false;
+        sent
+    }
+
+    /// Time until a `len`-byte frame may start in the beacon's slot; 0
+    /// without time slots or before GPS time is known. With acknowledged
+    /// delivery the slot also has to hold the ACK.
+    fn slot_wait(&self, len: usize, now: u64) -> u64 {
+        let (Some(plan), Some(slot), Some(offset)) = (self.tdma, self.slot(), self.time_offset_ms) else {
+            return 0;
+        };
+        let params = self.radio_config.params();
+        let mut airtime_us = params.time_on_air_us(len) as u64;
+        if self.ack.is_some() {
+            airtime_us += params.time_on_air_us(ACK_LEN) as u64;
+        }
+        plan.wait_ms(slot, now + offset, airtime_us.div_ceil(1000))
+    }
+
+    /// Settings the frame in `lora_buf` goes out with.
+    fn tx_config(&self) -> RadioConfig {
+        self.config_for(&self.frame_nonce)
+    }
+
+    /// Settings a frame with `nonce` goes out with: with hopping on the
+    /// channel its nonce picks, with LoRaWAN on the MAC's next channel.
+    fn config_for(&self, nonce: &[u8; NONCE_LEN]) -> RadioConfig {
+        if let Some(mac) = &self.lorawan {
+            return mac.tx_config();
+        }
+        let config = self.radio_config;
+        if !self.hopping {
+            return config;
+        }
+        let channel = hop_channel(nonce, config.region().channels());
+        // a channel of the configured region, so valid as well
+        config.with_channel(config.region(), channel).unwrap_or(config)
+    }
+
+    /// With hopping, tunes the radio to the channel of the frame with
+    /// `nonce`; with LoRaWAN back from the receive windows to the uplink.
+    fn tune<R: Radio, L: LogSink>(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        radio: &mut R,
+        log: &mut Logger<L>,
+    ) -> Result<(), R::Error> {
+        if !self.hopping && self.lorawan.is_none() {
+            return Ok(());
+        }
+        let config = self.config_for(nonce);
+        if self.hopping {
+            debug!(log, TAG, "hopping to channel {}", config.channel());
+        }
+        radio.configure(&config)
+    }
+
+    /// Counts a `len`-byte frame against the duty-cycle budget of its
+    /// sub-band, or drops the frame in `lora_buf` when it never fits; the
+    /// caller holds it back until `Exceeded::Until`.
+    fn reserve_airtime<L: LogSink>(&mut self, len: usize, log: &mut Logger<L>, now: u64) -> Result<(), Exceeded> {
+        let config = self.tx_config();
+        let airtime_ms = config.params().time_on_air_us(len).div_ceil(1000);
+        let result = self.duty.reserve(config.frequency_hz(), airtime_ms, now);
+        match result {
+            Ok(()) => {}
+            Err(Exceeded::Until(t)) => {
+                info!(log, TAG, "duty cycle used up, holding frame for {} s", (t - now).div_ceil(1000));
+            }
+            Err(Exceeded::TooLong) => {
+                warn!(log, TAG, "frame takes {} ms on air, more than the duty cycle allows; dropped", airtime_ms);
+                self.last_lora_packet_len = 0;
+                self.held_until = None;
+            }
+        }
+        result
+    }
+
+    /// Gives back what `reserve_airtime` counted for a frame that did not go out.
+    fn release_airtime(&mut self, len: usize, now: u64) {
+        let config = self.tx_config();
+        let airtime_ms = config.params().time_on_air_us(len).div_ceil(1000);
+        self.duty.release(config.frequency_hz(), airtime_ms, now);
+    }
+}
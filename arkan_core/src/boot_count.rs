@@ -0,0 +1,142 @@
+//! Boot counter kept in the persistent store.
+//!
+//! The beacon folds the boot count into the upper half of its nonce counter so
+//! that a reset never reuses a ChaCha20 nonce. The LoRaWAN counters that must
+//! not repeat across resets are kept the same way at their own offset. Counts are appended as 4-byte
+//! little-endian slots into one of two erase blocks; once it is full the next
+//! count goes into the other one before the full block is erased, so a power
+//! loss at any point leaves the last count in the store.
+
+use crate::hal::Store;
+
+const SLOT: u32 = 4;
+const EMPTY: u32 = u32::MAX;
+/// Erase blocks the counter takes, starting at its offset.
+pub const BLOCKS: u32 = 2;
+
+/// Reads the last boot count stored in the erase blocks at `offset`, if any.
+pub fn current<S: Store>(store: &mut S, offset: u32) -> Result<Option<u32>, S::Error> {
+    Ok(latest(store, offset)?.map(|(_, _, last)| last))
+}
+
+/// Increments the stored boot count and returns the new value (0 on first boot).
+pub fn increment<S: Store>(store: &mut S, offset: u32) -> Result<u32, S::Error> {
+    let Some((block, used, last)) = latest(store, offset)? else {
+        store.write(offset, &0u32.to_le_bytes())?;
+        return Ok(0);
+    };
+    let next = last.wrapping_add(1);
+    append(store, offset, block, used, next)?;
+    Ok(next)
+}
+
+/// Stores `count`, which must lie above the stored one, as the current count.
+pub fn advance<S: Store>(store: &mut S, offset: u32, count: u32) -> Result<(), S::Error> {
+    match latest(store, offset)? {
+        Some((block, used, _)) => append(store, offset, block, used, count),
+        None => store.write(offset, &count.to_le_bytes()),
+    }
+}
+
+/// Writes `count` after the `used` slots of `block`, or into the other block
+/// when it is full.
+fn append<S: Store>(store: &mut S, offset: u32, block: u32, used: u32, count: u32) -> Result<(), S::Error> {
+    if used < S::ERASE_SIZE / SLOT {
+        return store.write(block + used * SLOT, &count.to_le_bytes());
+    }
+    let spare = if block == offset { offset + S::ERASE_SIZE } else { offset };
+    // the full block keeps the count until the spare one has the next
+    store.erase(spare, S::ERASE_SIZE)?;
+    store.write(spare, &count.to_le_bytes())?;
+    store.erase(block, S::ERASE_SIZE)
+}
+
+/// Returns the block with the highest count, its used slots and that count.
+fn latest<S: Store>(store: &mut S, offset: u32) -> Result<Option<(u32, u32, u32)>, S::Error> {
+    let mut latest: Option<(u32, u32, u32)> = None;
+    for block in (0..BLOCKS).map(|i| offset + i * S::ERASE_SIZE) {
+        let (used, last) = scan(store, block)?;
+        if let Some(last) = last
+            && latest.is_none_or(|(_, _, max)| last > max)
+        {
+            latest = Some((block, used, last));
+        }
+    }
+    Ok(latest)
+}
+
+/// Returns the number of used slots and the value in the last one.
+fn scan<S: Store>(store: &mut S, offset: u32) -> Result<(u32, Option<u32>), S::Error> {
+    let mut used = 0;
+    let mut last = None;
+    let mut slot = [0u8; SLOT as usize];
+    for i in 0..S::ERASE_SIZE / SLOT {
+        store.read(offset + i * SLOT, &mut slot)?;
+        let v = u32::from_le_bytes(slot);
+        if v == EMPTY {
+            break;
+        }
+        used = i + 1;
+        last = Some(v);
+    }
+    Ok((used, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockStore;
+
+    #[test]
+    fn counts_from_zero() {
+        let mut store = MockStore::new(3 * 4096);
+        assert_eq!(current(&mut store, 4096), Ok(None));
+        assert_eq!(increment(&mut store, 4096), Ok(0));
+        assert_eq!(increment(&mut store, 4096), Ok(1));
+        assert_eq!(current(&mut store, 4096), Ok(Some(1)));
+        assert!(store.data[..4096].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn moves_to_the_other_block_when_full() {
+        let mut store = MockStore::new(2 * 4096);
+        for i in 0..1024 {
+            assert_eq!(increment(&mut store, 0), Ok(i));
+        }
+        assert_eq!(store.erase_count, 0);
+        assert_eq!(increment(&mut store, 0), Ok(1024));
+        assert_eq!(current(&mut store, 0), Ok(Some(1024)));
+        assert!(store.data[..4096].iter().all(|&b| b == 0xFF));
+        for i in 1025..2049 {
+            assert_eq!(increment(&mut store, 0), Ok(i));
+        }
+        assert_eq!(current(&mut store, 0), Ok(Some(2048)));
+        assert!(store.data[4096..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn advances_past_skipped_counts() {
+        let mut store = MockStore::new(2 * 4096);
+        assert_eq!(advance(&mut store, 0, 256), Ok(()));
+        assert_eq!(current(&mut store, 0), Ok(Some(256)));
+        assert_eq!(advance(&mut store, 0, 512), Ok(()));
+        assert_eq!(increment(&mut store, 0), Ok(513));
+    }
+
+    #[test]
+    fn power_loss_while_moving_keeps_the_count() {
+        // erase the spare block, write it, erase the full one
+        for cut in 0..3 {
+            let mut store = MockStore::new(2 * 4096);
+            for _ in 0..1024 {
+                increment(&mut store, 0).unwrap();
+            }
+            store.power_cut = Some(cut);
+            increment(&mut store, 0).unwrap();
+            store.power_cut = None;
+            assert!(current(&mut store, 0).unwrap() >= Some(1023));
+            let next = increment(&mut store, 0).unwrap();
+            assert!(next >= 1024, "cut after {cut} steps: count {next}");
+        }
+    }
+}
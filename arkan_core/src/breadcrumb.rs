@@ -0,0 +1,308 @@
+//! Breadcrumb trail: several fixes sent in one frame.
+//!
+//! Track frame: `[header][nonce: 12][ciphertext]`, with the plaintext
+//!
+//! `[count][first point, bit-packed][dt, dlat, dlon]...[age]`
+//!
+//! Points are in time order and quantized to the precision in flag bits 1-2,
+//! like compact frames. `dt` is the whole seconds since the previous point,
+//! `dlat`/`dlon` the change since the previous point, all zigzag varints.
+//! `age` is how many seconds before the transmission the newest point was
+//! taken, so the receiver can timestamp every point against its own clock.
+
+use crate::compact::{apply_keystream, get_varint, pack, put_varint, unpack, Precision};
+use crate::decryption::{DecryptError, NONCE_LEN};
+use crate::encryption::GpsCoord;
+use crate::protocol::{header, Kind, HEADER_LEN};
+
+/// Most points the receiver unpacks from one frame.
+pub const MAX_POINTS: usize = 64;
+const MAX_PLAIN: usize = 255 - HEADER_LEN - NONCE_LEN;
+/// Three varints of at most 5 bytes.
+const MAX_POINT_LEN: usize = 15;
+const MAX_AGE_LEN: usize = 5;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BatchConfig {
+    /// Points per frame, at most `MAX_POINTS`. A frame is also sent early when
+    /// the next point might not fit.
+    pub points: u8,
+    /// Fixes closer to the previous point than this are skipped.
+    pub interval_ms: u64,
+    pub precision: Precision,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        // one frame per minute
+        Self { points: 12, interval_ms: 5_000, precision: Precision::E5 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TrackPoint {
+    pub coord: GpsCoord,
+    /// Seconds between taking the fix and sending the frame.
+    pub age_s: u32,
+}
+
+pub type Points = heapless::Vec<TrackPoint, MAX_POINTS>;
+
+/// Beacon side: collects fixes and seals them into a track frame.
+pub struct Batch {
+    config: BatchConfig,
+    plain: [u8; MAX_PLAIN],
+    /// Longest plaintext that leaves room for the framing around the frame.
+    max_plain: usize,
+    len: usize,
+    count: u8,
+    first_ms: u64,
+    last_ms: u64,
+    /// Seconds of the last point since the first one.
+    last_s: u32,
+    /// Last point, quantized.
+    last_q: (i32, i32),
+}
+
+impl Batch {
+    pub fn new(config: BatchConfig) -> Self {
+        let points = config.points.clamp(1, MAX_POINTS as u8);
+        Self {
+            config: BatchConfig { points, ..config },
+            plain: [0; MAX_PLAIN],
+            max_plain: MAX_PLAIN,
+            len: 1,
+            count: 0,
+            first_ms: 0,
+            last_ms: 0,
+            last_s: 0,
+            last_q: (0, 0),
+        }
+    }
+
+    /// Keeps `bytes` of the 255-byte frame free for the framing it is wrapped
+    /// in on air, such as a relay frame.
+    pub fn set_overhead(&mut self, bytes: usize) {
+        self.max_plain = MAX_PLAIN.saturating_sub(bytes);
+    }
+
+    /// Number of collected points.
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The frame is full and should be sent.
+    pub fn is_ready(&self) -> bool {
+        self.count >= self.config.points || self.len + MAX_POINT_LEN + MAX_AGE_LEN > self.max_plain
+    }
+
+    /// Adds a fix unless the batch is full or the last point is more recent than
+    /// the interval; returns whether it was taken.
+    pub fn push(&mut self, now_ms: u64, coord: &GpsCoord) -> bool {
+        if self.is_ready() || (self.count > 0 && now_ms.saturating_sub(self.last_ms) < self.config.interval_ms) {
+            return false;
+        }
+        let p = self.config.precision;
+        let q = (p.quantize(coord.lat_deg_e7), p.quantize(coord.lon_deg_e7));
+        if self.count == 0 {
+            self.len += pack(p, coord, &mut self.plain[self.len..]);
+            self.first_ms = now_ms;
+        } else {
+            let s = secs(now_ms - self.first_ms);
+            self.len += put_varint(s.saturating_sub(self.last_s) as i32, &mut self.plain[self.len..]);
+            self.len += put_varint(q.0 - self.last_q.0, &mut self.plain[self.len..]);
+            self.len += put_varint(q.1 - self.last_q.1, &mut self.plain[self.len..]);
+            self.last_s = s;
+        }
+        self.last_ms = now_ms;
+        self.last_q = q;
+        self.count += 1;
+        true
+    }
+
+    /// Writes the collected points as a track frame into `buf` and empties the
+    /// batch; returns the frame length.
+    pub fn seal(&mut self, now_ms: u64, nonce: &[u8; NONCE_LEN], flags: u8, buf: &mut [u8; 255]) -> usize {
+        self.plain[0] = self.count;
+        let mut n = self.len;
+        n += put_varint(secs(now_ms.saturating_sub(self.last_ms)) as i32, &mut self.plain[n..]);
+
+        buf[0] = header(Kind::Track, flags | self.config.precision.flags());
+        buf[HEADER_LEN..HEADER_LEN + NONCE_LEN].copy_from_slice(nonce);
+        let ct = &mut buf[HEADER_LEN + NONCE_LEN..HEADER_LEN + NONCE_LEN + n];
+        ct.copy_from_slice(&self.plain[..n]);
+        apply_keystream(nonce, ct);
+
+        *self = Self { max_plain: self.max_plain, ..Self::new(self.config) };
+        HEADER_LEN + NONCE_LEN + n
+    }
+}
+
+fn secs(ms: u64) -> u32 {
+    ((ms + 500) / 1000).min(i32::MAX as u64) as u32
+}
+
+/// Decrypts the body (after the header) of a track frame; returns its nonce and points, oldest first.
+pub fn decode(flags: u8, body: &[u8]) -> Result<([u8; NONCE_LEN], Points), DecryptError> {
+    if body.len() < NONCE_LEN + 2 {
+        return Err(DecryptError::PacketTooShort);
+    }
+    let (nonce, ct) = body.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| DecryptError::PacketTooShort)?;
+    let mut plain = [0u8; MAX_PLAIN];
+    let plain = plain.get_mut(..ct.len()).ok_or(DecryptError::MalformedPlaintext)?;
+    plain.copy_from_slice(ct);
+    apply_keystream(&nonce, plain);
+
+    let p = Precision::from_flags(flags);
+    let count = plain[0] as usize;
+    if count == 0 || count > MAX_POINTS {
+        return Err(DecryptError::MalformedPlaintext);
+    }
+    let mut pos = 1;
+    let first = unpack(p, plain.get(pos..).unwrap_or_default()).ok_or(DecryptError::PacketTooShort)?;
+    pos += p.packed_len();
+
+    // (seconds since the first point, quantized position)
+    let mut raw: heapless::Vec<(u32, i32, i32), MAX_POINTS> = heapless::Vec::new();
+    let (mut s, mut lat, mut lon) = (0u32, p.quantize(first.lat_deg_e7), p.quantize(first.lon_deg_e7));
+    let _ = raw.push((s, lat, lon));
+    let mut next = || -> Result<i32, DecryptError> {
+        let (v, used) = get_varint(&plain[pos..]).ok_or(DecryptError::MalformedPlaintext)?;
+        pos += used;
+        Ok(v)
+    };
+    for _ in 1..count {
+        s = s.saturating_add(next()? as u32);
+        lat = lat.wrapping_add(next()?);
+        lon = lon.wrapping_add(next()?);
+        let _ = raw.push((s, lat, lon));
+    }
+    let age = next()? as u32;
+
+    let points = raw
+        .iter()
+        .map(|&(t, lat, lon)| TrackPoint {
+            coord: GpsCoord { lat_deg_e7: p.dequantize(lat), lon_deg_e7: p.dequantize(lon) },
+            age_s: age.saturating_add(s - t),
+        })
+        .collect();
+    Ok((nonce, points))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ack_frame, parse_header, MIC_LEN, TAG_LEN};
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 0, 0, 0, 1, 0, 0, 0, 5];
+
+    fn at(lat: i32, lon: i32) -> GpsCoord {
+        GpsCoord { lat_deg_e7: lat, lon_deg_e7: lon }
+    }
+
+    fn round_trip(batch: &mut Batch, now_ms: u64) -> Points {
+        let mut buf = [0u8; 255];
+        let len = batch.seal(now_ms, &NONCE, 0, &mut buf);
+        let (kind, flags, body) = parse_header(&buf[..len]).unwrap();
+        assert_eq!(kind, Kind::Track);
+        let (nonce, points) = decode(flags, body).unwrap();
+        assert_eq!(nonce, NONCE);
+        points
+    }
+
+    #[test]
+    fn points_keep_their_age() {
+        let mut batch = Batch::new(BatchConfig { points: 3, interval_ms: 5_000, precision: Precision::E5 });
+        assert!(batch.push(10_000, &at(504_501_234, 305_234_567)));
+        // too soon after the first point
+        assert!(!batch.push(12_000, &at(0, 0)));
+        assert!(batch.push(15_400, &at(504_502_000, 305_230_000)));
+        assert!(!batch.is_ready());
+        assert!(batch.push(21_000, &at(-1, 1_800_000_000)));
+        assert!(batch.is_ready());
+        assert!(!batch.push(40_000, &at(0, 0)));
+
+        let points = round_trip(&mut batch, 23_000);
+        assert!(batch.is_empty());
+        assert_eq!(
+            points[..],
+            [
+                TrackPoint { coord: at(504_501_200, 305_234_600), age_s: 13 },
+                TrackPoint { coord: at(504_502_000, 305_230_000), age_s: 8 },
+                TrackPoint { coord: at(0, 1_800_000_000), age_s: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn large_batches_fit_one_frame() {
+        let mut batch = Batch::new(BatchConfig { points: 64, interval_ms: 0, precision: Precision::E7 });
+        let mut taken = 0;
+        // worst case: every delta needs five bytes
+        while batch.push(taken * 1_000, &at(if taken % 2 == 0 { 900_000_000 } else { -900_000_000 }, 0)) {
+            taken += 1;
+        }
+        assert!(batch.is_ready());
+        assert!(taken < 64);
+        let points = round_trip(&mut batch, taken * 1_000);
+        assert_eq!(points.len(), taken as usize);
+        assert_eq!(points.last().unwrap().coord, at(if taken % 2 == 0 { -900_000_000 } else { 900_000_000 }, 0));
+    }
+
+    #[test]
+    fn frames_leave_room_for_the_overhead() {
+        // hours apart and on opposite sides of the globe: the longest points there are
+        let fill = |batch: &mut Batch| {
+            let mut i = 0;
+            let lon = |i: u64| if i < 3 { 0 } else if i.is_multiple_of(2) { 1_000_000_000 } else { -1_000_000_000 };
+            while batch.push(i * 10_000_000, &at(if i.is_multiple_of(2) { 900_000_000 } else { -900_000_000 }, lon(i))) {
+                i += 1;
+            }
+            let mut buf = [0u8; 255];
+            batch.seal(i * 10_000_000, &NONCE, 0, &mut buf)
+        };
+        let mut batch = Batch::new(BatchConfig { points: 64, interval_ms: 0, precision: Precision::E7 });
+        assert!(fill(&mut batch) > 255 - HEADER_LEN - MIC_LEN);
+        batch.set_overhead(HEADER_LEN + MIC_LEN);
+        assert!(fill(&mut batch) <= 255 - HEADER_LEN - MIC_LEN);
+        // still in effect for the next frame
+        assert!(fill(&mut batch) <= 255 - HEADER_LEN - MIC_LEN);
+    }
+
+    #[test]
+    fn track_frames_leave_the_ack_keystream_alone() {
+        // an upload batch at its longest: every delta needs four or five bytes
+        let mut batch = Batch::new(BatchConfig { points: 16, interval_ms: 0, precision: Precision::E5 });
+        for i in 0..16 {
+            assert!(batch.push(i * 1_000, &at(if i % 2 == 0 { 900_000_000 } else { -900_000_000 }, 0)));
+        }
+        let mut buf = [0u8; 255];
+        let len = batch.seal(16_000, &NONCE, 0, &mut buf);
+        let ct_len = len - HEADER_LEN - NONCE_LEN;
+        assert!(ct_len > 64);
+
+        let mut keystream = [0u8; MAX_PLAIN];
+        apply_keystream(&NONCE, &mut keystream[..ct_len]);
+        let tag = &ack_frame(&NONCE)[HEADER_LEN + NONCE_LEN..];
+        assert_eq!(tag.len(), TAG_LEN);
+        assert!(!keystream[..ct_len].windows(TAG_LEN).any(|w| w == tag));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let mut batch = Batch::new(BatchConfig::default());
+        batch.push(0, &at(1, 1));
+        batch.push(10_000, &at(2, 2));
+        let mut buf = [0u8; 255];
+        let len = batch.seal(10_000, &NONCE, 0, &mut buf);
+        let (_, flags, body) = parse_header(&buf[..len]).unwrap();
+        assert!(decode(flags, body).is_ok());
+        assert!(decode(flags, &body[..body.len() - 1]).is_err());
+        assert!(decode(flags, &body[..12]).is_err());
+    }
+}
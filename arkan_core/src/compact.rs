@@ -0,0 +1,581 @@
+//! Compact position frames.
+//!
+//! A full frame (`Kind::Position`) spends 12 bytes on the nonce and 8 on raw
+//! e7 coordinates. Compact frames carry a 1-byte beacon tag (the last byte of
+//! the device id) and the low 16 bits of the nonce counter instead; the
+//! receiver restores the nonce from the last frame it decoded from that beacon.
+//! While it knows several beacons with the same tag it cannot tell which one
+//! sent a compact frame, and only decodes their full frames.
+//! Coordinates are quantized to a selectable precision and either bit-packed
+//! (`Kind::Compact`) or sent as zigzag varint deltas (`Kind::Delta`) against an
+//! earlier fix, named by the low byte of its counter.
+//!
+//! - Compact: `[header][tag][seq: 2][ciphertext]`
+//! - Delta: `[header][tag][seq: 2][ref][ciphertext]`
+//!
+//! Flag bits 1-2 carry the precision. The ciphertext is the body XORed with the
+//! ChaCha20 keystream of the restored nonce, as for full frames.
+
+use core::fmt;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+
+use crate::breadcrumb::{self, Points};
+use crate::decryption::{decrypt_packet, DecryptError, NONCE_LEN};
+use crate::encryption::{GpsCoord, KEY};
+use crate::gps_proccess::{encode_full, NonceCounter};
+use crate::hal::LogSink;
+use crate::log::Logger;
+use crate::protocol::{header, Kind, HEADER_LEN};
+
+const PRECISION_SHIFT: u8 = 1;
+const PRECISION_MASK: u8 = 0b0110;
+/// Header, beacon tag and sequence number.
+const PREFIX_LEN: usize = HEADER_LEN + 3;
+/// Without ACKs the beacon cannot know what the receiver decoded, so it sends a
+/// full frame every this many frames.
+pub const FULL_INTERVAL: u8 = 16;
+
+/// Decimal places of degrees kept by compact frames.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Precision {
+    /// About 11 m
+    E4,
+    /// About 1.1 m
+    E5,
+    /// About 11 cm
+    E6,
+    /// Full GPS resolution, about 1.1 cm
+    E7,
+}
+
+impl Precision {
+    pub const ALL: [Precision; 4] = [Precision::E4, Precision::E5, Precision::E6, Precision::E7];
+
+    pub const fn decimals(self) -> u32 {
+        match self {
+            Precision::E4 => 4,
+            Precision::E5 => 5,
+            Precision::E6 => 6,
+            Precision::E7 => 7,
+        }
+    }
+
+    const fn step(self) -> i64 {
+        10i64.pow(7 - self.decimals())
+    }
+
+    pub(crate) const fn flags(self) -> u8 {
+        ((self.decimals() - 4) as u8) << PRECISION_SHIFT
+    }
+
+    pub(crate) const fn from_flags(flags: u8) -> Self {
+        Self::ALL[((flags & PRECISION_MASK) >> PRECISION_SHIFT) as usize]
+    }
+
+    /// Rounds an e7 value to this precision, in units of the precision.
+    pub fn quantize(self, e7: i32) -> i32 {
+        (e7 as i64 + self.step() / 2).div_euclid(self.step()) as i32
+    }
+
+    pub fn dequantize(self, q: i32) -> i32 {
+        (q as i64 * self.step()) as i32
+    }
+
+    /// Coordinates as the receiver decodes them.
+    pub fn round(self, c: &GpsCoord) -> GpsCoord {
+        GpsCoord {
+            lat_deg_e7: self.dequantize(self.quantize(c.lat_deg_e7)),
+            lon_deg_e7: self.dequantize(self.quantize(c.lon_deg_e7)),
+        }
+    }
+
+    /// Offset and bit width of a quantized coordinate in `-max_deg..=max_deg`.
+    const fn field(self, max_deg: u64) -> (u64, u32) {
+        let half = max_deg * 10u64.pow(self.decimals());
+        (half, 64 - (2 * half).leading_zeros())
+    }
+
+    /// Length of a bit-packed position.
+    pub const fn packed_len(self) -> usize {
+        let bits = self.field(90).1 + self.field(180).1;
+        bits.div_ceil(8) as usize
+    }
+}
+
+/// How the beacon encodes positions.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Full,
+    Compact(Precision),
+    /// Deltas against the last acknowledged fix; needs acknowledged delivery and
+    /// falls back to `Compact` without it.
+    Delta(Precision),
+}
+
+impl Encoding {
+    /// Frame length; for `Delta` with moves of up to 63 units per coordinate.
+    pub const fn frame_len(self) -> usize {
+        match self {
+            Encoding::Full => HEADER_LEN + NONCE_LEN + 8,
+            Encoding::Compact(p) => PREFIX_LEN + p.packed_len(),
+            Encoding::Delta(_) => PREFIX_LEN + 1 + 2,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Full => f.write_str("full"),
+            Encoding::Compact(p) => write!(f, "compact e{}", p.decimals()),
+            Encoding::Delta(p) => write!(f, "delta e{}", p.decimals()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Fix {
+    counter: u64,
+    /// Coordinates as decoded by the receiver.
+    coord: GpsCoord,
+}
+
+pub(crate) fn counter_of(nonce: &[u8; NONCE_LEN]) -> u64 {
+    u64::from_be_bytes(nonce[4..].try_into().unwrap_or_default())
+}
+
+pub(crate) fn apply_keystream(nonce: &[u8; NONCE_LEN], body: &mut [u8]) {
+    ChaCha20::new(KEY.secret_bytes().into(), nonce.into()).apply_keystream(body);
+}
+
+pub(crate) fn pack(p: Precision, c: &GpsCoord, out: &mut [u8]) -> usize {
+    let (lat_off, lat_bits) = p.field(90);
+    let (lon_off, _) = p.field(180);
+    let lat = (p.quantize(c.lat_deg_e7) as i64 + lat_off as i64) as u64;
+    let lon = (p.quantize(c.lon_deg_e7) as i64 + lon_off as i64) as u64;
+    let len = p.packed_len();
+    out[..len].copy_from_slice(&(lat | lon << lat_bits).to_le_bytes()[..len]);
+    len
+}
+
+pub(crate) fn unpack(p: Precision, data: &[u8]) -> Option<GpsCoord> {
+    let (lat_off, lat_bits) = p.field(90);
+    let (lon_off, _) = p.field(180);
+    let mut raw = [0u8; 8];
+    raw[..p.packed_len()].copy_from_slice(data.get(..p.packed_len())?);
+    let v = u64::from_le_bytes(raw);
+    let lat = (v & ((1 << lat_bits) - 1)) as i64 - lat_off as i64;
+    let lon = (v >> lat_bits) as i64 - lon_off as i64;
+    Some(GpsCoord { lat_deg_e7: p.dequantize(lat as i32), lon_deg_e7: p.dequantize(lon as i32) })
+}
+
+pub(crate) fn put_varint(v: i32, out: &mut [u8]) -> usize {
+    let mut z = ((v << 1) ^ (v >> 31)) as u32;
+    let mut n = 0;
+    loop {
+        let b = (z & 0x7F) as u8;
+        z >>= 7;
+        if z == 0 {
+            out[n] = b;
+            return n + 1;
+        }
+        out[n] = b | 0x80;
+        n += 1;
+    }
+}
+
+pub(crate) fn get_varint(data: &[u8]) -> Option<(i32, usize)> {
+    let mut z = 0u32;
+    for (i, &b) in data.iter().enumerate().take(5) {
+        z |= ((b & 0x7F) as u32) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some(((z >> 1) as i32 ^ -((z & 1) as i32), i + 1));
+        }
+    }
+    None
+}
+
+/// Beacon side: builds position frames in the configured encoding.
+pub struct Encoder {
+    encoding: Encoding,
+    /// Positions are acknowledged; compact frames then wait for the first ACK.
+    acked: bool,
+    /// Last fix the receiver acknowledged.
+    anchor: Option<Fix>,
+    /// Fix in the frame sent last.
+    sent: Option<Fix>,
+    frames_since_full: u8,
+}
+
+impl Encoder {
+    pub const fn new(encoding: Encoding) -> Self {
+        Self { encoding, acked: false, anchor: None, sent: None, frames_since_full: FULL_INTERVAL }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn set_acked(&mut self, acked: bool) {
+        self.acked = acked;
+        self.reset();
+    }
+
+    /// Forgets what the receiver knows, so the next frame is a full one.
+    pub fn reset(&mut self) {
+        self.anchor = None;
+        self.frames_since_full = FULL_INTERVAL;
+    }
+
+    /// Records that the frame sent with `nonce` was acknowledged.
+    pub fn acknowledged(&mut self, nonce: &[u8; NONCE_LEN]) {
+        if let Some(fix) = self.sent
+            && fix.counter == counter_of(nonce)
+        {
+            self.anchor = Some(fix);
+        }
+    }
+
+    /// Writes a position frame into `buf`; returns its length and nonce.
+    pub fn encode<S: LogSink>(
+        &mut self,
+        coord: &GpsCoord,
+        nonces: &mut NonceCounter,
+        flags: u8,
+        log: &mut Logger<S>,
+        buf: &mut [u8; 255],
+    ) -> Option<(usize, [u8; NONCE_LEN])> {
+        let nonce = nonces.next_nonce();
+        let counter = counter_of(&nonce);
+        // the receiver restores the counter from within half the 16-bit sequence space
+        let synced = if self.acked {
+            self.anchor.is_some_and(|a| counter - a.counter < 0x8000)
+        } else {
+            self.frames_since_full < FULL_INTERVAL
+        };
+        let (precision, delta) = match self.encoding {
+            Encoding::Compact(p) if synced => (p, false),
+            Encoding::Delta(p) if synced => (p, self.acked),
+            _ => {
+                let len = encode_full(coord, &nonce, flags, log, buf)?;
+                self.sent = Some(Fix { counter, coord: *coord });
+                self.frames_since_full = 1;
+                return Some((len, nonce));
+            }
+        };
+
+        buf[1] = nonce[3];
+        buf[2..4].copy_from_slice(&(counter as u16).to_le_bytes());
+        let mut len = PREFIX_LEN;
+        let kind = match (delta, self.anchor) {
+            (true, Some(anchor)) => {
+                buf[len] = anchor.counter as u8;
+                len += 1;
+                let body = len;
+                len += put_varint(precision.quantize(coord.lat_deg_e7) - precision.quantize(anchor.coord.lat_deg_e7), &mut buf[len..]);
+                len += put_varint(precision.quantize(coord.lon_deg_e7) - precision.quantize(anchor.coord.lon_deg_e7), &mut buf[len..]);
+                apply_keystream(&nonce, &mut buf[body..len]);
+                Kind::Delta
+            }
+            _ => {
+                let body = len;
+                len += pack(precision, coord, &mut buf[len..]);
+                apply_keystream(&nonce, &mut buf[body..len]);
+                Kind::Compact
+            }
+        };
+        buf[0] = header(kind, flags | precision.flags());
+        self.sent = Some(Fix { counter, coord: precision.round(coord) });
+        self.frames_since_full = self.frames_since_full.saturating_add(1);
+        Some((len, nonce))
+    }
+}
+
+/// What the receiver knows about one beacon.
+struct Track {
+    device_id: [u8; 4],
+    counter: u64,
+    /// Recently decoded fixes, for delta frames.
+    history: [Option<Fix>; 8],
+    next: usize,
+}
+
+/// Receiver side: decodes position frames of up to `N` beacons.
+pub struct Decoder<const N: usize> {
+    /// The beacon heard from last at the back.
+    tracks: heapless::Vec<Track, N>,
+}
+
+impl<const N: usize> Decoder<N> {
+    pub const fn new() -> Self {
+        Self { tracks: heapless::Vec::new() }
+    }
+
+    /// Decodes the body (after the header) of a position frame; returns its nonce and position.
+    pub fn decode(&mut self, kind: Kind, flags: u8, body: &[u8]) -> Result<([u8; NONCE_LEN], GpsCoord), DecryptError> {
+        let (nonce, coord) = match kind {
+            Kind::Position => {
+                let coord = decrypt_packet(body)?;
+                (body[..NONCE_LEN].try_into().unwrap_or_default(), coord)
+            }
+            Kind::Compact | Kind::Delta => self.decode_compact(kind, flags, body)?,
+            Kind::Ack | Kind::Track | Kind::Relay | Kind::Diagnostic | Kind::Telemetry => {
+                return Err(DecryptError::MalformedPlaintext)
+            }
+        };
+        self.record(&nonce, coord);
+        Ok((nonce, coord))
+    }
+
+    /// Decodes the body of a track frame; returns its nonce and points, oldest first.
+    pub fn decode_track(&mut self, flags: u8, body: &[u8]) -> Result<([u8; NONCE_LEN], Points), DecryptError> {
+        let (nonce, points) = breadcrumb::decode(flags, body)?;
+        if let Some(newest) = points.last() {
+            self.record(&nonce, newest.coord);
+        }
+        Ok((nonce, points))
+    }
+
+    fn decode_compact(&self, kind: Kind, flags: u8, body: &[u8]) -> Result<([u8; NONCE_LEN], GpsCoord), DecryptError> {
+        let prefix = PREFIX_LEN - HEADER_LEN;
+        if body.len() < prefix + 1 {
+            return Err(DecryptError::PacketTooShort);
+        }
+        let mut tracks = self.tracks.iter().filter(|t| t.device_id[3] == body[0]);
+        let track = tracks.next().ok_or(DecryptError::UnknownBeacon)?;
+        if tracks.next().is_some() {
+            return Err(DecryptError::AmbiguousBeacon);
+        }
+        let seq = u16::from_le_bytes([body[1], body[2]]) as u64;
+        // the counter closest to the last one seen with these low 16 bits
+        let counter = (track.counter.wrapping_add(0x8000).wrapping_sub(seq) & !0xFFFF) | seq;
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..4].copy_from_slice(&track.device_id);
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+
+        let precision = Precision::from_flags(flags);
+        let mut plain = [0u8; 16];
+        let coord = if kind == Kind::Delta {
+            let reference = body[prefix];
+            let anchor = track
+                .history
+                .iter()
+                .flatten()
+                .filter(|f| f.counter as u8 == reference && f.counter < counter)
+                .max_by_key(|f| f.counter)
+                .ok_or(DecryptError::UnknownReference)?;
+            let data = &body[prefix + 1..];
+            let n = data.len().min(plain.len());
+            plain[..n].copy_from_slice(&data[..n]);
+            apply_keystream(&nonce, &mut plain[..n]);
+            let (dlat, used) = get_varint(&plain[..n]).ok_or(DecryptError::MalformedPlaintext)?;
+            let (dlon, _) = get_varint(&plain[used..n]).ok_or(DecryptError::MalformedPlaintext)?;
+            GpsCoord {
+                lat_deg_e7: precision.dequantize(precision.quantize(anchor.coord.lat_deg_e7) + dlat),
+                lon_deg_e7: precision.dequantize(precision.quantize(anchor.coord.lon_deg_e7) + dlon),
+            }
+        } else {
+            let data = &body[prefix..];
+            let n = precision.packed_len();
+            if data.len() < n {
+                return Err(DecryptError::PacketTooShort);
+            }
+            plain[..n].copy_from_slice(&data[..n]);
+            apply_keystream(&nonce, &mut plain[..n]);
+            unpack(precision, &plain).ok_or(DecryptError::MalformedPlaintext)?
+        };
+        Ok((nonce, coord))
+    }
+
+    fn record(&mut self, nonce: &[u8; NONCE_LEN], coord: GpsCoord) {
+        let device_id: [u8; 4] = nonce[..4].try_into().unwrap_or_default();
+        let counter = counter_of(nonce);
+        let mut track = match self.tracks.iter().position(|t| t.device_id == device_id) {
+            Some(i) => self.tracks.remove(i),
+            None => {
+                if self.tracks.is_full() {
+                    // forget the beacon heard from least recently
+                    self.tracks.remove(0);
+                }
+                Track { device_id, counter, history: [None; 8], next: 0 }
+            }
+        };
+        track.counter = track.counter.max(counter);
+        track.history[track.next] = Some(Fix { counter, coord });
+        track.next = (track.next + 1) % track.history.len();
+        let _ = self.tracks.push(track);
+    }
+}
+
+impl<const N: usize> Default for Decoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockLog;
+    use crate::log::Level;
+    use crate::protocol::parse_header;
+
+    const KYIV: GpsCoord = GpsCoord { lat_deg_e7: 504_501_234, lon_deg_e7: 305_234_567 };
+
+    fn frame(enc: &mut Encoder, nonces: &mut NonceCounter, coord: &GpsCoord) -> (Vec<u8>, [u8; 12]) {
+        let mut log = Logger::new(MockLog::default(), Level::Trace);
+        let mut buf = [0u8; 255];
+        let (len, nonce) = enc.encode(coord, nonces, 0, &mut log, &mut buf).unwrap();
+        (buf[..len].to_vec(), nonce)
+    }
+
+    fn decode(dec: &mut Decoder<4>, frame: &[u8]) -> Result<([u8; 12], GpsCoord), DecryptError> {
+        let (kind, flags, body) = parse_header(frame).unwrap();
+        dec.decode(kind, flags, body)
+    }
+
+    #[test]
+    fn quantizes_with_rounding() {
+        assert_eq!(Precision::E5.quantize(504_501_250), 5_045_013);
+        assert_eq!(Precision::E5.quantize(-504_501_250), -5_045_012);
+        assert_eq!(Precision::E4.round(&KYIV), GpsCoord { lat_deg_e7: 504_501_000, lon_deg_e7: 305_235_000 });
+        assert_eq!(Precision::E7.round(&KYIV), KYIV);
+    }
+
+    #[test]
+    fn packs_extreme_coordinates() {
+        for p in Precision::ALL {
+            for c in [
+                GpsCoord { lat_deg_e7: 900_000_000, lon_deg_e7: 1_800_000_000 },
+                GpsCoord { lat_deg_e7: -900_000_000, lon_deg_e7: -1_800_000_000 },
+                KYIV,
+            ] {
+                let mut buf = [0u8; 8];
+                let n = pack(p, &c, &mut buf);
+                assert_eq!(n, p.packed_len());
+                assert_eq!(unpack(p, &buf[..n]), Some(p.round(&c)));
+            }
+        }
+        assert_eq!([4, 5, 6, 7].map(|d| Precision::ALL[d - 4].packed_len()), [6, 7, 8, 8]);
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let mut buf = [0u8; 5];
+        for v in [0, 1, -1, 63, -64, 64, i32::MAX, i32::MIN] {
+            let n = put_varint(v, &mut buf);
+            assert_eq!(get_varint(&buf[..n]), Some((v, n)));
+        }
+        assert_eq!(put_varint(-64, &mut buf), 1);
+    }
+
+    #[test]
+    fn compact_frames_follow_a_full_frame() {
+        let mut enc = Encoder::new(Encoding::Compact(Precision::E5));
+        let mut dec = Decoder::new();
+        let mut nonces = NonceCounter::new([1, 2, 3, 4], 2);
+
+        let (full, _) = frame(&mut enc, &mut nonces, &KYIV);
+        assert_eq!(full.len(), Encoding::Full.frame_len());
+        let (compact, nonce) = frame(&mut enc, &mut nonces, &KYIV);
+        assert_eq!(compact.len(), Encoding::Compact(Precision::E5).frame_len());
+        assert_eq!(compact[0], 0x32);
+
+        // nothing to restore the nonce from yet
+        assert_eq!(decode(&mut dec, &compact), Err(DecryptError::UnknownBeacon));
+        assert_eq!(decode(&mut dec, &full), Ok((full[1..13].try_into().unwrap(), KYIV)));
+        assert_eq!(decode(&mut dec, &compact), Ok((nonce, Precision::E5.round(&KYIV))));
+
+        for _ in 2..FULL_INTERVAL {
+            frame(&mut enc, &mut nonces, &KYIV);
+        }
+        assert_eq!(frame(&mut enc, &mut nonces, &KYIV).0.len(), Encoding::Full.frame_len());
+    }
+
+    #[test]
+    fn compact_frames_of_beacons_sharing_a_tag_are_dropped() {
+        let mut dec = Decoder::new();
+        let mut enc = Encoder::new(Encoding::Compact(Precision::E5));
+        let mut nonces = NonceCounter::new([1, 2, 3, 4], 2);
+        let (full, _) = frame(&mut enc, &mut nonces, &KYIV);
+        let (compact, _) = frame(&mut enc, &mut nonces, &KYIV);
+        decode(&mut dec, &full).unwrap();
+
+        let mut other = Encoder::new(Encoding::Compact(Precision::E5));
+        let mut other_nonces = NonceCounter::new([9, 9, 9, 4], 0);
+        let (other_full, _) = frame(&mut other, &mut other_nonces, &KYIV);
+        decode(&mut dec, &other_full).unwrap();
+        // either beacon may have sent it
+        assert_eq!(decode(&mut dec, &compact), Err(DecryptError::AmbiguousBeacon));
+        assert!(decode(&mut dec, &full).is_ok());
+    }
+
+    #[test]
+    fn forgets_the_beacon_heard_from_least_recently() {
+        let mut dec = Decoder::new();
+        let mut beacons: Vec<_> = (0..5u8)
+            .map(|id| (Encoder::new(Encoding::Compact(Precision::E5)), NonceCounter::new([0, 0, 0, id], 0)))
+            .collect();
+        let mut compact = Vec::new();
+        for (enc, nonces) in &mut beacons[..4] {
+            decode(&mut dec, &frame(enc, nonces, &KYIV).0).unwrap();
+            compact.push(frame(enc, nonces, &KYIV).0);
+        }
+        // beacon 0 was heard first but also last before beacon 4 came
+        decode(&mut dec, &compact[0]).unwrap();
+        let (enc, nonces) = &mut beacons[4];
+        decode(&mut dec, &frame(enc, nonces, &KYIV).0).unwrap();
+        assert!(decode(&mut dec, &compact[0]).is_ok());
+        assert_eq!(decode(&mut dec, &compact[1]), Err(DecryptError::UnknownBeacon));
+    }
+
+    #[test]
+    fn deltas_refer_to_the_acknowledged_fix() {
+        let mut enc = Encoder::new(Encoding::Delta(Precision::E5));
+        enc.set_acked(true);
+        let mut dec = Decoder::new();
+        let mut nonces = NonceCounter::new([1, 2, 3, 4], 2);
+
+        let (full, nonce) = frame(&mut enc, &mut nonces, &KYIV);
+        assert_eq!(full.len(), Encoding::Full.frame_len());
+        decode(&mut dec, &full).unwrap();
+        // still full until the receiver confirmed it has the anchor
+        let (full2, _) = frame(&mut enc, &mut nonces, &KYIV);
+        assert_eq!(full2.len(), Encoding::Full.frame_len());
+        decode(&mut dec, &full2).unwrap();
+        enc.acknowledged(&nonce);
+        assert!(enc.anchor.is_none());
+        enc.acknowledged(&full2[1..13].try_into().unwrap());
+
+        let moved = GpsCoord { lat_deg_e7: KYIV.lat_deg_e7 + 3_000, lon_deg_e7: KYIV.lon_deg_e7 - 250_000 };
+        let (delta, nonce) = frame(&mut enc, &mut nonces, &moved);
+        assert_eq!(delta[0], 0x42);
+        assert_eq!(delta.len(), Encoding::Delta(Precision::E5).frame_len() + 1);
+        assert_eq!(decode(&mut dec, &delta), Ok((nonce, Precision::E5.round(&moved))));
+
+        // a receiver that missed the reference cannot decode the delta
+        assert_eq!(decode(&mut Decoder::new(), &delta), Err(DecryptError::UnknownBeacon));
+        enc.reset();
+        assert_eq!(frame(&mut enc, &mut nonces, &moved).0.len(), Encoding::Full.frame_len());
+    }
+
+    #[test]
+    fn restores_counter_across_16_bit_wrap() {
+        let mut dec = Decoder::<4>::new();
+        let mut enc = Encoder::new(Encoding::Compact(Precision::E7));
+        let mut nonces = NonceCounter::new([1, 2, 3, 4], 2);
+        for _ in 0..0xFFF0 {
+            nonces.next_nonce();
+        }
+        let (full, _) = frame(&mut enc, &mut nonces, &KYIV);
+        decode(&mut dec, &full).unwrap();
+        for _ in 0..0x20 {
+            nonces.next_nonce();
+        }
+        let (compact, nonce) = frame(&mut enc, &mut nonces, &KYIV);
+        assert_eq!(counter_of(&nonce) & 0xFFFF, 0x0011);
+        assert_eq!(decode(&mut dec, &compact), Ok((nonce, KYIV)));
+    }
+}
@@ -0,0 +1,167 @@
+//! Line-based command console on the USB serial port.
+//!
+//! Commands:
+//! - `log <error|warn|info|debug|trace>`: set the log level
+//! - `log dump`: print the RAM log buffer
+//! - `mode <field|debug>`: hide or show sensitive output (positions, nonces, packets)
+//! - `airtime`: print frame sizes and time on air per encoding and spreading factor
+//! - `track dump`: print the store-and-forward log, positions only in debug mode
+//! - `radio`: print the radio settings
+//! - `profile <standard|long-range|fast|low-power>`: switch the radio profile
+//! - `region <eu433|eu868|us915-1..8> [channel]`: switch the frequency plan and channel (default 0)
+//! - `slots`: print the time slot plan, and on the receiver what was heard in each slot
+//! - `gps`: print the GPS input counters: bytes lost and lines dropped
+//! - `battery`: print the battery voltage, charge and level
+
+use crate::log::{Level, Mode};
+use crate::radio_config::{Profile, Region};
+
+const LINE_LEN: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    LogLevel(Level),
+    LogDump,
+    Mode(Mode),
+    Airtime,
+    TrackDump,
+    Radio,
+    Profile(Profile),
+    Region(Region, u8),
+    Slots,
+    Gps,
+    Battery,
+}
+
+/// The line was not a valid command.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnknownCommand;
+
+/// Collects typed bytes into lines; terminals send CR, CRLF or LF on enter.
+pub struct Console {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl Console {
+    pub const fn new() -> Self {
+        Self { buf: [0; LINE_LEN], len: 0, overflow: false }
+    }
+
+    /// Feeds one byte; returns the result of parsing a completed line.
+    /// Overlong lines are discarded as a whole.
+    pub fn push(&mut self, b: u8) -> Option<Result<Command, UnknownCommand>> {
+        match b {
+            b'\r' | b'\n' => {
+                let line = &self.buf[..self.len];
+                let result = if self.overflow {
+                    Some(Err(UnknownCommand))
+                } else if line.is_empty() {
+                    None
+                } else {
+                    Some(parse(line))
+                };
+                self.len = 0;
+                self.overflow = false;
+                result
+            }
+            _ if self.len < LINE_LEN => {
+                self.buf[self.len] = b;
+                self.len += 1;
+                None
+            }
+            _ => {
+                self.overflow = true;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn parse(line: &[u8]) -> Result<Command, UnknownCommand> {
+    let mut words = line.split(|&b| b == b' ').filter(|w| !w.is_empty());
+    let cmd = match (words.next(), words.next()) {
+        (Some(b"log"), Some(b"dump")) => Command::LogDump,
+        (Some(b"log"), Some(level)) => Command::LogLevel(Level::parse(level).ok_or(UnknownCommand)?),
+        (Some(b"mode"), Some(mode)) => Command::Mode(Mode::parse(mode).ok_or(UnknownCommand)?),
+        (Some(b"airtime"), None) => return Ok(Command::Airtime),
+        (Some(b"track"), Some(b"dump")) => Command::TrackDump,
+        (Some(b"radio"), None) => return Ok(Command::Radio),
+        (Some(b"slots"), None) => return Ok(Command::Slots),
+        (Some(b"gps"), None) => return Ok(Command::Gps),
+        (Some(b"battery"), None) => return Ok(Command::Battery),
+        (Some(b"profile"), Some(name)) => Command::Profile(Profile::parse(name).ok_or(UnknownCommand)?),
+        (Some(b"region"), Some(name)) => {
+            let region = Region::parse(name).ok_or(UnknownCommand)?;
+            let channel = match words.next() {
+                Some(w) => core::str::from_utf8(w).ok().and_then(|w| w.parse().ok()).ok_or(UnknownCommand)?,
+                None => 0,
+            };
+            Command::Region(region, channel)
+        }
+        _ => return Err(UnknownCommand),
+    };
+    match words.next() {
+        Some(_) => Err(UnknownCommand),
+        None => Ok(cmd),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(console: &mut Console, input: &[u8]) -> Vec<Result<Command, UnknownCommand>> {
+        input.iter().filter_map(|&b| console.push(b)).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        let mut c = Console::new();
+        assert_eq!(
+            feed(&mut c, b"log debug\r\nlog  dump\rmode field\rairtime\rtrack dump\rslots\rgps\rbattery\rbogus\n"),
+            [
+                Ok(Command::LogLevel(Level::Debug)),
+                Ok(Command::LogDump),
+                Ok(Command::Mode(Mode::Field)),
+                Ok(Command::Airtime),
+                Ok(Command::TrackDump),
+                Ok(Command::Slots),
+                Ok(Command::Gps),
+                Ok(Command::Battery),
+                Err(UnknownCommand),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_radio_commands() {
+        let mut c = Console::new();
+        assert_eq!(
+            feed(&mut c, b"radio\rprofile fast\rregion eu433 2\rregion us915-2\rprofile loud\rregion eu433 x\r"),
+            [
+                Ok(Command::Radio),
+                Ok(Command::Profile(Profile::FAST)),
+                Ok(Command::Region(Region::Eu433, 2)),
+                Ok(Command::Region(Region::Us915(2), 0)),
+                Err(UnknownCommand),
+                Err(UnknownCommand),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let mut c = Console::new();
+        let mut input = vec![b'x'; 100];
+        input.extend_from_slice(b"\rlog warn\r");
+        assert_eq!(feed(&mut c, &input), [Err(UnknownCommand), Ok(Command::LogLevel(Level::Warn))]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Panic records and the diagnostic frame that reports them.
+//!
+//! The firmwares' panic handler writes a `PanicRecord` with the panic's
+//! location and message into RAM that the startup code leaves alone, then
+//! resets the board. The next boot takes the record, logs it and, on the
+//! beacon, sends it to the receiver once in a diagnostic frame:
+//! `[header][nonce: 12][ciphertext]`. The plaintext is
+//! `[line: 4][file length: 1][file][message]`, line little endian, encrypted
+//! like a position under `KEY` and the frame's own nonce.
+//!
+//! File paths keep their last `FILE_LEN` bytes and messages their first
+//! `MESSAGE_LEN`, so the frame stays short.
+
+use core::fmt::{self, Write};
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+
+use crate::decryption::{DecryptError, NONCE_LEN};
+use crate::encryption::KEY;
+use crate::protocol::{header, Kind, HEADER_LEN};
+
+pub const FILE_LEN: usize = 32;
+pub const MESSAGE_LEN: usize = 64;
+/// Line and file length ahead of the file in the plaintext.
+const FIXED_LEN: usize = 5;
+/// Marks a complete record; RAM holds anything after power-on.
+const MAGIC: u32 = 0x5041_4e43;
+
+/// Where and why the firmware panicked.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct PanicRecord {
+    magic: u32,
+    line: u32,
+    file_len: u8,
+    message_len: u8,
+    file: [u8; FILE_LEN],
+    message: [u8; MESSAGE_LEN],
+    checksum: u32,
+}
+
+impl PanicRecord {
+    pub fn new(file: &str, line: u32, message: fmt::Arguments) -> Self {
+        // the end of the path names the file
+        let mut start = file.len().saturating_sub(FILE_LEN);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let file = &file.as_bytes()[start..];
+        let mut record = Self {
+            magic: MAGIC,
+            line,
+            file_len: file.len() as u8,
+            message_len: 0,
+            file: [0; FILE_LEN],
+            message: [0; MESSAGE_LEN],
+            checksum: 0,
+        };
+        record.file[..file.len()].copy_from_slice(file);
+        let mut out = Truncated { buf: &mut record.message, len: 0 };
+        let _ = out.write_fmt(message);
+        record.message_len = out.len as u8;
+        record.checksum = record.sum();
+        record
+    }
+
+    pub fn file(&self) -> &str {
+        core::str::from_utf8(&self.file[..self.file_len as usize]).unwrap_or("?")
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn message(&self) -> &str {
+        core::str::from_utf8(&self.message[..self.message_len as usize]).unwrap_or("?")
+    }
+
+    /// Whether the record is complete, rather than what RAM held at power-on
+    /// or a record already taken.
+    pub fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.file_len as usize <= FILE_LEN
+            && self.message_len as usize <= MESSAGE_LEN
+            && self.checksum == self.sum()
+    }
+
+    /// Marks the record as taken.
+    pub fn clear(&mut self) {
+        self.magic = 0;
+    }
+
+    /// Builds the diagnostic frame that reports the record; returns its length.
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], out: &mut [u8; 255]) -> usize {
+        out[0] = header(Kind::Diagnostic, 0);
+        out[HEADER_LEN..HEADER_LEN + NONCE_LEN].copy_from_slice(nonce);
+        let start = HEADER_LEN + NONCE_LEN;
+        let mut len = start;
+        let file = &self.file[..self.file_len as usize];
+        let message = &self.message[..self.message_len as usize];
+        for part in [&self.line.to_le_bytes()[..], &[self.file_len], file, message] {
+            out[len..len + part.len()].copy_from_slice(part);
+            len += part.len();
+        }
+        let mut cipher = ChaCha20::new(KEY.secret_bytes().into(), nonce.into());
+        cipher.apply_keystream(&mut out[start..len]);
+        len
+    }
+
+    fn sum(&self) -> u32 {
+        // FNV-1a
+        let fixed = [self.line.to_le_bytes(), [self.file_len, self.message_len, 0, 0]];
+        fixed.iter().flatten().chain(&self.file).chain(&self.message).fold(0x811c_9dc5, |h, &b| {
+            (h ^ b as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+}
+
+impl fmt::Display for PanicRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file(), self.line, self.message())
+    }
+}
+
+/// Decrypts the body of a diagnostic frame; returns its nonce and the record.
+pub fn open(body: &[u8]) -> Result<([u8; NONCE_LEN], PanicRecord), DecryptError> {
+    let (nonce, ciphertext) = body.split_at_checked(NONCE_LEN).ok_or(DecryptError::PacketTooShort)?;
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| DecryptError::PacketTooShort)?;
+    if ciphertext.len() < FIXED_LEN {
+        return Err(DecryptError::PacketTooShort);
+    }
+    let mut buf = [0u8; FIXED_LEN + FILE_LEN + MESSAGE_LEN];
+    let plain = buf.get_mut(..ciphertext.len()).ok_or(DecryptError::MalformedPlaintext)?;
+    plain.copy_from_slice(ciphertext);
+    let mut cipher = ChaCha20::new(KEY.secret_bytes().into(), (&nonce).into());
+    cipher.apply_keystream(plain);
+
+    let line = u32::from_le_bytes([plain[0], plain[1], plain[2], plain[3]]);
+    let (file, message) = plain[FIXED_LEN..]
+        .split_at_checked(plain[4] as usize)
+        .filter(|(file, message)| file.len() <= FILE_LEN && message.len() <= MESSAGE_LEN)
+        .ok_or(DecryptError::MalformedPlaintext)?;
+    let file = core::str::from_utf8(file).map_err(|_| DecryptError::MalformedPlaintext)?;
+    let message = core::str::from_utf8(message).map_err(|_| DecryptError::MalformedPlaintext)?;
+    Ok((nonce, PanicRecord::new(file, line, format_args!("{}", message))))
+}
+
+/// Writes as much as fits into `buf`, ending on a character boundary.
+struct Truncated<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Truncated<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut n = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(n) {
+            n -= 1;
+        }
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 9];
+
+    #[test]
+    fn keeps_the_end_of_the_path_and_the_start_of_the_message() {
+        let path = "/home/builder/arkan/receiver/arkan_receiver/src/main.rs";
+        let record = PanicRecord::new(path, 172, format_args!("Could not connect to LoRa: {}", "é".repeat(40)));
+        assert_eq!(record.file(), "eiver/arkan_receiver/src/main.rs");
+        assert_eq!(record.message().len(), MESSAGE_LEN - 1);
+        assert!(record.message().starts_with("Could not connect to LoRa: é"));
+        assert!(record.is_valid());
+        assert_eq!(record.to_string(), format!("{}:172: {}", record.file(), record.message()));
+
+        let mut corrupt = record;
+        corrupt.message[0] ^= 1;
+        assert!(!corrupt.is_valid());
+        let mut taken = record;
+        taken.clear();
+        assert!(!taken.is_valid());
+    }
+
+    #[test]
+    fn diagnostic_frame_round_trip() {
+        let record = PanicRecord::new("src/main.rs", 7, format_args!("called `Option::unwrap()` on a `None` value"));
+        let mut frame = [0u8; 255];
+        let len = record.seal(&NONCE, &mut frame);
+        assert_eq!(len, HEADER_LEN + NONCE_LEN + FIXED_LEN + 11 + 43);
+        assert_eq!(frame[0], header(Kind::Diagnostic, 0));
+        // encrypted
+        assert!(!frame[..len].windows(4).any(|w| w == b"main"));
+        assert_eq!(open(&frame[HEADER_LEN..len]), Ok((NONCE, record)));
+
+        assert_eq!(open(&frame[HEADER_LEN..HEADER_LEN + NONCE_LEN + 2]), Err(DecryptError::PacketTooShort));
+        frame[HEADER_LEN + NONCE_LEN + 4] ^= 0x80;
+        assert_eq!(open(&frame[HEADER_LEN..len]), Err(DecryptError::MalformedPlaintext));
+    }
+}
@@ -1,28 +1,23 @@
 use core::convert::TryInto;
 use chacha20::{cipher::{KeyIvInit, StreamCipher}, ChaCha20};
 
-// 32‑byte shared key (same on sender and receiver)
-const KEY: [u8; 32] = [
-    0x47, 0xa5, 0x00, 0x52, 0x7a, 0xef, 0x77, 0x0d,
-    0x36, 0x3c, 0x0b, 0xe3, 0xe2, 0xaf, 0x50, 0xa8,
-    0x1d, 0x62, 0x3e, 0x9e, 0x2d, 0x1a, 0x21, 0xc0,
-    0x15, 0x3a, 0x9d, 0x53, 0xa7, 0x0f, 0x79, 0xd4,
-];
+use crate::encryption::KEY;
+pub use crate::encryption::GpsCoord;
 
 pub const NONCE_LEN: usize = 12;
 pub const COORD_LEN: usize = 8;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct GpsCoord {
-    pub lat_deg_e7: i32,
-    pub lon_deg_e7: i32,
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DecryptError {
     PacketTooShort,
     CipherError,
     MalformedPlaintext,
+    /// Compact frame from a beacon without a decoded full frame to restore its nonce from.
+    UnknownBeacon,
+    /// Delta frame against a fix that was not decoded here.
+    UnknownReference,
+    /// Compact frame with a tag that several known beacons share.
+    AmbiguousBeacon,
 }
 
 pub fn decrypt_packet(packet: &[u8]) -> Result<GpsCoord, DecryptError> {
@@ -38,7 +33,7 @@ pub fn decrypt_packet(packet: &[u8]) -> Result<GpsCoord, DecryptError> {
     }
     buf.copy_from_slice(&ciphertext[..COORD_LEN]);
 
-    let mut cipher = ChaCha20::new_from_slices(&KEY, nonce).map_err(|_| DecryptError::CipherError)?;
+    let mut cipher = ChaCha20::new_from_slices(KEY.secret_bytes(), nonce).map_err(|_| DecryptError::CipherError)?;
     cipher.apply_keystream(&mut buf);
 
     let lat = i32::from_le_bytes(buf[0..4].try_into().map_err(|_| DecryptError::MalformedPlaintext)?);
@@ -0,0 +1,200 @@
+//! Duty-cycle limits of the European short-range device sub-bands.
+//!
+//! ERC Recommendation 70-03 caps the share of time a device may transmit in
+//! each sub-band, measured over one hour. `DutyCycle` keeps the time on air of
+//! the last hour in twelve 5-minute buckets per sub-band and refuses a
+//! transmission that would take the sum over the limit. A transmission counts
+//! in the bucket it started in, so the budget frees up to 5 minutes later than
+//! strictly needed. Callers reserve the time on air before they transmit and
+//! `release` it when the transmission fails. Frequencies outside these
+//! sub-bands (US915) have no limit.
+
+use core::ops::Range;
+
+/// Duty cycles are measured over this period.
+pub const WINDOW_MS: u64 = 3_600_000;
+const BUCKETS: usize = 12;
+const BUCKET_MS: u64 = WINDOW_MS / BUCKETS as u64;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SubBand {
+    pub range_hz: Range<u32>,
+    /// Allowed time on air in thousandths of the window.
+    pub duty_per_mille: u32,
+}
+
+impl SubBand {
+    /// Time on air allowed per window.
+    pub const fn budget_ms(&self) -> u64 {
+        WINDOW_MS * self.duty_per_mille as u64 / 1000
+    }
+}
+
+/// Non-specific short-range device bands h1.2 to h1.7 of ERC 70-03 annex 1.
+pub const SUB_BANDS: [SubBand; 7] = [
+    SubBand { range_hz: 433_050_000..434_790_000, duty_per_mille: 100 },
+    SubBand { range_hz: 863_000_000..865_000_000, duty_per_mille: 1 },
+    SubBand { range_hz: 865_000_000..868_000_000, duty_per_mille: 10 },
+    SubBand { range_hz: 868_000_000..868_600_000, duty_per_mille: 10 },
+    SubBand { range_hz: 868_700_000..869_200_000, duty_per_mille: 1 },
+    SubBand { range_hz: 869_400_000..869_650_000, duty_per_mille: 100 },
+    SubBand { range_hz: 869_700_000..870_000_000, duty_per_mille: 10 },
+];
+
+/// Index into `SUB_BANDS` of the sub-band `hz` lies in.
+pub fn sub_band(hz: u32) -> Option<usize> {
+    SUB_BANDS.iter().position(|b| b.range_hz.contains(&hz))
+}
+
+/// Why a transmission may not go out now.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Exceeded {
+    /// The budget allows it from this time on (ms on the caller's clock).
+    Until(u64),
+    /// It takes longer than the whole budget of the sub-band.
+    TooLong,
+}
+
+/// Rolling time-on-air budget per sub-band.
+pub struct DutyCycle {
+    /// Time on air in ms per sub-band and bucket.
+    used_ms: [[u32; BUCKETS]; SUB_BANDS.len()],
+    /// Number of the newest bucket (`now / BUCKET_MS`) per sub-band.
+    newest: [u64; SUB_BANDS.len()],
+}
+
+impl DutyCycle {
+    pub const fn new() -> Self {
+        Self { used_ms: [[0; BUCKETS]; SUB_BANDS.len()], newest: [0; SUB_BANDS.len()] }
+    }
+
+    /// Time on air in the sub-band of `hz` over the last window.
+    pub fn used_ms(&mut self, hz: u32, now: u64) -> u64 {
+        let Some(band) = sub_band(hz) else { return 0 };
+        self.advance(band, now);
+        self.used_ms[band].iter().map(|&ms| ms as u64).sum()
+    }
+
+    /// Counts a transmission of `airtime_ms` on `hz` starting at `now`, unless
+    /// it would exceed the sub-band's budget.
+    pub fn reserve(&mut self, hz: u32, airtime_ms: u32, now: u64) -> Result<(), Exceeded> {
+        let Some(band) = sub_band(hz) else { return Ok(()) };
+        let budget = SUB_BANDS[band].budget_ms();
+        if airtime_ms as u64 > budget {
+            return Err(Exceeded::TooLong);
+        }
+        let mut used = self.used_ms(hz, now);
+        let newest = self.newest[band];
+        if used + airtime_ms as u64 <= budget {
+            self.used_ms[band][newest as usize % BUCKETS] += airtime_ms;
+            return Ok(());
+        }
+        // buckets leave the window oldest first; find the one after which it fits
+        for k in newest.saturating_sub(BUCKETS as u64 - 1)..newest {
+            used -= self.used_ms[band][k as usize % BUCKETS] as u64;
+            if used + airtime_ms as u64 <= budget {
+                return Err(Exceeded::Until((k + BUCKETS as u64) * BUCKET_MS));
+            }
+        }
+        // only the current bucket is left, so it fits once that one expires
+        Err(Exceeded::Until((newest + BUCKETS as u64) * BUCKET_MS))
+    }
+
+    /// Gives back `airtime_ms` reserved on `hz` at `now` for a transmission
+    /// that did not go out.
+    pub fn release(&mut self, hz: u32, airtime_ms: u32, now: u64) {
+        let Some(band) = sub_band(hz) else { return };
+        let bucket = now / BUCKET_MS;
+        // a bucket that left the window was cleared already
+        if bucket <= self.newest[band] && bucket + BUCKETS as u64 > self.newest[band] {
+            let used = &mut self.used_ms[band][bucket as usize % BUCKETS];
+            *used = used.saturating_sub(airtime_ms);
+        }
+    }
+
+    /// Clears the buckets that left the window since the last call.
+    fn advance(&mut self, band: usize, now: u64) {
+        let bucket = now / BUCKET_MS;
+        let newest = self.newest[band];
+        for k in (newest + 1)..=bucket.min(newest + BUCKETS as u64) {
+            self.used_ms[band][k as usize % BUCKETS] = 0;
+        }
+        self.newest[band] = bucket.max(newest);
+    }
+}
+
+impl Default for DutyCycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EU433: u32 = 433_175_000;
+    const G1: u32 = 868_100_000;
+
+    #[test]
+    fn sub_bands() {
+        assert_eq!(sub_band(EU433), Some(0));
+        assert_eq!(sub_band(G1), Some(3));
+        assert_eq!(sub_band(869_525_000), Some(5));
+        assert_eq!(sub_band(868_650_000), None);
+        assert_eq!(sub_band(902_300_000), None);
+        assert_eq!(SUB_BANDS[0].budget_ms(), 360_000);
+    }
+
+    #[test]
+    fn budget_refills_as_buckets_leave_the_window() {
+        let mut duty = DutyCycle::new();
+        // 36 s per hour at 1 %
+        for i in 0..18 {
+            assert_eq!(duty.reserve(G1, 1_000, i * 1_000), Ok(()));
+        }
+        for i in 0..18 {
+            assert_eq!(duty.reserve(G1, 1_000, 2 * BUCKET_MS + i * 1_000), Ok(()));
+        }
+        assert_eq!(duty.used_ms(G1, 2 * BUCKET_MS), 36_000);
+        assert_eq!(duty.reserve(G1, 1_000, 3 * BUCKET_MS), Err(Exceeded::Until(WINDOW_MS)));
+        // another sub-band has its own budget
+        assert_eq!(duty.reserve(869_525_000, 1_000, 3 * BUCKET_MS), Ok(()));
+
+        assert_eq!(duty.reserve(G1, 1_000, WINDOW_MS), Ok(()));
+        assert_eq!(duty.used_ms(G1, WINDOW_MS), 19_000);
+        assert_eq!(duty.used_ms(G1, 2 * WINDOW_MS), 0);
+    }
+
+    #[test]
+    fn a_single_bucket_holding_the_budget_expires_as_a_whole() {
+        let mut duty = DutyCycle::new();
+        let start = 5 * WINDOW_MS + 10;
+        assert_eq!(duty.reserve(EU433, 360_000, start), Ok(()));
+        assert_eq!(duty.reserve(EU433, 1, start + 1), Err(Exceeded::Until(6 * WINDOW_MS)));
+    }
+
+    #[test]
+    fn released_airtime_is_available_again() {
+        let mut duty = DutyCycle::new();
+        assert_eq!(duty.reserve(G1, 30_000, 0), Ok(()));
+        assert_eq!(duty.reserve(G1, 10_000, BUCKET_MS), Err(Exceeded::Until(WINDOW_MS)));
+        duty.release(G1, 30_000, 0);
+        assert_eq!(duty.used_ms(G1, BUCKET_MS), 0);
+        assert_eq!(duty.reserve(G1, 10_000, BUCKET_MS), Ok(()));
+        // nothing to give back once the bucket left the window
+        assert_eq!(duty.reserve(G1, 5_000, 2 * WINDOW_MS), Ok(()));
+        duty.release(G1, 5_000, BUCKET_MS);
+        assert_eq!(duty.used_ms(G1, 2 * WINDOW_MS), 5_000);
+    }
+
+    #[test]
+    fn unlimited_outside_the_sub_bands() {
+        let mut duty = DutyCycle::new();
+        // 3.6 s per hour at 0.1 %
+        assert_eq!(duty.reserve(869_000_000, 4_000, 0), Err(Exceeded::TooLong));
+        for i in 0..100 {
+            assert_eq!(duty.reserve(902_300_000, 4_000, i), Ok(()));
+        }
+    }
+}
@@ -2,6 +2,49 @@
 
 use core::fmt;
 
+/// Secret key material. `Debug` is redacted and there is no other formatting,
+/// so a key cannot end up in a log record by accident.
+#[derive(Clone, Copy)]
+pub struct Key<const N: usize = 32>([u8; N]);
+
+impl<const N: usize> Key<N> {
+    pub const fn new(bytes: [u8; N]) -> Self {
+        Self(bytes)
+    }
+
+    /// Raw key bytes, to be handed to a cipher and nothing else.
+    pub const fn secret_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+}
+
+impl<const N: usize> fmt::Debug for Key<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(<redacted>)")
+    }
+}
+
+// 32‑byte shared key (same on sender and receiver)
+pub const KEY: Key = Key::new([
+    0x47, 0xa5, 0x00, 0x52, 0x7a, 0xef, 0x77, 0x0d,
+    0x36, 0x3c, 0x0b, 0xe3, 0xe2, 0xaf, 0x50, 0xa8,
+    0x1d, 0x62, 0x3e, 0x9e, 0x2d, 0x1a, 0x21, 0xc0,
+    0x15, 0x3a, 0x9d, 0x53, 0xa7, 0x0f, 0x79, 0xd4,
+]);
+
+// 16-byte network key: relays check frames with it, but cannot read positions
+pub const NET_KEY: Key<16> = Key::new([
+    0x9b, 0x31, 0xe8, 0x04, 0x6c, 0xd2, 0x57, 0xa3,
+    0x20, 0x7f, 0xc5, 0x18, 0x8e, 0x63, 0xb9, 0x4a,
+]);
+
+// 16-byte frame authentication key: beacons and receivers only, so relays
+// can neither read frames nor forge them
+pub const AUTH_KEY: Key<16> = Key::new([
+    0x5e, 0xc2, 0x91, 0x3b, 0xf4, 0x08, 0x6d, 0xa7,
+    0x12, 0xe9, 0x84, 0x3f, 0xb0, 0x27, 0xd5, 0x6a,
+]);
+
 /// - `lat_deg_e7` and `lon_deg_e7`: degrees scaled by 1e7 (e.g., 50.4501° => 504501000)
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct GpsCoord {
@@ -120,4 +163,4 @@ impl CoordinateEncryptor for MyCipher {
 
         Ok(pt_len)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,132 @@
+use crate::encryption::{MyCipher, GpsCoord, EncryptConfig, CoordinateEncryptor, KEY};
+use crate::hal::LogSink;
+use crate::log::{Hex, Logger};
+use crate::nmea::{field, is_sentence, nmea_to_e7};
+use crate::protocol::{header, Kind, HEADER_LEN};
+use crate::{error, info, sensitive, warn};
+
+const TAG: &str = "gps";
+
+/// Source of per-packet nonces: 4-byte device id followed by an 8-byte counter.
+/// The boot count fills the upper 32 bits of the counter so nonces stay unique across resets.
+pub struct NonceCounter {
+    device_id: [u8; 4],
+    counter: u64,
+}
+
+impl NonceCounter {
+    pub const fn new(device_id: [u8; 4], boot_count: u32) -> Self {
+        Self { device_id, counter: (boot_count as u64) << 32 }
+    }
+
+    pub fn device_id(&self) -> [u8; 4] {
+        self.device_id
+    }
+
+    pub fn next_nonce(&mut self) -> [u8; 12] {
+        let n = self.peek_nonce();
+        self.counter = self.counter.wrapping_add(1);
+        n
+    }
+
+    /// The nonce `next_nonce` returns next.
+    pub fn peek_nonce(&self) -> [u8; 12] {
+        let mut n = [0u8; 12];
+        n[0..4].copy_from_slice(&self.device_id);
+        n[4..12].copy_from_slice(&self.counter.to_be_bytes());
+        n
+    }
+}
+
+/// Turns a GGA sentence with a fix into a position frame with the given header
+/// `flags` in `lora_buf`; returns the frame length.
+pub fn gps_proccess<S: LogSink>(
+    line: &[u8],
+    log: &mut Logger<S>,
+    nonces: &mut NonceCounter,
+    flags: u8,
+    lora_buf: &mut [u8; 255],
+) -> Option<usize> {
+    let coords = parse_fix(line, log)?;
+    encode_full(&coords, &nonces.next_nonce(), flags, log, lora_buf)
+}
+
+/// Returns the position of a GGA sentence with a fix.
+pub fn parse_fix<S: LogSink>(line: &[u8], log: &mut Logger<S>) -> Option<GpsCoord> {
+    // NEO-6M reports as $GPGGA, multi-GNSS receivers as $GNGGA
+    if !is_sentence(line, b"GGA") {
+        return None;
+    }
+    let fix_quality = field(line, 6);
+    if fix_quality != Some(b"1") && fix_quality != Some(b"2") {
+        warn!(log, TAG, "GGA invalid");
+        return None;
+    }
+    let lat_raw = field(line, 2)?;
+    let lat_hemi = field(line, 3)?;
+    let lon_raw = field(line, 4)?;
+    let lon_hemi = field(line, 5)?;
+
+    let count_sat = field(line, 7)?;
+    info!(log, TAG, "Satellites: {}", core::str::from_utf8(count_sat).unwrap_or("?"));
+
+    let mut lat = nmea_to_e7(lat_raw, true)?;
+    let mut lon = nmea_to_e7(lon_raw, false)?;
+
+    if lat_hemi == b"S" { lat = -lat; }
+    if lon_hemi == b"W" { lon = -lon; }
+
+    // Print raw fixed‑point coords to serial
+    sensitive!(log, Info, TAG, "RAW lat_e7={}, lon_e7={}", lat, lon);
+
+    Some(GpsCoord {
+        lat_deg_e7: lat,
+        lon_deg_e7: lon,
+    })
+}
+
+/// Writes a full position frame `[header || nonce || ciphertext]` into `lora_buf`;
+/// returns its length.
+pub fn encode_full<S: LogSink>(
+    coords: &GpsCoord,
+    nonce: &[u8; 12],
+    flags: u8,
+    log: &mut Logger<S>,
+    lora_buf: &mut [u8; 255],
+) -> Option<usize> {
+    // Build encryption config
+    let enc_cfg = EncryptConfig {
+        key: KEY.secret_bytes(),
+        iv: Some(nonce),
+        aad: None,
+    };
+
+    // Encrypt into a small temp buffer
+    let mut cipher = MyCipher::new();
+    let mut ct = [0u8; 16];
+    let enc_len = match cipher.encrypt_into(coords, &enc_cfg, &mut ct) {
+        Ok(len) => len,
+        Err(_) => {
+            error!(log, TAG, "Encryption error");
+            return None;
+        }
+    };
+
+    // Log encrypted bytes to serial as hex
+    sensitive!(log, Debug, TAG, "NONCE: {}", Hex(nonce));
+    sensitive!(log, Debug, TAG, "CIPHERTEXT: {}", Hex(&ct[..enc_len]));
+
+    // Final LoRa payload: [header || nonce || ciphertext]
+    let len = HEADER_LEN + 12 + enc_len;
+    if lora_buf.len() < len {
+        error!(log, TAG, "LoRa buffer too small");
+        return None;
+    }
+
+    lora_buf[0] = header(Kind::Position, flags);
+    lora_buf[HEADER_LEN..HEADER_LEN + 12].copy_from_slice(nonce);
+    lora_buf[HEADER_LEN + 12..len].copy_from_slice(&ct[..enc_len]);
+
+    // Return total payload length for caller to send
+    Some(len)
+}
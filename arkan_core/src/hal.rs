@@ -0,0 +1,105 @@
+//! Traits that separate the firmware logic from the RP2040 peripherals.
+//!
+//! The RP2040 implementations live in `rp2040` (feature `rp2040`), the host
+//! test doubles in `mock` (feature `mock`).
+
+use core::fmt;
+
+use crate::log::Level;
+use crate::radio_config::RadioConfig;
+
+/// Destination for diagnostic output (the USB CDC port on the Pico).
+pub trait LogSink {
+    fn write(&mut self, data: &[u8]);
+
+    /// Writes one log record. Backends with their own notion of levels override this.
+    fn record(&mut self, level: Level, tag: &str, args: fmt::Arguments) {
+        crate::log::write_record(self, level, tag, args);
+    }
+}
+
+/// LoRa packet radio (SX1278 on both boards).
+pub trait Radio {
+    type Error;
+
+    /// Applies frequency, modem settings and TX power, leaving the radio in standby.
+    fn configure(&mut self, config: &RadioConfig) -> Result<(), Self::Error>;
+
+    /// Starts transmitting `payload` (at most 255 bytes).
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), Self::Error>;
+
+    /// Like `transmit`, but without listening before talking: for answers
+    /// the other side only waits a short time for.
+    fn transmit_now(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.transmit(payload)
+    }
+
+    /// Whether the last `transmit` is still on air. Switching to receive
+    /// before it finished aborts the transmission.
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error>;
+
+    /// Starts channel activity detection: the radio listens for a LoRa
+    /// preamble for about two symbols, then returns to standby.
+    fn start_cad(&mut self) -> Result<(), Self::Error>;
+
+    /// Outcome of the detection started by `start_cad`: `None` while it runs,
+    /// then whether the channel was busy.
+    fn cad_result(&mut self) -> Result<Option<bool>, Self::Error>;
+
+    /// Puts the radio into continuous receive mode.
+    fn start_receive(&mut self) -> Result<(), Self::Error>;
+
+    /// Copies a received packet into `buf` and returns its length, if one is waiting.
+    fn receive(&mut self, buf: &mut [u8; 255]) -> Result<Option<usize>, Self::Error>;
+}
+
+/// Byte stream from the GPS module.
+pub trait ByteSource {
+    /// Returns the next received byte, if any.
+    fn read_byte(&mut self) -> Option<u8>;
+}
+
+/// Monotonic millisecond clock.
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+}
+
+impl<C: Clock> Clock for &C {
+    fn now_ms(&self) -> u64 {
+        (**self).now_ms()
+    }
+}
+
+/// Non-volatile storage with NOR flash semantics: `write` can only clear bits,
+/// so a region has to be erased (set to 0xFF) before it is rewritten.
+pub trait Store {
+    type Error;
+
+    /// Size of an erase block in bytes.
+    const ERASE_SIZE: u32;
+
+    /// Size of the store in bytes.
+    fn capacity(&self) -> u32;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erases `len` bytes at `offset`; both must be multiples of `ERASE_SIZE`.
+    fn erase(&mut self, offset: u32, len: u32) -> Result<(), Self::Error>;
+}
+
+/// Errors reported by the `Store` implementations in this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    OutOfBounds,
+    Unaligned,
+}
+
+#[cfg(feature = "usb-serial")]
+impl<B: usb_device::bus::UsbBus> LogSink for usbd_serial::SerialPort<'_, B> {
+    fn write(&mut self, data: &[u8]) {
+        // Output is best effort: drop it when the host is not reading.
+        let _ = usbd_serial::SerialPort::write(self, data);
+    }
+}
@@ -0,0 +1,149 @@
+//! Frequency hopping over the channels of the region plan.
+//!
+//! Every frame goes out on a channel picked by its nonce: the ChaCha20
+//! keystream of the nonce at block 2^16, which no frame or ACK uses, taken
+//! modulo the number of channels. Only holders of `KEY` can tell the channel
+//! of the next frame. Retries and stale repeats of a frame keep its nonce and
+//! so its channel, and the receiver's ACK goes out on the channel of the frame.
+//!
+//! A beacon's nonces count up by one per frame. After a frame the receiver
+//! tunes to the channel of the beacon's next nonce. When nothing arrives
+//! there for `follow_ms`, because a frame was lost or the beacon slept, it
+//! scans the channels one after the other for `dwell_ms` each until it hears
+//! a frame again. With several beacons the receiver follows the one heard
+//! last and finds the others while scanning.
+
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+
+use crate::decryption::NONCE_LEN;
+use crate::encryption::KEY;
+
+/// Channel of the frame sent with `nonce`, out of `channels`.
+pub fn hop_channel(nonce: &[u8; NONCE_LEN], channels: u8) -> u8 {
+    let mut bytes = [0u8; 4];
+    let mut cipher = ChaCha20::new(KEY.secret_bytes().into(), nonce.into());
+    cipher.seek(64u64 << 16);
+    cipher.apply_keystream(&mut bytes);
+    (u32::from_le_bytes(bytes) % channels.max(1) as u32) as u8
+}
+
+/// Nonce of the frame after the one sent with `nonce`.
+pub fn next_nonce(nonce: &[u8; NONCE_LEN]) -> [u8; NONCE_LEN] {
+    let mut next = *nonce;
+    let mut counter = [0u8; 8];
+    counter.copy_from_slice(&nonce[4..]);
+    next[4..].copy_from_slice(&u64::from_be_bytes(counter).wrapping_add(1).to_be_bytes());
+    next
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HopConfig {
+    /// How long to wait for the next frame on its channel before scanning.
+    pub follow_ms: u64,
+    /// Time spent on each channel while scanning.
+    pub dwell_ms: u64,
+}
+
+impl Default for HopConfig {
+    fn default() -> Self {
+        // beacons send about one frame per second while they have a fix
+        Self { follow_ms: 3_000, dwell_ms: 1_000 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Following { until: u64 },
+    Scanning { until: u64 },
+}
+
+/// Receiver side: picks the channel to listen on.
+pub struct HopFollower {
+    config: HopConfig,
+    channels: u8,
+    channel: u8,
+    state: State,
+}
+
+impl HopFollower {
+    /// Starts scanning `channels` channels.
+    pub const fn new(config: HopConfig, channels: u8) -> Self {
+        Self { config, channels, channel: 0, state: State::Scanning { until: 0 } }
+    }
+
+    pub fn config(&self) -> HopConfig {
+        self.config
+    }
+
+    pub fn is_following(&self) -> bool {
+        matches!(self.state, State::Following { .. })
+    }
+
+    /// Follows the beacon that just sent the frame with `nonce`.
+    pub fn heard(&mut self, nonce: &[u8; NONCE_LEN], now: u64) {
+        self.channel = hop_channel(&next_nonce(nonce), self.channels);
+        self.state = State::Following { until: now + self.config.follow_ms };
+    }
+
+    /// When `channel` moves on by itself: following times out or the scan
+    /// goes to the next channel.
+    pub fn wake_at(&self) -> u64 {
+        match self.state {
+            State::Following { until } | State::Scanning { until } => until,
+        }
+    }
+
+    /// The channel to listen on at `now`.
+    pub fn channel(&mut self, now: u64) -> u8 {
+        match self.state {
+            State::Following { until } | State::Scanning { until } if now < until => {}
+            State::Following { .. } => {
+                self.state = State::Scanning { until: now + self.config.dwell_ms };
+            }
+            State::Scanning { .. } => {
+                self.channel = (self.channel + 1) % self.channels.max(1);
+                self.state = State::Scanning { until: now + self.config.dwell_ms };
+            }
+        }
+        self.channel
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hops_spread_over_all_channels() {
+        let mut nonce = [1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, 0];
+        let mut count = [0; 3];
+        for _ in 0..300 {
+            count[hop_channel(&nonce, 3) as usize] += 1;
+            nonce = next_nonce(&nonce);
+        }
+        assert_eq!(nonce[4..], [0, 0, 0, 7, 0, 0, 1, 44]);
+        assert!(count.iter().all(|&n| (70..130).contains(&n)), "{:?}", count);
+        // another beacon hops differently
+        let other: Vec<_> = (0..8u8).map(|i| hop_channel(&[9, 9, 9, 9, 0, 0, 0, 0, 0, 0, 0, i], 3)).collect();
+        let own: Vec<_> = (0..8u8).map(|i| hop_channel(&[1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, i], 3)).collect();
+        assert_ne!(other, own);
+    }
+
+    #[test]
+    fn follows_the_beacon_and_scans_when_it_is_lost() {
+        let mut hop = HopFollower::new(HopConfig { follow_ms: 3_000, dwell_ms: 1_000 }, 3);
+        assert_eq!([hop.channel(0), hop.channel(999), hop.channel(1_000), hop.channel(2_000)], [1, 1, 2, 0]);
+
+        let nonce = [1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, 5];
+        hop.heard(&nonce, 2_500);
+        let next = hop_channel(&next_nonce(&nonce), 3);
+        assert!(hop.is_following());
+        assert_eq!(hop.wake_at(), 5_500);
+        assert_eq!(hop.channel(5_499), next);
+        // lost: scanning goes on from the followed channel
+        assert_eq!(hop.channel(5_500), next);
+        assert!(!hop.is_following());
+        assert_eq!(hop.channel(6_500), (next + 1) % 3);
+    }
+}
@@ -0,0 +1,325 @@
+//! Listen-before-talk with channel activity detection (CAD).
+//!
+//! `ListenBeforeTalk` wraps a `Radio` and runs a CAD before every frame it is
+//! given. A clear channel sends the frame right away. A busy one makes it wait
+//! a random time drawn from a window that doubles with every busy detection,
+//! then the CAD is repeated; after `max_attempts` busy detections the frame is
+//! dropped. From `transmit` until the frame left the radio or was dropped the
+//! wrapper reports it as on air, so callers wait for it as for any transmission.
+//! `transmit_now` skips the check, for ACKs: their sender waits only briefly,
+//! and a backoff would outlast that.
+//! CAD only finds LoRa preambles, not frames already past them.
+
+use core::fmt;
+
+use crate::hal::{Clock, Radio};
+use crate::radio_config::RadioConfig;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LbtConfig {
+    /// Backoff window after the first busy detection.
+    pub backoff_ms: u64,
+    /// Busy detections after which the frame is dropped.
+    pub max_attempts: u8,
+}
+
+impl Default for LbtConfig {
+    fn default() -> Self {
+        // a full frame takes 263 ms on air at the standard profile
+        Self { backoff_ms: 300, max_attempts: 5 }
+    }
+}
+
+/// Channel statistics since power-up.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct ChannelStats {
+    /// Frames handed to `transmit` or `transmit_now`.
+    pub frames: u32,
+    /// Channel activity detections run.
+    pub detections: u32,
+    /// Detections that found the channel busy.
+    pub busy: u32,
+    /// Frames dropped after `max_attempts` busy detections.
+    pub dropped: u32,
+}
+
+impl fmt::Display for ChannelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames, {} channel checks, {} busy, {} dropped",
+            self.frames, self.detections, self.busy, self.dropped
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    Detecting,
+    Backoff { until: u64 },
+    OnAir,
+}
+
+pub struct ListenBeforeTalk<R, C> {
+    radio: R,
+    clock: C,
+    config: LbtConfig,
+    state: State,
+    frame: [u8; 255],
+    len: usize,
+    /// Busy detections of the current frame.
+    attempt: u8,
+    rng: u32,
+    stats: ChannelStats,
+}
+
+impl<R: Radio, C: Clock> ListenBeforeTalk<R, C> {
+    /// `seed` should differ between devices, so that beacons that found the
+    /// channel busy at the same time do not retry at the same time.
+    pub fn new(radio: R, clock: C, config: LbtConfig, seed: u32) -> Self {
+        Self {
+            radio,
+            clock,
+            config,
+            state: State::Idle,
+            frame: [0; 255],
+            len: 0,
+            attempt: 0,
+            rng: seed | 1,
+            stats: ChannelStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        self.stats
+    }
+
+    /// When a frame backing off checks the channel again. Detections and
+    /// transmissions end with DIO0 instead.
+    pub fn wake_at(&self) -> Option<u64> {
+        match self.state {
+            State::Backoff { until } => Some(until),
+            _ => None,
+        }
+    }
+
+    pub fn radio(&self) -> &R {
+        &self.radio
+    }
+
+    pub fn radio_mut(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Moves a waiting frame on: checks the channel, sends or backs off. Call
+    /// on every main loop iteration; `is_transmitting` calls it as well.
+    pub fn poll(&mut self) -> Result<(), R::Error> {
+        match self.state {
+            State::Idle => {}
+            State::Detecting => match self.radio.cad_result()? {
+                None => {}
+                Some(false) => {
+                    self.radio.transmit(&self.frame[..self.len])?;
+                    self.state = State::OnAir;
+                }
+                Some(true) => {
+                    self.stats.busy += 1;
+                    self.attempt += 1;
+                    if self.attempt >= self.config.max_attempts {
+                        self.stats.dropped += 1;
+                        self.state = State::Idle;
+                    } else {
+                        let window = doubled(self.config.backoff_ms, self.attempt - 1);
+                        let wait = 1 + self.random() as u64 % window.max(1);
+                        self.state = State::Backoff { until: self.clock.now_ms().saturating_add(wait) };
+                    }
+                }
+            },
+            State::Backoff { until } => {
+                if self.clock.now_ms() >= until {
+                    self.detect()?;
+                }
+            }
+            State::OnAir => {
+                if !self.radio.is_transmitting()? {
+                    self.state = State::Idle;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn detect(&mut self) -> Result<(), R::Error> {
+        self.stats.detections += 1;
+        self.state = State::Detecting;
+        self.radio.start_cad()
+    }
+
+    /// xorshift32
+    fn random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+}
+
+impl<R: Radio, C: Clock> Radio for ListenBeforeTalk<R, C> {
+    type Error = R::Error;
+
+    fn configure(&mut self, config: &RadioConfig) -> Result<(), Self::Error> {
+        self.state = State::Idle;
+        self.radio.configure(config)
+    }
+
+    /// Queues `payload` and starts checking the channel; a frame still
+    /// waiting is replaced.
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.frame[..payload.len()].copy_from_slice(payload);
+        self.len = payload.len();
+        self.attempt = 0;
+        self.stats.frames += 1;
+        self.detect()
+    }
+
+    /// Sends `payload` at once, replacing a frame still waiting.
+    fn transmit_now(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.stats.frames += 1;
+        self.radio.transmit(payload)?;
+        self.state = State::OnAir;
+        Ok(())
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        self.poll()?;
+        Ok(self.state != State::Idle)
+    }
+
+    fn start_cad(&mut self) -> Result<(), Self::Error> {
+        self.radio.start_cad()
+    }
+
+    fn cad_result(&mut self) -> Result<Option<bool>, Self::Error> {
+        self.radio.cad_result()
+    }
+
+    /// Drops a frame still waiting for the channel.
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.state = State::Idle;
+        self.radio.start_receive()
+    }
+
+    fn receive(&mut self, buf: &mut [u8; 255]) -> Result<Option<usize>, Self::Error> {
+        self.radio.receive(buf)
+    }
+}
+
+/// `base_ms` doubled `times` times; saturates instead of overflowing for
+/// any number of attempts or retries.
+pub(crate) fn doubled(base_ms: u64, times: u8) -> u64 {
+    base_ms.saturating_mul(1u64.checked_shl(times as u32).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockClock, MockRadio};
+
+    fn lbt(clock: &MockClock) -> ListenBeforeTalk<MockRadio, &MockClock> {
+        ListenBeforeTalk::new(MockRadio::default(), clock, LbtConfig { backoff_ms: 100, max_attempts: 3 }, 7)
+    }
+
+    #[test]
+    fn clear_channel_sends_at_once() {
+        let clock = MockClock::new(0);
+        let mut radio = lbt(&clock);
+        radio.transmit(&[1, 2]).unwrap();
+        assert!(radio.radio().sent.is_empty());
+        assert_eq!(radio.is_transmitting(), Ok(true));
+        assert_eq!(radio.radio().sent, [vec![1, 2]]);
+        // the mock reports the frame on air once
+        assert_eq!(radio.is_transmitting(), Ok(true));
+        assert_eq!(radio.is_transmitting(), Ok(false));
+        assert_eq!(radio.stats(), ChannelStats { frames: 1, detections: 1, busy: 0, dropped: 0 });
+    }
+
+    #[test]
+    fn busy_channel_backs_off() {
+        let clock = MockClock::new(0);
+        let mut radio = lbt(&clock);
+        radio.radio.cad_busy = 2;
+        radio.transmit(&[1]).unwrap();
+        radio.poll().unwrap();
+        let State::Backoff { until } = radio.state else { panic!("{:?}", radio.state) };
+        assert!((1..=100).contains(&until));
+        assert_eq!(radio.wake_at(), Some(until));
+
+        clock.advance(until);
+        radio.poll().unwrap();
+        radio.poll().unwrap();
+        let State::Backoff { until: second } = radio.state else { panic!("{:?}", radio.state) };
+        // the window doubled
+        assert!((until + 1..=until + 200).contains(&second));
+
+        clock.advance(200);
+        while radio.is_transmitting().unwrap() {}
+        assert_eq!(radio.radio().sent.len(), 1);
+        assert_eq!(radio.stats(), ChannelStats { frames: 1, detections: 3, busy: 2, dropped: 0 });
+    }
+
+    #[test]
+    fn replies_go_out_without_a_channel_check() {
+        let clock = MockClock::new(0);
+        let mut radio = lbt(&clock);
+        radio.radio.cad_busy = 2;
+        radio.transmit(&[1]).unwrap();
+        radio.poll().unwrap();
+        assert!(matches!(radio.state, State::Backoff { .. }));
+        // the ACK replaces the frame waiting for the channel
+        radio.transmit_now(&[2]).unwrap();
+        assert_eq!(radio.radio().sent, [vec![2]]);
+        while radio.is_transmitting().unwrap() {}
+        assert_eq!(radio.stats(), ChannelStats { frames: 2, detections: 1, busy: 1, dropped: 0 });
+    }
+
+    #[test]
+    fn backoff_windows_saturate() {
+        assert_eq!(doubled(300, 0), 300);
+        assert_eq!(doubled(300, 4), 4_800);
+        assert_eq!(doubled(300, 62), u64::MAX);
+        assert_eq!(doubled(1, 63), 1 << 63);
+        assert_eq!(doubled(1, 64), u64::MAX);
+        assert_eq!(doubled(0, 255), 0);
+
+        // far more attempts than the window has bits
+        let clock = MockClock::new(0);
+        let config = LbtConfig { backoff_ms: 1, max_attempts: 100 };
+        let mut radio = ListenBeforeTalk::new(MockRadio::default(), &clock, config, 7);
+        radio.radio.cad_busy = 100;
+        radio.transmit(&[1]).unwrap();
+        while radio.is_transmitting().unwrap() {
+            clock.advance(1 << 32);
+        }
+        assert_eq!(radio.stats().dropped, 1);
+    }
+
+    #[test]
+    fn frames_are_dropped_when_the_channel_stays_busy() {
+        let clock = MockClock::new(0);
+        let mut radio = lbt(&clock);
+        radio.radio.cad_busy = 10;
+        radio.transmit(&[1]).unwrap();
+        while radio.is_transmitting().unwrap() {
+            clock.advance(10);
+        }
+        assert!(radio.radio().sent.is_empty());
+        assert_eq!(radio.stats(), ChannelStats { frames: 1, detections: 3, busy: 3, dropped: 1 });
+
+        let mut s = heapless::String::<64>::new();
+        core::fmt::write(&mut s, format_args!("{}", radio.stats())).unwrap();
+        assert_eq!(s, "1 frames, 3 channel checks, 3 busy, 1 dropped");
+    }
+}
@@ -0,0 +1,42 @@
+#![cfg_attr(not(test), no_std)]
+
+//! Hardware independent beacon/receiver logic shared by both firmwares.
+
+#[cfg(feature = "mock")]
+extern crate std;
+
+pub mod airtime;
+pub mod battery;
+pub mod beacon;
+pub mod boot_count;
+pub mod breadcrumb;
+pub mod compact;
+pub mod console;
+pub mod crash;
+pub mod decryption;
+pub mod duty_cycle;
+pub mod encryption;
+pub mod gps_proccess;
+pub mod hal;
+pub mod hopping;
+pub mod lbt;
+pub mod log;
+pub mod lorawan;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod nmea;
+pub mod protocol;
+pub mod radio_health;
+pub mod radio_config;
+pub mod receiver;
+pub mod relay;
+#[cfg(feature = "rp2040")]
+pub mod rp2040;
+pub mod rx_queue;
+pub mod scan;
+pub mod sx127x;
+pub mod tdma;
+pub mod telemetry;
+pub mod track_log;
+pub mod uart_rx;
+pub mod watchdog;
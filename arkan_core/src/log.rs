@@ -0,0 +1,380 @@
+//! Leveled logging with module tags.
+//!
+//! Records go through a `Logger`, which drops everything above its runtime
+//! level and hands the rest to a `LogSink` backend: the USB CDC port, defmt
+//! over RTT (feature `defmt`) or the RAM ring buffer `RingLog`.
+//!
+//! Never pass key material to a log call; `encryption::Key` has no formatting
+//! that reveals it. Positions, nonces, ciphertext and raw packets go through
+//! `sensitive!` and are only written in `Mode::Debug`.
+
+use core::fmt::{self, Write};
+
+use crate::hal::LogSink;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    /// Parses a level name as typed on the console (`error`, `warn`, ...).
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace]
+            .into_iter()
+            .find(|l| s.eq_ignore_ascii_case(l.as_str().as_bytes()))
+    }
+}
+
+/// Whether sensitive records are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Deployed beacons: nothing that reveals positions or packet contents.
+    Field,
+    /// Bench work: everything, as verbose as the level allows.
+    Debug,
+}
+
+impl Mode {
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        if s.eq_ignore_ascii_case(b"field") {
+            Some(Mode::Field)
+        } else if s.eq_ignore_ascii_case(b"debug") {
+            Some(Mode::Debug)
+        } else {
+            None
+        }
+    }
+}
+
+/// Adapts a `LogSink` to `core::fmt::Write`.
+pub struct SinkWriter<'a, S: ?Sized>(pub &'a mut S);
+
+impl<S: LogSink + ?Sized> Write for SinkWriter<'_, S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writes one record as `[LEVEL tag] message\r\n`; the default `LogSink::record`.
+pub fn write_record<S: LogSink + ?Sized>(sink: &mut S, level: Level, tag: &str, args: fmt::Arguments) {
+    let _ = write!(SinkWriter(sink), "[{} {}] {}\r\n", level.as_str(), tag, args);
+}
+
+/// Formats bytes as uppercase hex without separators.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats text as the contents of a JSON string, escaping quotes,
+/// backslashes and control characters.
+pub struct JsonStr<'a>(pub &'a str);
+
+impl fmt::Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Logger<S> {
+    sink: S,
+    level: Level,
+    mode: Mode,
+}
+
+impl<S: LogSink> Logger<S> {
+    /// Creates a logger in `Mode::Field`.
+    pub const fn new(sink: S, level: Level) -> Self {
+        Self { sink, level, mode: Mode::Field }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    pub fn log(&mut self, level: Level, tag: &str, args: fmt::Arguments) {
+        if self.enabled(level) {
+            self.sink.record(level, tag, args);
+        }
+    }
+
+    /// Logs a record that reveals positions or packet contents; dropped in `Mode::Field`.
+    pub fn log_sensitive(&mut self, level: Level, tag: &str, args: fmt::Arguments) {
+        if self.mode == Mode::Debug {
+            self.log(level, tag, args);
+        }
+    }
+
+    /// Writes program output (e.g. the receiver's JSON records) unfiltered and without a prefix.
+    pub fn print(&mut self, args: fmt::Arguments) {
+        let _ = SinkWriter(&mut self.sink).write_fmt(args);
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($log:expr, $tag:expr, $($arg:tt)+) => {
+        $log.log($crate::log::Level::Error, $tag, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($log:expr, $tag:expr, $($arg:tt)+) => {
+        $log.log($crate::log::Level::Warn, $tag, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($log:expr, $tag:expr, $($arg:tt)+) => {
+        $log.log($crate::log::Level::Info, $tag, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($log:expr, $tag:expr, $($arg:tt)+) => {
+        $log.log($crate::log::Level::Debug, $tag, format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($log:expr, $tag:expr, $($arg:tt)+) => {
+        $log.log($crate::log::Level::Trace, $tag, format_args!($($arg)+))
+    };
+}
+
+/// Logs at the given level (`Info`, `Debug`, ...) only in `Mode::Debug`.
+#[macro_export]
+macro_rules! sensitive {
+    ($log:expr, $level:ident, $tag:expr, $($arg:tt)+) => {
+        $log.log_sensitive($crate::log::Level::$level, $tag, format_args!($($arg)+))
+    };
+}
+
+/// RAM ring buffer backend. Keeps the newest `N` bytes of output; older
+/// output is overwritten and counted in `dropped`.
+pub struct RingLog<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+    dropped: u32,
+}
+
+impl<const N: usize> RingLog<N> {
+    pub const fn new() -> Self {
+        Self { buf: [0; N], start: 0, len: 0, dropped: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes overwritten before they were read.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Returns the oldest contiguous chunk of buffered output.
+    pub fn peek(&self) -> &[u8] {
+        let end = (self.start + self.len).min(N);
+        &self.buf[self.start..end]
+    }
+
+    /// Discards `n` bytes from the front, e.g. after `peek`ed data was sent.
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.len);
+        self.start = (self.start + n) % N;
+        self.len -= n;
+    }
+
+    /// Moves buffered output into `out` until it is empty or `out` stops accepting data.
+    /// `out` returns how many bytes it took.
+    pub fn drain(&mut self, mut out: impl FnMut(&[u8]) -> usize) {
+        while !self.is_empty() {
+            let n = out(self.peek());
+            if n == 0 {
+                break;
+            }
+            self.consume(n);
+        }
+    }
+}
+
+impl<const N: usize> Default for RingLog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LogSink for RingLog<N> {
+    fn write(&mut self, data: &[u8]) {
+        for &b in data {
+            if self.len == N {
+                self.start = (self.start + 1) % N;
+                self.len -= 1;
+                self.dropped = self.dropped.saturating_add(1);
+            }
+            self.buf[(self.start + self.len) % N] = b;
+            self.len += 1;
+        }
+    }
+}
+
+/// defmt backend: records are sent over RTT at their defmt level, program
+/// output as info-level lines.
+#[cfg(feature = "defmt")]
+#[derive(Default)]
+pub struct DefmtSink {
+    line: heapless::String<128>,
+}
+
+#[cfg(feature = "defmt")]
+impl LogSink for DefmtSink {
+    fn write(&mut self, data: &[u8]) {
+        for &b in data {
+            match b {
+                b'\r' => {}
+                b'\n' => {
+                    defmt::info!("{=str}", self.line.as_str());
+                    self.line.clear();
+                }
+                _ => {
+                    if self.line.push(b as char).is_err() {
+                        defmt::info!("{=str}", self.line.as_str());
+                        self.line.clear();
+                    }
+                }
+            }
+        }
+    }
+
+    fn record(&mut self, level: Level, tag: &str, args: fmt::Arguments) {
+        let mut msg = heapless::String::<128>::new();
+        let _ = msg.write_fmt(args);
+        match level {
+            Level::Error => defmt::error!("[{=str}] {=str}", tag, msg.as_str()),
+            Level::Warn => defmt::warn!("[{=str}] {=str}", tag, msg.as_str()),
+            Level::Info => defmt::info!("[{=str}] {=str}", tag, msg.as_str()),
+            Level::Debug => defmt::debug!("[{=str}] {=str}", tag, msg.as_str()),
+            Level::Trace => defmt::trace!("[{=str}] {=str}", tag, msg.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockLog;
+
+    #[test]
+    fn filters_by_level() {
+        let mut log = Logger::new(MockLog::default(), Level::Info);
+        crate::info!(log, "gps", "Satellites: {}", 7);
+        crate::debug!(log, "gps", "hidden");
+        log.set_level(Level::Debug);
+        crate::debug!(log, "radio", "shown");
+        assert_eq!(log.sink().text(), "[INFO gps] Satellites: 7\r\n[DEBUG radio] shown\r\n");
+    }
+
+    #[test]
+    fn field_mode_drops_sensitive_records() {
+        let mut log = Logger::new(MockLog::default(), Level::Trace);
+        crate::sensitive!(log, Info, "gps", "RAW lat_e7={}", 1);
+        assert_eq!(log.sink().text(), "");
+        log.set_mode(Mode::Debug);
+        crate::sensitive!(log, Info, "gps", "RAW lat_e7={}", 1);
+        assert_eq!(log.sink().text(), "[INFO gps] RAW lat_e7=1\r\n");
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(Level::parse(b"warn"), Some(Level::Warn));
+        assert_eq!(Level::parse(b"TRACE"), Some(Level::Trace));
+        assert_eq!(Level::parse(b"verbose"), None);
+    }
+
+    #[test]
+    fn ring_keeps_newest_output() {
+        let mut ring = RingLog::<8>::new();
+        ring.write(b"0123456789");
+        assert_eq!(ring.dropped(), 2);
+        let mut out = Vec::new();
+        ring.drain(|chunk| {
+            out.extend_from_slice(chunk);
+            chunk.len()
+        });
+        assert_eq!(out, b"23456789");
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn hex_formats_bytes() {
+        let mut s = heapless::String::<16>::new();
+        write!(s, "{}", Hex(&[0x01, 0xAB, 0xFF])).unwrap();
+        assert_eq!(s, "01ABFF");
+    }
+
+    #[test]
+    fn json_str_escapes() {
+        let mut s = heapless::String::<32>::new();
+        write!(s, "{}", JsonStr("say \"hi\"\\\n")).unwrap();
+        assert_eq!(s, "say \\\"hi\\\"\\\\\\u000a");
+    }
+}
@@ -0,0 +1,725 @@
+//! LoRaWAN 1.0.x Class A end device, a transport to LoRaWAN gateways instead
+//! of an own receiver.
+//!
+//! Frames go out unchanged as the FRMPayload of data uplinks on `FPORT`, so
+//! the application behind the network server decodes them like the receiver
+//! does. The device joins by OTAA or starts from ABP session keys, keeps the
+//! frame counters and drops downlinks with a wrong MIC or an old counter.
+//! With acknowledged delivery uplinks are confirmed, and the ACK bit of a
+//! downlink acknowledges the frame.
+//!
+//! After an uplink the radio listens with the RX1 settings from the end of the
+//! transmission until RX2 opens, then with the RX2 settings for as long as a
+//! join accept takes on air. Class A only asks to listen at the start of each
+//! window; listening throughout needs no precise timer. MAC commands and the
+//! CFList of a join accept are ignored, so ADR stays off.
+//!
+//! The plans follow the LoRaWAN 1.0.x regional parameters of EU433, EU868 and
+//! US915, the latter on one 8-channel sub-band. The Ra-02 only tunes to EU433.
+
+use core::fmt;
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+
+use crate::airtime::LoraParams;
+use crate::encryption::Key;
+use crate::hal::Radio;
+use crate::radio_config::{ConfigError, Profile, RadioConfig, Region};
+
+/// FPort the beacon's frames go out on.
+pub const FPORT: u8 = 1;
+/// MHDR, DevAddr, FCtrl, FCnt, FPort and MIC around the payload of an uplink.
+pub const OVERHEAD: usize = 13;
+/// Wait after a join request that was not answered.
+pub const JOIN_RETRY_MS: u64 = 30_000;
+/// Uplink counts stored ahead at a time with ABP, so the store is written
+/// once every this many uplinks.
+pub const FCNT_RESERVE: u32 = 256;
+
+/// Public LoRaWAN networks.
+const SYNC_WORD: u8 = 0x34;
+const JOIN_ACCEPT_DELAY1_MS: u64 = 5_000;
+/// RX2 opens a second after RX1.
+const RX2_AFTER_RX1_MS: u64 = 1_000;
+/// Longest join accept, with CFList; RX2 stays open as long as it takes on air.
+const JOIN_ACCEPT_LEN: usize = 33;
+
+// MHDR of LoRaWAN R1 frames
+const JOIN_REQUEST: u8 = 0x00;
+pub const JOIN_ACCEPT: u8 = 0x20;
+pub const UNCONFIRMED_UP: u8 = 0x40;
+pub const UNCONFIRMED_DOWN: u8 = 0x60;
+pub const CONFIRMED_UP: u8 = 0x80;
+pub const CONFIRMED_DOWN: u8 = 0xA0;
+/// FCtrl bit acknowledging the last confirmed frame of the other side.
+pub const FCTRL_ACK: u8 = 0x20;
+
+pub const UP: u8 = 0;
+pub const DOWN: u8 = 1;
+
+/// Example credentials; replace them with the ones registered on the network server.
+pub const OTAA_KEYS: OtaaKeys = OtaaKeys {
+    dev_eui: [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x06, 0x1A, 0x01],
+    join_eui: [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x00, 0x00, 0x00],
+    app_key: Key::new([
+        0x2b, 0x7e, 0x15, 0x16, 0x28, 0xae, 0xd2, 0xa6,
+        0xab, 0xf7, 0x15, 0x88, 0x09, 0xcf, 0x4f, 0x3c,
+    ]),
+};
+
+pub const ABP_SESSION: Session = Session::abp(
+    0x260B_1A01,
+    Key::new([
+        0x44, 0x02, 0x42, 0x41, 0xed, 0x4c, 0xe9, 0xa6,
+        0x8c, 0x6a, 0x8b, 0xc0, 0x55, 0x23, 0x3f, 0xd3,
+    ]),
+    Key::new([
+        0xec, 0x92, 0x58, 0x02, 0xae, 0x43, 0x0c, 0xa7,
+        0x7f, 0xd3, 0xdd, 0x73, 0xcb, 0x2c, 0xc5, 0x88,
+    ]),
+);
+
+#[derive(Clone, Copy, Debug)]
+pub struct LorawanConfig {
+    /// EU433, EU868 or US915 plan.
+    pub region: Region,
+    /// Uplink data rate, DR0..DR5 in Europe, DR0..DR4 in the US.
+    pub data_rate: u8,
+    /// Lowered to the region's limit.
+    pub tx_power_dbm: i8,
+}
+
+impl Default for LorawanConfig {
+    fn default() -> Self {
+        // DR3 is SF9 at 125 kHz in Europe, like the standard profile
+        Self { region: Region::Eu433, data_rate: 3, tx_power_dbm: 20 }
+    }
+}
+
+/// OTAA credentials. EUIs are written most significant byte first, as
+/// network servers show them.
+#[derive(Clone, Copy, Debug)]
+pub struct OtaaKeys {
+    pub dev_eui: [u8; 8],
+    pub join_eui: [u8; 8],
+    pub app_key: Key<16>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Session {
+    pub dev_addr: u32,
+    pub nwk_s_key: Key<16>,
+    pub app_s_key: Key<16>,
+    /// Counter of the next uplink.
+    pub fcnt_up: u32,
+    /// Counter of the last downlink, `None` before the first.
+    pub fcnt_down: Option<u32>,
+    /// Receive window settings of the join accept.
+    pub rx1_dr_offset: u8,
+    /// `None` for the plan's default.
+    pub rx2_data_rate: Option<u8>,
+    pub rx_delay_s: u8,
+}
+
+impl Session {
+    /// Session with personalized keys and the plan's receive windows.
+    pub const fn abp(dev_addr: u32, nwk_s_key: Key<16>, app_s_key: Key<16>) -> Self {
+        Self {
+            dev_addr,
+            nwk_s_key,
+            app_s_key,
+            fcnt_up: 0,
+            fcnt_down: None,
+            rx1_dr_offset: 0,
+            rx2_data_rate: None,
+            rx_delay_s: 1,
+        }
+    }
+
+    /// Session of a join accept with the plaintext `fields` AppNonce, NetID,
+    /// DevAddr, DLSettings and RxDelay, answering the request with `dev_nonce`.
+    pub fn from_join(app_key: &Key<16>, fields: &[u8; 12], dev_nonce: u16) -> Self {
+        let derive = |kind: u8| {
+            let mut block = [0u8; 16];
+            block[0] = kind;
+            block[1..7].copy_from_slice(&fields[..6]);
+            block[7..9].copy_from_slice(&dev_nonce.to_le_bytes());
+            encrypt_block(app_key, &mut block);
+            Key::new(block)
+        };
+        let dev_addr = u32::from_le_bytes([fields[6], fields[7], fields[8], fields[9]]);
+        Self {
+            rx1_dr_offset: fields[10] >> 4 & 0x07,
+            rx2_data_rate: Some(fields[10] & 0x0F),
+            // 0 means 1 s as well
+            rx_delay_s: (fields[11] & 0x0F).max(1),
+            ..Self::abp(dev_addr, derive(0x01), derive(0x02))
+        }
+    }
+}
+
+pub enum Activation {
+    Otaa { keys: OtaaKeys, dev_nonce: u16 },
+    Abp(Session),
+}
+
+/// What the receive windows after a transmission brought.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    Joined,
+    /// A downlink for this device; `ack` acknowledges the last confirmed uplink.
+    Downlink { ack: bool },
+    /// Both windows closed without a downlink.
+    NoDownlink,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    /// An uplink or join request is on air.
+    Sending,
+    Rx1 { rx2_at: u64 },
+    Rx2 { until: u64 },
+}
+
+pub struct Mac {
+    config: LorawanConfig,
+    keys: Option<OtaaKeys>,
+    /// DevNonce of the next join request.
+    dev_nonce: u16,
+    session: Option<Session>,
+    state: State,
+    /// Plan channel of the last transmission.
+    channel: u8,
+    /// The last downlink was confirmed and the next uplink acknowledges it.
+    ack_down: bool,
+    join_at: u64,
+    /// The count that is kept in the store; nothing goes out with it or above.
+    stored_count: Option<u32>,
+}
+
+impl Mac {
+    /// Fails when the radio cannot tune to the plan's channels.
+    pub fn new(config: LorawanConfig, activation: Activation) -> Result<Self, ConfigError> {
+        let (keys, dev_nonce, session) = match activation {
+            Activation::Otaa { keys, dev_nonce } => (Some(keys), dev_nonce, None),
+            Activation::Abp(session) => (None, 0, Some(session)),
+        };
+        let mac = Self { config, keys, dev_nonce, session, state: State::Idle, channel: 0, ack_down: false, join_at: 0, stored_count: None };
+        mac.channel_config(0, config.data_rate)?;
+        mac.rx1_config()?;
+        mac.rx2_config()?;
+        Ok(mac)
+    }
+
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    pub fn is_joined(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Joined, and the receive windows of the last uplink are closed.
+    pub fn is_ready(&self) -> bool {
+        self.is_joined() && self.state == State::Idle && self.count_stored()
+    }
+
+    /// Whether a join request should go out now.
+    pub fn join_due(&self, now: u64) -> bool {
+        !self.is_joined() && self.state == State::Idle && now >= self.join_at && self.count_stored()
+    }
+
+    /// The count that must not repeat across resets: the DevNonce of the
+    /// next join request with OTAA, the uplink counter with ABP.
+    pub fn count(&self) -> u32 {
+        match &self.session {
+            Some(session) if self.keys.is_none() => session.fcnt_up,
+            _ => self.dev_nonce as u32,
+        }
+    }
+
+    /// Holds join requests or uplinks back until the count is stored; see
+    /// `count_to_store`.
+    pub fn keep_count(&mut self) {
+        self.stored_count = Some(self.count());
+    }
+
+    /// The count to write to the store before the next join request or
+    /// uplink may go out: one above the DevNonce, so every join request is
+    /// stored, or `FCNT_RESERVE` above the uplink counter.
+    pub fn count_to_store(&self) -> Option<u32> {
+        // a join request on air may still be answered
+        if self.count_stored() || self.state != State::Idle {
+            return None;
+        }
+        let ahead = if self.keys.is_some() { 1 } else { FCNT_RESERVE };
+        Some(self.count().saturating_add(ahead))
+    }
+
+    /// The store now holds `count`; counts below it may go out.
+    pub fn stored(&mut self, count: u32) {
+        self.stored_count = Some(count);
+    }
+
+    fn count_stored(&self) -> bool {
+        // the DevNonce is not used again once joined
+        (self.keys.is_some() && self.is_joined()) || self.stored_count.is_none_or(|stored| self.count() < stored)
+    }
+
+    /// Puts the next join request off until `at`.
+    pub fn defer_join(&mut self, at: u64) {
+        self.join_at = at;
+    }
+
+    /// Settings the next uplink or join request goes out with; the channels
+    /// of the plan are used in turn.
+    pub fn tx_config(&self) -> RadioConfig {
+        let count = self.session.map_or(self.dev_nonce as u32, |s| s.fcnt_up);
+        let channel = (count % self.config.region.channels() as u32) as u8;
+        // checked in `new` for channel 0, the others lie next to it
+        self.channel_config(channel, self.config.data_rate).unwrap_or_default()
+    }
+
+    /// Writes a join request into `out`; returns its length, `None` with ABP.
+    pub fn join_request(&self, out: &mut [u8; 255]) -> Option<usize> {
+        let keys = self.keys.as_ref()?;
+        out[0] = JOIN_REQUEST;
+        out[1..9].copy_from_slice(&keys.join_eui);
+        out[1..9].reverse();
+        out[9..17].copy_from_slice(&keys.dev_eui);
+        out[9..17].reverse();
+        out[17..19].copy_from_slice(&self.dev_nonce.to_le_bytes());
+        let mic = mic(&keys.app_key, &[&out[..19]]);
+        out[19..23].copy_from_slice(&mic);
+        Some(23)
+    }
+
+    /// Writes the uplink carrying `payload` into `out`; returns its length,
+    /// `None` before the join or when the payload is too long.
+    pub fn uplink(&self, payload: &[u8], confirmed: bool, out: &mut [u8; 255]) -> Option<usize> {
+        let session = self.session.as_ref()?;
+        let mhdr = if confirmed { CONFIRMED_UP } else { UNCONFIRMED_UP };
+        let fctrl = if self.ack_down { FCTRL_ACK } else { 0 };
+        data_frame(session, UP, mhdr, fctrl, session.fcnt_up, payload, out)
+    }
+
+    /// Counts the join request or uplink just put on air with `tx_config`
+    /// and opens the receive windows once it is out.
+    pub fn sent(&mut self) {
+        self.channel = self.tx_config().channel();
+        match &mut self.session {
+            Some(session) => session.fcnt_up = session.fcnt_up.wrapping_add(1),
+            None => self.dev_nonce = self.dev_nonce.wrapping_add(1),
+        }
+        self.ack_down = false;
+        self.state = State::Sending;
+    }
+
+    /// Runs the receive windows; call on every main loop iteration.
+    pub fn poll<R: Radio>(&mut self, radio: &mut R, now: u64) -> Result<Option<Event>, R::Error> {
+        let result = self.step(radio, now);
+        if result.is_err() {
+            self.state = State::Idle;
+        }
+        result
+    }
+
+    fn step<R: Radio>(&mut self, radio: &mut R, now: u64) -> Result<Option<Event>, R::Error> {
+        match self.state {
+            State::Idle => return Ok(None),
+            State::Sending => {
+                if !radio.is_transmitting()? {
+                    let rx1_ms = match self.session {
+                        Some(session) => session.rx_delay_s as u64 * 1000,
+                        None => JOIN_ACCEPT_DELAY1_MS,
+                    };
+                    // checked in `new`
+                    radio.configure(&self.rx1_config().unwrap_or_default())?;
+                    radio.start_receive()?;
+                    self.state = State::Rx1 { rx2_at: now + rx1_ms + RX2_AFTER_RX1_MS };
+                }
+                return Ok(None);
+            }
+            State::Rx1 { .. } | State::Rx2 { .. } => {}
+        }
+        let mut buf = [0u8; 255];
+        if let Some(len) = radio.receive(&mut buf)?
+            && let Some(event) = self.accept(&buf[..len])
+        {
+            self.state = State::Idle;
+            return Ok(Some(event));
+        }
+        match self.state {
+            State::Rx1 { rx2_at } if now >= rx2_at => {
+                let config = self.rx2_config().unwrap_or_default();
+                radio.configure(&config)?;
+                radio.start_receive()?;
+                let window_ms = config.params().time_on_air_us(JOIN_ACCEPT_LEN).div_ceil(1000) as u64;
+                self.state = State::Rx2 { until: now + window_ms };
+            }
+            State::Rx2 { until } if now >= until => {
+                self.state = State::Idle;
+                if !self.is_joined() {
+                    self.join_at = now + JOIN_RETRY_MS;
+                }
+                return Ok(Some(Event::NoDownlink));
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Takes a join accept or a downlink for this device.
+    fn accept(&mut self, frame: &[u8]) -> Option<Event> {
+        let Some(session) = &mut self.session else {
+            let keys = self.keys.as_ref()?;
+            // answers the request before the last `sent`
+            let session = join_accept(keys, frame, self.dev_nonce.wrapping_sub(1))?;
+            self.session = Some(session);
+            return Some(Event::Joined);
+        };
+        let down = parse_data(frame)?;
+        if down.dev_addr != session.dev_addr || !matches!(down.mhdr, UNCONFIRMED_DOWN | CONFIRMED_DOWN) {
+            return None;
+        }
+        let fcnt = match session.fcnt_down {
+            Some(last) => full_fcnt(last.wrapping_add(1), down.fcnt),
+            None => down.fcnt as u32,
+        };
+        if !down.mic_ok(&session.nwk_s_key, DOWN, fcnt) {
+            return None;
+        }
+        session.fcnt_down = Some(fcnt);
+        self.ack_down = down.mhdr == CONFIRMED_DOWN;
+        Some(Event::Downlink { ack: down.fctrl & FCTRL_ACK != 0 })
+    }
+
+    fn channel_config(&self, channel: u8, data_rate: u8) -> Result<RadioConfig, ConfigError> {
+        let params = lora_params(self.config.region, data_rate).ok_or(ConfigError::NoSuchChannel)?;
+        let profile = Profile { name: "lorawan", params, tx_power_dbm: self.config.tx_power_dbm };
+        RadioConfig::new(profile, self.config.region, channel)
+    }
+
+    fn rx1_config(&self) -> Result<RadioConfig, ConfigError> {
+        let offset = self.session.map_or(0, |s| s.rx1_dr_offset);
+        let up = self.config.data_rate;
+        let (data_rate, hz) = match self.config.region {
+            Region::Eu433 | Region::Eu868 => (up.saturating_sub(offset), None),
+            // downlink channel `channel % 8` of the 500 kHz channels
+            Region::Us915(_) => ((10 + up).min(13).saturating_sub(offset).max(8), Some(923_300_000)),
+        };
+        let config = self.channel_config(self.channel, data_rate)?;
+        let hz = hz.map_or(config.frequency_hz(), |base| base + self.channel as u32 * 600_000);
+        downlink(config, hz)
+    }
+
+    fn rx2_config(&self) -> Result<RadioConfig, ConfigError> {
+        let (default_rate, hz) = match self.config.region {
+            Region::Eu433 => (0, 434_665_000),
+            Region::Eu868 => (0, 869_525_000),
+            Region::Us915(_) => (8, 923_300_000),
+        };
+        let data_rate = self.session.and_then(|s| s.rx2_data_rate).unwrap_or(default_rate);
+        downlink(self.channel_config(0, data_rate)?, hz)
+    }
+}
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "LoRaWAN on {}, DR{}: ", self.config.region, self.config.data_rate)?;
+        match &self.session {
+            Some(s) => write!(f, "device {:08x}, uplink counter {}", s.dev_addr, s.fcnt_up),
+            None => f.write_str("not joined"),
+        }
+    }
+}
+
+/// Modem settings of data rate `dr` in the plan of `region`, for uplinks.
+pub fn lora_params(region: Region, dr: u8) -> Option<LoraParams> {
+    let (spreading_factor, bandwidth_hz) = match (region, dr) {
+        (Region::Eu433 | Region::Eu868, 0..=5) => (12 - dr, 125_000),
+        (Region::Eu433 | Region::Eu868, 6) => (7, 250_000),
+        (Region::Us915(_), 0..=3) => (10 - dr, 125_000),
+        (Region::Us915(_), 4) => (8, 500_000),
+        (Region::Us915(_), 8..=13) => (20 - dr, 500_000),
+        _ => return None,
+    };
+    Some(LoraParams {
+        spreading_factor,
+        bandwidth_hz,
+        coding_rate: 5,
+        preamble_len: 8,
+        explicit_header: true,
+        crc: true,
+        sync_word: SYNC_WORD,
+        invert_iq: false,
+    })
+}
+
+/// Downlinks are sent with inverted IQ and without payload CRC.
+fn downlink(config: RadioConfig, hz: u32) -> Result<RadioConfig, ConfigError> {
+    let profile = config.profile();
+    let params = LoraParams { crc: false, invert_iq: true, ..profile.params };
+    config.with_profile(Profile { params, ..profile }).with_frequency(hz)
+}
+
+/// Fields of a data frame.
+pub struct DataFrame<'a> {
+    pub mhdr: u8,
+    pub dev_addr: u32,
+    pub fctrl: u8,
+    /// Low 16 bits of the frame counter.
+    pub fcnt: u16,
+    pub port: Option<u8>,
+    /// Still encrypted.
+    pub payload: &'a [u8],
+    /// Everything the MIC covers.
+    msg: &'a [u8],
+    mic: [u8; 4],
+}
+
+impl DataFrame<'_> {
+    /// Checks the MIC, taking `fcnt` as the full frame counter.
+    pub fn mic_ok(&self, nwk_s_key: &Key<16>, dir: u8, fcnt: u32) -> bool {
+        frame_mic(nwk_s_key, dir, self.dev_addr, fcnt, self.msg) == self.mic
+    }
+}
+
+pub fn parse_data(frame: &[u8]) -> Option<DataFrame<'_>> {
+    let (msg, mic) = frame.split_at_checked(frame.len().checked_sub(4)?)?;
+    let fhdr_end = 8 + (*msg.get(5)? & 0x0F) as usize;
+    let rest = msg.get(fhdr_end..)?;
+    Some(DataFrame {
+        mhdr: msg[0],
+        dev_addr: u32::from_le_bytes([msg[1], msg[2], msg[3], msg[4]]),
+        fctrl: msg[5],
+        fcnt: u16::from_le_bytes([msg[6], msg[7]]),
+        port: rest.first().copied(),
+        payload: rest.get(1..).unwrap_or(&[]),
+        msg,
+        mic: [mic[0], mic[1], mic[2], mic[3]],
+    })
+}
+
+/// Writes a data frame carrying `payload` on `FPORT` into `out`, without
+/// FPort when it is empty; returns its length.
+pub fn data_frame(
+    session: &Session,
+    dir: u8,
+    mhdr: u8,
+    fctrl: u8,
+    fcnt: u32,
+    payload: &[u8],
+    out: &mut [u8; 255],
+) -> Option<usize> {
+    if payload.len() > 255 - OVERHEAD {
+        return None;
+    }
+    out[0] = mhdr;
+    out[1..5].copy_from_slice(&session.dev_addr.to_le_bytes());
+    out[5] = fctrl;
+    out[6..8].copy_from_slice(&(fcnt as u16).to_le_bytes());
+    let mut len = 8;
+    if !payload.is_empty() {
+        out[8] = FPORT;
+        out[9..9 + payload.len()].copy_from_slice(payload);
+        crypt(&session.app_s_key, dir, session.dev_addr, fcnt, &mut out[9..9 + payload.len()]);
+        len = 9 + payload.len();
+    }
+    let mic = frame_mic(&session.nwk_s_key, dir, session.dev_addr, fcnt, &out[..len]);
+    out[len..len + 4].copy_from_slice(&mic);
+    Some(len + 4)
+}
+
+/// En- or decrypts an FRMPayload in place.
+pub fn crypt(key: &Key<16>, dir: u8, dev_addr: u32, fcnt: u32, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(16).enumerate() {
+        let mut block = block(0x01, dir, dev_addr, fcnt, i as u8 + 1);
+        encrypt_block(key, &mut block);
+        chunk.iter_mut().zip(block).for_each(|(b, k)| *b ^= k);
+    }
+}
+
+/// First four bytes of the AES-CMAC of `parts`.
+pub fn mic(key: &Key<16>, parts: &[&[u8]]) -> [u8; 4] {
+    // a 16-byte key always fits
+    let mut cmac = <Cmac<Aes128> as cmac::Mac>::new(key.secret_bytes().into());
+    parts.iter().for_each(|part| cmac.update(part));
+    let tag = cmac.finalize().into_bytes();
+    [tag[0], tag[1], tag[2], tag[3]]
+}
+
+/// Decrypts a join accept and checks its MIC.
+fn join_accept(keys: &OtaaKeys, frame: &[u8], dev_nonce: u16) -> Option<Session> {
+    if frame.first() != Some(&JOIN_ACCEPT) || !matches!(frame.len(), 17 | 33) {
+        return None;
+    }
+    // the network server encrypts with AES decryption, so this undoes it
+    let mut plain = [0u8; 32];
+    let plain = &mut plain[..frame.len() - 1];
+    plain.copy_from_slice(&frame[1..]);
+    plain.chunks_mut(16).for_each(|block| encrypt_block(&keys.app_key, block.try_into().unwrap()));
+    let (body, tag) = plain.split_at(plain.len() - 4);
+    if mic(&keys.app_key, &[&[JOIN_ACCEPT], body]) != *tag {
+        return None;
+    }
+    let fields: &[u8; 12] = body[..12].try_into().ok()?;
+    Some(Session::from_join(&keys.app_key, fields, dev_nonce))
+}
+
+/// The full counter following `next - 1` with the low 16 bits `low`.
+fn full_fcnt(next: u32, low: u16) -> u32 {
+    let fcnt = next & !0xFFFF | low as u32;
+    if fcnt < next { fcnt.wrapping_add(0x1_0000) } else { fcnt }
+}
+
+fn frame_mic(key: &Key<16>, dir: u8, dev_addr: u32, fcnt: u32, msg: &[u8]) -> [u8; 4] {
+    mic(key, &[&block(0x49, dir, dev_addr, fcnt, msg.len() as u8), msg])
+}
+
+/// The A (encryption) and B0 (MIC) blocks.
+fn block(first: u8, dir: u8, dev_addr: u32, fcnt: u32, last: u8) -> [u8; 16] {
+    let mut b = [0u8; 16];
+    b[0] = first;
+    b[5] = dir;
+    b[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    b[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    b[15] = last;
+    b
+}
+
+fn encrypt_block(key: &Key<16>, block: &mut [u8; 16]) {
+    Aes128::new(key.secret_bytes().into()).encrypt_block(block.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockNetworkServer, MockRadio};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn matches_a_known_uplink() {
+        // reference frame of the lora-packet decoder: "test" from 49BE7DF1, FCnt 2
+        let frame = hex("40F17DBE4900020001954378762B11FF0D");
+        let session = Session { fcnt_up: 2, ..Session::abp(0x49BE_7DF1, ABP_SESSION.nwk_s_key, ABP_SESSION.app_s_key) };
+        let mut out = [0u8; 255];
+        let len = data_frame(&session, UP, UNCONFIRMED_UP, 0, 2, b"test", &mut out).unwrap();
+        assert_eq!(out[..len], frame[..]);
+
+        let up = parse_data(&frame).unwrap();
+        assert_eq!((up.dev_addr, up.fcnt, up.port), (0x49BE_7DF1, 2, Some(FPORT)));
+        assert!(up.mic_ok(&session.nwk_s_key, UP, 2));
+        assert!(!up.mic_ok(&session.nwk_s_key, UP, 0x1_0002));
+        let mut payload = up.payload.to_vec();
+        crypt(&session.app_s_key, UP, up.dev_addr, 2, &mut payload);
+        assert_eq!(payload, b"test");
+    }
+
+    #[test]
+    fn plans_and_receive_windows() {
+        let mac = Mac::new(LorawanConfig::default(), Activation::Abp(ABP_SESSION)).unwrap();
+        let tx = mac.tx_config();
+        assert_eq!((tx.frequency_hz(), tx.params().spreading_factor, tx.params().sync_word), (433_175_000, 9, 0x34));
+        assert_eq!(tx.tx_power_dbm(), 10);
+        let rx1 = mac.rx1_config().unwrap();
+        assert_eq!((rx1.frequency_hz(), rx1.params().spreading_factor, rx1.params().invert_iq), (433_175_000, 9, true));
+        let rx2 = mac.rx2_config().unwrap();
+        assert_eq!((rx2.frequency_hz(), rx2.params().spreading_factor), (434_665_000, 12));
+
+        assert_eq!(lora_params(Region::Us915(2), 0).map(|p| p.spreading_factor), Some(10));
+        assert_eq!(lora_params(Region::Us915(2), 8).map(|p| (p.spreading_factor, p.bandwidth_hz)), Some((12, 500_000)));
+        assert_eq!(lora_params(Region::Eu868, 7), None);
+        // the Ra-02 cannot tune to the other plans
+        let eu868 = LorawanConfig { region: Region::Eu868, ..LorawanConfig::default() };
+        assert!(matches!(Mac::new(eu868, Activation::Abp(ABP_SESSION)), Err(ConfigError::OutOfRange(868_100_000))));
+    }
+
+    #[test]
+    fn joins_and_takes_only_fresh_downlinks() {
+        let mut ns = MockNetworkServer::new(OTAA_KEYS);
+        let activation = Activation::Otaa { keys: OTAA_KEYS, dev_nonce: 0x0100 };
+        let mut mac = Mac::new(LorawanConfig::default(), activation).unwrap();
+        let mut radio = MockRadio::default();
+        let mut frame = [0u8; 255];
+
+        // the first request goes unanswered
+        assert!(mac.join_due(0));
+        mac.join_request(&mut frame).unwrap();
+        assert_eq!(frame[17..19], [0x00, 0x01]);
+        mac.sent();
+        assert_eq!(mac.poll(&mut radio, 0), Ok(None));
+        assert_eq!(mac.poll(&mut radio, 5_999), Ok(None));
+        assert_eq!(mac.poll(&mut radio, 6_000), Ok(None));
+        assert_eq!(radio.config.unwrap().frequency_hz(), 434_665_000);
+        // RX2 stays open while a join accept takes on air at SF12
+        assert_eq!(mac.poll(&mut radio, 7_810), Ok(None));
+        assert_eq!(mac.poll(&mut radio, 7_811), Ok(Some(Event::NoDownlink)));
+        assert!(!mac.join_due(7_811 + JOIN_RETRY_MS - 1));
+        assert!(mac.join_due(7_811 + JOIN_RETRY_MS));
+
+        let len = mac.join_request(&mut frame).unwrap();
+        assert_eq!(len, 23);
+        mac.sent();
+        mac.poll(&mut radio, 40_000).unwrap();
+        radio.incoming.push_back(ns.handle(&frame[..len]).unwrap());
+        assert_eq!(mac.poll(&mut radio, 40_200), Ok(Some(Event::Joined)));
+        assert_eq!(mac.session().unwrap().dev_addr, ns.session.unwrap().dev_addr);
+
+        // a confirmed uplink and its ACK
+        let len = mac.uplink(b"fix", true, &mut frame).unwrap();
+        assert_eq!(len, 3 + OVERHEAD);
+        mac.sent();
+        let ack = ns.handle(&frame[..len]).unwrap();
+        assert_eq!(ns.uplinks, [b"fix".to_vec()]);
+        radio.incoming.push_back(ack.clone());
+        mac.poll(&mut radio, 41_000).unwrap();
+        assert!(radio.config.unwrap().params().invert_iq);
+        assert_eq!(mac.poll(&mut radio, 41_100), Ok(Some(Event::Downlink { ack: true })));
+
+        // a replayed downlink is dropped
+        let len = mac.uplink(b"fix", true, &mut frame).unwrap();
+        mac.sent();
+        radio.incoming.push_back(ack);
+        mac.poll(&mut radio, 50_000).unwrap();
+        assert_eq!(mac.poll(&mut radio, 50_100), Ok(None));
+        radio.incoming.push_back(ns.handle(&frame[..len]).unwrap());
+        assert_eq!(mac.poll(&mut radio, 50_200), Ok(Some(Event::Downlink { ack: true })));
+        assert_eq!(mac.session().unwrap().fcnt_up, 2);
+        assert_eq!(ns.rejected, 0);
+    }
+
+    #[test]
+    fn counts_wait_for_the_store() {
+        let activation = Activation::Otaa { keys: OTAA_KEYS, dev_nonce: 0x0100 };
+        let mut mac = Mac::new(LorawanConfig::default(), activation).unwrap();
+        assert_eq!(mac.count_to_store(), None);
+        mac.keep_count();
+        assert!(!mac.join_due(0));
+        assert_eq!(mac.count_to_store(), Some(0x0101));
+        mac.stored(0x0101);
+        assert!(mac.join_due(0));
+        mac.sent();
+        assert_eq!(mac.count_to_store(), None);
+
+        let mut mac = Mac::new(LorawanConfig::default(), Activation::Abp(ABP_SESSION)).unwrap();
+        mac.keep_count();
+        assert!(!mac.is_ready());
+        assert_eq!(mac.count_to_store(), Some(FCNT_RESERVE));
+        mac.stored(FCNT_RESERVE);
+        assert!(mac.is_ready());
+    }
+
+    #[test]
+    fn counters_carry_over_16_bits() {
+        assert_eq!(full_fcnt(0x1_FFFF, 0xFFFF), 0x1_FFFF);
+        assert_eq!(full_fcnt(0x1_FFFF, 0x0001), 0x2_0001);
+        assert_eq!(full_fcnt(5, 7), 7);
+    }
+}
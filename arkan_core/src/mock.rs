@@ -0,0 +1,320 @@
+//! Host test doubles for the `hal` traits.
+
+use core::cell::Cell;
+use std::collections::VecDeque;
+use std::string::String;
+use std::vec::Vec;
+
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+
+use crate::hal::{ByteSource, Clock, LogSink, Radio, Store, StoreError};
+use crate::lorawan::{self, OtaaKeys, Session, CONFIRMED_UP, DOWN, FCTRL_ACK, JOIN_ACCEPT, UNCONFIRMED_DOWN, UP};
+use crate::radio_config::RadioConfig;
+
+/// Collects everything written to it.
+#[derive(Default)]
+pub struct MockLog(pub Vec<u8>);
+
+impl MockLog {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0).into_owned()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl LogSink for MockLog {
+    fn write(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+}
+
+/// Records transmitted packets and hands out queued received ones while in
+/// receive mode. A transmission is reported on air until the next `is_transmitting`,
+/// a channel activity detection finishes on the next `cad_result`.
+#[derive(Default)]
+pub struct MockRadio {
+    pub sent: Vec<Vec<u8>>,
+    pub incoming: VecDeque<Vec<u8>>,
+    pub receiving: bool,
+    pub transmitting: bool,
+    /// Last applied configuration.
+    pub config: Option<RadioConfig>,
+    /// When set, `transmit` fails.
+    pub fail_tx: bool,
+    /// Channel activity detections run so far.
+    pub cad_runs: u32,
+    /// Number of the next detections that find the channel busy.
+    pub cad_busy: u32,
+    pub detecting: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MockRadioError;
+
+impl Radio for MockRadio {
+    type Error = MockRadioError;
+
+    fn configure(&mut self, config: &RadioConfig) -> Result<(), Self::Error> {
+        self.receiving = false;
+        self.transmitting = false;
+        self.config = Some(*config);
+        Ok(())
+    }
+
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        if self.fail_tx {
+            return Err(MockRadioError);
+        }
+        self.receiving = false;
+        self.transmitting = true;
+        self.sent.push(payload.to_vec());
+        Ok(())
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        Ok(core::mem::take(&mut self.transmitting))
+    }
+
+    fn start_cad(&mut self) -> Result<(), Self::Error> {
+        self.receiving = false;
+        self.transmitting = false;
+        self.detecting = true;
+        self.cad_runs += 1;
+        Ok(())
+    }
+
+    fn cad_result(&mut self) -> Result<Option<bool>, Self::Error> {
+        if !core::mem::take(&mut self.detecting) {
+            return Ok(None);
+        }
+        let busy = self.cad_busy > 0;
+        self.cad_busy = self.cad_busy.saturating_sub(1);
+        Ok(Some(busy))
+    }
+
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.receiving = true;
+        self.detecting = false;
+        Ok(())
+    }
+
+    fn receive(&mut self, buf: &mut [u8; 255]) -> Result<Option<usize>, Self::Error> {
+        if !self.receiving {
+            return Ok(None);
+        }
+        match self.incoming.pop_front() {
+            Some(p) => {
+                buf[..p.len()].copy_from_slice(&p);
+                Ok(Some(p.len()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Replays a recorded byte stream.
+#[derive(Default)]
+pub struct MockGps(pub VecDeque<u8>);
+
+impl MockGps {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self(bytes.iter().copied().collect())
+    }
+}
+
+impl ByteSource for MockGps {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.0.pop_front()
+    }
+}
+
+/// Manually advanced clock.
+#[derive(Default)]
+pub struct MockClock(pub Cell<u64>);
+
+impl MockClock {
+    pub fn new(now_ms: u64) -> Self {
+        Self(Cell::new(now_ms))
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.0.set(self.0.get() + ms);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// RAM-backed store with NOR flash semantics.
+pub struct MockStore {
+    pub data: Vec<u8>,
+    pub erase_count: usize,
+    /// Writes and erases that complete before the power is lost: the next one
+    /// tears, writing nothing or erasing only the first half of its range, and
+    /// later ones do nothing.
+    pub power_cut: Option<usize>,
+    /// The power was cut.
+    off: bool,
+}
+
+impl MockStore {
+    pub fn new(capacity: u32) -> Self {
+        Self { data: std::vec![0xFF; capacity as usize], erase_count: 0, power_cut: None, off: false }
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<core::ops::Range<usize>, StoreError> {
+        let start = offset as usize;
+        let end = start.checked_add(len).ok_or(StoreError::OutOfBounds)?;
+        if end > self.data.len() {
+            return Err(StoreError::OutOfBounds);
+        }
+        Ok(start..end)
+    }
+
+    /// Counts down `power_cut`.
+    fn power(&mut self) -> Power {
+        match &mut self.power_cut {
+            None => {
+                self.off = false;
+                Power::On
+            }
+            Some(_) if self.off => Power::Off,
+            Some(0) => {
+                self.off = true;
+                Power::Tearing
+            }
+            Some(n) => {
+                *n -= 1;
+                Power::On
+            }
+        }
+    }
+}
+
+enum Power {
+    On,
+    Tearing,
+    Off,
+}
+
+impl Store for MockStore {
+    type Error = StoreError;
+
+    const ERASE_SIZE: u32 = 4096;
+
+    fn capacity(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let r = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[r]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let r = self.range(offset, data.len())?;
+        if !matches!(self.power(), Power::On) {
+            return Ok(());
+        }
+        for (cell, b) in self.data[r].iter_mut().zip(data) {
+            *cell &= *b;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, offset: u32, len: u32) -> Result<(), Self::Error> {
+        if !offset.is_multiple_of(Self::ERASE_SIZE) || !len.is_multiple_of(Self::ERASE_SIZE) {
+            return Err(StoreError::Unaligned);
+        }
+        let r = self.range(offset, len as usize)?;
+        match self.power() {
+            Power::On => {}
+            Power::Tearing => {
+                self.data[r.start..r.start + r.len() / 2].fill(0xFF);
+                return Ok(());
+            }
+            Power::Off => return Ok(()),
+        }
+        self.data[r].fill(0xFF);
+        self.erase_count += 1;
+        Ok(())
+    }
+}
+
+/// LoRaWAN network server stand-in for one OTAA device, reached through a
+/// gateway: answers join requests, and confirmed uplinks with an ACK.
+pub struct MockNetworkServer {
+    keys: OtaaKeys,
+    /// Server side of the session; `fcnt_up` is the next expected counter.
+    pub session: Option<Session>,
+    /// Decrypted payloads of the accepted uplinks.
+    pub uplinks: Vec<Vec<u8>>,
+    /// Frames dropped for a wrong MIC, DevAddr or counter.
+    pub rejected: u32,
+    fcnt_down: u32,
+}
+
+impl MockNetworkServer {
+    pub fn new(keys: OtaaKeys) -> Self {
+        Self { keys, session: None, uplinks: Vec::new(), rejected: 0, fcnt_down: 0 }
+    }
+
+    /// Handles a frame the gateway heard; returns the downlink for RX1.
+    pub fn handle(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        // MHDR 0x00 is a join request
+        if frame.first() == Some(&0x00) { self.join(frame) } else { self.uplink(frame) }
+    }
+
+    fn join(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let app_key = &self.keys.app_key;
+        if frame.len() != 23 || lorawan::mic(app_key, &[&frame[..19]]) != frame[19..] {
+            self.rejected += 1;
+            return None;
+        }
+        let dev_nonce = u16::from_le_bytes([frame[17], frame[18]]);
+        // AppNonce, NetID 0x000013, DevAddr 0x26011301, RX1 offset 0, RX2 DR0, RX1 after 1 s
+        let fields = [0x01, 0x02, 0x03, 0x13, 0x00, 0x00, 0x01, 0x13, 0x01, 0x26, 0x00, 0x01];
+        let mic = lorawan::mic(app_key, &[&[JOIN_ACCEPT], &fields]);
+        let mut block = [0u8; 16];
+        block[..12].copy_from_slice(&fields);
+        block[12..].copy_from_slice(&mic);
+        Aes128::new(app_key.secret_bytes().into()).decrypt_block((&mut block).into());
+        self.session = Some(Session::from_join(app_key, &fields, dev_nonce));
+        self.uplinks.clear();
+        self.fcnt_down = 0;
+        let mut accept = std::vec![JOIN_ACCEPT];
+        accept.extend_from_slice(&block);
+        Some(accept)
+    }
+
+    fn uplink(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let (Some(session), Some(up)) = (self.session.as_mut(), lorawan::parse_data(frame)) else {
+            self.rejected += 1;
+            return None;
+        };
+        let fcnt = session.fcnt_up & !0xFFFF | up.fcnt as u32;
+        if up.dev_addr != session.dev_addr || fcnt < session.fcnt_up || !up.mic_ok(&session.nwk_s_key, UP, fcnt) {
+            self.rejected += 1;
+            return None;
+        }
+        session.fcnt_up = fcnt + 1;
+        let mut payload = up.payload.to_vec();
+        lorawan::crypt(&session.app_s_key, UP, up.dev_addr, fcnt, &mut payload);
+        self.uplinks.push(payload);
+        if up.mhdr != CONFIRMED_UP {
+            return None;
+        }
+        let mut out = [0u8; 255];
+        let len = lorawan::data_frame(session, DOWN, UNCONFIRMED_DOWN, FCTRL_ACK, self.fcnt_down, &[], &mut out)?;
+        self.fcnt_down += 1;
+        Some(out[..len].to_vec())
+    }
+}
@@ -0,0 +1,217 @@
+//! NMEA 0183 sentence assembly and field parsing.
+
+pub const LINE_LEN: usize = 128;
+
+/// Returns field `idx` of a sentence, `$GPGGA` being field 0.
+pub fn field(s: &[u8], idx: usize) -> Option<&[u8]> {
+    let mut start = 0;
+    let mut current = 0;
+    for i in 0..=s.len() {
+        if i == s.len() || s[i] == b',' || s[i] == b'*' {
+            if current == idx {
+                return Some(&s[start..i]);
+            }
+            current += 1;
+            start = i + 1;
+        }
+    }
+    None
+}
+
+/// Returns true if `s` is a sentence of `kind` (e.g. `b"GGA"`) from any talker (`$GP`, `$GN`, ...).
+pub fn is_sentence(s: &[u8], kind: &[u8]) -> bool {
+    match field(s, 0) {
+        Some(id) => id.len() == 3 + kind.len() && id[0] == b'$' && &id[3..] == kind,
+        None => false,
+    }
+}
+
+// Convert "ddmm.mmmm" (lat) or "dddmm.mmmm" (lon) to degrees * 1e7 (i32)
+pub fn nmea_to_e7(txt: &[u8], is_lat: bool) -> Option<i32> {
+    let s = core::str::from_utf8(txt).ok()?;
+    let deg_len = if is_lat { 2 } else { 3 };
+    if s.len() < deg_len + 2 { return None; }
+    let (deg_part, min_part) = s.split_at(deg_len);
+    let deg: i64 = deg_part.parse().ok()?;
+
+    // Parse minutes with fractional part
+    let mut minutes_scaled = 0i64;
+    let mut scale = 1i64;
+    let mut after_dot = false;
+    for c in min_part.bytes() {
+        match c {
+            b'0'..=b'9' => {
+                minutes_scaled = minutes_scaled * 10 + (c - b'0') as i64;
+                if after_dot { scale *= 10; }
+            }
+            b'.' if !after_dot => after_dot = true,
+            _ => break,
+        }
+    }
+    let deg_e7 = deg * 10_000_000 + ((minutes_scaled * 10_000_000 + (60 * scale / 2)) / (60 * scale));
+    Some(deg_e7 as i32)
+}
+
+// Convert "hhmmss.ss" (UTC) to seconds since midnight, dropping the fraction
+pub fn time_of_day(txt: &[u8]) -> Option<u32> {
+    let [h1, h2, m1, m2, s1, s2, ..] = *txt else { return None };
+    let h = two_digits(h1, h2)?;
+    let m = two_digits(m1, m2)?;
+    let s = two_digits(s1, s2)?;
+    if h > 23 || m > 59 || s > 60 { return None; }
+    Some(h * 3600 + m * 60 + s)
+}
+
+// Convert "ddmmyy" to days since 1970-01-01, taking yy as 20yy
+pub fn days_since_epoch(txt: &[u8]) -> Option<u32> {
+    let [d1, d2, m1, m2, y1, y2] = *txt else { return None };
+    let d = two_digits(d1, d2)?;
+    let m = two_digits(m1, m2)?;
+    let y = 2000 + two_digits(y1, y2)?;
+    if d == 0 || d > 31 || m == 0 || m > 12 { return None; }
+
+    // days from civil, with years starting in March so the leap day comes last
+    let y = if m <= 2 { y - 1 } else { y };
+    let yoe = y % 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((y / 400) * 146_097 + doe - 719_468)
+}
+
+/// Returns true if `s` ends in a `*hh` checksum matching the XOR of the bytes between `$` and `*`.
+pub fn checksum_ok(s: &[u8]) -> bool {
+    let [b'$', ref body @ .., b'*', hi, lo] = *s else { return false };
+    let sum = body.iter().fold(0, |sum, &b| sum ^ b);
+    match ((hi as char).to_digit(16), (lo as char).to_digit(16)) {
+        (Some(hi), Some(lo)) => hi * 16 + lo == sum as u32,
+        _ => false,
+    }
+}
+
+fn two_digits(a: u8, b: u8) -> Option<u32> {
+    if !a.is_ascii_digit() || !b.is_ascii_digit() { return None; }
+    Some(((a - b'0') * 10 + (b - b'0')) as u32)
+}
+
+/// Splits the GPS UART byte stream into NMEA lines.
+///
+/// Lines that do not fit the buffer or hold a control byte, such as the
+/// ring buffer's gap marker, are dropped as a whole; so is a line cut short by
+/// the `$` of the next sentence, and a line whose `*hh` checksum is missing or
+/// wrong, as after a bit error on the wire.
+pub struct LineAssembler {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    // the current line is dropped up to its end
+    skipping: bool,
+    discarded: u32,
+}
+
+impl LineAssembler {
+    pub const fn new() -> Self {
+        Self { buf: [0; LINE_LEN], len: 0, skipping: false, discarded: 0 }
+    }
+
+    /// Lines dropped since power-up.
+    pub fn discarded(&self) -> u32 {
+        self.discarded
+    }
+
+    /// Feeds one received byte. Returns the completed line, without CR/LF, on `\n`.
+    pub fn push(&mut self, b: u8) -> Option<&[u8]> {
+        match b {
+            b'\n' => {
+                let (len, skipped) = (self.len, self.skipping);
+                self.len = 0;
+                self.skipping = false;
+                if skipped {
+                    return None;
+                }
+                if !checksum_ok(&self.buf[..len]) {
+                    self.discarded += 1;
+                    return None;
+                }
+                return Some(&self.buf[..len]);
+            }
+            // ignore CR from CRLF
+            b'\r' => {}
+            b'$' if self.len > 0 && !self.skipping => {
+                self.discarded += 1;
+                self.buf[0] = b;
+                self.len = 1;
+            }
+            b'$' if self.skipping => {
+                self.skipping = false;
+                self.buf[0] = b;
+                self.len = 1;
+            }
+            _ if self.skipping => {}
+            0x20..=0x7e if self.len < self.buf.len() => {
+                self.buf[self.len] = b;
+                self.len += 1;
+            }
+            _ => {
+                self.discarded += 1;
+                self.skipping = true;
+                self.len = 0;
+            }
+        }
+        None
+    }
+}
+
+impl Default for LineAssembler { fn default() -> Self { Self::new() } }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uart_rx::GAP;
+
+    fn lines(input: &[u8]) -> (Vec<Vec<u8>>, u32) {
+        let mut assembler = LineAssembler::new();
+        let lines = input.iter().filter_map(|&b| assembler.push(b).map(<[u8]>::to_vec)).collect();
+        (lines, assembler.discarded())
+    }
+
+    #[test]
+    fn drops_broken_lines_whole() {
+        let mut input = b"$GPGGA,1*4B\r\n$GPGGA,12".to_vec();
+        // bytes lost in the middle of a sentence
+        input.extend_from_slice(&[GAP]);
+        input.extend_from_slice(b"45,N*00\r\n$GPRMC,1*56\r\n$GPGGA,7$GPGGA,8*42\r\n");
+        input.extend_from_slice(&[b'x'; LINE_LEN + 1]);
+        input.extend_from_slice(b"\n$GPGGA,9*43\n");
+        let (lines, discarded) = lines(&input);
+        assert_eq!(lines, [&b"$GPGGA,1*4B"[..], b"$GPRMC,1*56", b"$GPGGA,8*42", b"$GPGGA,9*43"]);
+        assert_eq!(discarded, 3);
+    }
+
+    #[test]
+    fn drops_lines_failing_their_checksum() {
+        let input = b"$GPGGA,1*4B\r\n$GPGGA,2*56\r\n$GPGGA,1*4\r\n$GPGGA,1\r\nGPGGA,1*4B\r\n$GPGGA,1*4B\r\n";
+        let (lines, discarded) = lines(input);
+        assert_eq!(lines, [&b"$GPGGA,1*4B"[..], b"$GPGGA,1*4B"]);
+        assert_eq!(discarded, 4);
+    }
+
+    #[test]
+    fn checks_the_checksum_in_either_case() {
+        assert!(checksum_ok(b"$GPGLL,5026.9967,N,03031.2450,E,120412.00,A,A*6F"));
+        assert!(checksum_ok(b"$GPGLL,5026.9967,N,03031.2450,E,120412.00,A,A*6f"));
+        assert!(!checksum_ok(b"$GPGLL,5026.9967,N,03031.2450,E,120412.00,A,A*6G"));
+        assert!(!checksum_ok(b"$GPGLL,5026.9967,N,03031.2450,E,120412.00,A,A*6F0"));
+        assert!(!checksum_ok(b"$*"));
+        assert!(!checksum_ok(b""));
+    }
+
+    #[test]
+    fn matches_sentences_from_any_talker() {
+        // the NEO-6M reports as GP, multi-GNSS receivers combine fixes as GN
+        for line in [&b"$GPGGA,1"[..], b"$GNGGA,1", b"$GLGGA,1", b"$GAGGA"] {
+            assert!(is_sentence(line, b"GGA"), "{:?}", line);
+        }
+        for line in [&b"$GPGSA,1"[..], b"$GNGGAX,1", b"GPGGA,1", b"$PUBX,00", b""] {
+            assert!(!is_sentence(line, b"GGA"), "{:?}", line);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Over-the-air frame layout shared by beacon and receiver.
+//!
+//! Every frame starts with a header byte: the frame kind in the upper nibble,
+//! flags in the lower one.
+//!
+//! - Position: `[header][nonce: 12][ciphertext: 8]`
+//! - Compact, Delta: shortened position frames, see `compact`
+//! - Track: several fixes in one frame, see `breadcrumb`
+//! - Ack: `[header][nonce: 12][tag: 8]`, echoing the nonce of the position frame
+//! - Relay: `[header][frame][mic: 4]`, any of the above wrapped for relays,
+//!   with the number of relays it passed in the flags
+//! - Diagnostic: `[header][nonce: 12][ciphertext]`, a panic record, see `crash`
+//! - Telemetry: `[header][nonce: 12][ciphertext]`, the beacon's state, see `telemetry`
+//!
+//! A position frame sent again after its fix was taken carries `FLAG_STALE`
+//! and ends with the fix's age in seconds: `[frame][age: 2]`, little endian
+//! and saturating at 65535 (18 h).
+//!
+//! Every frame but Ack and Relay ends with a MAC over all of it, the header
+//! and the age included: `[frame][mac: 4]`. It is SipHash-2-4 under
+//! `AUTH_KEY`, cut to 4 bytes, and the receiver drops frames without a valid
+//! one before it decodes, prints or acknowledges them.
+//!
+//! The ACK tag is the ChaCha20 keystream of the echoed nonce at block 4. A
+//! frame's plaintext is at most 242 bytes and is encrypted from block 0, so
+//! it never reaches block 4 and the tag gives none of its keystream away.
+//! Only a holder of `KEY` can produce the tag.
+//!
+//! The relay MIC is SipHash-2-4 of the wrapped frame under `NET_KEY`, cut to 4
+//! bytes. Relays hold `NET_KEY` only: they can check and forward frames but
+//! not read them. The hop count is outside the MIC so relays can raise it.
+
+use chacha20::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
+use chacha20::ChaCha20;
+use core::hash::Hasher;
+use siphasher::sip::SipHasher24;
+
+use crate::decryption::NONCE_LEN;
+use crate::encryption::{Key, AUTH_KEY, KEY, NET_KEY};
+
+pub const HEADER_LEN: usize = 1;
+pub const TAG_LEN: usize = 8;
+pub const ACK_LEN: usize = HEADER_LEN + NONCE_LEN + TAG_LEN;
+pub const AGE_LEN: usize = 2;
+pub const MIC_LEN: usize = 4;
+pub const AUTH_LEN: usize = 4;
+/// Hop counts fit into the flags of a relay frame.
+pub const MAX_HOPS: u8 = 0x0F;
+/// Keystream block of the ACK tag, past the 4 blocks the longest frame uses.
+pub const ACK_BLOCK: u32 = 4;
+
+/// Position frame flag: the sender listens for an ACK after transmitting.
+pub const FLAG_ACK_REQUEST: u8 = 0x01;
+/// Position frame flag: the frame repeats an earlier fix and ends with its age.
+pub const FLAG_STALE: u8 = 0x08;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    Position,
+    Ack,
+    Compact,
+    Delta,
+    Track,
+    Relay,
+    Diagnostic,
+    Telemetry,
+}
+
+impl Kind {
+    const fn code(self) -> u8 {
+        match self {
+            Kind::Position => 1,
+            Kind::Ack => 2,
+            Kind::Compact => 3,
+            Kind::Delta => 4,
+            Kind::Track => 5,
+            Kind::Relay => 6,
+            Kind::Diagnostic => 7,
+            Kind::Telemetry => 8,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Kind::Position),
+            2 => Some(Kind::Ack),
+            3 => Some(Kind::Compact),
+            4 => Some(Kind::Delta),
+            5 => Some(Kind::Track),
+            6 => Some(Kind::Relay),
+            7 => Some(Kind::Diagnostic),
+            8 => Some(Kind::Telemetry),
+            _ => None,
+        }
+    }
+}
+
+pub const fn header(kind: Kind, flags: u8) -> u8 {
+    kind.code() << 4 | (flags & 0x0F)
+}
+
+/// Splits a frame into kind, flags and body.
+pub fn parse_header(frame: &[u8]) -> Option<(Kind, u8, &[u8])> {
+    let (&h, body) = frame.split_first()?;
+    Some((Kind::from_code(h >> 4)?, h & 0x0F, body))
+}
+
+/// Marks the position frame in `frame[..len]` as a fix taken `age_s` seconds
+/// ago; returns the new length.
+pub fn mark_stale(frame: &mut [u8; 255], len: usize, age_s: u32) -> usize {
+    frame[0] |= FLAG_STALE;
+    let age = age_s.min(u16::MAX as u32) as u16;
+    frame[len..len + AGE_LEN].copy_from_slice(&age.to_le_bytes());
+    len + AGE_LEN
+}
+
+/// Splits the age off the body of a stale frame; returns the body without it
+/// and the age, if the frame is stale.
+pub fn split_age(flags: u8, body: &[u8]) -> (&[u8], Option<u32>) {
+    match body.len().checked_sub(AGE_LEN) {
+        Some(n) if flags & FLAG_STALE != 0 => (&body[..n], Some(u16::from_le_bytes([body[n], body[n + 1]]) as u32)),
+        _ => (body, None),
+    }
+}
+
+/// SipHash-2-4 of `data` under `key`, cut to 4 bytes.
+fn siphash(key: &Key<16>, data: &[u8]) -> [u8; 4] {
+    let mut hasher = SipHasher24::new_with_key(key.secret_bytes());
+    hasher.write(data);
+    let hash = hasher.finish().to_le_bytes();
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+fn mic(frame: &[u8]) -> [u8; MIC_LEN] {
+    siphash(&NET_KEY, frame)
+}
+
+/// Appends the MAC to the frame in `frame[..len]`; returns the new length, or
+/// `None` when it would not fit.
+pub fn sign(frame: &mut [u8; 255], len: usize) -> Option<usize> {
+    let signed = len + AUTH_LEN;
+    if signed > frame.len() {
+        return None;
+    }
+    let mac = siphash(&AUTH_KEY, &frame[..len]);
+    frame[len..signed].copy_from_slice(&mac);
+    Some(signed)
+}
+
+/// Returns the frame without its MAC if it carries a valid one.
+pub fn verify(frame: &[u8]) -> Option<&[u8]> {
+    let (inner, mac) = frame.split_at_checked(frame.len().checked_sub(AUTH_LEN)?)?;
+    // not constant time, but a wrong guess only costs the attacker one frame
+    (siphash(&AUTH_KEY, inner) == mac).then_some(inner)
+}
+
+/// Wraps `frame[..len]` in a relay frame that has passed no relay yet;
+/// returns the new length, or `None` when it would not fit.
+pub fn wrap_relay(frame: &mut [u8; 255], len: usize) -> Option<usize> {
+    let wrapped = HEADER_LEN + len + MIC_LEN;
+    if wrapped > frame.len() {
+        return None;
+    }
+    frame.copy_within(..len, HEADER_LEN);
+    frame[0] = header(Kind::Relay, 0);
+    let mic = mic(&frame[HEADER_LEN..HEADER_LEN + len]);
+    frame[HEADER_LEN + len..wrapped].copy_from_slice(&mic);
+    Some(wrapped)
+}
+
+/// Returns the hop count and the wrapped frame if `frame` is a relay frame
+/// with a valid MIC.
+pub fn parse_relay(frame: &[u8]) -> Option<(u8, &[u8])> {
+    let (Kind::Relay, hops, body) = parse_header(frame)? else {
+        return None;
+    };
+    let (inner, tag) = body.split_at_checked(body.len().checked_sub(MIC_LEN)?)?;
+    (mic(inner) == tag).then_some((hops, inner))
+}
+
+fn ack_tag(nonce: &[u8; NONCE_LEN]) -> [u8; TAG_LEN] {
+    let mut tag = [0u8; TAG_LEN];
+    let mut cipher = ChaCha20::new(KEY.secret_bytes().into(), nonce.into());
+    cipher.seek(ACK_BLOCK * 64);
+    cipher.apply_keystream(&mut tag);
+    tag
+}
+
+/// Builds the ACK for the position frame sent with `nonce`.
+pub fn ack_frame(nonce: &[u8; NONCE_LEN]) -> [u8; ACK_LEN] {
+    let mut frame = [0u8; ACK_LEN];
+    frame[0] = header(Kind::Ack, 0);
+    frame[HEADER_LEN..HEADER_LEN + NONCE_LEN].copy_from_slice(nonce);
+    frame[HEADER_LEN + NONCE_LEN..].copy_from_slice(&ack_tag(nonce));
+    frame
+}
+
+/// Returns the acknowledged nonce if `frame` is an ACK with a valid tag.
+pub fn parse_ack(frame: &[u8]) -> Option<[u8; NONCE_LEN]> {
+    let (Kind::Ack, _, body) = parse_header(frame)? else {
+        return None;
+    };
+    if body.len() != NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, tag) = body.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().ok()?;
+    // not constant time, but a wrong guess only costs the attacker one frame
+    (ack_tag(&nonce) == tag).then_some(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, 9];
+
+    #[test]
+    fn ack_round_trip() {
+        let frame = ack_frame(&NONCE);
+        assert_eq!(frame[0], 0x20);
+        assert_eq!(parse_ack(&frame), Some(NONCE));
+    }
+
+    #[test]
+    fn rejects_forged_acks() {
+        let mut frame = ack_frame(&NONCE);
+        frame[ACK_LEN - 1] ^= 1;
+        assert_eq!(parse_ack(&frame), None);
+
+        // tag of another sequence number
+        let mut frame = ack_frame(&NONCE);
+        frame[HEADER_LEN + NONCE_LEN - 1] = 10;
+        assert_eq!(parse_ack(&frame), None);
+
+        let mut frame = ack_frame(&NONCE);
+        frame[0] = header(Kind::Position, 0);
+        assert_eq!(parse_ack(&frame), None);
+        assert_eq!(parse_ack(&frame[..ACK_LEN - 1]), None);
+    }
+
+    #[test]
+    fn stale_frames_end_with_the_age() {
+        let mut frame = [0u8; 255];
+        frame[..3].copy_from_slice(&[header(Kind::Compact, 0b0100), 7, 8]);
+        assert_eq!(mark_stale(&mut frame, 3, 70_000), 5);
+        let (kind, flags, body) = parse_header(&frame[..5]).unwrap();
+        assert_eq!((kind, flags), (Kind::Compact, 0b1100));
+        assert_eq!(split_age(flags, body), (&[7, 8][..], Some(65_535)));
+        assert_eq!(split_age(0, body), (body, None));
+    }
+
+    #[test]
+    fn relay_frames_carry_a_mic() {
+        let mut frame = [0u8; 255];
+        frame[..ACK_LEN].copy_from_slice(&ack_frame(&NONCE));
+        let len = wrap_relay(&mut frame, ACK_LEN).unwrap();
+        assert_eq!(len, ACK_LEN + 5);
+        assert_eq!(frame[0], 0x60);
+        assert_eq!(parse_relay(&frame[..len]), Some((0, &ack_frame(&NONCE)[..])));
+
+        // relays raise the hop count without touching the MIC
+        frame[0] = header(Kind::Relay, 2);
+        assert_eq!(parse_relay(&frame[..len]).map(|(hops, _)| hops), Some(2));
+        frame[5] ^= 1;
+        assert_eq!(parse_relay(&frame[..len]), None);
+        assert_eq!(parse_relay(&frame[..3]), None);
+        assert_eq!(wrap_relay(&mut frame, 251), None);
+    }
+
+    #[test]
+    fn signed_frames_carry_a_mac() {
+        let mut frame = [0u8; 255];
+        frame[..3].copy_from_slice(&[header(Kind::Position, FLAG_ACK_REQUEST), 7, 8]);
+        let len = sign(&mut frame, 3).unwrap();
+        assert_eq!(len, 3 + AUTH_LEN);
+        assert_eq!(verify(&frame[..len]), Some(&frame[..3]));
+
+        // the flags and the age are covered as well
+        let mut forged = frame;
+        forged[0] |= FLAG_STALE;
+        assert_eq!(verify(&forged[..len]), None);
+        assert_eq!(mark_stale(&mut forged, 3, 60), 5);
+        assert_eq!(verify(&forged[..len + AGE_LEN]), None);
+        assert_eq!(verify(&frame[..len - 1]), None);
+        assert_eq!(verify(&frame[..2]), None);
+        assert_eq!(sign(&mut frame, 252), None);
+    }
+
+    #[test]
+    fn header_packs_kind_and_flags() {
+        let h = header(Kind::Position, FLAG_ACK_REQUEST);
+        assert_eq!(parse_header(&[h, 9]), Some((Kind::Position, FLAG_ACK_REQUEST, &[9][..])));
+        assert_eq!(parse_header(&[0x90]), None);
+        assert_eq!(parse_header(&[]), None);
+    }
+}
@@ -0,0 +1,279 @@
+//! Named radio profiles and regional frequency plans.
+//!
+//! A `RadioConfig` combines a profile (modem settings and wanted TX power)
+//! with a channel of a region plan. The TX power is capped at the region's
+//! limit, taken as conducted power into an antenna without gain, and
+//! frequencies the Ai-Thinker Ra-02 (SX1278) cannot tune to are rejected.
+//! Beacon and receiver must use the same profile, region and channel.
+
+use core::fmt;
+use core::ops::RangeInclusive;
+
+use crate::airtime::LoraParams;
+
+/// Frequencies the SX1278 on the Ra-02 module covers.
+pub const RA02_RANGE_HZ: RangeInclusive<u32> = 410_000_000..=525_000_000;
+/// Output power range of the PA_BOOST pin the Ra-02 antenna is wired to.
+pub const PA_RANGE_DBM: RangeInclusive<i8> = 2..=20;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Profile {
+    pub name: &'static str,
+    pub params: LoraParams,
+    /// Wanted output power; lowered to the region's limit.
+    pub tx_power_dbm: i8,
+}
+
+impl Profile {
+    /// The modem settings both firmwares used before profiles existed.
+    pub const STANDARD: Profile = Profile { name: "standard", params: LoraParams::ARKAN, tx_power_dbm: 17 };
+    /// Highest sensitivity: 2.1 s on air for a full frame.
+    pub const LONG_RANGE: Profile = Profile {
+        name: "long-range",
+        params: LoraParams { spreading_factor: 12, ..LoraParams::ARKAN },
+        tx_power_dbm: 20,
+    };
+    /// Shortest time on air, for short distances and busy channels.
+    pub const FAST: Profile = Profile {
+        name: "fast",
+        params: LoraParams { spreading_factor: 7, bandwidth_hz: 250_000, coding_rate: 5, preamble_len: 8, ..LoraParams::ARKAN },
+        tx_power_dbm: 17,
+    };
+    /// Short frames at low power, for bench tests and nearby receivers.
+    pub const LOW_POWER: Profile = Profile {
+        name: "low-power",
+        params: LoraParams { spreading_factor: 7, coding_rate: 5, preamble_len: 8, ..LoraParams::ARKAN },
+        tx_power_dbm: 5,
+    };
+
+    pub const ALL: [Profile; 4] = [Profile::STANDARD, Profile::LONG_RANGE, Profile::FAST, Profile::LOW_POWER];
+
+    pub fn parse(name: &[u8]) -> Option<Profile> {
+        Profile::ALL.into_iter().find(|p| p.name.as_bytes() == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    /// LoRaWAN EU433 default channels, 10 mW ERP.
+    Eu433,
+    /// LoRaWAN EU868 default channels, 25 mW ERP.
+    Eu868,
+    /// One of the eight 8-channel US915 sub-bands (1..=8), 1 W conducted.
+    Us915(u8),
+}
+
+impl Region {
+    pub fn parse(name: &[u8]) -> Option<Region> {
+        match name {
+            b"eu433" => Some(Region::Eu433),
+            b"eu868" => Some(Region::Eu868),
+            [b'u', b's', b'9', b'1', b'5', b'-', n @ b'1'..=b'8'] => Some(Region::Us915(n - b'0')),
+            _ => None,
+        }
+    }
+
+    /// Number of channels in the plan.
+    pub const fn channels(self) -> u8 {
+        match self {
+            Region::Eu433 | Region::Eu868 => 3,
+            Region::Us915(_) => 8,
+        }
+    }
+
+    /// Centre frequency of `channel`, if the plan has it.
+    pub const fn frequency_hz(self, channel: u8) -> Option<u32> {
+        if channel >= self.channels() {
+            return None;
+        }
+        if let Region::Us915(sub_band) = self
+            && (sub_band == 0 || sub_band > 8)
+        {
+            return None;
+        }
+        let first = match self {
+            Region::Eu433 => 433_175_000,
+            Region::Eu868 => 868_100_000,
+            Region::Us915(sub_band) => 902_300_000 + (sub_band as u32 - 1) * 1_600_000,
+        };
+        Some(first + channel as u32 * 200_000)
+    }
+
+    pub const fn max_power_dbm(self) -> i8 {
+        match self {
+            Region::Eu433 => 10,
+            Region::Eu868 => 14,
+            Region::Us915(_) => 30,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Region::Eu433 => f.write_str("EU433"),
+            Region::Eu868 => f.write_str("EU868"),
+            Region::Us915(n) => write!(f, "US915 sub-band {}", n),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConfigError {
+    /// The region plan has no such channel.
+    NoSuchChannel,
+    /// The radio module cannot tune to the channel.
+    OutOfRange(u32),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NoSuchChannel => f.write_str("no such channel"),
+            ConfigError::OutOfRange(hz) => {
+                write!(f, "{} is outside the Ra-02's 410-525 MHz", Mhz(*hz))
+            }
+        }
+    }
+}
+
+/// A validated profile, region and channel.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RadioConfig {
+    profile: Profile,
+    region: Region,
+    channel: u8,
+    frequency_hz: u32,
+}
+
+impl RadioConfig {
+    /// Standard profile on the first EU433 channel.
+    pub const DEFAULT: RadioConfig =
+        RadioConfig { profile: Profile::STANDARD, region: Region::Eu433, channel: 0, frequency_hz: 433_175_000 };
+
+    pub fn new(profile: Profile, region: Region, channel: u8) -> Result<Self, ConfigError> {
+        let hz = region.frequency_hz(channel).ok_or(ConfigError::NoSuchChannel)?;
+        Self { profile, region, channel, frequency_hz: hz }.with_frequency(hz)
+    }
+
+    /// The same settings on a frequency off the channel plan, such as a
+    /// LoRaWAN receive window; `channel` stays the plan channel it came from.
+    pub fn with_frequency(self, hz: u32) -> Result<Self, ConfigError> {
+        if !RA02_RANGE_HZ.contains(&hz) {
+            return Err(ConfigError::OutOfRange(hz));
+        }
+        Ok(Self { frequency_hz: hz, ..self })
+    }
+
+    /// The same channel with another profile.
+    pub fn with_profile(self, profile: Profile) -> Self {
+        Self { profile, ..self }
+    }
+
+    /// The same profile on another channel.
+    pub fn with_channel(self, region: Region, channel: u8) -> Result<Self, ConfigError> {
+        Self::new(self.profile, region, channel)
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn params(&self) -> LoraParams {
+        self.profile.params
+    }
+
+    pub fn frequency_hz(&self) -> u32 {
+        self.frequency_hz
+    }
+
+    /// Profile power, capped at the region's limit and the PA range.
+    pub fn tx_power_dbm(&self) -> i8 {
+        self.profile
+            .tx_power_dbm
+            .min(self.region.max_power_dbm())
+            .clamp(*PA_RANGE_DBM.start(), *PA_RANGE_DBM.end())
+    }
+}
+
+impl Default for RadioConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl fmt::Display for RadioConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = self.params();
+        write!(
+            f,
+            "{} on {} channel {}: {}, SF{}, {} kHz, CR 4/{}, preamble {}, {} dBm",
+            self.profile.name,
+            self.region,
+            self.channel,
+            Mhz(self.frequency_hz()),
+            p.spreading_factor,
+            p.bandwidth_hz / 1000,
+            p.coding_rate,
+            p.preamble_len,
+            self.tx_power_dbm()
+        )
+    }
+}
+
+struct Mhz(u32);
+
+impl fmt::Display for Mhz {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:03} MHz", self.0 / 1_000_000, self.0 / 1000 % 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_plans() {
+        assert_eq!(Region::Eu433.frequency_hz(2), Some(433_575_000));
+        assert_eq!(Region::Eu433.frequency_hz(3), None);
+        assert_eq!(Region::Eu868.frequency_hz(0), Some(868_100_000));
+        assert_eq!(Region::Us915(2).frequency_hz(0), Some(903_900_000));
+        assert_eq!(Region::Us915(8).frequency_hz(7), Some(914_900_000));
+        assert_eq!(Region::parse(b"us915-2"), Some(Region::Us915(2)));
+        assert_eq!(Region::parse(b"us915-9"), None);
+    }
+
+    #[test]
+    fn rejects_channels_the_ra02_cannot_reach() {
+        assert_eq!(RadioConfig::new(Profile::FAST, Region::Eu433, 3), Err(ConfigError::NoSuchChannel));
+        assert_eq!(
+            RadioConfig::new(Profile::FAST, Region::Eu868, 0),
+            Err(ConfigError::OutOfRange(868_100_000))
+        );
+        assert!(RadioConfig::new(Profile::FAST, Region::Us915(1), 0).is_err());
+        assert!(RadioConfig::new(Profile::FAST, Region::Eu433, 2).is_ok());
+        let rx2 = RadioConfig::DEFAULT.with_frequency(434_665_000).unwrap();
+        assert_eq!((rx2.channel(), rx2.frequency_hz()), (0, 434_665_000));
+        assert!(RadioConfig::DEFAULT.with_frequency(869_525_000).is_err());
+    }
+
+    #[test]
+    fn power_is_capped_by_region() {
+        assert_eq!(RadioConfig::DEFAULT.tx_power_dbm(), 10);
+        let low = RadioConfig::new(Profile::LOW_POWER, Region::Eu433, 1).unwrap();
+        assert_eq!(low.tx_power_dbm(), 5);
+
+        let mut s = heapless::String::<96>::new();
+        core::fmt::write(&mut s, format_args!("{}", low)).unwrap();
+        assert_eq!(s, "low-power on EU433 channel 1: 433.375 MHz, SF7, 125 kHz, CR 4/5, preamble 8, 5 dBm");
+    }
+}
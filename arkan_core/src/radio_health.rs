@@ -0,0 +1,277 @@
+//! Radio health: notices when the LoRa module stops working.
+//!
+//! `Monitored` wraps a `Radio` and watches every call. After `max_failures`
+//! failed calls in a row, or a transmission or channel activity detection
+//! that does not finish within `stuck_ms`, the radio is `Failing`. The
+//! firmware then pulses the module's reset line, initialises and configures
+//! it again and tells the wrapper how that went. A radio that did not come
+//! back is `Down`: the beacon keeps its fixes in the track log only, and the
+//! firmware tries the reset again every `retry_ms`.
+
+use core::fmt;
+
+use crate::hal::{Clock, Radio};
+use crate::radio_config::RadioConfig;
+
+#[derive(Clone, Copy, Debug)]
+pub struct HealthConfig {
+    /// Failed calls in a row after which the radio is reset.
+    pub max_failures: u8,
+    /// A transmission or detection still running after this long hung.
+    pub stuck_ms: u64,
+    /// Wait between resets of a radio that is down.
+    pub retry_ms: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        // a full frame takes 263 ms on air at the standard profile, and
+        // listen-before-talk backs off for up to 4.8 s
+        Self { max_failures: 3, stuck_ms: 10_000, retry_ms: 60_000 }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Health {
+    Up,
+    /// Needs a reset.
+    Failing,
+    /// A reset did not bring it back; the next one is due at `retry_at`.
+    Down { retry_at: u64 },
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Health::Up => "up",
+            Health::Failing => "failing",
+            Health::Down { .. } => "down",
+        })
+    }
+}
+
+/// Radio faults since power-up.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct HealthStats {
+    /// Calls that returned an error.
+    pub errors: u32,
+    /// Transmissions and detections that hung.
+    pub stuck: u32,
+    /// Resets that brought a failed radio back.
+    pub recoveries: u32,
+}
+
+impl fmt::Display for HealthStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} radio errors, {} hung transmissions, {} recoveries", self.errors, self.stuck, self.recoveries)
+    }
+}
+
+pub struct Monitored<R, C> {
+    radio: R,
+    clock: C,
+    config: HealthConfig,
+    health: Health,
+    /// Failed calls since the last successful one.
+    failures: u8,
+    /// When the running transmission or detection started.
+    busy_since: Option<u64>,
+    stats: HealthStats,
+}
+
+impl<R: Radio, C: Clock> Monitored<R, C> {
+    pub fn new(radio: R, clock: C, config: HealthConfig) -> Self {
+        Self { radio, clock, config, health: Health::Up, failures: 0, busy_since: None, stats: HealthStats::default() }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health
+    }
+
+    pub fn stats(&self) -> HealthStats {
+        self.stats
+    }
+
+    pub fn radio(&self) -> &R {
+        &self.radio
+    }
+
+    pub fn radio_mut(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Whether the radio should be reset now: it is failing, or it is down
+    /// and the next try is due.
+    pub fn needs_reset(&self) -> bool {
+        match self.health {
+            Health::Up => false,
+            Health::Failing => true,
+            Health::Down { retry_at } => self.clock.now_ms() >= retry_at,
+        }
+    }
+
+    /// Takes the outcome of a reset, with the radio initialised and
+    /// configured again when `up`.
+    pub fn reset_done(&mut self, up: bool) {
+        if !up {
+            self.health = Health::Down { retry_at: self.clock.now_ms() + self.config.retry_ms };
+            return;
+        }
+        if self.health != Health::Up {
+            self.stats.recoveries += 1;
+        }
+        self.health = Health::Up;
+        self.failures = 0;
+        self.busy_since = None;
+    }
+
+    fn check<T, E>(&mut self, result: Result<T, E>) -> Result<T, E> {
+        match result {
+            Ok(_) => self.failures = 0,
+            Err(_) => {
+                self.stats.errors += 1;
+                self.failures = self.failures.saturating_add(1);
+                if self.failures >= self.config.max_failures {
+                    self.fail();
+                }
+            }
+        }
+        result
+    }
+
+    /// A transmission or detection is still running; fails the radio when it hung.
+    fn still_busy(&mut self) {
+        if let Some(since) = self.busy_since
+            && self.clock.now_ms().saturating_sub(since) > self.config.stuck_ms
+        {
+            self.stats.stuck += 1;
+            self.busy_since = None;
+            self.fail();
+        }
+    }
+
+    fn fail(&mut self) {
+        if self.health == Health::Up {
+            self.health = Health::Failing;
+        }
+    }
+}
+
+impl<R: Radio, C: Clock> Radio for Monitored<R, C> {
+    type Error = R::Error;
+
+    fn configure(&mut self, config: &RadioConfig) -> Result<(), Self::Error> {
+        self.busy_since = None;
+        let result = self.radio.configure(config);
+        self.check(result)
+    }
+
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        let result = self.radio.transmit(payload);
+        self.busy_since = result.is_ok().then(|| self.clock.now_ms());
+        self.check(result)
+    }
+
+    fn transmit_now(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        let result = self.radio.transmit_now(payload);
+        self.busy_since = result.is_ok().then(|| self.clock.now_ms());
+        self.check(result)
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        let result = self.radio.is_transmitting();
+        match result {
+            Ok(true) => self.still_busy(),
+            Ok(false) => self.busy_since = None,
+            Err(_) => {}
+        }
+        self.check(result)
+    }
+
+    fn start_cad(&mut self) -> Result<(), Self::Error> {
+        let result = self.radio.start_cad();
+        self.busy_since = result.is_ok().then(|| self.clock.now_ms());
+        self.check(result)
+    }
+
+    fn cad_result(&mut self) -> Result<Option<bool>, Self::Error> {
+        let result = self.radio.cad_result();
+        match result {
+            Ok(None) => self.still_busy(),
+            Ok(Some(_)) => self.busy_since = None,
+            Err(_) => {}
+        }
+        self.check(result)
+    }
+
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.busy_since = None;
+        let result = self.radio.start_receive();
+        self.check(result)
+    }
+
+    fn receive(&mut self, buf: &mut [u8; 255]) -> Result<Option<usize>, Self::Error> {
+        let result = self.radio.receive(buf);
+        self.check(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockClock, MockRadio};
+
+    fn monitored(clock: &MockClock) -> Monitored<MockRadio, &MockClock> {
+        Monitored::new(MockRadio::default(), clock, HealthConfig { max_failures: 2, stuck_ms: 1_000, retry_ms: 5_000 })
+    }
+
+    #[test]
+    fn repeated_errors_fail_the_radio_until_a_reset_brings_it_back() {
+        let clock = MockClock::new(0);
+        let mut radio = monitored(&clock);
+        radio.radio_mut().fail_tx = true;
+        assert!(radio.transmit(&[1]).is_err());
+        // a success in between starts the count again
+        radio.radio_mut().fail_tx = false;
+        radio.transmit(&[1]).unwrap();
+        radio.radio_mut().fail_tx = true;
+        assert!(radio.transmit(&[1]).is_err());
+        assert_eq!(radio.health(), Health::Up);
+        assert!(radio.transmit(&[1]).is_err());
+        assert_eq!(radio.health(), Health::Failing);
+        assert!(radio.needs_reset());
+
+        radio.reset_done(false);
+        assert_eq!(radio.health(), Health::Down { retry_at: 5_000 });
+        assert!(!radio.needs_reset());
+        clock.advance(5_000);
+        assert!(radio.needs_reset());
+        radio.radio_mut().fail_tx = false;
+        radio.reset_done(true);
+        assert_eq!(radio.health(), Health::Up);
+        assert_eq!(radio.stats(), HealthStats { errors: 3, stuck: 0, recoveries: 1 });
+    }
+
+    #[test]
+    fn hung_transmissions_fail_the_radio() {
+        let clock = MockClock::new(0);
+        let mut radio = monitored(&clock);
+        radio.transmit(&[1]).unwrap();
+        clock.advance(900);
+        radio.radio_mut().transmitting = true;
+        assert_eq!(radio.is_transmitting(), Ok(true));
+        assert_eq!(radio.health(), Health::Up);
+        clock.advance(200);
+        radio.radio_mut().transmitting = true;
+        assert_eq!(radio.is_transmitting(), Ok(true));
+        assert_eq!(radio.health(), Health::Failing);
+
+        radio.reset_done(true);
+        radio.start_cad().unwrap();
+        assert_eq!(radio.cad_result(), Ok(Some(false)));
+        clock.advance(2_000);
+        assert_eq!(radio.cad_result(), Ok(None));
+        assert_eq!(radio.health(), Health::Up);
+        assert_eq!(radio.stats(), HealthStats { errors: 0, stuck: 1, recoveries: 1 });
+    }
+}
@@ -0,0 +1,456 @@
+//! Receiver logic: decodes beacon packets, acknowledges them and reports them over USB.
+//! Optionally it also relays the relay frames it hears, or scans several
+//! frequency and spreading factor pairs for beacons.
+
+use crate::battery::Level;
+use crate::breadcrumb::{Points, TrackPoint};
+use crate::compact::{counter_of, Decoder};
+use crate::crash;
+use crate::decryption::{DecryptError, NONCE_LEN};
+use crate::duty_cycle::DutyCycle;
+use crate::hal::{Clock, LogSink, Radio};
+use crate::hopping::{HopConfig, HopFollower};
+use crate::log::{Hex, JsonStr, Logger};
+use crate::protocol::{
+    ack_frame, parse_header, parse_relay, split_age, verify, wrap_relay, Kind, ACK_LEN, FLAG_ACK_REQUEST,
+};
+use crate::radio_config::RadioConfig;
+use crate::relay::{Relay, RelayConfig};
+use crate::scan::Scanner;
+use crate::tdma::{SlotPlan, SlotTracker};
+use crate::telemetry;
+use crate::{debug, error, info, sensitive, warn};
+
+const TAG: &str = "rx";
+
+/// Number of beacons whose compact frames can be decoded at the same time.
+pub const MAX_BEACONS: usize = 8;
+
+pub struct Receiver {
+    decoder: Decoder<MAX_BEACONS>,
+    /// The radio is in receive mode; cleared while an ACK goes out.
+    listening: bool,
+    /// Device id and counter of the last position reported per beacon, the
+    /// beacon heard from last at the back; retries are acknowledged again but
+    /// not reported.
+    reported: heapless::Vec<([u8; 4], u64), MAX_BEACONS>,
+    /// Settings the radio runs with, for the duty cycle of the ACKs.
+    config: RadioConfig,
+    duty: DutyCycle,
+    slots: Option<SlotTracker<MAX_BEACONS>>,
+    hop: Option<HopFollower>,
+    /// Channel the radio is tuned to while hopping.
+    tuned: Option<u8>,
+    relay: Option<Relay>,
+    scan: Option<Scanner>,
+}
+
+impl Receiver {
+    pub const fn new() -> Self {
+        Self {
+            decoder: Decoder::new(),
+            listening: false,
+            reported: heapless::Vec::new(),
+            config: RadioConfig::DEFAULT,
+            duty: DutyCycle::new(),
+            slots: None,
+            hop: None,
+            tuned: None,
+            relay: None,
+            scan: None,
+        }
+    }
+
+    /// Switches the radio to `config`; receiving resumes on the next `poll`.
+    pub fn configure<R: Radio>(&mut self, radio: &mut R, config: &RadioConfig) -> Result<(), R::Error> {
+        self.listening = false;
+        self.config = *config;
+        self.tuned = None;
+        if let Some(hop) = &mut self.hop {
+            *hop = HopFollower::new(hop.config(), config.region().channels());
+        }
+        if let Some(scan) = &mut self.scan {
+            scan.retune();
+        }
+        radio.configure(config)
+    }
+
+    /// Scans the pairs of `scanner` with channel activity detection instead
+    /// of listening on the configured channel; `configure` does not change the
+    /// pairs. Not combined with hopping or relaying.
+    pub fn set_scan(&mut self, scanner: Option<Scanner>) {
+        self.scan = scanner;
+        self.listening = false;
+    }
+
+    pub fn scanner(&self) -> Option<&Scanner> {
+        self.scan.as_ref()
+    }
+
+    /// Follows the beacons' hop sequence over the channels of the configured
+    /// region; `configure` picks the region.
+    pub fn set_hopping(&mut self, config: Option<HopConfig>) {
+        self.hop = config.map(|c| HopFollower::new(c, self.config.region().channels()));
+        self.tuned = None;
+    }
+
+    /// Enables the relay role: relay frames heard are forwarded once the
+    /// radio is free.
+    pub fn set_relay(&mut self, config: Option<RelayConfig>) {
+        self.relay = config.map(Relay::new);
+    }
+
+    pub fn relay(&self) -> Option<&Relay> {
+        self.relay.as_ref()
+    }
+
+    /// Checks every decoded frame against the time slots of `plan`.
+    pub fn set_tdma(&mut self, plan: Option<SlotPlan>) {
+        self.slots = plan.map(SlotTracker::new);
+    }
+
+    pub fn slots(&self) -> Option<&SlotTracker<MAX_BEACONS>> {
+        self.slots.as_ref()
+    }
+
+    /// Handles at most one received packet. Leaves the radio alone while an ACK
+    /// or a relayed frame is on air.
+    pub fn poll<R: Radio, L: LogSink, C: Clock>(&mut self, radio: &mut R, log: &mut Logger<L>, clock: &C) {
+        if let Some(hop) = &mut self.hop {
+            let following = hop.is_following();
+            let channel = hop.channel(clock.now_ms());
+            if following && !hop.is_following() {
+                debug!(log, TAG, "no frame on the hop channel, scanning");
+            }
+            if self.tuned != Some(channel) && !radio.is_transmitting().unwrap_or(true) {
+                let config = self.config.with_channel(self.config.region(), channel).unwrap_or(self.config);
+                if radio.configure(&config).is_err() {
+                    error!(log, TAG, "LoRa configuration failed");
+                    return;
+                }
+                self.tuned = Some(channel);
+                self.listening = false;
+            }
+        }
+        if self.scan.is_some() {
+            if !self.scan(radio, log, clock) {
+                return;
+            }
+        } else if !self.listening {
+            if radio.is_transmitting().unwrap_or(false) || self.forward(radio, log, clock) {
+                return;
+            }
+            if radio.start_receive().is_err() {
+                error!(log, TAG, "LoRa receive failed");
+                return;
+            }
+            self.listening = true;
+        }
+        let mut buf = [0u8; 255];
+        if let Ok(Some(size)) = radio.receive(&mut buf) {
+            self.handle_packet(&buf[..size], radio, log, clock);
+            if let Some(scan) = &mut self.scan {
+                scan.received();
+            }
+        }
+    }
+
+    /// Runs the channel scan; returns true while it is locked on a pair and
+    /// the radio receives.
+    fn scan<R: Radio, L: LogSink, C: Clock>(&mut self, radio: &mut R, log: &mut Logger<L>, clock: &C) -> bool {
+        let Some(scan) = &mut self.scan else { return false };
+        // an ACK on air
+        if radio.is_transmitting().unwrap_or(true) {
+            return false;
+        }
+        let locked = scan.is_locked();
+        match scan.poll(radio, clock.now_ms()) {
+            Ok(true) => {
+                if !locked {
+                    let config = scan.current();
+                    debug!(log, TAG, "preamble on {} SF{}", config.profile().name, config.params().spreading_factor);
+                }
+                true
+            }
+            Ok(false) => false,
+            Err(_) => {
+                error!(log, TAG, "LoRa channel scan failed");
+                false
+            }
+        }
+    }
+
+    /// When `poll` has work again without the radio raising DIO0: a hop or
+    /// scan timeout, or at once when the radio is to listen again. `None`
+    /// while only a received packet or an ended transmission brings work.
+    pub fn wake_at(&self) -> Option<u64> {
+        if !self.listening && self.scan.is_none() {
+            return Some(0);
+        }
+        let hop = self.hop.as_ref().map(HopFollower::wake_at);
+        let scan = self.scan.as_ref().map(Scanner::wake_at);
+        hop.into_iter().chain(scan).min()
+    }
+
+    /// Logs a received packet, checks its MAC, prints its decrypted positions as JSON lines (with
+    /// `age_s` for breadcrumbs and stale fixes) and sends an ACK when the beacon asked for one
+    /// and the duty cycle allows it. Relay frames are queued for forwarding with
+    /// the relay role and then handled like the frame they wrap; their ACK is wrapped as well.
+    pub fn handle_packet<R: Radio, L: LogSink, C: Clock>(
+        &mut self,
+        packet: &[u8],
+        radio: &mut R,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        sensitive!(log, Debug, TAG, "RX RAW: {}", Hex(packet));
+
+        let mut packet = packet;
+        let mut wrapped = false;
+        if let Some((Kind::Relay, _, _)) = parse_header(packet) {
+            if let Some(relay) = &mut self.relay
+                && relay.offer(packet, clock.now_ms())
+            {
+                // sent from `poll` once the radio is free
+                self.listening = false;
+            }
+            let Some((hops, inner)) = parse_relay(packet) else {
+                warn!(log, TAG, "relay frame with a wrong MIC");
+                return;
+            };
+            debug!(log, TAG, "frame passed {} relays", hops);
+            packet = inner;
+            wrapped = true;
+        }
+
+        let (kind, flags, body) = match parse_header(packet) {
+            Some((Kind::Ack, _, _)) => {
+                debug!(log, TAG, "ignoring ACK frame");
+                return;
+            }
+            Some(_) => match verify(packet).and_then(parse_header) {
+                Some(frame) => frame,
+                None => {
+                    warn!(log, TAG, "frame with a wrong MAC");
+                    return;
+                }
+            },
+            None => {
+                warn!(log, TAG, "Unknown frame");
+                return;
+            }
+        };
+
+        if kind == Kind::Diagnostic {
+            self.report_panic(body, log, clock);
+            return;
+        }
+        if kind == Kind::Telemetry {
+            self.report_telemetry(body, log, clock);
+            return;
+        }
+
+        // single fixes become one point, aged only when sent again as a stale fix
+        let (body, stale_s) = split_age(flags, body);
+        let decoded = match kind {
+            Kind::Track => self.decoder.decode_track(flags, body),
+            _ => self.decoder.decode(kind, flags, body).map(|(nonce, coord)| {
+                let mut points = Points::new();
+                let _ = points.push(TrackPoint { coord, age_s: stale_s.unwrap_or(0) });
+                (nonce, points)
+            }),
+        };
+        match decoded {
+            Ok((nonce, points)) => {
+                self.check_slot(&nonce, packet.len(), log, clock);
+                if let Some(hop) = &mut self.hop {
+                    hop.heard(&nonce, clock.now_ms());
+                }
+                // stale repeats tell the fix is still the latest, so they are printed every time
+                if self.report(&nonce) || stale_s.is_some() {
+                    let beacon = Hex(&nonce[..4]);
+                    for p in &points {
+                        let c = &p.coord;
+                        if kind == Kind::Track || stale_s.is_some() {
+                            log.print(format_args!(
+                                "{{\"beacon\":\"{}\",\"lat\":{},\"long\":{},\"age_s\":{}}}\r\n",
+                                beacon, c.lat_deg_e7, c.lon_deg_e7, p.age_s
+                            ));
+                        } else {
+                            log.print(format_args!(
+                                "{{\"beacon\":\"{}\",\"lat\":{},\"long\":{}}}\r\n",
+                                beacon, c.lat_deg_e7, c.lon_deg_e7
+                            ));
+                        }
+                    }
+                }
+                if flags & FLAG_ACK_REQUEST != 0 {
+                    self.acknowledge(&nonce, wrapped, radio, log, clock);
+                }
+            }
+            Err(err) => {
+                let reason = match err {
+                    DecryptError::PacketTooShort => "packet too short",
+                    DecryptError::CipherError => "cipher init failed",
+                    DecryptError::MalformedPlaintext => "invalid plaintext",
+                    DecryptError::UnknownBeacon => "compact frame before any full frame",
+                    DecryptError::UnknownReference => "delta against an unknown fix",
+                    DecryptError::AmbiguousBeacon => "compact frame from one of several beacons with its tag",
+                };
+                warn!(log, TAG, "Decrypt error: {}", reason);
+            }
+        }
+    }
+
+    /// Records the position with `nonce` as reported; returns false when it
+    /// was the last one reported for its beacon.
+    fn report(&mut self, nonce: &[u8; NONCE_LEN]) -> bool {
+        let device_id: [u8; 4] = nonce[..4].try_into().unwrap_or_default();
+        let counter = counter_of(nonce);
+        let last = match self.reported.iter().position(|&(id, _)| id == device_id) {
+            Some(i) => Some(self.reported.remove(i).1),
+            None => {
+                if self.reported.is_full() {
+                    self.reported.remove(0);
+                }
+                None
+            }
+        };
+        let _ = self.reported.push((device_id, counter));
+        last != Some(counter)
+    }
+
+    /// Reports the panic record a beacon sent after it reset.
+    fn report_panic<L: LogSink, C: Clock>(&mut self, body: &[u8], log: &mut Logger<L>, clock: &C) {
+        let Ok((nonce, record)) = crash::open(body) else {
+            warn!(log, TAG, "Decrypt error: invalid diagnostic frame");
+            return;
+        };
+        // the frame took the beacon's next nonce
+        if let Some(hop) = &mut self.hop {
+            hop.heard(&nonce, clock.now_ms());
+        }
+        log.print(format_args!(
+            "{{\"device\":\"{}\",\"panic\":\"{}:{}: {}\"}}\r\n",
+            Hex(&nonce[..4]),
+            JsonStr(record.file()),
+            record.line(),
+            JsonStr(record.message())
+        ));
+    }
+
+    /// Reports the telemetry of a beacon as its own record, and warns when
+    /// its battery is critical.
+    fn report_telemetry<L: LogSink, C: Clock>(&mut self, body: &[u8], log: &mut Logger<L>, clock: &C) {
+        let Ok((nonce, telemetry)) = telemetry::open(body) else {
+            warn!(log, TAG, "Decrypt error: invalid telemetry frame");
+            return;
+        };
+        if let Some(hop) = &mut self.hop {
+            hop.heard(&nonce, clock.now_ms());
+        }
+        log.print(format_args!("{{\"device\":\"{}\",\"telemetry\":{}}}\r\n", Hex(&nonce[..4]), telemetry));
+        if let Some(battery) = telemetry.battery
+            && battery.level == Level::Critical
+        {
+            warn!(log, TAG, "beacon {} {}", Hex(&nonce[..4]), battery);
+        }
+    }
+
+    /// Tells when a beacon sent outside its time slot, skipped superframes or
+    /// shares its slot with another one.
+    fn check_slot<L: LogSink, C: Clock>(
+        &mut self,
+        nonce: &[u8; NONCE_LEN],
+        len: usize,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        let airtime_ms = self.tuned().params().time_on_air_us(len).div_ceil(1000) as u64;
+        let Some(slots) = &mut self.slots else { return };
+        let device_id = [nonce[0], nonce[1], nonce[2], nonce[3]];
+        let heard = slots.heard(device_id, clock.now_ms().saturating_sub(airtime_ms));
+        if !heard.in_slot {
+            warn!(log, TAG, "frame outside slot {}", heard.slot);
+        }
+        if heard.missed > 0 {
+            info!(log, TAG, "slot {}: {} superframes missed", heard.slot, heard.missed);
+        }
+        if heard.shares_slot {
+            warn!(log, TAG, "slot {} is shared by two beacons", heard.slot);
+        }
+    }
+
+    /// Sends an ACK for `nonce`, wrapped for relays if the frame came
+    /// wrapped; the beacon retries when it is not sent.
+    fn acknowledge<R: Radio, L: LogSink, C: Clock>(
+        &mut self,
+        nonce: &[u8; NONCE_LEN],
+        wrapped: bool,
+        radio: &mut R,
+        log: &mut Logger<L>,
+        clock: &C,
+    ) {
+        let mut frame = [0u8; 255];
+        frame[..ACK_LEN].copy_from_slice(&ack_frame(nonce));
+        if let Some(relay) = &mut self.relay {
+            relay.remember(&frame[..ACK_LEN], clock.now_ms());
+        }
+        let len = if wrapped { wrap_relay(&mut frame, ACK_LEN).unwrap_or(ACK_LEN) } else { ACK_LEN };
+        let tuned = self.tuned();
+        let airtime_ms = tuned.params().time_on_air_us(len).div_ceil(1000);
+        let now = clock.now_ms();
+        if self.duty.reserve(tuned.frequency_hz(), airtime_ms, now).is_err() {
+            warn!(log, TAG, "duty cycle used up, ACK not sent");
+            return;
+        }
+        // the beacon listens only for its ACK window (1 s), which a backoff could outlast
+        match radio.transmit_now(&frame[..len]) {
+            Ok(()) => self.listening = false,
+            Err(_) => {
+                error!(log, TAG, "ACK transmit failed");
+                self.duty.release(tuned.frequency_hz(), airtime_ms, now);
+            }
+        }
+    }
+
+    /// Sends the frame the relay role queued; returns true when it went on air.
+    fn forward<R: Radio, L: LogSink, C: Clock>(&mut self, radio: &mut R, log: &mut Logger<L>, clock: &C) -> bool {
+        let tuned = self.tuned();
+        let Some(relay) = &mut self.relay else { return false };
+        let Some(frame) = relay.pending() else { return false };
+        let airtime_ms = tuned.params().time_on_air_us(frame.len()).div_ceil(1000);
+        let now = clock.now_ms();
+        let sent = if self.duty.reserve(tuned.frequency_hz(), airtime_ms, now).is_err() {
+            warn!(log, TAG, "duty cycle used up, frame not relayed");
+            false
+        } else if radio.transmit(frame).is_err() {
+            error!(log, TAG, "relay transmit failed");
+            self.duty.release(tuned.frequency_hz(), airtime_ms, now);
+            false
+        } else {
+            debug!(log, TAG, "relayed frame");
+            true
+        };
+        relay.done(sent);
+        sent
+    }
+
+    /// Settings the radio runs with; replies go out on the channel and
+    /// profile the frame came in on.
+    fn tuned(&self) -> RadioConfig {
+        if let Some(scan) = &self.scan {
+            return scan.current();
+        }
+        match self.tuned {
+            Some(channel) => self.config.with_channel(self.config.region(), channel).unwrap_or(self.config),
+            None => self.config,
+        }
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,209 @@
+//! Relay role: forwards relay frames heard from other devices.
+//!
+//! A relay checks the MIC of every relay frame it hears (see `protocol`),
+//! drops the copies it forwarded or sent itself a short while ago, and queues
+//! the rest to be sent again with the hop count raised by one. Copies are
+//! told apart by the kind, beacon id (or the beacon tag of compact frames) and
+//! sequence number of the wrapped frame, so the copies other relays send
+//! back are not forwarded again. A retry the beacon sends after `dedup_ms` is
+//! forwarded again. Relays hold `NET_KEY` only and never decrypt positions.
+
+use core::fmt;
+
+use crate::decryption::NONCE_LEN;
+use crate::protocol::{header, parse_header, parse_relay, Kind, MAX_HOPS};
+
+/// Frames remembered for duplicate suppression.
+const SEEN: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RelayConfig {
+    /// Frames that have passed this many relays are not forwarded again.
+    pub max_hops: u8,
+    /// How long a forwarded frame is remembered.
+    pub dedup_ms: u64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        // beacons retry unacknowledged frames after 3 s at the earliest
+        Self { max_hops: 3, dedup_ms: 2_000 }
+    }
+}
+
+/// Relay statistics since power-up.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RelayStats {
+    /// Relay frames with a valid MIC.
+    pub heard: u32,
+    pub forwarded: u32,
+    /// Copies of frames forwarded or sent shortly before.
+    pub duplicates: u32,
+    /// Relay frames whose MIC did not match.
+    pub rejected: u32,
+}
+
+impl fmt::Display for RelayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} relay frames heard, {} forwarded, {} duplicates, {} rejected",
+            self.heard, self.forwarded, self.duplicates, self.rejected
+        )
+    }
+}
+
+/// Kind, beacon id and low bytes of the sequence number of a wrapped frame.
+type FrameId = [u8; 8];
+
+fn frame_id(frame: &[u8]) -> Option<FrameId> {
+    let (kind, _, body) = parse_header(frame)?;
+    let mut id = [0u8; 8];
+    id[0] = frame[0] >> 4;
+    match kind {
+        Kind::Position | Kind::Ack | Kind::Track | Kind::Diagnostic | Kind::Telemetry => {
+            let nonce = body.get(..NONCE_LEN)?;
+            id[1..5].copy_from_slice(&nonce[..4]);
+            id[5..].copy_from_slice(&nonce[NONCE_LEN - 3..]);
+        }
+        // beacon tag and 16-bit sequence number
+        Kind::Compact | Kind::Delta => id[4..7].copy_from_slice(body.get(..3)?),
+        Kind::Relay => return None,
+    }
+    Some(id)
+}
+
+pub struct Relay {
+    config: RelayConfig,
+    /// Recently forwarded or sent frames, until when they count as duplicates.
+    seen: heapless::Vec<(FrameId, u64), SEEN>,
+    frame: [u8; 255],
+    /// Length of the frame waiting to be forwarded, 0 when none.
+    len: usize,
+    stats: RelayStats,
+}
+
+impl Relay {
+    pub const fn new(config: RelayConfig) -> Self {
+        Self {
+            config,
+            seen: heapless::Vec::new(),
+            frame: [0; 255],
+            len: 0,
+            stats: RelayStats { heard: 0, forwarded: 0, duplicates: 0, rejected: 0 },
+        }
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        self.stats
+    }
+
+    /// Checks a received relay frame and queues it for forwarding; returns
+    /// true when it was queued. A frame still waiting is replaced.
+    pub fn offer(&mut self, frame: &[u8], now: u64) -> bool {
+        let Some((hops, inner)) = parse_relay(frame) else {
+            if let Some((Kind::Relay, _, _)) = parse_header(frame) {
+                self.stats.rejected += 1;
+            }
+            return false;
+        };
+        self.stats.heard += 1;
+        let Some(id) = frame_id(inner) else { return false };
+        if self.seen(id, now) {
+            self.stats.duplicates += 1;
+            return false;
+        }
+        self.remember_id(id, now);
+        if hops >= self.config.max_hops.min(MAX_HOPS) {
+            return false;
+        }
+        self.frame[..frame.len()].copy_from_slice(frame);
+        self.frame[0] = header(Kind::Relay, hops + 1);
+        self.len = frame.len();
+        true
+    }
+
+    /// Remembers a frame this device sends itself (unwrapped), so that copies
+    /// relays send back are not forwarded.
+    pub fn remember(&mut self, frame: &[u8], now: u64) {
+        if let Some(id) = frame_id(frame)
+            && !self.seen(id, now)
+        {
+            self.remember_id(id, now);
+        }
+    }
+
+    /// The frame waiting to be forwarded.
+    pub fn pending(&self) -> Option<&[u8]> {
+        (self.len > 0).then(|| &self.frame[..self.len])
+    }
+
+    /// Takes the waiting frame off the queue; `sent` tells whether it went out.
+    pub fn done(&mut self, sent: bool) {
+        self.len = 0;
+        self.stats.forwarded += sent as u32;
+    }
+
+    fn seen(&self, id: FrameId, now: u64) -> bool {
+        self.seen.iter().any(|&(seen, until)| seen == id && now < until)
+    }
+
+    fn remember_id(&mut self, id: FrameId, now: u64) {
+        self.seen.retain(|&(_, until)| now < until);
+        if self.seen.is_full() {
+            self.seen.remove(0);
+        }
+        let _ = self.seen.push((id, now + self.config.dedup_ms));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ack_frame, wrap_relay, ACK_LEN};
+
+    fn wrapped(seq: u8) -> Vec<u8> {
+        let mut frame = [0u8; 255];
+        frame[..ACK_LEN].copy_from_slice(&ack_frame(&[1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, seq]));
+        let len = wrap_relay(&mut frame, ACK_LEN).unwrap();
+        frame[..len].to_vec()
+    }
+
+    #[test]
+    fn forwards_each_frame_once() {
+        let mut relay = Relay::new(RelayConfig { max_hops: 2, dedup_ms: 2_000 });
+        let frame = wrapped(1);
+        assert!(relay.offer(&frame, 0));
+        let forwarded = relay.pending().unwrap().to_vec();
+        assert_eq!(forwarded[0], header(Kind::Relay, 1));
+        assert_eq!(forwarded[1..], frame[1..]);
+        relay.done(true);
+        assert_eq!(relay.pending(), None);
+
+        // the copy another relay sends back
+        assert!(!relay.offer(&forwarded, 500));
+        // a retry after the dedup time
+        assert!(relay.offer(&frame, 2_000));
+        relay.done(false);
+
+        // hop limit
+        let mut far = wrapped(2);
+        far[0] = header(Kind::Relay, 2);
+        assert!(!relay.offer(&far, 3_000));
+
+        let mut forged = wrapped(3);
+        forged[3] ^= 1;
+        assert!(!relay.offer(&forged, 3_000));
+        assert!(!relay.offer(&ack_frame(&[0; 12]), 3_000));
+        assert_eq!(relay.stats(), RelayStats { heard: 4, forwarded: 1, duplicates: 1, rejected: 1 });
+    }
+
+    #[test]
+    fn own_frames_are_not_forwarded() {
+        let mut relay = Relay::new(RelayConfig::default());
+        let frame = wrapped(1);
+        relay.remember(&frame[1..1 + ACK_LEN], 0);
+        assert!(!relay.offer(&frame, 100));
+        assert!(relay.offer(&wrapped(2), 100));
+    }
+}
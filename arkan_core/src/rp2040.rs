@@ -0,0 +1,554 @@
+//! RP2040 implementations of the `hal` traits, and what the firmwares need to
+//! run on the embassy executor: a time driver and interrupt signals. Both
+//! firmwares also share their USB and watchdog tasks, the radio reset, the
+//! LED that shows the radio state and the panic handler from here.
+
+use core::cell::RefCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Waker;
+
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer as Delay};
+use embassy_time_driver::Driver;
+use embassy_time_queue_utils::Queue;
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::{OutputPin, PinState};
+use rp2040_hal::fugit::MicrosDurationU32;
+use rp2040_hal::pac::{self, interrupt, Interrupt};
+use rp2040_hal::timer::Timer;
+use rp2040_hal::uart::{Enabled, ReadErrorType, UartDevice, UartPeripheral, ValidUartPinout};
+use rp2040_hal::watchdog::{ScratchRegister, Watchdog};
+use usb_device::bus::UsbBus;
+use usb_device::device::UsbDevice;
+use usbd_serial::SerialPort;
+
+use crate::console::{Command, Console};
+use crate::crash::PanicRecord;
+use crate::hal::{ByteSource, Clock, LogSink, Store, StoreError};
+use crate::log::{Level, Logger, Mode, RingLog};
+use crate::sx127x::Bus;
+use crate::uart_rx::RxRing;
+use crate::warn;
+use crate::watchdog::{stall_record, ResetReason, Supervisor};
+
+/// SPI bus to the SX1278 with its chip select (NSS) pin.
+pub struct SpiBus<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS: OutputPin> SpiBus<SPI, CS> {
+    pub fn new(spi: SPI, mut cs: CS) -> Self {
+        let _ = cs.set_high();
+        Self { spi, cs }
+    }
+
+    fn select<T, E>(&mut self, f: impl FnOnce(&mut SPI) -> Result<T, E>) -> Result<T, E> {
+        let _ = self.cs.set_low();
+        let result = f(&mut self.spi);
+        let _ = self.cs.set_high();
+        result
+    }
+}
+
+impl<SPI, CS, E> Bus for SpiBus<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin,
+{
+    type Error = E;
+
+    fn read(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.select(|spi| {
+            spi.write(&[reg & 0x7F])?;
+            buf.fill(0);
+            spi.transfer(buf)?;
+            Ok(())
+        })
+    }
+
+    fn write(&mut self, reg: u8, data: &[u8]) -> Result<(), Self::Error> {
+        self.select(|spi| {
+            spi.write(&[reg | 0x80])?;
+            spi.write(data)
+        })
+    }
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> ByteSource for UartPeripheral<Enabled, D, P> {
+    fn read_byte(&mut self) -> Option<u8> {
+        embedded_hal::serial::Read::read(self).ok()
+    }
+}
+
+/// Moves what the UART received into `ring`; call from its interrupt handler,
+/// with the receive interrupt enabled.
+pub fn drain_uart<D: UartDevice, P: ValidUartPinout<D>, const N: usize>(
+    uart: &UartPeripheral<Enabled, D, P>,
+    ring: &mut RxRing<N>,
+) {
+    let mut b = [0u8; 1];
+    loop {
+        match uart.read_raw(&mut b) {
+            Ok(_) => ring.push(b[0]),
+            Err(nb::Error::Other(e)) if matches!(e.err_type, ReadErrorType::Overrun) => ring.overrun(),
+            Err(nb::Error::Other(_)) => ring.error(),
+            Err(nb::Error::WouldBlock) => break,
+        }
+    }
+}
+
+// Outside .bss, so the startup code leaves it as the panic handler wrote it.
+#[unsafe(link_section = ".uninit.PANIC_RECORD")]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Saves where and why the firmware panicked for the next boot to report,
+/// then resets the board through the watchdog.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    let (file, line) = info.location().map_or(("?", 0), |l| (l.file(), l.line()));
+    let record = PanicRecord::new(file, line, format_args!("{}", info.message()));
+    // SAFETY: interrupts are off and nothing else runs any more
+    unsafe {
+        core::ptr::write_volatile(&raw mut PANIC_RECORD, MaybeUninit::new(record));
+        (*pac::WATCHDOG::ptr()).ctrl().modify(|_, w| w.trigger().set_bit());
+    }
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+/// The record of a panic before the last reset, if any; taken only once.
+pub fn take_panic_record() -> Option<PanicRecord> {
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: any bytes make a PanicRecord, and `is_valid` tells whether
+        // the panic handler wrote them; the panic handler runs with
+        // interrupts off and does not return
+        unsafe {
+            let record = core::ptr::read_volatile(&raw const PANIC_RECORD).assume_init();
+            let mut taken = record;
+            taken.clear();
+            core::ptr::write_volatile(&raw mut PANIC_RECORD, MaybeUninit::new(taken));
+            record.is_valid().then_some(record)
+        }
+    })
+}
+
+/// Why the board reset, from the watchdog's registers; clears the stall record.
+pub fn take_reset_reason(watchdog: &mut Watchdog) -> ResetReason {
+    // SAFETY: REASON is read-only
+    let reason = unsafe { (*pac::WATCHDOG::ptr()).reason().read() };
+    let scratch = watchdog.read_scratch(ScratchRegister::Scratch0);
+    watchdog.write_scratch(ScratchRegister::Scratch0, 0);
+    ResetReason::from_registers(reason.timer().bit_is_set(), reason.force().bit_is_set(), scratch)
+}
+
+/// Records `task` as stalled, for `take_reset_reason` after the watchdog reset.
+pub fn record_stall(watchdog: &mut Watchdog, task: usize) {
+    watchdog.write_scratch(ScratchRegister::Scratch0, stall_record(task));
+}
+
+impl Clock for Timer {
+    fn now_ms(&self) -> u64 {
+        // the timer counts microseconds
+        self.get_counter().ticks() / 1_000
+    }
+}
+
+const XIP_BASE: u32 = 0x1000_0000;
+const PAGE_SIZE: u32 = 256;
+
+/// Region of the on-board QSPI flash used as persistent store.
+pub struct FlashStore {
+    offset: u32,
+    len: u32,
+}
+
+impl FlashStore {
+    /// Creates a store over `len` bytes starting `offset` bytes into flash.
+    ///
+    /// # Safety
+    ///
+    /// `offset` and `len` must be multiples of the 4 KiB erase block. The region
+    /// must be excluded from the program image (see `memory.x`) and not used by
+    /// anything else. Core 1 must not run code from flash while the store is written.
+    pub const unsafe fn new(offset: u32, len: u32) -> Self {
+        Self { offset, len }
+    }
+
+    fn check(&self, offset: u32, len: u32) -> Result<(), StoreError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(()),
+            _ => Err(StoreError::OutOfBounds),
+        }
+    }
+}
+
+impl Store for FlashStore {
+    type Error = StoreError;
+
+    const ERASE_SIZE: u32 = 4096;
+
+    fn capacity(&self) -> u32 {
+        self.len
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(offset, buf.len() as u32)?;
+        let src = (XIP_BASE + self.offset + offset) as *const u8;
+        // SAFETY: the range was checked to lie inside the flash region, which is memory mapped through XIP.
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, data.len() as u32)?;
+        // Flash is programmed in whole pages; bytes outside `data` are rewritten with their current value.
+        let mut pos = offset;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let page_start = pos - pos % PAGE_SIZE;
+            let in_page = (pos - page_start) as usize;
+            let n = rest.len().min(PAGE_SIZE as usize - in_page);
+
+            let mut page = [0u8; PAGE_SIZE as usize];
+            self.read(page_start, &mut page)?;
+            page[in_page..in_page + n].copy_from_slice(&rest[..n]);
+
+            cortex_m::interrupt::free(|_| {
+                // SAFETY: interrupts are off and the region is reserved for the store (see `new`).
+                unsafe { rp2040_flash::flash::flash_range_program(self.offset + page_start, &page, true) };
+            });
+            pos += n as u32;
+            rest = &rest[n..];
+        }
+        Ok(())
+    }
+
+    /// Keeps interrupts off for about 45 ms per erase block, while XIP is down.
+    fn erase(&mut self, offset: u32, len: u32) -> Result<(), Self::Error> {
+        if !offset.is_multiple_of(Self::ERASE_SIZE) || !len.is_multiple_of(Self::ERASE_SIZE) {
+            return Err(StoreError::Unaligned);
+        }
+        self.check(offset, len)?;
+        cortex_m::interrupt::free(|_| {
+            // SAFETY: interrupts are off and the region is reserved for the store (see `new`).
+            unsafe { rp2040_flash::flash::flash_range_erase(self.offset + offset, len, true) };
+        });
+        Ok(())
+    }
+}
+
+/// Wakes a task from an interrupt handler. The handler masks its interrupt,
+/// so that a level-triggered source does not fire again before the task served
+/// it; `wait` unmasks it.
+pub struct IrqSignal {
+    irq: Interrupt,
+    signal: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl IrqSignal {
+    pub const fn new(irq: Interrupt) -> Self {
+        Self { irq, signal: Signal::new() }
+    }
+
+    /// Call from the interrupt handler.
+    pub fn on_interrupt(&self) {
+        NVIC::mask(self.irq);
+        self.signal.signal(());
+    }
+
+    /// Waits for the next interrupt; one already pending fires at once.
+    pub async fn wait(&self) {
+        self.signal.reset();
+        // SAFETY: the handler only masks the interrupt and signals
+        unsafe { NVIC::unmask(self.irq) };
+        self.signal.wait().await
+    }
+}
+
+/// Time driver for embassy-time on the microsecond counter that `Clock for
+/// Timer` reads as well. It wakes tasks with alarm 3, which the firmwares
+/// leave to it.
+struct TimeDriver {
+    queue: Mutex<RefCell<Queue>>,
+}
+
+embassy_time_driver::time_driver_impl!(
+    static DRIVER: TimeDriver = TimeDriver { queue: Mutex::new(RefCell::new(Queue::new())) }
+);
+
+/// Starts the embassy-time driver; call once, after `Timer::new`.
+pub fn start_time_driver() {
+    timer().inte().modify(|_, w| w.alarm_3().set_bit());
+    // SAFETY: the handler only touches the alarm and the timer queue, in a critical section
+    unsafe { NVIC::unmask(Interrupt::TIMER_IRQ_3) };
+}
+
+fn timer() -> &'static pac::timer::RegisterBlock {
+    // SAFETY: the driver only uses the counter, which is read-only, and alarm 3
+    unsafe { &*pac::TIMER::ptr() }
+}
+
+impl TimeDriver {
+    /// Arms alarm 3 for `at`; false when `at` has passed already.
+    fn set_alarm(&self, at: u64) -> bool {
+        let timer = timer();
+        if at == u64::MAX {
+            timer.armed().write(|w| unsafe { w.bits(1 << 3) });
+            return true;
+        }
+        // the alarm compares the low 32 bits, so one more than 71 minutes
+        // ahead fires early and is armed again
+        timer.alarm3().write(|w| unsafe { w.bits(at as u32) });
+        if self.now() >= at {
+            timer.armed().write(|w| unsafe { w.bits(1 << 3) });
+            return false;
+        }
+        true
+    }
+
+    /// Wakes the tasks whose time has come and arms the alarm for the next one.
+    fn wake_due(&self, queue: &mut Queue) {
+        let mut next = queue.next_expiration(self.now());
+        while !self.set_alarm(next) {
+            next = queue.next_expiration(self.now());
+        }
+    }
+}
+
+impl Driver for TimeDriver {
+    fn now(&self) -> u64 {
+        let timer = timer();
+        loop {
+            let hi = timer.timerawh().read().bits();
+            let lo = timer.timerawl().read().bits();
+            if timer.timerawh().read().bits() == hi {
+                return (hi as u64) << 32 | lo as u64;
+            }
+        }
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        cortex_m::interrupt::free(|cs| {
+            let mut queue = self.queue.borrow(cs).borrow_mut();
+            if queue.schedule_wake(at, waker) {
+                self.wake_due(&mut queue);
+            }
+        })
+    }
+}
+
+#[interrupt]
+fn TIMER_IRQ_3() {
+    timer().intr().write(|w| w.alarm_3().clear_bit_by_one());
+    cortex_m::interrupt::free(|cs| DRIVER.wake_due(&mut DRIVER.queue.borrow(cs).borrow_mut()));
+}
+
+/// The supervisor's number of the radio task, which both firmwares run.
+pub const RADIO_TASK: usize = 0;
+/// The supervisor's number of the USB task.
+pub const USB_TASK: usize = 1;
+/// Running tasks check in with the supervisor at least this often; sleeping
+/// ones this long after their sleep ends.
+pub const CHECK_IN_MS: u64 = 2_000;
+/// The watchdog resets the board when it is not fed for this long.
+const WATCHDOG_TIMEOUT: MicrosDurationU32 = MicrosDurationU32::millis(5_000);
+/// The supervisor task feeds the watchdog this often.
+const FEED_MS: u64 = 1_000;
+/// Reset pulses before the radio counts as down.
+const RESET_ATTEMPTS: u8 = 3;
+/// The LED toggles this often while the radio is down.
+const BLINK_MS: u64 = 250;
+
+/// The logger all tasks write to.
+pub type SharedLog<S> = embassy_sync::blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Logger<S>>>;
+
+/// Console commands the USB task passes on to the radio task.
+pub static COMMANDS: Channel<ThreadModeRawMutex, Command, 4> = Channel::new();
+/// Set while `log dump` or `track dump` is copying the log to USB; cleared once the buffer is empty.
+pub static DUMPING: AtomicBool = AtomicBool::new(false);
+/// Raised by the firmware's USBCTRL_IRQ handler for the USB task.
+pub static USB_IRQ: IrqSignal = IrqSignal::new(Interrupt::USBCTRL_IRQ);
+/// Raised by `Signalled` when log output was buffered for the USB task.
+static LOG_OUTPUT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Set by the radio task while the LoRa module is down.
+static RADIO_DOWN: AtomicBool = AtomicBool::new(false);
+/// Raised when `RADIO_DOWN` changes.
+static RADIO_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static SUPERVISOR: embassy_sync::blocking_mutex::Mutex<ThreadModeRawMutex, RefCell<Supervisor<2>>> =
+    embassy_sync::blocking_mutex::Mutex::new(RefCell::new(Supervisor::new(["radio", "usb"])));
+
+/// Tells the supervisor that `task` runs and checks in again within `within_ms`.
+pub fn check_in(task: usize, within_ms: u64) {
+    let now = Instant::now().as_millis();
+    SUPERVISOR.lock(|supervisor| supervisor.borrow_mut().check_in(task, now, within_ms));
+}
+
+/// Logs why the board reset, naming the task that stalled.
+pub fn report_reset<S: LogSink>(reason: ResetReason, log: &mut Logger<S>) {
+    SUPERVISOR.lock(|supervisor| supervisor.borrow().report(reason, log));
+}
+
+/// Feeds the watchdog while every supervised task checks in on time. When one
+/// is overdue it records the task and lets the watchdog reset the board.
+pub async fn supervise(mut watchdog: Watchdog) -> ! {
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+    loop {
+        let now = Instant::now().as_millis();
+        if let Some(task) = SUPERVISOR.lock(|supervisor| supervisor.borrow().overdue(now)) {
+            record_stall(&mut watchdog, task);
+            core::future::pending::<()>().await;
+        }
+        watchdog.feed();
+        Delay::after_millis(FEED_MS).await;
+    }
+}
+
+/// Log backends the USB task can send on.
+pub trait UsbLog: LogSink {
+    /// Copies buffered output to `serial`; returns true once the buffer is empty.
+    fn flush<B: UsbBus>(&mut self, serial: &mut SerialPort<B>) -> bool;
+}
+
+/// Log backend that wakes the USB task whenever `S` takes output.
+#[derive(Default)]
+pub struct Signalled<S>(pub S);
+
+impl<S: LogSink> LogSink for Signalled<S> {
+    fn write(&mut self, data: &[u8]) {
+        self.0.write(data);
+        LOG_OUTPUT.signal(());
+    }
+
+    fn record(&mut self, level: Level, tag: &str, args: fmt::Arguments) {
+        self.0.record(level, tag, args);
+        LOG_OUTPUT.signal(());
+    }
+}
+
+impl<S: UsbLog> UsbLog for Signalled<S> {
+    fn flush<B: UsbBus>(&mut self, serial: &mut SerialPort<B>) -> bool {
+        self.0.flush(serial)
+    }
+}
+
+impl<const N: usize> UsbLog for RingLog<N> {
+    fn flush<B: UsbBus>(&mut self, serial: &mut SerialPort<B>) -> bool {
+        self.drain(|chunk| serial.write(chunk).unwrap_or(0));
+        self.is_empty()
+    }
+}
+
+/// Records went out over RTT already.
+#[cfg(feature = "defmt")]
+impl UsbLog for crate::log::DefmtSink {
+    fn flush<B: UsbBus>(&mut self, _serial: &mut SerialPort<B>) -> bool {
+        true
+    }
+}
+
+/// Serves the USB serial port: reads console commands and sends the log. The
+/// log commands are carried out here, the others go to `COMMANDS`. The log
+/// streams to the port with `stream`, and otherwise only for `log dump`;
+/// debug mode needs `debug_log`, the firmware's build feature. The task only
+/// wakes for the USB interrupt and for output a `Signalled` log buffered.
+pub async fn serve_usb<B: UsbBus, S: UsbLog>(
+    mut usb_dev: UsbDevice<'static, B>,
+    mut serial: SerialPort<'static, B>,
+    log: &SharedLog<S>,
+    stream: bool,
+    debug_log: bool,
+) -> ! {
+    let mut console = Console::new();
+    loop {
+        check_in(USB_TASK, CHECK_IN_MS);
+        if usb_dev.poll(&mut [&mut serial]) {
+            let mut rx = [0u8; 64];
+            let n = serial.read(&mut rx).unwrap_or(0);
+            for &b in &rx[..n] {
+                let command = log.lock(|log| {
+                    let log = &mut *log.borrow_mut();
+                    match console.push(b) {
+                        Some(Ok(Command::LogLevel(level))) => log.set_level(level),
+                        Some(Ok(Command::LogDump)) => DUMPING.store(true, Ordering::Relaxed),
+                        Some(Ok(Command::Mode(Mode::Debug))) if !debug_log => {
+                            warn!(log, "console", "debug mode needs a debug-log build")
+                        }
+                        Some(Ok(Command::Mode(mode))) => log.set_mode(mode),
+                        Some(Ok(command)) => return Some(command),
+                        Some(Err(_)) => warn!(log, "console", "unknown command"),
+                        None => {}
+                    }
+                    None
+                });
+                if let Some(command) = command {
+                    COMMANDS.send(command).await;
+                }
+            }
+        }
+        if stream || DUMPING.load(Ordering::Relaxed) {
+            let flushed = log.lock(|log| log.borrow_mut().sink().flush(&mut serial));
+            DUMPING.store(!flushed, Ordering::Relaxed);
+        }
+        // waiting for the host or for log output is no stall
+        check_in(USB_TASK, u64::MAX);
+        select(USB_IRQ.wait(), LOG_OUTPUT.wait()).await;
+    }
+}
+
+/// Whether the LoRa module is down.
+pub fn radio_down() -> bool {
+    RADIO_DOWN.load(Ordering::Relaxed)
+}
+
+/// Marks the LoRa module as down or back up; only the radio task calls this.
+pub fn set_radio_down(down: bool) {
+    if radio_down() != down {
+        RADIO_DOWN.store(down, Ordering::Relaxed);
+        RADIO_CHANGED.signal(());
+    }
+}
+
+/// Holds `led` at `lit` while the radio is up and blinks it while the radio
+/// is down; returns once that changes.
+pub async fn show_radio(led: &mut impl OutputPin, lit: bool) {
+    RADIO_CHANGED.reset();
+    if !radio_down() {
+        let _ = led.set_state(PinState::from(lit));
+        return RADIO_CHANGED.wait().await;
+    }
+    let mut on = false;
+    loop {
+        on = !on;
+        let _ = led.set_state(PinState::from(on));
+        if let Either::First(()) = select(RADIO_CHANGED.wait(), Delay::after_millis(BLINK_MS)).await {
+            return;
+        }
+    }
+}
+
+/// Pulses the radio's reset line, then has `bring_up` initialise and
+/// configure the radio, up to `RESET_ATTEMPTS` times; returns whether it came up.
+pub async fn pulse_reset(rst: &mut impl OutputPin, mut bring_up: impl FnMut() -> bool) -> bool {
+    for _ in 0..RESET_ATTEMPTS {
+        let _ = rst.set_low();
+        Delay::after_millis(20).await;
+        let _ = rst.set_high();
+        Delay::after_millis(50).await;
+        if bring_up() {
+            return true;
+        }
+    }
+    false
+}
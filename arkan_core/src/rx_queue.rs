@@ -0,0 +1,151 @@
+//! Received packets queued from the radio's DIO0 interrupt.
+//!
+//! `RxQueue` wraps a `Radio`. The DIO0 interrupt handler calls `on_dio0`,
+//! which copies a received packet out of the radio's FIFO before the next one
+//! overwrites it, however long the main loop is busy elsewhere, for instance
+//! with USB. `receive` hands the queued packets out in order, so the main loop
+//! can sleep until the interrupt wakes it. DIO0 also rises when a
+//! transmission or a channel activity detection ended; `on_dio0` leaves those
+//! to the main loop.
+
+use core::fmt;
+
+use crate::hal::Radio;
+use crate::radio_config::RadioConfig;
+
+/// Packets the queue holds.
+pub const DEPTH: usize = 4;
+
+/// Queue statistics since power-up.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct QueueStats {
+    /// Packets dropped because the main loop left the queue full.
+    pub overflows: u32,
+    /// FIFO reads that failed in the interrupt handler.
+    pub errors: u32,
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} packets lost to a full queue, {} FIFO read errors", self.overflows, self.errors)
+    }
+}
+
+pub struct RxQueue<R> {
+    radio: R,
+    packets: heapless::Deque<([u8; 255], u8), DEPTH>,
+    stats: QueueStats,
+}
+
+impl<R: Radio> RxQueue<R> {
+    pub const fn new(radio: R) -> Self {
+        Self { radio, packets: heapless::Deque::new(), stats: QueueStats { overflows: 0, errors: 0 } }
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats
+    }
+
+    pub fn radio(&self) -> &R {
+        &self.radio
+    }
+
+    pub fn radio_mut(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Whether packets are waiting for `receive`.
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Queues the packet the radio received, if any; call from the DIO0
+    /// interrupt handler.
+    pub fn on_dio0(&mut self) {
+        let mut buf = [0u8; 255];
+        match self.radio.receive(&mut buf) {
+            Ok(Some(len)) => {
+                if self.packets.push_back((buf, len as u8)).is_err() {
+                    self.stats.overflows += 1;
+                }
+            }
+            Ok(None) => {}
+            Err(_) => self.stats.errors += 1,
+        }
+    }
+}
+
+impl<R: Radio> Radio for RxQueue<R> {
+    type Error = R::Error;
+
+    fn configure(&mut self, config: &RadioConfig) -> Result<(), Self::Error> {
+        self.radio.configure(config)
+    }
+
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.radio.transmit(payload)
+    }
+
+    fn transmit_now(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.radio.transmit_now(payload)
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        self.radio.is_transmitting()
+    }
+
+    fn start_cad(&mut self) -> Result<(), Self::Error> {
+        self.radio.start_cad()
+    }
+
+    fn cad_result(&mut self) -> Result<Option<bool>, Self::Error> {
+        self.radio.cad_result()
+    }
+
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.radio.start_receive()
+    }
+
+    /// Hands out the oldest queued packet, or a packet the interrupt has not
+    /// picked up yet.
+    fn receive(&mut self, buf: &mut [u8; 255]) -> Result<Option<usize>, Self::Error> {
+        match self.packets.pop_front() {
+            Some((packet, len)) => {
+                let len = len as usize;
+                buf[..len].copy_from_slice(&packet[..len]);
+                Ok(Some(len))
+            }
+            None => self.radio.receive(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRadio;
+
+    #[test]
+    fn keeps_packets_until_the_main_loop_takes_them() {
+        let mut radio = RxQueue::new(MockRadio::default());
+        radio.start_receive().unwrap();
+        for i in 0..DEPTH as u8 + 1 {
+            radio.radio_mut().incoming.push_back(vec![i; 3]);
+            radio.on_dio0();
+        }
+        // an interrupt without a packet, after a transmission
+        radio.on_dio0();
+        assert_eq!(radio.stats(), QueueStats { overflows: 1, errors: 0 });
+
+        let mut buf = [0u8; 255];
+        for i in 0..DEPTH as u8 {
+            assert_eq!(radio.receive(&mut buf), Ok(Some(3)));
+            assert_eq!(buf[..3], [i; 3]);
+        }
+        assert!(radio.is_empty());
+        // a packet the interrupt missed
+        radio.radio_mut().incoming.push_back(vec![9]);
+        assert_eq!(radio.receive(&mut buf), Ok(Some(1)));
+        assert_eq!(radio.receive(&mut buf), Ok(None));
+    }
+}
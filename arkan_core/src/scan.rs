@@ -0,0 +1,179 @@
+//! Channel scanning over several frequency and spreading factor pairs.
+//!
+//! The receiver tunes to each pair in turn and runs a channel activity
+//! detection, which listens for a LoRa preamble for about two symbols. When it
+//! finds one, the scanner locks on: the radio stays in receive mode on that
+//! pair until a frame arrived, and its ACK went out, or for as long as a
+//! 255-byte frame takes on air. Then scanning goes on with the next pair.
+//!
+//! A frame is only caught when the scan comes by its pair while the preamble
+//! is on air. One round over all pairs takes about two symbols per pair, 8 ms
+//! at SF9 and 66 ms at SF12, while the standard profile's preamble lasts 67 ms;
+//! a pair whose detection never reports a result holds the scan for twice that.
+//! Keep the list short, and with slow pairs in it give the beacons longer
+//! preambles or acknowledged delivery, which retries missed frames.
+
+use core::fmt;
+
+use crate::hal::Radio;
+use crate::radio_config::RadioConfig;
+
+/// Most pairs a scanner takes.
+pub const MAX_PAIRS: usize = 8;
+/// Frames are at most this long.
+const FRAME_LEN: usize = 255;
+/// A channel activity detection listens for this many symbols.
+const CAD_SYMBOLS: u64 = 2;
+/// Symbols to wait beyond that for its result before the scan moves on
+/// without one, in case the radio reports it late or not at all.
+const CAD_MARGIN_SYMBOLS: u64 = 2;
+
+/// Detections and frames per pair since power-up.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PairStats {
+    pub preambles: u32,
+    pub frames: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Tune,
+    Detecting { until: u64 },
+    Locked { until: u64 },
+}
+
+pub struct Scanner {
+    pairs: heapless::Vec<(RadioConfig, PairStats), MAX_PAIRS>,
+    index: usize,
+    state: State,
+}
+
+impl Scanner {
+    /// Scans `pairs`, the first `MAX_PAIRS` of them; at least one is needed.
+    pub fn new(pairs: &[RadioConfig]) -> Option<Self> {
+        let pairs: heapless::Vec<_, MAX_PAIRS> =
+            pairs.iter().take(MAX_PAIRS).map(|&c| (c, PairStats::default())).collect();
+        (!pairs.is_empty()).then_some(Self { pairs, index: 0, state: State::Tune })
+    }
+
+    /// Settings of the pair scanned or locked on.
+    pub fn current(&self) -> RadioConfig {
+        self.pairs[self.index].0
+    }
+
+    pub fn is_locked(&self) -> bool {
+        matches!(self.state, State::Locked { .. })
+    }
+
+    /// Every pair with its counts.
+    pub fn stats(&self) -> impl Iterator<Item = (RadioConfig, PairStats)> + '_ {
+        self.pairs.iter().copied()
+    }
+
+    /// Moves the scan on; call on every main loop iteration while the radio
+    /// does not transmit. Returns true while locked on a pair, with the radio
+    /// in receive mode.
+    pub fn poll<R: Radio>(&mut self, radio: &mut R, now: u64) -> Result<bool, R::Error> {
+        let params = self.current().params();
+        match self.state {
+            State::Tune => {
+                radio.configure(&self.current())?;
+                radio.start_cad()?;
+                let symbols = CAD_SYMBOLS + CAD_MARGIN_SYMBOLS;
+                let until = now + (symbols * params.symbol_us() as u64).div_ceil(1000);
+                self.state = State::Detecting { until };
+            }
+            State::Detecting { until } => match radio.cad_result()? {
+                Some(true) => {
+                    radio.start_receive()?;
+                    self.pairs[self.index].1.preambles += 1;
+                    let lock_ms = params.time_on_air_us(FRAME_LEN).div_ceil(1000) as u64;
+                    self.state = State::Locked { until: now + lock_ms };
+                }
+                Some(false) => self.next(),
+                None if now >= until => self.next(),
+                None => {}
+            },
+            State::Locked { until } if now >= until => self.next(),
+            State::Locked { .. } => {}
+        }
+        Ok(self.is_locked())
+    }
+
+    /// When `poll` has to run without a finished detection: at once to tune
+    /// to the next pair, or when the detection or the lock times out.
+    pub fn wake_at(&self) -> u64 {
+        match self.state {
+            State::Tune => 0,
+            State::Detecting { until } | State::Locked { until } => until,
+        }
+    }
+
+    /// Counts a frame received on the current pair. Scanning resumes on the
+    /// next `poll`, after its ACK is out.
+    pub fn received(&mut self) {
+        self.pairs[self.index].1.frames += 1;
+        self.next();
+    }
+
+    /// Restarts the scan on the current pair, after the radio was reconfigured.
+    pub fn retune(&mut self) {
+        self.state = State::Tune;
+    }
+
+    fn next(&mut self) {
+        self.index = (self.index + 1) % self.pairs.len();
+        self.state = State::Tune;
+    }
+}
+
+impl fmt::Display for PairStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} preambles, {} frames", self.preambles, self.frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockRadio;
+    use crate::radio_config::Profile;
+
+    #[test]
+    fn locks_on_a_preamble_and_resumes() {
+        let pairs = Profile::ALL.map(|p| RadioConfig::DEFAULT.with_profile(p));
+        let mut scan = Scanner::new(&pairs).unwrap();
+        let mut radio = MockRadio::default();
+
+        // nothing on the first pair
+        assert_eq!(scan.poll(&mut radio, 0), Ok(false));
+        assert_eq!(radio.config, Some(pairs[0]));
+        assert_eq!(scan.poll(&mut radio, 1), Ok(false));
+        assert_eq!(scan.poll(&mut radio, 2), Ok(false));
+        assert_eq!(radio.config, Some(pairs[1]));
+
+        // a preamble on the long-range pair
+        radio.cad_busy = 1;
+        assert_eq!(scan.poll(&mut radio, 3), Ok(true));
+        assert!(radio.receiving);
+        assert_eq!(scan.current(), pairs[1]);
+        scan.received();
+        assert_eq!(scan.poll(&mut radio, 4), Ok(false));
+        assert_eq!(radio.config, Some(pairs[2]));
+
+        // a false detection times out
+        radio.cad_busy = 1;
+        assert_eq!(scan.poll(&mut radio, 5), Ok(true));
+        let lock_ms = pairs[2].params().time_on_air_us(255).div_ceil(1000) as u64;
+        assert_eq!(scan.wake_at(), 5 + lock_ms);
+        assert_eq!(scan.poll(&mut radio, 5 + lock_ms - 1), Ok(true));
+        assert_eq!(scan.poll(&mut radio, 5 + lock_ms), Ok(false));
+        assert_eq!(scan.wake_at(), 0);
+        assert_eq!(scan.current(), pairs[3]);
+
+        let stats: Vec<_> = scan.stats().map(|(_, s)| s).collect();
+        assert_eq!(stats[1], PairStats { preambles: 1, frames: 1 });
+        assert_eq!(stats[2], PairStats { preambles: 1, frames: 0 });
+        assert!(Scanner::new(&[]).is_none());
+    }
+}
@@ -0,0 +1,429 @@
+//! Register-level driver for the Semtech SX1276/77/78 in LoRa mode.
+//!
+//! Replaces the `sx127x_lora` crate, which only tunes to whole megahertz and
+//! keeps register access private. Register numbers and bit layouts follow the
+//! SX1276/77/78 datasheet, section 6.4. DIO0 rises when a packet was received
+//! or sent, or a channel activity detection finished (see `rx_queue`).
+
+use crate::hal::Radio;
+use crate::radio_config::RadioConfig;
+
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_OCP: u8 = 0x0B;
+const REG_LNA: u8 = 0x0C;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE: u8 = 0x0E;
+const REG_FIFO_RX_BASE: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_DETECT_OPTIMIZE: u8 = 0x31;
+const REG_INVERT_IQ: u8 = 0x33;
+const REG_DETECTION_THRESHOLD: u8 = 0x37;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_INVERT_IQ2: u8 = 0x3B;
+const REG_DIO_MAPPING_1: u8 = 0x40;
+const REG_VERSION: u8 = 0x42;
+const REG_PA_DAC: u8 = 0x4D;
+
+const MODE_LORA: u8 = 0x80;
+const MODE_MASK: u8 = 0x07;
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x01;
+const MODE_TX: u8 = 0x03;
+const MODE_RX_CONTINUOUS: u8 = 0x05;
+const MODE_CAD: u8 = 0x07;
+
+const IRQ_RX_DONE: u8 = 0x40;
+const IRQ_CRC_ERROR: u8 = 0x20;
+const IRQ_CAD_DONE: u8 = 0x04;
+const IRQ_CAD_DETECTED: u8 = 0x01;
+const IRQ_ALL: u8 = 0xFF;
+
+/// `RegDioMapping1` values for DIO0: RxDone in receive and TxDone in transmit
+/// mode, or CadDone.
+const DIO0_RX_TX_DONE: u8 = 0x00;
+const DIO0_CAD_DONE: u8 = 0x80;
+
+/// `RegInvertIQ` bits. Bit 0 set means the TX path is *not* inverted, as
+/// after reset (0x27); the reserved bits keep their reset value.
+const INVERT_IQ_RX: u8 = 0x40;
+const INVERT_IQ_TX_OFF: u8 = 0x01;
+const INVERT_IQ_RESERVED: u8 = 0x26;
+
+const SX1278_VERSION: u8 = 0x12;
+const FXOSC_HZ: u64 = 32_000_000;
+
+/// SPI access to the chip's registers; `data` is read or written in one burst
+/// starting at `reg`, which for `REG_FIFO` means consecutive FIFO bytes.
+pub trait Bus {
+    type Error;
+
+    fn read(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    fn write(&mut self, reg: u8, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error<E> {
+    Bus(E),
+    /// `RegVersion` did not read as an SX1276/77/78; 0x00 or 0xFF when no
+    /// chip answers.
+    UnknownChip(u8),
+    /// `RegOpMode` did not read as the mode the chip was put in: it was
+    /// reset, lost power or stopped answering.
+    NoResponse(u8),
+}
+
+pub struct Sx127x<B> {
+    bus: B,
+    crc_errors: u32,
+}
+
+impl<B: Bus> Sx127x<B> {
+    /// Checks the chip version and puts it into LoRa standby. Reset the chip
+    /// before calling this, then `configure` it.
+    pub fn new(bus: B) -> Result<Self, Error<B::Error>> {
+        let mut radio = Self::unchecked(bus);
+        radio.init()?;
+        Ok(radio)
+    }
+
+    /// Takes `bus` without talking to the chip; `init` it before use. Keeps
+    /// the bus when the chip does not answer, so it can be tried again.
+    pub const fn unchecked(bus: B) -> Self {
+        Self { bus, crc_errors: 0 }
+    }
+
+    /// Checks the chip version and puts it into LoRa standby, as `new` does;
+    /// also after a reset of the chip.
+    pub fn init(&mut self) -> Result<(), Error<B::Error>> {
+        let version = self.read(REG_VERSION)?;
+        if version != SX1278_VERSION {
+            return Err(Error::UnknownChip(version));
+        }
+        // the LoRa bit can only be changed in sleep mode
+        self.set_mode(MODE_SLEEP)?;
+        self.write(REG_FIFO_TX_BASE, 0)?;
+        self.write(REG_FIFO_RX_BASE, 0)?;
+        // LNA boost for the high frequency port
+        let lna = self.read(REG_LNA)?;
+        self.write(REG_LNA, lna | 0x03)?;
+        self.set_mode(MODE_STANDBY)?;
+        self.expect_lora(MODE_STANDBY)
+    }
+
+    /// Received packets dropped for a wrong CRC, mostly collisions.
+    pub fn crc_errors(&self) -> u32 {
+        self.crc_errors
+    }
+
+    /// Gives the bus back, e.g. to reinitialize the chip after a reset.
+    pub fn release(self) -> B {
+        self.bus
+    }
+
+    fn read(&mut self, reg: u8) -> Result<u8, Error<B::Error>> {
+        let mut b = [0u8];
+        self.bus.read(reg, &mut b).map_err(Error::Bus)?;
+        Ok(b[0])
+    }
+
+    fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<B::Error>> {
+        self.bus.write(reg, &[value]).map_err(Error::Bus)
+    }
+
+    fn set_mode(&mut self, mode: u8) -> Result<(), Error<B::Error>> {
+        self.write(REG_OP_MODE, MODE_LORA | mode)
+    }
+
+    /// Reads `RegOpMode` back; `mode` in LoRa mode unless the chip fell out of it.
+    fn expect_lora(&mut self, mode: u8) -> Result<(), Error<B::Error>> {
+        let op = self.read(REG_OP_MODE)?;
+        if op & (MODE_LORA | MODE_MASK) != MODE_LORA | mode {
+            return Err(Error::NoResponse(op));
+        }
+        Ok(())
+    }
+
+    fn set_power(&mut self, dbm: i8) -> Result<(), Error<B::Error>> {
+        // PA_BOOST: 2..=17 dBm, or up to 20 dBm with the high power DAC setting
+        let (pa_dac, level, ocp_ma) = if dbm > 17 { (0x87, dbm - 5, 140u8) } else { (0x84, dbm - 2, 120u8) };
+        self.write(REG_PA_DAC, pa_dac)?;
+        self.write(REG_PA_CONFIG, 0x80 | level.clamp(0, 15) as u8)?;
+        let trim = if ocp_ma <= 120 { (ocp_ma - 45) / 5 } else { (ocp_ma + 30) / 10 };
+        self.write(REG_OCP, 0x20 | trim)
+    }
+}
+
+/// `RegModemConfig1` bandwidth code; unsupported values round down.
+fn bandwidth_code(hz: u32) -> u8 {
+    const STEPS: [u32; 10] = [7_800, 10_400, 15_600, 20_800, 31_250, 41_700, 62_500, 125_000, 250_000, 500_000];
+    STEPS.iter().rposition(|&s| hz >= s).unwrap_or(0) as u8
+}
+
+impl<B: Bus> Radio for Sx127x<B> {
+    type Error = Error<B::Error>;
+
+    fn configure(&mut self, config: &RadioConfig) -> Result<(), Self::Error> {
+        let p = config.params();
+        self.set_mode(MODE_SLEEP)?;
+
+        let frf = ((config.frequency_hz() as u64) << 19) / FXOSC_HZ;
+        self.bus.write(REG_FRF_MSB, &[(frf >> 16) as u8, (frf >> 8) as u8, frf as u8]).map_err(Error::Bus)?;
+
+        let cr = p.coding_rate.clamp(5, 8) - 4;
+        self.write(REG_MODEM_CONFIG_1, bandwidth_code(p.bandwidth_hz) << 4 | cr << 1 | !p.explicit_header as u8)?;
+        let sf = p.spreading_factor.clamp(6, 12);
+        self.write(REG_MODEM_CONFIG_2, sf << 4 | (p.crc as u8) << 2)?;
+        // AGC on, low data rate optimization as the radio expects it (section 4.1.1.6)
+        self.write(REG_MODEM_CONFIG_3, (p.low_data_rate_optimize() as u8) << 3 | 0x04)?;
+        let (optimize, threshold) = if sf == 6 { (0xC5, 0x0C) } else { (0xC3, 0x0A) };
+        self.write(REG_DETECT_OPTIMIZE, optimize)?;
+        self.write(REG_DETECTION_THRESHOLD, threshold)?;
+        self.write(REG_SYNC_WORD, p.sync_word)?;
+        // RX path inverted or not; TX is never inverted, so ACKs and uplinks
+        // always reach a receiver listening upright
+        let (rx, iq2) = if p.invert_iq { (INVERT_IQ_RX, 0x19) } else { (0, 0x1D) };
+        self.write(REG_INVERT_IQ, INVERT_IQ_RESERVED | INVERT_IQ_TX_OFF | rx)?;
+        self.write(REG_INVERT_IQ2, iq2)?;
+        self.bus.write(REG_PREAMBLE_MSB, &p.preamble_len.to_be_bytes()).map_err(Error::Bus)?;
+
+        self.set_power(config.tx_power_dbm())?;
+        self.set_mode(MODE_STANDBY)
+    }
+
+    fn transmit(&mut self, payload: &[u8]) -> Result<(), Self::Error> {
+        self.set_mode(MODE_STANDBY)?;
+        self.write(REG_FIFO_ADDR_PTR, 0)?;
+        self.bus.write(REG_FIFO, payload).map_err(Error::Bus)?;
+        self.write(REG_PAYLOAD_LENGTH, payload.len() as u8)?;
+        self.write(REG_DIO_MAPPING_1, DIO0_RX_TX_DONE)?;
+        self.write(REG_IRQ_FLAGS, IRQ_ALL)?;
+        self.set_mode(MODE_TX)?;
+        self.expect_lora(MODE_TX)
+    }
+
+    fn is_transmitting(&mut self) -> Result<bool, Self::Error> {
+        // the radio drops back to standby once the packet is out, but a reset
+        // drops it into FSK mode
+        let op = self.read(REG_OP_MODE)?;
+        if op & MODE_LORA == 0 {
+            return Err(Error::NoResponse(op));
+        }
+        Ok(op & MODE_MASK == MODE_TX)
+    }
+
+    fn start_cad(&mut self) -> Result<(), Self::Error> {
+        self.set_mode(MODE_STANDBY)?;
+        self.write(REG_DIO_MAPPING_1, DIO0_CAD_DONE)?;
+        self.write(REG_IRQ_FLAGS, IRQ_ALL)?;
+        self.set_mode(MODE_CAD)
+    }
+
+    fn cad_result(&mut self) -> Result<Option<bool>, Self::Error> {
+        let irq = self.read(REG_IRQ_FLAGS)?;
+        if irq & IRQ_CAD_DONE == 0 {
+            return Ok(None);
+        }
+        self.write(REG_IRQ_FLAGS, IRQ_ALL)?;
+        Ok(Some(irq & IRQ_CAD_DETECTED != 0))
+    }
+
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.write(REG_DIO_MAPPING_1, DIO0_RX_TX_DONE)?;
+        self.write(REG_IRQ_FLAGS, IRQ_ALL)?;
+        self.set_mode(MODE_RX_CONTINUOUS)
+    }
+
+    fn receive(&mut self, buf: &mut [u8; 255]) -> Result<Option<usize>, Self::Error> {
+        let irq = self.read(REG_IRQ_FLAGS)?;
+        if irq & IRQ_RX_DONE == 0 {
+            return Ok(None);
+        }
+        self.write(REG_IRQ_FLAGS, IRQ_ALL)?;
+        if irq & IRQ_CRC_ERROR != 0 {
+            self.crc_errors += 1;
+            return Ok(None);
+        }
+        let len = self.read(REG_RX_NB_BYTES)? as usize;
+        let start = self.read(REG_FIFO_RX_CURRENT)?;
+        self.write(REG_FIFO_ADDR_PTR, start)?;
+        self.bus.read(REG_FIFO, &mut buf[..len]).map_err(Error::Bus)?;
+        Ok(Some(len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airtime::LoraParams;
+    use crate::radio_config::{Profile, Region};
+
+    /// Register file with a FIFO behind `REG_FIFO`.
+    struct Regs {
+        regs: [u8; 0x80],
+        fifo: [u8; 256],
+    }
+
+    impl Regs {
+        fn new() -> Self {
+            let mut regs = [0u8; 0x80];
+            regs[REG_VERSION as usize] = SX1278_VERSION;
+            Self { regs, fifo: [0; 256] }
+        }
+    }
+
+    impl Bus for &mut Regs {
+        type Error = ();
+
+        fn read(&mut self, reg: u8, buf: &mut [u8]) -> Result<(), ()> {
+            for b in buf {
+                if reg == REG_FIFO {
+                    let ptr = &mut self.regs[REG_FIFO_ADDR_PTR as usize];
+                    *b = self.fifo[*ptr as usize];
+                    *ptr = ptr.wrapping_add(1);
+                } else {
+                    *b = self.regs[reg as usize];
+                }
+            }
+            Ok(())
+        }
+
+        fn write(&mut self, reg: u8, data: &[u8]) -> Result<(), ()> {
+            for (i, &b) in data.iter().enumerate() {
+                if reg == REG_FIFO {
+                    let ptr = &mut self.regs[REG_FIFO_ADDR_PTR as usize];
+                    self.fifo[*ptr as usize] = b;
+                    *ptr = ptr.wrapping_add(1);
+                } else if reg == REG_IRQ_FLAGS {
+                    // flags are cleared by writing ones
+                    self.regs[reg as usize] &= !b;
+                } else {
+                    self.regs[reg as usize + i] = b;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn configures_standard_profile() {
+        let mut regs = Regs::new();
+        let mut radio = Sx127x::new(&mut regs).unwrap();
+        radio.configure(&RadioConfig::DEFAULT).unwrap();
+        let r = &regs.regs;
+        // 433.175 MHz
+        assert_eq!(r[0x06..0x09], [0x6C, 0x4B, 0x33]);
+        assert_eq!(r[REG_MODEM_CONFIG_1 as usize], 0x78);
+        assert_eq!(r[REG_MODEM_CONFIG_2 as usize], 0x94);
+        assert_eq!(r[REG_MODEM_CONFIG_3 as usize], 0x04);
+        assert_eq!(r[0x20..0x22], [0, 12]);
+        assert_eq!(r[REG_SYNC_WORD as usize], 0x12);
+        assert_eq!([r[REG_INVERT_IQ as usize], r[REG_INVERT_IQ2 as usize]], [0x27, 0x1D]);
+        // 10 dBm on PA_BOOST
+        assert_eq!(r[REG_PA_CONFIG as usize], 0x88);
+        assert_eq!(r[REG_OP_MODE as usize], MODE_LORA | MODE_STANDBY);
+
+        let mut regs = Regs::new();
+        let config = RadioConfig::new(Profile::LONG_RANGE, Region::Eu433, 2).unwrap();
+        Sx127x::new(&mut regs).unwrap().configure(&config).unwrap();
+        assert_eq!(regs.regs[REG_MODEM_CONFIG_3 as usize], 0x0C);
+    }
+
+    #[test]
+    fn inverts_iq_on_the_rx_path_only() {
+        // how LoRaWAN receive windows listen
+        let params = LoraParams { invert_iq: true, ..LoraParams::ARKAN };
+        let profile = Profile { params, ..Profile::STANDARD };
+        let config = RadioConfig::DEFAULT.with_profile(profile);
+        let mut regs = Regs::new();
+        Sx127x::new(&mut regs).unwrap().configure(&config).unwrap();
+        let r = &regs.regs;
+        assert_eq!([r[REG_INVERT_IQ as usize], r[REG_INVERT_IQ2 as usize]], [0x67, 0x19]);
+        // bit 0 set: what the radio sends stays upright, like the receiver's ACKs
+        assert_eq!(r[REG_INVERT_IQ as usize] & INVERT_IQ_TX_OFF, INVERT_IQ_TX_OFF);
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        let mut regs = Regs::new();
+        regs.regs[REG_VERSION as usize] = 0x22;
+        assert_eq!(Sx127x::new(&mut regs).err(), Some(Error::UnknownChip(0x22)));
+    }
+
+    #[test]
+    fn notices_a_reset_or_missing_chip() {
+        let mut regs = Regs::new();
+        let mut radio = Sx127x::new(&mut regs).unwrap();
+        radio.transmit(&[1]).unwrap();
+        // the chip reset during the transmission
+        radio.bus.regs[REG_OP_MODE as usize] = 0x09;
+        assert_eq!(radio.is_transmitting(), Err(Error::NoResponse(0x09)));
+        assert_eq!(radio.init(), Ok(()));
+        assert_eq!(radio.is_transmitting(), Ok(false));
+
+        // without a chip MISO reads all zeros
+        radio.bus.regs = [0; 0x80];
+        assert_eq!(radio.init(), Err(Error::UnknownChip(0)));
+        let mut regs = Regs::new();
+        regs.regs[REG_VERSION as usize] = 0;
+        let mut radio = Sx127x::unchecked(&mut regs);
+        assert_eq!(radio.init(), Err(Error::UnknownChip(0)));
+        radio.bus.regs[REG_VERSION as usize] = SX1278_VERSION;
+        assert_eq!(radio.init(), Ok(()));
+    }
+
+    #[test]
+    fn moves_packets_through_the_fifo() {
+        let mut regs = Regs::new();
+        let mut radio = Sx127x::new(&mut regs).unwrap();
+        radio.transmit(&[1, 2, 3]).unwrap();
+        assert!(radio.is_transmitting().unwrap());
+        let bus = radio.release();
+        assert_eq!(bus.fifo[..3], [1, 2, 3]);
+        assert_eq!(bus.regs[REG_PAYLOAD_LENGTH as usize], 3);
+
+        // a packet arrives at FIFO address 0x40
+        bus.regs[REG_OP_MODE as usize] = MODE_LORA | MODE_STANDBY;
+        bus.fifo[0x40..0x42].copy_from_slice(&[7, 8]);
+        bus.regs[REG_FIFO_RX_CURRENT as usize] = 0x40;
+        bus.regs[REG_RX_NB_BYTES as usize] = 2;
+        let mut radio = Sx127x { bus, crc_errors: 0 };
+        let mut buf = [0u8; 255];
+        assert_eq!(radio.receive(&mut buf), Ok(None));
+        radio.bus.regs[REG_IRQ_FLAGS as usize] = IRQ_RX_DONE;
+        assert_eq!(radio.receive(&mut buf), Ok(Some(2)));
+        assert_eq!(buf[..2], [7, 8]);
+
+        radio.bus.regs[REG_IRQ_FLAGS as usize] = IRQ_RX_DONE | IRQ_CRC_ERROR;
+        assert_eq!(radio.receive(&mut buf), Ok(None));
+        assert_eq!(radio.crc_errors(), 1);
+    }
+
+    #[test]
+    fn reports_channel_activity() {
+        let mut regs = Regs::new();
+        let mut radio = Sx127x::new(&mut regs).unwrap();
+        radio.start_cad().unwrap();
+        assert_eq!(radio.bus.regs[REG_OP_MODE as usize], MODE_LORA | MODE_CAD);
+        assert_eq!(radio.bus.regs[REG_DIO_MAPPING_1 as usize], DIO0_CAD_DONE);
+        assert_eq!(radio.cad_result(), Ok(None));
+        radio.bus.regs[REG_IRQ_FLAGS as usize] = IRQ_CAD_DONE | IRQ_CAD_DETECTED;
+        assert_eq!(radio.cad_result(), Ok(Some(true)));
+        assert_eq!(radio.bus.regs[REG_IRQ_FLAGS as usize], 0);
+        radio.start_cad().unwrap();
+        radio.bus.regs[REG_IRQ_FLAGS as usize] = IRQ_CAD_DONE;
+        assert_eq!(radio.cad_result(), Ok(Some(false)));
+        radio.start_receive().unwrap();
+        assert_eq!(radio.bus.regs[REG_DIO_MAPPING_1 as usize], DIO0_RX_TX_DONE);
+    }
+}
@@ -0,0 +1,222 @@
+//! Time-division slots for beacons sharing one receiver.
+//!
+//! GPS time is cut into superframes of `superframe_ms`, aligned to UTC
+//! midnight, and every superframe into `slots` equal slots. A beacon owns slot
+//! `device_id[3] % slots` and starts a frame `guard_ms` into its slot at the
+//! earliest, and late enough for the frame (and with `ack` the receiver's
+//! ACK) to end `guard_ms` before the slot does.
+//!
+//! The boards have no PPS line, so beacons take GPS time from the GGA
+//! sentence, which completes a while after the second it names. Beacons with
+//! the same GPS module and output settings are late by about the same time;
+//! the guard covers the difference.
+//!
+//! The receiver has no GPS. It anchors the superframes at the first frame it
+//! hears, follows the drift with every frame heard in its slot, and counts the
+//! superframes a beacon skipped since it was last heard.
+
+use core::fmt;
+
+const DAY_MS: u64 = 86_400_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SlotPlan {
+    /// Must divide a day.
+    pub superframe_ms: u32,
+    pub slots: u8,
+    /// Quiet time at both ends of a slot.
+    pub guard_ms: u32,
+}
+
+impl Default for SlotPlan {
+    fn default() -> Self {
+        // a full frame and its ACK take 533 ms at the standard profile
+        Self { superframe_ms: 10_000, slots: 8, guard_ms: 100 }
+    }
+}
+
+impl SlotPlan {
+    pub const fn slot_ms(&self) -> u64 {
+        self.superframe_ms as u64 / self.slots as u64
+    }
+
+    pub const fn slot_of(&self, device_id: &[u8; 4]) -> u8 {
+        device_id[3] % self.slots
+    }
+
+    /// Time from `time_ms` (ms since midnight, GPS time) until a transmission
+    /// of `airtime_ms` may start in `slot`; 0 when it may start now. One too
+    /// long for the slot starts at the beginning of it and overruns it.
+    pub fn wait_ms(&self, slot: u8, time_ms: u64, airtime_ms: u64) -> u64 {
+        let superframe = self.superframe_ms as u64;
+        let pos = time_ms % DAY_MS % superframe;
+        let first = slot as u64 * self.slot_ms() + self.guard_ms as u64;
+        let last = ((slot as u64 + 1) * self.slot_ms()).saturating_sub(self.guard_ms as u64 + airtime_ms);
+        if (first..=last.max(first)).contains(&pos) {
+            0
+        } else if pos < first {
+            first - pos
+        } else {
+            superframe - pos + first
+        }
+    }
+}
+
+/// Reception statistics of one beacon.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SlotStats {
+    pub slot: u8,
+    pub heard: u32,
+    /// Superframes skipped between two frames: lost frames, and the time the
+    /// beacon slept or had nothing to send.
+    pub missed: u32,
+    /// Frames that started outside the beacon's slot.
+    pub outside: u32,
+}
+
+/// What `SlotTracker::heard` found out about a frame.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Heard {
+    pub slot: u8,
+    pub in_slot: bool,
+    /// Superframes skipped since the beacon was heard last.
+    pub missed: u32,
+    /// The beacon is new and its slot already belongs to another one.
+    pub shares_slot: bool,
+}
+
+struct Entry {
+    device_id: [u8; 4],
+    stats: SlotStats,
+    superframe: i64,
+}
+
+/// Receiver side: checks frames against the slots of up to `N` beacons.
+pub struct SlotTracker<const N: usize> {
+    plan: SlotPlan,
+    /// Local time at which a superframe started.
+    anchor: Option<u64>,
+    beacons: heapless::Vec<Entry, N>,
+}
+
+impl<const N: usize> SlotTracker<N> {
+    pub const fn new(plan: SlotPlan) -> Self {
+        Self { plan, anchor: None, beacons: heapless::Vec::new() }
+    }
+
+    pub fn plan(&self) -> SlotPlan {
+        self.plan
+    }
+
+    /// Records a frame from `device_id` that started at `start_ms` on the local clock.
+    pub fn heard(&mut self, device_id: [u8; 4], start_ms: u64) -> Heard {
+        let slot = self.plan.slot_of(&device_id);
+        // beacons start one guard time into their slot
+        let offset = slot as u64 * self.plan.slot_ms() + self.plan.guard_ms as u64;
+        let anchor = *self.anchor.get_or_insert(start_ms.wrapping_sub(offset));
+        let superframe_ms = self.plan.superframe_ms as i64;
+        let since = start_ms.wrapping_sub(anchor) as i64;
+        let superframe = since.div_euclid(superframe_ms);
+        let pos = since.rem_euclid(superframe_ms) as u64;
+        let in_slot = (slot as u64 * self.plan.slot_ms()..(slot as u64 + 1) * self.plan.slot_ms()).contains(&pos);
+        if in_slot {
+            // follow the drift between GPS time and the local clock halfway
+            let error = pos as i64 - offset as i64;
+            self.anchor = Some(anchor.wrapping_add_signed(error / 2));
+        }
+
+        let mut shares_slot = false;
+        let i = match self.beacons.iter().position(|e| e.device_id == device_id) {
+            Some(i) => i,
+            None => {
+                shares_slot = self.beacons.iter().any(|e| e.stats.slot == slot);
+                if self.beacons.is_full() {
+                    // forget the beacon heard from least recently
+                    self.beacons.remove(0);
+                }
+                let stats = SlotStats { slot, heard: 0, missed: 0, outside: 0 };
+                let _ = self.beacons.push(Entry { device_id, stats, superframe });
+                self.beacons.len() - 1
+            }
+        };
+        let mut entry = self.beacons.remove(i);
+        let missed = (superframe - entry.superframe - 1).max(0) as u32;
+        entry.superframe = superframe;
+        entry.stats.heard += 1;
+        entry.stats.missed += missed;
+        entry.stats.outside += !in_slot as u32;
+        let _ = self.beacons.push(entry);
+        Heard { slot, in_slot, missed, shares_slot }
+    }
+
+    /// Statistics per beacon, least recently heard first.
+    pub fn stats(&self) -> impl Iterator<Item = SlotStats> + '_ {
+        self.beacons.iter().map(|e| e.stats)
+    }
+}
+
+impl fmt::Display for SlotPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} slots of {} ms in a {} ms superframe, {} ms guard",
+            self.slots,
+            self.slot_ms(),
+            self.superframe_ms,
+            self.guard_ms
+        )
+    }
+}
+
+impl fmt::Display for SlotStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slot {}: {} heard, {} missed, {} outside", self.slot, self.heard, self.missed, self.outside)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAN: SlotPlan = SlotPlan { superframe_ms: 10_000, slots: 8, guard_ms: 100 };
+
+    #[test]
+    fn waits_for_the_own_slot() {
+        // slot 2 spans 2500..3750 ms of every superframe
+        assert_eq!(PLAN.slot_of(&[1, 2, 3, 10]), 2);
+        assert_eq!(PLAN.wait_ms(2, 2_600, 300), 0);
+        assert_eq!(PLAN.wait_ms(2, 3_350, 300), 0);
+        assert_eq!(PLAN.wait_ms(2, 3_351, 300), 9_249);
+        assert_eq!(PLAN.wait_ms(2, 1_000, 300), 1_600);
+        // 14:32:40.000 UTC is the start of a superframe
+        assert_eq!(PLAN.wait_ms(2, 52_360_000, 300), 2_600);
+        // frames longer than the slot start at its beginning
+        assert_eq!(PLAN.wait_ms(2, 2_600, 2_000), 0);
+        assert_eq!(PLAN.wait_ms(2, 2_601, 2_000), 9_999);
+    }
+
+    #[test]
+    fn counts_missed_superframes() {
+        let mut tracker = SlotTracker::<4>::new(PLAN);
+        let a = [0, 0, 0, 1];
+        let b = [0, 0, 0, 2];
+        // the receiver's clock is 123 ms off the superframes
+        let start = 50_000 + 123;
+        let heard = tracker.heard(a, start + 1_350);
+        assert_eq!(heard, Heard { slot: 1, in_slot: true, missed: 0, shares_slot: false });
+        assert_eq!(tracker.heard(b, start + 2_600 + 10).missed, 0);
+        assert_eq!(tracker.heard(a, start + 10_000 + 1_350 + 20).missed, 0);
+        assert_eq!(tracker.heard(a, start + 40_000 + 1_350 + 20).missed, 2);
+        assert!(!tracker.heard(b, start + 40_000 + 1_000).in_slot);
+        assert!(tracker.heard([0, 0, 0, 9], start + 50_000 + 1_350).shares_slot);
+
+        let stats: Vec<_> = tracker.stats().collect();
+        assert_eq!(stats[0], SlotStats { slot: 1, heard: 3, missed: 2, outside: 0 });
+        assert_eq!(stats[1], SlotStats { slot: 2, heard: 2, missed: 3, outside: 1 });
+        assert_eq!(stats[2].heard, 1);
+
+        let mut s = heapless::String::<64>::new();
+        core::fmt::write(&mut s, format_args!("{}", stats[1])).unwrap();
+        assert_eq!(s, "slot 2: 2 heard, 3 missed, 1 outside");
+    }
+}
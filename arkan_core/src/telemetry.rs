@@ -0,0 +1,380 @@
+//! Telemetry frames: what the beacon tells the receiver about itself.
+//!
+//! `[header][nonce: 12][ciphertext]`, encrypted like a position under `KEY`
+//! and the frame's own nonce. The plaintext is a list of fields, each
+//! `[tag][length][value]`, so receivers skip the fields they do not know and
+//! newer beacons can add some. Multi-byte values are little endian.
+//!
+//! - `TAG_BATTERY`: `[mV: 2][percent][level]`, level as in `battery::Level`
+//! - `TAG_UPTIME`: `[seconds since boot: 4]`
+//! - `TAG_VERSION`: `[major][minor][patch]` of the firmware
+//! - `TAG_RESET`: `[reason][stalled task]`, reason 0 power-on, 1 watchdog,
+//!   2 forced; task 0xFF when not known
+//! - `TAG_TEMPERATURE`: `[°C: signed]` of the RP2040
+//! - `TAG_GPS`: `[satellites][time to fix in s: 2]`, 0xFFFF before the first fix
+//! - `TAG_TX`: `[sent: 4][acknowledged: 4][failed: 4]` frames
+//! - `TAG_FAULTS`: `[radio errors: 2][hung transmissions: 2][radio resets: 2]
+//!   [GPS bytes lost: 2][NMEA lines dropped: 2]`, saturating at 65535
+
+use core::fmt;
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+
+use crate::battery::{BatteryStatus, Level};
+use crate::decryption::{DecryptError, NONCE_LEN};
+use crate::encryption::KEY;
+use crate::protocol::{header, Kind, HEADER_LEN};
+use crate::watchdog::ResetReason;
+
+pub const TAG_BATTERY: u8 = 1;
+pub const TAG_UPTIME: u8 = 2;
+pub const TAG_VERSION: u8 = 3;
+pub const TAG_RESET: u8 = 4;
+pub const TAG_TEMPERATURE: u8 = 5;
+pub const TAG_GPS: u8 = 6;
+pub const TAG_TX: u8 = 7;
+pub const TAG_FAULTS: u8 = 8;
+/// Plaintext that fits into a frame.
+const MAX_LEN: usize = 255 - HEADER_LEN - NONCE_LEN;
+/// Time to fix before the first fix.
+const NO_FIX: u16 = u16::MAX;
+/// Stalled task of a watchdog reset when the supervisor saw none.
+const NO_TASK: u8 = 0xFF;
+
+/// Firmware version.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Version {
+    /// Parses `major.minor.patch`, ignoring a pre-release or build suffix.
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(['.', '-', '+']);
+        let mut next = || parts.next()?.parse().ok();
+        Some(Self { major: next()?, minor: next()?, patch: next()? })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GpsState {
+    /// Satellites in the last GGA sentence.
+    pub satellites: u8,
+    /// From boot or the last wake-up to the first fix after it.
+    pub ttf_s: Option<u16>,
+}
+
+/// Frames the beacon sent since boot.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct TxStats {
+    pub sent: u32,
+    /// Position frames the receiver acknowledged.
+    pub acked: u32,
+    /// Transmissions the radio refused.
+    pub failed: u32,
+}
+
+/// Faults since boot.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Faults {
+    pub radio_errors: u32,
+    pub radio_hangs: u32,
+    /// Resets that brought a failed radio back.
+    pub radio_resets: u32,
+    /// GPS bytes lost to a full buffer, UART overruns and framing errors.
+    pub gps_bytes_lost: u32,
+    pub gps_lines_dropped: u32,
+}
+
+/// The fields of one telemetry frame; those not known are left out.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Telemetry {
+    pub battery: Option<BatteryStatus>,
+    pub uptime_s: Option<u32>,
+    pub version: Option<Version>,
+    pub reset: Option<ResetReason>,
+    pub temp_c: Option<i8>,
+    pub gps: Option<GpsState>,
+    pub tx: Option<TxStats>,
+    pub faults: Option<Faults>,
+}
+
+impl Telemetry {
+    /// Builds the telemetry frame; returns its length.
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], out: &mut [u8; 255]) -> usize {
+        out[0] = header(Kind::Telemetry, 0);
+        out[HEADER_LEN..HEADER_LEN + NONCE_LEN].copy_from_slice(nonce);
+        let start = HEADER_LEN + NONCE_LEN;
+        let mut len = start;
+        let mut field = |tag: u8, value: &[u8]| {
+            out[len] = tag;
+            out[len + 1] = value.len() as u8;
+            out[len + 2..len + 2 + value.len()].copy_from_slice(value);
+            len += 2 + value.len();
+        };
+        if let Some(b) = &self.battery {
+            let mv = b.mv.to_le_bytes();
+            field(TAG_BATTERY, &[mv[0], mv[1], b.percent, level_code(b.level)]);
+        }
+        if let Some(s) = self.uptime_s {
+            field(TAG_UPTIME, &s.to_le_bytes());
+        }
+        if let Some(v) = &self.version {
+            field(TAG_VERSION, &[v.major, v.minor, v.patch]);
+        }
+        if let Some(reset) = self.reset {
+            let value = match reset {
+                ResetReason::PowerOn => [0, NO_TASK],
+                ResetReason::Watchdog(task) => [1, task.map_or(NO_TASK, |t| t.min(NO_TASK as usize - 1) as u8)],
+                ResetReason::Forced => [2, NO_TASK],
+            };
+            field(TAG_RESET, &value);
+        }
+        if let Some(c) = self.temp_c {
+            field(TAG_TEMPERATURE, &c.to_le_bytes());
+        }
+        if let Some(gps) = &self.gps {
+            let ttf = gps.ttf_s.map_or(NO_FIX, |s| s.min(NO_FIX - 1)).to_le_bytes();
+            field(TAG_GPS, &[gps.satellites, ttf[0], ttf[1]]);
+        }
+        if let Some(tx) = &self.tx {
+            let mut value = [0u8; 12];
+            for (chunk, n) in value.chunks_exact_mut(4).zip([tx.sent, tx.acked, tx.failed]) {
+                chunk.copy_from_slice(&n.to_le_bytes());
+            }
+            field(TAG_TX, &value);
+        }
+        if let Some(x) = &self.faults {
+            let mut value = [0u8; 10];
+            let counts = [x.radio_errors, x.radio_hangs, x.radio_resets, x.gps_bytes_lost, x.gps_lines_dropped];
+            for (chunk, n) in value.chunks_exact_mut(2).zip(counts) {
+                chunk.copy_from_slice(&(n.min(u16::MAX as u32) as u16).to_le_bytes());
+            }
+            field(TAG_FAULTS, &value);
+        }
+        let mut cipher = ChaCha20::new(KEY.secret_bytes().into(), nonce.into());
+        cipher.apply_keystream(&mut out[start..len]);
+        len
+    }
+}
+
+/// Writes the fields as one JSON object.
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        let mut member = |f: &mut fmt::Formatter<'_>, args: fmt::Arguments| {
+            let result = write!(f, "{}{}", sep, args);
+            sep = ",";
+            result
+        };
+        f.write_str("{")?;
+        if let Some(s) = self.uptime_s {
+            member(f, format_args!("\"uptime_s\":{}", s))?;
+        }
+        if let Some(v) = &self.version {
+            member(f, format_args!("\"version\":\"{}\"", v))?;
+        }
+        match self.reset {
+            Some(ResetReason::PowerOn) => member(f, format_args!("\"reset\":\"power-on\""))?,
+            Some(ResetReason::Forced) => member(f, format_args!("\"reset\":\"forced\""))?,
+            Some(ResetReason::Watchdog(None)) => member(f, format_args!("\"reset\":\"watchdog\""))?,
+            Some(ResetReason::Watchdog(Some(task))) => {
+                member(f, format_args!("\"reset\":\"watchdog\",\"stalled_task\":{}", task))?
+            }
+            None => {}
+        }
+        if let Some(b) = &self.battery {
+            member(f, format_args!("\"battery_mv\":{},\"battery_pct\":{}", b.mv, b.percent))?;
+            member(f, format_args!("\"battery\":\"{}\"", b.level.as_str()))?;
+        }
+        if let Some(c) = self.temp_c {
+            member(f, format_args!("\"temp_c\":{}", c))?;
+        }
+        if let Some(gps) = &self.gps {
+            member(f, format_args!("\"satellites\":{}", gps.satellites))?;
+            if let Some(s) = gps.ttf_s {
+                member(f, format_args!("\"ttf_s\":{}", s))?;
+            }
+        }
+        if let Some(tx) = &self.tx {
+            member(f, format_args!("\"tx_sent\":{},\"tx_acked\":{},\"tx_failed\":{}", tx.sent, tx.acked, tx.failed))?;
+        }
+        if let Some(x) = &self.faults {
+            member(f, format_args!("\"radio_errors\":{},\"radio_hangs\":{}", x.radio_errors, x.radio_hangs))?;
+            member(f, format_args!("\"radio_resets\":{},\"gps_bytes_lost\":{}", x.radio_resets, x.gps_bytes_lost))?;
+            member(f, format_args!("\"gps_lines_dropped\":{}", x.gps_lines_dropped))?;
+        }
+        f.write_str("}")
+    }
+}
+
+/// Decrypts the body of a telemetry frame; returns its nonce and the fields.
+pub fn open(body: &[u8]) -> Result<([u8; NONCE_LEN], Telemetry), DecryptError> {
+    let (nonce, ciphertext) = body.split_at_checked(NONCE_LEN).ok_or(DecryptError::PacketTooShort)?;
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| DecryptError::PacketTooShort)?;
+    let mut buf = [0u8; MAX_LEN];
+    let plain = buf.get_mut(..ciphertext.len()).ok_or(DecryptError::MalformedPlaintext)?;
+    plain.copy_from_slice(ciphertext);
+    let mut cipher = ChaCha20::new(KEY.secret_bytes().into(), (&nonce).into());
+    cipher.apply_keystream(plain);
+
+    let mut t = Telemetry::default();
+    let mut rest = &plain[..];
+    while let [tag, len, tail @ ..] = rest {
+        let (value, tail) = tail.split_at_checked(*len as usize).ok_or(DecryptError::MalformedPlaintext)?;
+        rest = tail;
+        let u16_at = |i: usize| u16::from_le_bytes([value[i], value[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([value[i], value[i + 1], value[i + 2], value[i + 3]]);
+        match (*tag, value.len()) {
+            (TAG_BATTERY, 4) => {
+                let level = level_from_code(value[3]).ok_or(DecryptError::MalformedPlaintext)?;
+                t.battery = Some(BatteryStatus { mv: u16_at(0), percent: value[2], level });
+            }
+            (TAG_UPTIME, 4) => t.uptime_s = Some(u32_at(0)),
+            (TAG_VERSION, 3) => t.version = Some(Version { major: value[0], minor: value[1], patch: value[2] }),
+            (TAG_RESET, 2) => {
+                let task = (value[1] != NO_TASK).then_some(value[1] as usize);
+                t.reset = Some(match value[0] {
+                    0 => ResetReason::PowerOn,
+                    1 => ResetReason::Watchdog(task),
+                    2 => ResetReason::Forced,
+                    _ => return Err(DecryptError::MalformedPlaintext),
+                });
+            }
+            (TAG_TEMPERATURE, 1) => t.temp_c = Some(value[0] as i8),
+            (TAG_GPS, 3) => {
+                let ttf_s = Some(u16_at(1)).filter(|&s| s != NO_FIX);
+                t.gps = Some(GpsState { satellites: value[0], ttf_s });
+            }
+            (TAG_TX, 12) => t.tx = Some(TxStats { sent: u32_at(0), acked: u32_at(4), failed: u32_at(8) }),
+            (TAG_FAULTS, 10) => {
+                let [radio_errors, radio_hangs, radio_resets, gps_bytes_lost, gps_lines_dropped] =
+                    [0, 2, 4, 6, 8].map(|i| u16_at(i) as u32);
+                t.faults = Some(Faults { radio_errors, radio_hangs, radio_resets, gps_bytes_lost, gps_lines_dropped });
+            }
+            (TAG_BATTERY..=TAG_FAULTS, _) => return Err(DecryptError::MalformedPlaintext),
+            // added by a newer beacon
+            _ => {}
+        }
+    }
+    if !rest.is_empty() {
+        return Err(DecryptError::MalformedPlaintext);
+    }
+    Ok((nonce, t))
+}
+
+/// RP2040 temperature in °C from a 12-bit reading of its sensor (ADC4)
+/// against the 3.3 V reference: 0.706 V at 27 °C, -1.721 mV per °C.
+pub fn chip_temp_c(raw: u16) -> i8 {
+    let uv = raw as i64 * 3_300_000 / 4_096;
+    let milli_c = 27_000 - (uv - 706_000) * 1_000 / 1_721;
+    (milli_c + 500).div_euclid(1_000).clamp(i8::MIN as i64, i8::MAX as i64) as i8
+}
+
+fn level_code(level: Level) -> u8 {
+    match level {
+        Level::Ok => 0,
+        Level::Low => 1,
+        Level::Critical => 2,
+        Level::External => 3,
+    }
+}
+
+fn level_from_code(code: u8) -> Option<Level> {
+    match code {
+        0 => Some(Level::Ok),
+        1 => Some(Level::Low),
+        2 => Some(Level::Critical),
+        3 => Some(Level::External),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NONCE: [u8; 12] = [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 9];
+
+    fn full() -> Telemetry {
+        Telemetry {
+            battery: Some(BatteryStatus { mv: 3_712, percent: 12, level: Level::Low }),
+            uptime_s: Some(86_400),
+            version: Version::parse("0.1.0"),
+            reset: Some(ResetReason::Watchdog(Some(1))),
+            temp_c: Some(-4),
+            gps: Some(GpsState { satellites: 8, ttf_s: Some(31) }),
+            tx: Some(TxStats { sent: 120, acked: 117, failed: 1 }),
+            faults: Some(Faults {
+                radio_errors: 3,
+                radio_hangs: 1,
+                radio_resets: 1,
+                gps_bytes_lost: 70_000,
+                gps_lines_dropped: 2,
+            }),
+        }
+    }
+
+    #[test]
+    fn telemetry_frame_round_trip() {
+        let telemetry = full();
+        let mut frame = [0u8; 255];
+        let len = telemetry.seal(&NONCE, &mut frame);
+        assert_eq!(len, HEADER_LEN + NONCE_LEN + 8 * 2 + 4 + 4 + 3 + 2 + 1 + 3 + 12 + 10);
+        assert_eq!(frame[0], header(Kind::Telemetry, 0));
+        // counts of faults saturate
+        let faults = Faults { gps_bytes_lost: 65_535, ..telemetry.faults.unwrap() };
+        assert_eq!(open(&frame[HEADER_LEN..len]), Ok((NONCE, Telemetry { faults: Some(faults), ..telemetry })));
+
+        // a field cut short
+        assert_eq!(open(&frame[HEADER_LEN..len - 1]), Err(DecryptError::MalformedPlaintext));
+        assert_eq!(open(&frame[HEADER_LEN..HEADER_LEN + 4]), Err(DecryptError::PacketTooShort));
+        let (nonce, empty) = open(&frame[HEADER_LEN..HEADER_LEN + NONCE_LEN]).unwrap();
+        assert_eq!((nonce, empty.to_string()), (NONCE, "{}".to_string()));
+    }
+
+    #[test]
+    fn prints_one_json_object() {
+        assert_eq!(
+            full().to_string(),
+            "{\"uptime_s\":86400,\"version\":\"0.1.0\",\"reset\":\"watchdog\",\"stalled_task\":1,\
+             \"battery_mv\":3712,\"battery_pct\":12,\"battery\":\"low\",\"temp_c\":-4,\"satellites\":8,\"ttf_s\":31,\
+             \"tx_sent\":120,\"tx_acked\":117,\"tx_failed\":1,\"radio_errors\":3,\"radio_hangs\":1,\
+             \"radio_resets\":1,\"gps_bytes_lost\":70000,\"gps_lines_dropped\":2}"
+        );
+        let searching = Telemetry { gps: Some(GpsState { satellites: 0, ttf_s: None }), ..Telemetry::default() };
+        assert_eq!(searching.to_string(), "{\"satellites\":0}");
+    }
+
+    #[test]
+    fn skips_unknown_fields() {
+        let mut plain = [0u8; 10];
+        plain[..4].copy_from_slice(&[0xEE, 2, 7, 7]);
+        plain[4..].copy_from_slice(&[TAG_BATTERY, 4, 0x40, 0x10, 100, 3]);
+        let mut body = [0u8; NONCE_LEN + 10];
+        body[..NONCE_LEN].copy_from_slice(&NONCE);
+        body[NONCE_LEN..].copy_from_slice(&plain);
+        ChaCha20::new(KEY.secret_bytes().into(), (&NONCE).into()).apply_keystream(&mut body[NONCE_LEN..]);
+        let battery = BatteryStatus { mv: 0x1040, percent: 100, level: Level::External };
+        assert_eq!(open(&body), Ok((NONCE, Telemetry { battery: Some(battery), ..Telemetry::default() })));
+    }
+
+    #[test]
+    fn converts_the_chip_temperature() {
+        assert_eq!(chip_temp_c(876), 27);
+        assert_eq!(chip_temp_c(891), 20);
+        assert_eq!(chip_temp_c(820), 53);
+        assert_eq!(chip_temp_c(977), -20);
+        assert_eq!(Version::parse("1.12.3-rc.1"), Some(Version { major: 1, minor: 12, patch: 3 }));
+        assert_eq!(Version::parse("1.2"), None);
+    }
+}
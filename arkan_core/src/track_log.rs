@@ -0,0 +1,326 @@
+//! Store-and-forward log of fixes kept in the persistent store.
+//!
+//! Every fix is appended as a 16-byte little-endian record
+//! `[seq: 4][time: 4][lat: 4][lon: 4]` to a ring of erase blocks. Record `seq`
+//! lives in slot `seq % slots`; when the ring wraps, the block about to be
+//! written is erased together with the oldest records in it. `time` is UTC in
+//! seconds since 1970.
+//!
+//! An erase keeps interrupts off for about 45 ms on the RP2040, longer than
+//! the UART FIFO holds GPS bytes. `erase_ahead` erases the next block while the
+//! GPS is quiet, so `append` only erases when that did not happen in time.
+//!
+//! Bit 31 of `seq` is set while the record has not been delivered. Marking it
+//! delivered clears the bit in place, which NOR flash allows without an erase.
+//!
+//! At one fix per second a 112 KiB region wraps every 2.0 hours, so each block
+//! sees about 4 400 erases a year of continuous tracking.
+
+use core::ops::Range;
+
+use crate::encryption::GpsCoord;
+use crate::hal::{LogSink, Store};
+use crate::log::{Logger, Mode};
+
+const RECORD: u32 = 16;
+const EMPTY: u32 = u32::MAX;
+const PENDING: u32 = 1 << 31;
+/// `erase_ahead` erases the next block once this few slots are left before it.
+const ERASE_AHEAD: u32 = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Record {
+    pub seq: u32,
+    /// UTC seconds since 1970.
+    pub time_s: u32,
+    pub coord: GpsCoord,
+    pub delivered: bool,
+}
+
+/// Position of the log in the store; the records themselves stay in the store.
+pub struct TrackLog {
+    offset: u32,
+    slots: u32,
+    slots_per_block: u32,
+    next: u32,
+    /// No record older than this is pending.
+    first_pending: u32,
+    /// The block `append` enters next is erased.
+    ahead_erased: bool,
+}
+
+impl TrackLog {
+    /// Opens the log in the `len` bytes at `offset`, which must span at least
+    /// two erase blocks, and finds the newest and the oldest pending record.
+    pub fn open<S: Store>(store: &mut S, offset: u32, len: u32) -> Result<Self, S::Error> {
+        let mut log = Self {
+            offset,
+            slots: len / S::ERASE_SIZE * S::ERASE_SIZE / RECORD,
+            slots_per_block: S::ERASE_SIZE / RECORD,
+            next: 0,
+            first_pending: u32::MAX,
+            ahead_erased: false,
+        };
+        let mut word = [0u8; 4];
+        for slot in 0..log.slots {
+            store.read(log.slot_offset(slot), &mut word)?;
+            let raw = u32::from_le_bytes(word);
+            if raw == EMPTY {
+                continue;
+            }
+            let seq = raw & !PENDING;
+            log.next = log.next.max(seq + 1);
+            if raw & PENDING != 0 {
+                log.first_pending = log.first_pending.min(seq);
+            }
+        }
+        log.first_pending = log.first_pending.min(log.next);
+        Ok(log)
+    }
+
+    /// Sequence number the next record gets.
+    pub fn next_seq(&self) -> u32 {
+        self.next
+    }
+
+    /// Records that may still be pending, oldest first; delivered and
+    /// overwritten ones in between are skipped by `read`.
+    pub fn pending(&self) -> Range<u32> {
+        self.first_pending..self.next
+    }
+
+    /// Records still in the store, oldest first.
+    pub fn stored(&self) -> Range<u32> {
+        self.oldest()..self.next
+    }
+
+    /// Appends a pending record; returns its sequence number.
+    pub fn append<S: Store>(&mut self, store: &mut S, time_s: u32, coord: &GpsCoord) -> Result<u32, S::Error> {
+        let seq = self.next;
+        let slot = seq % self.slots;
+        if slot.is_multiple_of(self.slots_per_block) {
+            if !self.ahead_erased {
+                self.erase_block(store, slot)?;
+            }
+            self.ahead_erased = false;
+        }
+        let mut record = [0u8; RECORD as usize];
+        record[0..4].copy_from_slice(&(seq | PENDING).to_le_bytes());
+        record[4..8].copy_from_slice(&time_s.to_le_bytes());
+        record[8..12].copy_from_slice(&coord.lat_deg_e7.to_le_bytes());
+        record[12..16].copy_from_slice(&coord.lon_deg_e7.to_le_bytes());
+        store.write(self.slot_offset(slot), &record)?;
+        self.next = seq + 1;
+        self.first_pending = self.first_pending.max(self.oldest());
+        Ok(seq)
+    }
+
+    /// Whether `erase_ahead` has a block to erase.
+    pub fn erase_due(&self) -> bool {
+        !self.ahead_erased && self.ahead() - self.next <= ERASE_AHEAD
+    }
+
+    /// Erases the block `append` enters next, with the oldest records in it;
+    /// call while no GPS bytes are arriving and `erase_due`.
+    pub fn erase_ahead<S: Store>(&mut self, store: &mut S) -> Result<(), S::Error> {
+        if !self.ahead_erased {
+            self.erase_block(store, self.ahead() % self.slots)?;
+            self.ahead_erased = true;
+            self.first_pending = self.first_pending.max(self.oldest());
+        }
+        Ok(())
+    }
+
+    /// Reads record `seq`, unless it was overwritten or not written yet.
+    pub fn read<S: Store>(&self, store: &mut S, seq: u32) -> Result<Option<Record>, S::Error> {
+        if !self.stored().contains(&seq) {
+            return Ok(None);
+        }
+        let mut record = [0u8; RECORD as usize];
+        store.read(self.slot_offset(seq % self.slots), &mut record)?;
+        let word = |i: usize| u32::from_le_bytes([record[i], record[i + 1], record[i + 2], record[i + 3]]);
+        let raw = word(0);
+        if raw == EMPTY || raw & !PENDING != seq {
+            return Ok(None);
+        }
+        Ok(Some(Record {
+            seq,
+            time_s: word(4),
+            coord: GpsCoord { lat_deg_e7: word(8) as i32, lon_deg_e7: word(12) as i32 },
+            delivered: raw & PENDING == 0,
+        }))
+    }
+
+    /// Marks the records in `seqs` as delivered.
+    pub fn mark_delivered<S: Store>(&mut self, store: &mut S, seqs: Range<u32>) -> Result<(), S::Error> {
+        for seq in seqs.start.max(self.first_pending)..seqs.end.min(self.next) {
+            if let Some(r) = self.read(store, seq)?
+                && !r.delivered
+            {
+                store.write(self.slot_offset(seq % self.slots), &seq.to_le_bytes())?;
+            }
+        }
+        while self.first_pending < self.next && self.read(store, self.first_pending)?.is_none_or(|r| r.delivered) {
+            self.first_pending += 1;
+        }
+        Ok(())
+    }
+
+    /// Prints record `seq` as a JSON line, without the position in field mode;
+    /// returns the next record to print, if any.
+    pub fn dump<S: Store, L: LogSink>(&self, store: &mut S, seq: u32, log: &mut Logger<L>) -> Result<Option<u32>, S::Error> {
+        let seq = seq.max(self.oldest());
+        if seq >= self.next {
+            return Ok(None);
+        }
+        if let Some(r) = self.read(store, seq)? {
+            log.print(format_args!("{{\"seq\":{},\"time\":{},", r.seq, r.time_s));
+            if log.mode() == Mode::Debug {
+                log.print(format_args!("\"lat\":{},\"long\":{},", r.coord.lat_deg_e7, r.coord.lon_deg_e7));
+            }
+            log.print(format_args!("\"delivered\":{}}}\r\n", r.delivered));
+        }
+        Ok(Some(seq + 1))
+    }
+
+    /// Oldest record not erased by the ring wrapping around.
+    fn oldest(&self) -> u32 {
+        if self.next == 0 {
+            return 0;
+        }
+        // the block holding the newest record only keeps the records written since it was erased
+        let newest = self.next - 1;
+        let in_block = newest % self.slots % self.slots_per_block;
+        let oldest = (newest - in_block).saturating_sub(self.slots - self.slots_per_block);
+        if self.ahead_erased {
+            // the records in the erased block went with it
+            return oldest.max((self.ahead() + self.slots_per_block).saturating_sub(self.slots));
+        }
+        oldest
+    }
+
+    /// First sequence number at a block start from `next` on.
+    fn ahead(&self) -> u32 {
+        self.next.div_ceil(self.slots_per_block) * self.slots_per_block
+    }
+
+    fn erase_block<S: Store>(&self, store: &mut S, slot: u32) -> Result<(), S::Error> {
+        let mut word = [0u8; 4];
+        store.read(self.slot_offset(slot), &mut word)?;
+        if u32::from_le_bytes(word) != EMPTY {
+            store.erase(self.slot_offset(slot), S::ERASE_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn slot_offset(&self, slot: u32) -> u32 {
+        self.offset + slot * RECORD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log::Level;
+    use crate::mock::{MockLog, MockStore};
+
+    fn at(lat: i32) -> GpsCoord {
+        GpsCoord { lat_deg_e7: lat, lon_deg_e7: -lat }
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let mut store = MockStore::new(3 * 4096);
+        let mut log = TrackLog::open(&mut store, 4096, 2 * 4096).unwrap();
+        assert_eq!(log.pending(), 0..0);
+        for i in 0..5 {
+            assert_eq!(log.append(&mut store, 1_000 + i, &at(i as i32)), Ok(i));
+        }
+        log.mark_delivered(&mut store, 0..2).unwrap();
+        log.mark_delivered(&mut store, 3..4).unwrap();
+        assert_eq!(log.pending(), 2..5);
+
+        let log = TrackLog::open(&mut store, 4096, 2 * 4096).unwrap();
+        assert_eq!(log.next_seq(), 5);
+        assert_eq!(log.pending(), 2..5);
+        assert_eq!(
+            log.read(&mut store, 3),
+            Ok(Some(Record { seq: 3, time_s: 1_003, coord: at(3), delivered: true }))
+        );
+        assert_eq!(log.read(&mut store, 4).unwrap().map(|r| r.delivered), Some(false));
+        assert_eq!(log.read(&mut store, 5), Ok(None));
+        // the boot counter block is left alone
+        assert!(store.data[..4096].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn wrapping_erases_the_oldest_block() {
+        let mut store = MockStore::new(2 * 4096);
+        let mut log = TrackLog::open(&mut store, 0, 2 * 4096).unwrap();
+        for i in 0..512 {
+            log.append(&mut store, i, &at(i as i32)).unwrap();
+        }
+        assert_eq!(store.erase_count, 0);
+        assert_eq!(log.stored(), 0..512);
+
+        log.append(&mut store, 512, &at(512)).unwrap();
+        assert_eq!(store.erase_count, 1);
+        assert_eq!(log.stored(), 256..513);
+        assert_eq!(log.pending(), 256..513);
+        assert_eq!(log.read(&mut store, 0), Ok(None));
+        assert_eq!(log.read(&mut store, 512).unwrap().map(|r| r.coord), Some(at(512)));
+
+        let log = TrackLog::open(&mut store, 0, 2 * 4096).unwrap();
+        assert_eq!(log.pending(), 256..513);
+    }
+
+    #[test]
+    fn blocks_erased_ahead_are_not_erased_again() {
+        let mut store = MockStore::new(2 * 4096);
+        let mut log = TrackLog::open(&mut store, 0, 2 * 4096).unwrap();
+        for i in 0..495 {
+            log.append(&mut store, i, &at(i as i32)).unwrap();
+        }
+        assert!(!log.erase_due());
+        log.append(&mut store, 495, &at(495)).unwrap();
+        assert!(log.erase_due());
+        log.erase_ahead(&mut store).unwrap();
+        assert_eq!(store.erase_count, 1);
+        assert!(!log.erase_due());
+        assert_eq!(log.stored(), 256..496);
+        assert_eq!(log.pending(), 256..496);
+
+        for i in 496..513 {
+            log.append(&mut store, i, &at(i as i32)).unwrap();
+        }
+        assert_eq!(store.erase_count, 1);
+        assert!(!log.erase_due());
+        assert_eq!(log.read(&mut store, 512).unwrap().map(|r| r.coord), Some(at(512)));
+        let log = TrackLog::open(&mut store, 0, 2 * 4096).unwrap();
+        assert_eq!(log.pending(), 256..513);
+    }
+
+    #[test]
+    fn dump_hides_positions_in_field_mode() {
+        let mut store = MockStore::new(2 * 4096);
+        let mut track = TrackLog::open(&mut store, 0, 2 * 4096).unwrap();
+        track.append(&mut store, 1_760_797_960, &at(7)).unwrap();
+        track.append(&mut store, 1_760_797_965, &at(8)).unwrap();
+        track.mark_delivered(&mut store, 0..1).unwrap();
+
+        let mut log = Logger::new(MockLog::default(), Level::Info);
+        let mut next = Some(0);
+        while let Some(seq) = next {
+            next = track.dump(&mut store, seq, &mut log).unwrap();
+        }
+        assert_eq!(
+            log.sink().text(),
+            "{\"seq\":0,\"time\":1760797960,\"delivered\":true}\r\n{\"seq\":1,\"time\":1760797965,\"delivered\":false}\r\n"
+        );
+
+        log.sink().clear();
+        log.set_mode(Mode::Debug);
+        assert_eq!(track.dump(&mut store, 1, &mut log), Ok(Some(2)));
+        assert_eq!(log.sink().text(), "{\"seq\":1,\"time\":1760797965,\"lat\":8,\"long\":-8,\"delivered\":false}\r\n");
+    }
+}
@@ -0,0 +1,131 @@
+//! Ring buffer between the GPS UART's interrupt and the beacon.
+//!
+//! The UART1 interrupt handler drains the UART's 32-byte receive FIFO into an
+//! `RxRing`, so bytes keep coming in while the radio task is busy, for
+//! instance with a transmission or a flash write. Where bytes were lost, to a
+//! full ring or in the UART, the ring holds a `GAP` byte. NMEA never carries
+//! NUL, and the line assembler drops the line a gap falls into instead of
+//! splicing its ends together.
+
+use core::fmt;
+
+/// Stands for lost bytes in the stream `pop` returns.
+pub const GAP: u8 = 0;
+
+/// Receive statistics since power-up.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct RxStats {
+    /// Bytes dropped because the ring was full.
+    pub overflows: u32,
+    /// Times the UART's FIFO overran before the interrupt handler emptied it.
+    pub overruns: u32,
+    /// Bytes received with a framing or parity error, and breaks.
+    pub errors: u32,
+}
+
+impl fmt::Display for RxStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes lost to a full buffer, {} UART overruns, {} framing errors",
+            self.overflows, self.overruns, self.errors
+        )
+    }
+}
+
+/// Bytes received from a UART, `N` at most.
+pub struct RxRing<const N: usize> {
+    bytes: heapless::Deque<u8, N>,
+    stats: RxStats,
+}
+
+impl<const N: usize> RxRing<N> {
+    pub const fn new() -> Self {
+        Self { bytes: heapless::Deque::new(), stats: RxStats { overflows: 0, overruns: 0, errors: 0 } }
+    }
+
+    pub fn stats(&self) -> RxStats {
+        self.stats
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Queues a received byte; call from the interrupt handler.
+    pub fn push(&mut self, b: u8) {
+        // the last slot is kept for the gap
+        if self.bytes.capacity() - self.bytes.len() > 1 {
+            let _ = self.bytes.push_back(b);
+        } else {
+            self.stats.overflows += 1;
+            self.gap();
+        }
+    }
+
+    /// Records that the UART's FIFO overran.
+    pub fn overrun(&mut self) {
+        self.stats.overruns += 1;
+        self.gap();
+    }
+
+    /// Records a byte the UART received garbled.
+    pub fn error(&mut self) {
+        self.stats.errors += 1;
+        self.gap();
+    }
+
+    /// Drops the queued bytes, for instance after the UART was off.
+    pub fn discard(&mut self) {
+        self.bytes.clear();
+        self.gap();
+    }
+
+    /// Returns the oldest byte, `GAP` where bytes were lost.
+    pub fn pop(&mut self) -> Option<u8> {
+        self.bytes.pop_front()
+    }
+
+    fn gap(&mut self) {
+        if self.bytes.back() != Some(&GAP) {
+            let _ = self.bytes.push_back(GAP);
+        }
+    }
+}
+
+impl<const N: usize> Default for RxRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain<const N: usize>(ring: &mut RxRing<N>) -> Vec<u8> {
+        core::iter::from_fn(|| ring.pop()).collect()
+    }
+
+    #[test]
+    fn marks_lost_bytes_with_one_gap() {
+        let mut ring = RxRing::<8>::new();
+        for &b in b"$GPGGA,123" {
+            ring.push(b);
+        }
+        assert_eq!(drain(&mut ring), b"$GPGGA,\0");
+        assert_eq!(ring.stats(), RxStats { overflows: 3, overruns: 0, errors: 0 });
+
+        ring.push(b'1');
+        ring.overrun();
+        ring.error();
+        ring.push(b'\n');
+        assert_eq!(drain(&mut ring), b"1\0\n");
+        assert_eq!(ring.stats(), RxStats { overflows: 3, overruns: 1, errors: 1 });
+
+        ring.push(b'$');
+        ring.discard();
+        assert_eq!(drain(&mut ring), [GAP]);
+        assert!(ring.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+//! Watchdog supervision of the firmware tasks.
+//!
+//! Every task the `Supervisor` watches checks in with the time it will check
+//! in again by: a tick or two while it runs, the length of its sleep plus
+//! some slack before the beacon powers down. The firmware's supervisor task
+//! feeds the hardware watchdog only while no task is overdue. A stalled task
+//! therefore resets the board, and so does a blocked executor, for instance
+//! after a hung SPI transaction, as then the supervisor task stops running
+//! too. Before it stops feeding, the supervisor task leaves a stall record in
+//! a watchdog scratch register, which survives the reset, so the next boot
+//! can tell which task stalled.
+
+use crate::hal::LogSink;
+use crate::log::Logger;
+use crate::{info, warn};
+
+const TAG: &str = "watchdog";
+
+/// Marks a stall record; the low byte holds the task.
+const STALL_MAGIC: u32 = 0x5354_4c00;
+
+/// Why the board last reset.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ResetReason {
+    /// Power-on, a brown-out or the RUN pin.
+    PowerOn,
+    /// The watchdog ran out, with the task that stalled if the supervisor saw one.
+    Watchdog(Option<usize>),
+    /// A reset forced through the watchdog: after a panic, or by the bootloader.
+    Forced,
+}
+
+impl ResetReason {
+    /// Decodes the watchdog's reason bits and the stall record.
+    pub fn from_registers(timer: bool, force: bool, scratch: u32) -> Self {
+        if timer {
+            let task = (scratch & !0xff == STALL_MAGIC).then_some((scratch & 0xff) as usize);
+            ResetReason::Watchdog(task)
+        } else if force {
+            ResetReason::Forced
+        } else {
+            ResetReason::PowerOn
+        }
+    }
+}
+
+/// The scratch register value that records `task` as stalled.
+pub fn stall_record(task: usize) -> u32 {
+    STALL_MAGIC | (task as u32 & 0xff)
+}
+
+/// Deadlines of `N` tasks.
+pub struct Supervisor<const N: usize> {
+    names: [&'static str; N],
+    deadlines: [u64; N],
+}
+
+impl<const N: usize> Supervisor<N> {
+    /// Watches the tasks `names`, each from its first check-in on.
+    pub const fn new(names: [&'static str; N]) -> Self {
+        Self { names, deadlines: [u64::MAX; N] }
+    }
+
+    pub fn name(&self, task: usize) -> &'static str {
+        self.names.get(task).copied().unwrap_or("unknown")
+    }
+
+    /// Task `task` runs at `now`, and checks in again within `within_ms`.
+    pub fn check_in(&mut self, task: usize, now: u64, within_ms: u64) {
+        self.deadlines[task] = now.saturating_add(within_ms);
+    }
+
+    /// The first task past its deadline at `now`, if any.
+    pub fn overdue(&self, now: u64) -> Option<usize> {
+        self.deadlines.iter().position(|&deadline| now > deadline)
+    }
+
+    /// Logs why the board reset; a watchdog reset is a warning.
+    pub fn report<S: LogSink>(&self, reason: ResetReason, log: &mut Logger<S>) {
+        match reason {
+            ResetReason::PowerOn => info!(log, TAG, "power-on reset"),
+            ResetReason::Forced => info!(log, TAG, "forced reset"),
+            ResetReason::Watchdog(Some(task)) => warn!(log, TAG, "reset after the {} task stalled", self.name(task)),
+            ResetReason::Watchdog(None) => warn!(log, TAG, "reset after the firmware hung"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_stalled_task() {
+        let mut supervisor = Supervisor::new(["radio", "usb"]);
+        assert_eq!(supervisor.overdue(1_000_000), None);
+        supervisor.check_in(0, 0, 2_000);
+        supervisor.check_in(1, 0, 2_000);
+        assert_eq!(supervisor.overdue(2_000), None);
+        // the radio task sleeps for a minute
+        supervisor.check_in(0, 1_500, 61_000);
+        supervisor.check_in(1, 2_500, 2_000);
+        assert_eq!(supervisor.overdue(4_000), None);
+        assert_eq!(supervisor.overdue(4_501), Some(1));
+        assert_eq!(supervisor.name(1), "usb");
+
+        let reason = ResetReason::from_registers(true, false, stall_record(1));
+        assert_eq!(reason, ResetReason::Watchdog(Some(1)));
+        assert_eq!(ResetReason::from_registers(true, false, 0), ResetReason::Watchdog(None));
+        assert_eq!(ResetReason::from_registers(false, true, 0), ResetReason::Forced);
+        assert_eq!(ResetReason::from_registers(false, false, stall_record(1)), ResetReason::PowerOn);
+    }
+}
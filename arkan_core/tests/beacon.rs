@@ -0,0 +1,867 @@
+//! Beacon and receiver logic driven through the host mocks.
+
+use arkan_core::battery::{BatteryStatus, Level as BatteryLevel};
+use arkan_core::beacon::{AckConfig, Beacon, CRITICAL_SLEEP_MS, LOW_BATTERY_SLEEP_MS, SLEEP_MS, TELEMETRY_MS};
+use arkan_core::boot_count;
+use arkan_core::breadcrumb::BatchConfig;
+use arkan_core::compact::{Encoding, Precision};
+use arkan_core::crash::PanicRecord;
+use arkan_core::encryption::GpsCoord;
+use arkan_core::gps_proccess::NonceCounter;
+use arkan_core::hal::{ByteSource, Clock, Radio};
+use arkan_core::hopping::{hop_channel, next_nonce, HopConfig};
+use arkan_core::log::{Level, Logger};
+use arkan_core::lorawan::{
+    parse_data, Activation, LorawanConfig, Mac, Session, ABP_SESSION, CONFIRMED_UP, FCNT_RESERVE, OTAA_KEYS,
+};
+use arkan_core::mock::{MockClock, MockGps, MockLog, MockNetworkServer, MockRadio, MockStore};
+use arkan_core::protocol::{ack_frame, parse_ack, wrap_relay, ACK_LEN, AUTH_LEN, FLAG_STALE};
+use arkan_core::radio_config::{Profile, RadioConfig};
+use arkan_core::radio_health::HealthStats;
+use arkan_core::receiver::Receiver;
+use arkan_core::relay::{RelayConfig, RelayStats};
+use arkan_core::scan::{PairStats, Scanner};
+use arkan_core::telemetry::Version;
+use arkan_core::tdma::SlotPlan;
+use arkan_core::track_log::TrackLog;
+use arkan_core::uart_rx::RxStats;
+use arkan_core::watchdog::ResetReason;
+
+const DGPS: &[u8] = include_bytes!("data/neo6m_dgps.nmea");
+const NO_FIX: &[u8] = include_bytes!("data/neo6m_no_fix.nmea");
+
+struct Bench {
+    beacon: Beacon,
+    radio: MockRadio,
+    store: MockStore,
+    log: Logger<MockLog>,
+    clock: MockClock,
+    rx: Option<RxSide>,
+    /// A relaying beacon between the two; the receiver then only hears the relay.
+    relay: Option<RelaySide>,
+    /// A LoRaWAN gateway and the network server behind it, with the frames already handled.
+    lorawan: Option<(MockNetworkServer, usize)>,
+}
+
+/// A receiver within range of the beacon.
+struct RxSide {
+    receiver: Receiver,
+    radio: MockRadio,
+    log: Logger<MockLog>,
+    /// Frames of each radio already put on air.
+    beacon_sent: usize,
+    rx_sent: usize,
+    /// The beacon frame whose preamble a scanning receiver may still catch, and until when.
+    preamble: Option<(Vec<u8>, u64)>,
+}
+
+impl Default for RxSide {
+    fn default() -> Self {
+        Self {
+            receiver: Receiver::new(),
+            radio: MockRadio::default(),
+            log: Logger::new(MockLog::default(), Level::Info),
+            beacon_sent: 0,
+            rx_sent: 0,
+            preamble: None,
+        }
+    }
+}
+
+struct RelaySide {
+    node: Beacon,
+    radio: MockRadio,
+    store: MockStore,
+    log: Logger<MockLog>,
+    /// Frames of the beacon and of the relay already put on air between the two.
+    beacon_sent: usize,
+    relay_sent: usize,
+}
+
+impl Default for RelaySide {
+    fn default() -> Self {
+        let mut node = Beacon::new(NonceCounter::new([5, 6, 7, 8], 1), 0);
+        node.set_relay(Some(RelayConfig::default()));
+        Self {
+            node,
+            radio: MockRadio::default(),
+            store: MockStore::new(4096),
+            log: Logger::new(MockLog::default(), Level::Debug),
+            beacon_sent: 0,
+            relay_sent: 0,
+        }
+    }
+}
+
+impl Bench {
+    fn new() -> Self {
+        let clock = MockClock::new(1_000);
+        Self {
+            beacon: Beacon::new(NonceCounter::new([1, 2, 3, 4], 7), clock.now_ms()),
+            radio: MockRadio::default(),
+            store: MockStore::new(3 * 4096),
+            log: Logger::new(MockLog::default(), Level::Trace),
+            clock,
+            rx: None,
+            relay: None,
+            lorawan: None,
+        }
+    }
+
+    fn with_ack(receiver: bool) -> Self {
+        let mut bench = Self::new();
+        bench.beacon.set_ack(Some(AckConfig::default()));
+        bench.rx = receiver.then(RxSide::default);
+        bench
+    }
+
+    /// Feeds the capture, advancing the clock by `ms_per_byte`; returns the first requested sleep.
+    fn feed(&mut self, capture: &[u8], ms_per_byte: u64) -> Option<u32> {
+        let mut gps = MockGps::new(capture);
+        while let Some(b) = gps.read_byte() {
+            self.clock.advance(ms_per_byte);
+            if let Some(ms) = self.step(Some(b)) {
+                return Some(ms);
+            }
+        }
+        None
+    }
+
+    /// Runs the main loops for `ms` without GPS data.
+    fn idle(&mut self, ms: u64) {
+        for _ in 0..ms / 10 {
+            self.clock.advance(10);
+            self.step(None);
+        }
+    }
+
+    fn step(&mut self, b: Option<u8>) -> Option<u32> {
+        if let Some(rx) = &mut self.rx {
+            // frames sent in the previous step arrive now, if the other side is listening
+            match &mut self.relay {
+                Some(relay) => {
+                    air(&self.radio, &mut relay.beacon_sent, &mut relay.radio);
+                    air(&relay.radio, &mut relay.relay_sent, &mut self.radio);
+                    air(&relay.radio, &mut rx.beacon_sent, &mut rx.radio);
+                    // a forwarded frame is off the air long before the receiver answers it
+                    relay.node.poll(&mut relay.radio, &mut relay.store, &mut relay.log, &self.clock);
+                    air(&rx.radio, &mut rx.rx_sent, &mut relay.radio);
+                }
+                None if rx.receiver.scanner().is_some() => {
+                    scan_air(&self.radio, rx, self.clock.now_ms());
+                    air(&rx.radio, &mut rx.rx_sent, &mut self.radio);
+                }
+                None => {
+                    air(&self.radio, &mut rx.beacon_sent, &mut rx.radio);
+                    air(&rx.radio, &mut rx.rx_sent, &mut self.radio);
+                }
+            }
+            rx.receiver.poll(&mut rx.radio, &mut rx.log, &self.clock);
+        }
+        if let Some((server, handled)) = &mut self.lorawan {
+            for frame in &self.radio.sent[*handled..] {
+                // the downlink waits until the beacon listens in RX1
+                self.radio.incoming.extend(server.handle(frame));
+            }
+            *handled = self.radio.sent.len();
+        }
+        let sleep = b.and_then(|b| self.beacon.handle_byte(b, &mut self.radio, &mut self.store, &mut self.log, &self.clock));
+        self.beacon.poll(&mut self.radio, &mut self.store, &mut self.log, &self.clock);
+        sleep
+    }
+}
+
+fn air(from: &MockRadio, sent: &mut usize, to: &mut MockRadio) {
+    let hz = |radio: &MockRadio| radio.config.unwrap_or_default().frequency_hz();
+    if to.receiving && hz(from) == hz(to) {
+        to.incoming.extend(from.sent[*sent..].iter().cloned());
+    }
+    *sent = from.sent.len();
+}
+
+/// Like `air` for a scanning receiver: a frame is heard when the receiver's CAD
+/// runs on its frequency and spreading factor while the preamble is on air.
+fn scan_air(from: &MockRadio, rx: &mut RxSide, now: u64) {
+    let config = from.config.unwrap_or_default();
+    if let Some(frame) = from.sent[rx.beacon_sent..].last() {
+        let p = config.params();
+        let preamble_ms = (p.symbol_us() as u64 * (p.preamble_len as u64 + 4)).div_ceil(1000);
+        rx.preamble = Some((frame.clone(), now + preamble_ms));
+    }
+    rx.beacon_sent = from.sent.len();
+    let Some((frame, until)) = &rx.preamble else { return };
+    let tuned = rx.radio.config.unwrap_or_default();
+    if now >= *until {
+        rx.preamble = None;
+    } else if tuned.frequency_hz() != config.frequency_hz() || tuned.params() != config.params() {
+    } else if rx.radio.detecting {
+        rx.radio.cad_busy = 1;
+    } else if rx.radio.receiving {
+        rx.radio.incoming.push_back(frame.clone());
+        rx.preamble = None;
+    }
+}
+
+#[test]
+fn fixes_are_sent_and_decoded_by_receiver() {
+    let mut bench = Bench::new();
+    assert_eq!(bench.feed(DGPS, 0), None);
+
+    // every GGA produces a fresh packet, and nothing is repeated before the heartbeat
+    let mut fresh = bench.radio.sent.clone();
+    fresh.dedup();
+    assert_eq!(fresh.len(), 3);
+    assert_eq!(bench.radio.sent.len(), 3);
+    assert_eq!(bench.log.sink().text().matches("sent data to LoRa\r\n").count(), 3);
+
+    let mut rx = RxSide::default();
+    rx.receiver.handle_packet(&bench.radio.sent[0], &mut rx.radio, &mut rx.log, &bench.clock);
+    assert_eq!(rx.log.sink().text(), "{\"beacon\":\"01020304\",\"lat\":-337520067,\"long\":-1183926183}\r\n");
+    // no ACK unless the beacon asked for one
+    assert!(rx.radio.sent.is_empty());
+}
+
+#[test]
+fn lost_fixes_are_repeated_as_stale_at_the_heartbeat() {
+    let mut bench = Bench::new();
+    bench.rx = Some(RxSide::default());
+    bench.feed(DGPS, 0);
+    // 14.4 s without a fix
+    assert_eq!(bench.feed(NO_FIX, 12), None);
+
+    assert_eq!(bench.radio.sent.len(), 4);
+    // both end with their MAC
+    let (last, stale) = (&bench.radio.sent[2], &bench.radio.sent[3]);
+    let (last, stale) = (&last[..last.len() - AUTH_LEN], &stale[..stale.len() - AUTH_LEN]);
+    assert_eq!(stale[0], last[0] | FLAG_STALE);
+    assert_eq!(stale[1..last.len()], last[1..]);
+    assert_eq!(stale[last.len()..], 10u16.to_le_bytes());
+    assert!(bench.log.sink().text().contains("sent last fix again, 10 s old\r\n"));
+
+    let rx = bench.rx.as_mut().unwrap();
+    assert!(rx.log.sink().text().ends_with(
+        "{\"beacon\":\"01020304\",\"lat\":-337520067,\"long\":-1183926183}\r\n{\"beacon\":\"01020304\",\"lat\":-337520067,\"long\":-1183926183,\"age_s\":10}\r\n"
+    ));
+}
+
+#[test]
+fn nonces_continue_from_boot_count() {
+    let mut bench = Bench::new();
+    bench.feed(DGPS, 0);
+    let first = &bench.radio.sent[0];
+    assert_eq!(first[1..13], [1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, 0]);
+}
+
+#[test]
+fn sleeps_after_sending_for_20_seconds() {
+    let mut bench = Bench::new();
+    // roughly one NMEA epoch per second at 9600 baud
+    assert_eq!(bench.feed(DGPS, 3), None);
+    let sleep = bench.feed(DGPS, 20);
+    assert_eq!(sleep, Some(SLEEP_MS));
+    assert!(bench.log.sink().text().ends_with("Been sending data for 20 seconds, going to sleep...\r\n"));
+
+    bench.clock.advance(SLEEP_MS as u64);
+    bench.beacon.wake(&mut bench.log, &bench.clock);
+    assert_eq!(bench.feed(DGPS, 0), None);
+}
+
+#[test]
+fn sleeps_without_gps_fix() {
+    let mut bench = Bench::new();
+    assert_eq!(bench.feed(NO_FIX, 1), None);
+    assert!(bench.radio.sent.is_empty());
+    let sleep = bench.feed(NO_FIX, 30);
+    assert_eq!(sleep, Some(SLEEP_MS));
+    assert!(bench.log.sink().text().ends_with("No valid GPS data for 30 sec, going to sleep...\r\n"));
+}
+
+#[test]
+fn transmit_errors_are_reported() {
+    let mut bench = Bench::new();
+    bench.radio.fail_tx = true;
+    bench.feed(DGPS, 0);
+    assert!(bench.log.sink().text().contains("[ERROR beacon] LoRa transmit failed\r\n"));
+
+    bench.radio.fail_tx = false;
+    let mut buf = [0u8; 255];
+    assert_eq!(bench.radio.receive(&mut buf), Ok(None));
+}
+
+#[test]
+fn repeats_stop_when_the_duty_cycle_is_used_up() {
+    let mut bench = Bench::new();
+    bench.beacon.set_radio_config(RadioConfig::DEFAULT.with_profile(Profile::LONG_RANGE));
+    // repeat on every sentence
+    bench.beacon.set_heartbeat(Some(0));
+    // 2.1 s per frame at SF12 against 360 s per hour on EU433
+    for _ in 0..10 {
+        bench.feed(DGPS, 0);
+    }
+    assert_eq!(bench.radio.sent.len(), 170);
+    // held frames are not retried until the budget is back, so this is logged once
+    assert_eq!(bench.log.sink().text().matches("duty cycle used up, holding frame for 3599 s\r\n").count(), 1);
+
+    bench.clock.advance(3_599_000);
+    bench.beacon.wake(&mut bench.log, &bench.clock);
+    assert_eq!(bench.feed(DGPS, 0), None);
+    // the held frame goes out as soon as the budget is back, then every sentence is repeated
+    assert_eq!(bench.radio.sent.len(), 170 + 1 + 24);
+}
+
+#[test]
+fn failed_transmissions_leave_the_duty_cycle_budget_alone() {
+    let config = RadioConfig::DEFAULT.with_profile(Profile::LONG_RANGE);
+    let mut bench = Bench::new();
+    bench.beacon.set_radio_config(config);
+    bench.beacon.set_heartbeat(Some(0));
+    bench.radio.fail_tx = true;
+    // more than the 170 frames of 2.1 s that fit into an hour on EU433
+    for _ in 0..10 {
+        bench.feed(DGPS, 0);
+    }
+    bench.radio.fail_tx = false;
+    bench.feed(DGPS, 0);
+    assert_eq!(bench.radio.sent.len(), 24);
+    assert!(!bench.log.sink().text().contains("duty cycle used up"));
+
+    // the same for the receiver's ACKs
+    let mut bench = Bench::with_ack(false);
+    let epoch = DGPS.split(|&b| b == b'\n').take(8).map(|l| l.len() + 1).sum();
+    bench.feed(&DGPS[..epoch], 0);
+    let mut rx = RxSide::default();
+    rx.receiver.configure(&mut rx.radio, &config).unwrap();
+    rx.radio.fail_tx = true;
+    for _ in 0..400 {
+        rx.receiver.handle_packet(&bench.radio.sent[0], &mut rx.radio, &mut rx.log, &bench.clock);
+    }
+    rx.radio.fail_tx = false;
+    rx.receiver.handle_packet(&bench.radio.sent[0], &mut rx.radio, &mut rx.log, &bench.clock);
+    assert_eq!(rx.radio.sent.len(), 1);
+    assert!(!rx.log.sink().text().contains("duty cycle used up"));
+}
+
+#[test]
+fn acknowledged_fixes_are_sent_once() {
+    let mut bench = Bench::with_ack(true);
+    assert_eq!(bench.feed(DGPS, 0), None);
+
+    assert_eq!(bench.radio.sent.len(), 3);
+    assert!(bench.radio.sent.iter().all(|f| f[0] == 0x11));
+    let rx = bench.rx.as_mut().unwrap();
+    assert_eq!(rx.radio.sent.len(), 3);
+    assert_eq!(parse_ack(&rx.radio.sent[2]).unwrap()[..], bench.radio.sent[2][1..13]);
+    assert_eq!(rx.log.sink().text().lines().count(), 3);
+    assert_eq!(bench.log.sink().text().matches("LoRa delivery acknowledged\r\n").count(), 3);
+}
+
+#[test]
+fn forged_frames_are_neither_printed_nor_acknowledged() {
+    let mut bench = Bench::with_ack(false);
+    let epoch = DGPS.split(|&b| b == b'\n').take(8).map(|l| l.len() + 1).sum();
+    bench.feed(&DGPS[..epoch], 0);
+    let mut rx = RxSide::default();
+    // the next nonce of the beacon with junk for a ciphertext
+    let mut forged = bench.radio.sent[0].clone();
+    forged[12] += 1;
+    forged[13..].fill(0x55);
+    rx.receiver.handle_packet(&forged, &mut rx.radio, &mut rx.log, &bench.clock);
+    // a stale flag added to a real frame
+    let mut stale = bench.radio.sent[0].clone();
+    stale[0] |= FLAG_STALE;
+    rx.receiver.handle_packet(&stale, &mut rx.radio, &mut rx.log, &bench.clock);
+
+    assert!(rx.radio.sent.is_empty());
+    assert_eq!(rx.log.sink().text().matches("frame with a wrong MAC").count(), 2);
+    assert!(!rx.log.sink().text().contains("\"lat\""));
+}
+
+#[test]
+fn retries_are_reported_once_per_beacon() {
+    let epoch = DGPS.split(|&b| b == b'\n').take(8).map(|l| l.len() + 1).sum();
+    let mut a = Bench::new();
+    a.feed(&DGPS[..epoch], 0);
+    let mut b = Bench::new();
+    b.beacon = Beacon::new(NonceCounter::new([5, 6, 7, 8], 7), b.clock.now_ms());
+    b.feed(&DGPS[..epoch], 0);
+
+    // the retry of the first beacon's fix arrives after a fix of the second
+    let mut rx = RxSide::default();
+    for frame in [&a.radio.sent[0], &b.radio.sent[0], &a.radio.sent[0]] {
+        rx.receiver.handle_packet(frame, &mut rx.radio, &mut rx.log, &a.clock);
+    }
+    assert_eq!(rx.log.sink().text().matches("\"lat\"").count(), 2);
+}
+
+#[test]
+fn lost_acks_are_retried_with_backoff() {
+    let mut bench = Bench::with_ack(false);
+    // stop after the first fix
+    let first_epoch = DGPS.split(|&b| b == b'\n').take(8).map(|l| l.len() + 1).sum();
+    bench.feed(&DGPS[..first_epoch], 0);
+    assert_eq!(bench.radio.sent.len(), 1);
+
+    // window 1 s, then retries after 2, 4 and 8 s, each with its own window
+    bench.idle(3_100);
+    assert_eq!(bench.radio.sent.len(), 2);
+    bench.idle(5_100);
+    assert_eq!(bench.radio.sent.len(), 3);
+    bench.idle(9_000);
+    assert_eq!(bench.radio.sent.len(), 4);
+    bench.idle(20_000);
+    assert_eq!(bench.radio.sent.len(), 4);
+    assert_eq!(bench.radio.sent[3], bench.radio.sent[0]);
+    let log = bench.log.sink().text();
+    assert!(log.contains("No ACK, retry 3 in 8000 ms\r\n"));
+    assert!(log.contains("No ACK after 3 retries, giving up\r\n"));
+}
+
+#[test]
+fn fixes_that_arrive_during_the_ack_window_follow_the_ack() {
+    let mut bench = Bench::with_ack(false);
+    let epoch = DGPS.split(|&b| b == b'\n').take(8).map(|l| l.len() + 1).sum();
+    bench.feed(&DGPS[..epoch], 0);
+    // the second fix comes in while the beacon listens for the first one's ACK
+    bench.feed(&DGPS[epoch..2 * epoch], 0);
+    assert_eq!(bench.radio.sent.len(), 1);
+
+    let first: [u8; 12] = bench.radio.sent[0][1..13].try_into().unwrap();
+    bench.radio.incoming.push_back(ack_frame(&first).to_vec());
+    bench.step(None);
+    assert_eq!(bench.radio.sent.len(), 2);
+    let second: [u8; 12] = bench.radio.sent[1][1..13].try_into().unwrap();
+    assert_ne!(second, first);
+
+    // and is retried like any other
+    bench.idle(3_100);
+    assert_eq!(bench.radio.sent.len(), 3);
+    assert_eq!(bench.radio.sent[2], bench.radio.sent[1]);
+    assert!(bench.log.sink().text().contains("No ACK, retry 1 in 2000 ms\r\n"));
+}
+
+#[test]
+fn only_acknowledged_fixes_count_as_delivered() {
+    // fixes without any receiver in range keep the beacon awake
+    let mut bench = Bench::with_ack(false);
+    assert_eq!(bench.feed(DGPS, 3), None);
+    assert_eq!(bench.feed(DGPS, 20), None);
+
+    let mut bench = Bench::with_ack(true);
+    assert_eq!(bench.feed(DGPS, 3), None);
+    assert_eq!(bench.feed(DGPS, 20), Some(SLEEP_MS));
+}
+
+#[test]
+fn acknowledged_fixes_shrink_to_deltas() {
+    let mut bench = Bench::with_ack(true);
+    bench.beacon.set_encoding(Encoding::Delta(Precision::E5));
+    bench.feed(DGPS, 0);
+
+    let lens: Vec<_> = bench.radio.sent.iter().map(Vec::len).collect();
+    assert_eq!(lens, [25, 11, 11]);
+    let rx = bench.rx.as_mut().unwrap();
+    assert_eq!(rx.radio.sent.len(), 3);
+    assert_eq!(
+        rx.log.sink().text(),
+        "{\"beacon\":\"01020304\",\"lat\":-337520067,\"long\":-1183926183}\r\n".to_string()
+            + "{\"beacon\":\"01020304\",\"lat\":-337520100,\"long\":-1183926200}\r\n"
+            + "{\"beacon\":\"01020304\",\"lat\":-337520100,\"long\":-1183926200}\r\n"
+    );
+}
+
+#[test]
+fn breadcrumbs_arrive_as_one_frame() {
+    let mut bench = Bench::with_ack(true);
+    bench.beacon.set_batch(Some(BatchConfig { points: 4, interval_ms: 2_000, precision: Precision::E5 }));
+    // GGA every 1.5 s: points are taken every other epoch
+    for _ in 0..3 {
+        bench.feed(DGPS, 3);
+    }
+
+    assert_eq!(bench.radio.sent.len(), 1);
+    assert_eq!(bench.radio.sent[0][0] >> 4, 5);
+    assert!(bench.log.sink().text().contains("sending 4 breadcrumbs\r\n"));
+    assert!(bench.log.sink().text().contains("LoRa delivery acknowledged\r\n"));
+    let rx = bench.rx.as_mut().unwrap();
+    let text = rx.log.sink().text();
+    let ages: Vec<_> = text.lines().map(|l| l.rsplit_once(':').unwrap().1.trim_end_matches('}').to_string()).collect();
+    assert_eq!(ages, ["9", "6", "3", "0"]);
+    assert!(text.starts_with("{\"beacon\":\"01020304\",\"lat\":-337520100,\"long\":-1183926200,\"age_s\":9}\r\n"));
+}
+
+#[test]
+fn logged_fixes_are_uploaded_when_the_receiver_returns() {
+    let mut bench = Bench::with_ack(false);
+    let track = TrackLog::open(&mut bench.store, 4096, 2 * 4096).unwrap();
+    bench.beacon.set_track_log(Some(track));
+    bench.feed(DGPS, 3);
+    bench.idle(20_000);
+    assert_eq!(bench.beacon.track_log().unwrap().pending(), 0..3);
+
+    bench.rx = Some(RxSide::default());
+    bench.feed(DGPS, 3);
+    bench.idle(5_000);
+    assert!(bench.log.sink().text().contains("uploading 3 logged fixes\r\n"));
+    let track = bench.beacon.track_log().unwrap();
+    assert_eq!(track.pending(), 6..6);
+    let first = track.read(&mut bench.store, 0).unwrap().unwrap();
+    // 2026-10-18 14:32:40 UTC
+    assert_eq!(first.time_s, 1_792_333_960);
+    assert!(first.delivered);
+
+    let rx = bench.rx.as_mut().unwrap();
+    let text = rx.log.sink().text();
+    assert_eq!(text.lines().filter(|l| l.contains("age_s")).count(), 3);
+    assert_eq!(text.lines().count(), 6);
+}
+
+#[test]
+fn delivered_fixes_are_skipped_by_the_upload() {
+    let mut bench = Bench::with_ack(true);
+    let mut track = TrackLog::open(&mut bench.store, 4096, 2 * 4096).unwrap();
+    let coord = GpsCoord { lat_deg_e7: 504_501_000, lon_deg_e7: 305_234_000 };
+    for i in 0..509 {
+        track.append(&mut bench.store, 1_792_333_000 + i, &coord).unwrap();
+    }
+    // live frames got through while the first fix did not
+    track.mark_delivered(&mut bench.store, 1..509).unwrap();
+    bench.beacon.set_track_log(Some(track));
+
+    // the radio fails right after the first live frame was acknowledged
+    let mut gps = MockGps::new(DGPS);
+    while !bench.log.sink().text().contains("LoRa delivery acknowledged") {
+        bench.clock.advance(3);
+        bench.step(gps.read_byte());
+    }
+    bench.beacon.set_log_only(true);
+    bench.feed(DGPS, 3);
+    // the first block was overwritten, what is left up to the live frame was delivered
+    assert_eq!(bench.beacon.track_log().unwrap().pending(), 256..513);
+
+    bench.beacon.set_log_only(false);
+    bench.idle(5_000);
+    assert!(!bench.log.sink().text().contains("uploading"));
+    assert_eq!(bench.beacon.track_log().unwrap().pending(), 510..513);
+}
+
+#[test]
+fn track_log_blocks_are_erased_while_the_gps_is_quiet() {
+    let mut bench = Bench::new();
+    let mut track = TrackLog::open(&mut bench.store, 4096, 2 * 4096).unwrap();
+    let coord = GpsCoord { lat_deg_e7: 504_501_000, lon_deg_e7: 305_234_000 };
+    for i in 0..500 {
+        track.append(&mut bench.store, 1_792_333_000 + i, &coord).unwrap();
+    }
+    bench.beacon.set_track_log(Some(track));
+    bench.idle(100);
+    assert_eq!(bench.store.erase_count, 1);
+    assert!(!bench.beacon.track_log().unwrap().erase_due());
+}
+
+#[test]
+fn fixes_are_logged_while_the_radio_is_down() {
+    let mut bench = Bench::with_ack(true);
+    let track = TrackLog::open(&mut bench.store, 4096, 2 * 4096).unwrap();
+    bench.beacon.set_track_log(Some(track));
+    bench.beacon.set_log_only(true);
+    bench.feed(DGPS, 3);
+    bench.idle(5_000);
+    assert!(bench.radio.sent.is_empty());
+    assert_eq!(bench.beacon.track_log().unwrap().pending(), 0..3);
+
+    // the radio is back
+    bench.beacon.set_log_only(false);
+    bench.feed(DGPS, 3);
+    bench.idle(5_000);
+    assert!(bench.log.sink().text().contains("uploading 3 logged fixes\r\n"));
+    assert_eq!(bench.beacon.track_log().unwrap().pending(), 6..6);
+}
+
+#[test]
+fn frames_wait_for_the_time_slot() {
+    let mut bench = Bench::new();
+    bench.rx = Some(RxSide::default());
+    bench.beacon.set_heartbeat(None);
+    bench.beacon.set_tdma(Some(SlotPlan::default()));
+    bench.rx.as_mut().unwrap().receiver.set_tdma(Some(SlotPlan::default()));
+    // device id 1.2.3.4: 5.0 to 6.25 s into every 10 s superframe
+    assert_eq!(bench.beacon.slot(), Some(4));
+
+    // the first GGA (14:32:40.00) starts a superframe
+    bench.feed(DGPS, 3);
+    assert!(bench.radio.sent.is_empty());
+    bench.idle(5_000);
+    assert_eq!(bench.radio.sent.len(), 1);
+    // held frames go out with the age of their fix
+    assert!(bench.log.sink().text().ends_with("sent data to LoRa, 3 s after the fix\r\n"));
+
+    let rx = bench.rx.as_mut().unwrap();
+    let stats: Vec<_> = rx.receiver.slots().unwrap().stats().collect();
+    assert_eq!((stats[0].slot, stats[0].heard, stats[0].outside), (4, 1, 0));
+    assert_eq!(rx.log.sink().text(), "{\"beacon\":\"01020304\",\"lat\":-337520067,\"long\":-1183926183,\"age_s\":3}\r\n");
+}
+
+#[test]
+fn receiver_follows_the_hop_sequence() {
+    let mut bench = Bench::with_ack(true);
+    bench.beacon.set_hopping(true);
+    bench.rx.as_mut().unwrap().receiver.set_hopping(Some(HopConfig::default()));
+    for _ in 0..4 {
+        bench.feed(DGPS, 3);
+    }
+
+    // every fix went out once, on the channel its nonce picks, and was acknowledged
+    let channels: Vec<u8> = bench.radio.sent.iter().map(|f| hop_channel(f[1..13].try_into().unwrap(), 3)).collect();
+    assert_eq!(channels.len(), 12);
+    assert!((0..3).all(|c| channels.contains(&c)), "{:?}", channels);
+    assert_eq!(bench.log.sink().text().matches("LoRa delivery acknowledged\r\n").count(), 12);
+    let rx = bench.rx.as_mut().unwrap();
+    assert_eq!(rx.log.sink().text().lines().count(), 12);
+
+    // the receiver waits on the channel of the next nonce
+    let last: [u8; 12] = bench.radio.sent[11][1..13].try_into().unwrap();
+    let next = hop_channel(&next_nonce(&last), 3);
+    assert_eq!(rx.radio.config.unwrap().channel(), next);
+    // and has nothing to do until it gives up on it, unless a frame comes in
+    let now = bench.clock.now_ms();
+    assert!(rx.receiver.wake_at().is_some_and(|t| t > now && t <= now + 3_000));
+}
+
+#[test]
+fn panic_reports_reach_the_receiver_once() {
+    let mut bench = Bench::with_ack(true);
+    bench.beacon.set_hopping(true);
+    bench.rx.as_mut().unwrap().receiver.set_hopping(Some(HopConfig::default()));
+    let record = PanicRecord::new("src/main.rs", 212, format_args!("Could not connect to \"LoRa\""));
+    bench.beacon.set_panic_report(Some(record));
+    bench.idle(1_000);
+    bench.feed(DGPS, 3);
+
+    assert_eq!(bench.radio.sent.len(), 4);
+    assert_eq!(bench.log.sink().text().matches("sent panic report\r\n").count(), 1);
+    // the report took a nonce in sequence, so the receiver still finds every fix
+    assert_eq!(bench.log.sink().text().matches("LoRa delivery acknowledged\r\n").count(), 3);
+    let rx = bench.rx.as_mut().unwrap();
+    let text = rx.log.sink().text();
+    assert!(text.starts_with(
+        "{\"device\":\"01020304\",\"panic\":\"src/main.rs:212: Could not connect to \\\"LoRa\\\"\"}\r\n"
+    ));
+    assert_eq!(text.lines().count(), 4);
+}
+
+#[test]
+fn battery_is_reported_and_a_critical_one_ends_sending() {
+    let mut bench = Bench::new();
+    bench.rx = Some(RxSide::default());
+    bench.beacon.set_telemetry(Some(TELEMETRY_MS));
+    let low = BatteryStatus { mv: 3_740, percent: 18, level: BatteryLevel::Low };
+    bench.beacon.update_battery(low, &mut bench.log);
+    bench.idle(100);
+    assert_eq!(bench.radio.sent.len(), 1);
+    assert!(bench.log.sink().text().contains("sent telemetry\r\n"));
+    // a low battery lengthens the sleeps
+    assert_eq!(bench.feed(NO_FIX, 30), Some(LOW_BATTERY_SLEEP_MS));
+
+    bench.clock.advance(LOW_BATTERY_SLEEP_MS as u64);
+    bench.beacon.wake(&mut bench.log, &bench.clock);
+    let critical = BatteryStatus { mv: 3_600, percent: 5, level: BatteryLevel::Critical };
+    bench.beacon.update_battery(critical, &mut bench.log);
+    // the final report goes out at once, then the beacon only sleeps
+    bench.idle(100);
+    assert_eq!(bench.radio.sent.len(), 2);
+    assert_eq!(bench.feed(DGPS, 0), Some(CRITICAL_SLEEP_MS));
+    assert_eq!(bench.radio.sent.len(), 2);
+
+    // until it is charged
+    bench.clock.advance(CRITICAL_SLEEP_MS as u64);
+    bench.beacon.wake(&mut bench.log, &bench.clock);
+    let usb = BatteryStatus { mv: 4_700, percent: 100, level: BatteryLevel::External };
+    bench.beacon.update_battery(usb, &mut bench.log);
+    assert_eq!(bench.feed(DGPS, 0), None);
+    // the periodic telemetry and the fixes
+    assert_eq!(bench.radio.sent.len(), 6);
+
+    let text = bench.rx.as_mut().unwrap().log.sink().text();
+    let mut lines = text.lines();
+    assert!(lines.next().unwrap().contains("\"battery_mv\":3740,\"battery_pct\":18,\"battery\":\"low\""));
+    assert!(lines.next().unwrap().contains("\"battery_mv\":3600,\"battery_pct\":5,\"battery\":\"critical\""));
+    assert!(lines.next().unwrap().ends_with("beacon 01020304 battery 3600 mV, 5 %, critical"));
+    assert!(lines.next().unwrap().contains("\"battery\":\"external\""));
+    assert!(lines.next().unwrap().starts_with("{\"beacon\":\"01020304\",\"lat\":"));
+}
+
+#[test]
+fn telemetry_reports_the_beacon_state() {
+    let mut bench = Bench::with_ack(true);
+    bench.beacon.set_telemetry(Some(TELEMETRY_MS));
+    bench.beacon.set_firmware(Version::parse("0.1.0"), ResetReason::Watchdog(Some(0)));
+    bench.beacon.update_temperature(31);
+    bench.feed(NO_FIX, 1);
+    bench.feed(DGPS, 3);
+    bench.clock.advance(TELEMETRY_MS);
+    bench.idle(100);
+
+    let rx = bench.rx.as_mut().unwrap();
+    let text = rx.log.sink().text();
+    let mut records = text.lines().filter(|l| l.contains("\"telemetry\""));
+    // the first one went out at once, before the GPS had a fix
+    assert!(records.next().unwrap().contains("\"tx_sent\":0,"));
+    // the GPS took a second to its first fix, then three fixes were sent and acknowledged
+    assert_eq!(
+        records.next(),
+        Some(
+            "{\"device\":\"01020304\",\"telemetry\":{\"uptime_s\":606,\"version\":\"0.1.0\",\
+             \"reset\":\"watchdog\",\"stalled_task\":0,\"temp_c\":31,\"satellites\":9,\
+             \"ttf_s\":1,\"tx_sent\":4,\"tx_acked\":3,\"tx_failed\":0,\
+             \"radio_errors\":0,\"radio_hangs\":0,\"radio_resets\":0,\"gps_bytes_lost\":0,\"gps_lines_dropped\":0}}"
+        )
+    );
+}
+
+#[test]
+fn fault_counters_saturate() {
+    let mut bench = Bench::new();
+    bench.beacon.update_faults(HealthStats::default(), RxStats { overflows: u32::MAX, overruns: 1, errors: 1 });
+    let faults = bench.beacon.telemetry(bench.clock.now_ms()).faults.unwrap();
+    assert_eq!(faults.gps_bytes_lost, u32::MAX);
+}
+
+#[test]
+fn telemetry_follows_a_duty_cycle_hold_with_acks() {
+    let mut bench = Bench::with_ack(false);
+    bench.beacon.set_radio_config(RadioConfig::DEFAULT.with_profile(Profile::LONG_RANGE));
+    bench.beacon.set_telemetry(Some(TELEMETRY_MS));
+    // unacknowledged fixes are retried until the budget of 170 frames at SF12 is used up
+    while !bench.log.sink().text().contains("duty cycle used up") {
+        bench.feed(DGPS, 0);
+        bench.idle(20_000);
+    }
+    let sent = bench.log.sink().text().matches("sent telemetry\r\n").count();
+
+    // the held fix goes out after its backoff, and the telemetry frame after it
+    bench.clock.advance(3_600_000);
+    bench.idle(20_000);
+    assert_eq!(bench.log.sink().text().matches("sent telemetry\r\n").count(), sent + 1);
+}
+
+#[test]
+fn relays_carry_frames_and_acks_beyond_range() {
+    let mut bench = Bench::with_ack(true);
+    bench.beacon.set_ack(Some(AckConfig { window_ms: 3_000, ..AckConfig::default() }));
+    bench.beacon.set_mesh(true);
+    bench.relay = Some(RelaySide::default());
+    bench.feed(DGPS, 3);
+    bench.idle(3_000);
+
+    // every fix went through the relay and its ACK came back the same way
+    assert_eq!(bench.radio.sent.len(), 3);
+    // the receiver only waits for the radio now
+    assert_eq!(bench.rx.as_ref().unwrap().receiver.wake_at(), None);
+    assert_eq!(bench.log.sink().text().matches("LoRa delivery acknowledged\r\n").count(), 3);
+    let rx = bench.rx.as_mut().unwrap();
+    assert_eq!(rx.log.sink().text().lines().count(), 3);
+    assert_eq!(rx.radio.sent.len(), 3);
+    assert_eq!(rx.radio.sent[0][0], 0x60);
+
+    let relay = bench.relay.as_mut().unwrap();
+    assert_eq!(relay.radio.sent.len(), 6);
+    assert_eq!(relay.radio.sent[0][0], 0x61);
+    // the relay only checked the frames, it never decoded a position
+    assert_eq!(relay.node.relay().unwrap().stats(), RelayStats { heard: 6, forwarded: 6, duplicates: 0, rejected: 0 });
+}
+
+#[test]
+fn relayed_frames_go_out_on_the_base_channel_while_hopping() {
+    let mut relay = RelaySide::default();
+    relay.node.set_hopping(true);
+    let clock = MockClock::new(1_000);
+    // tuned elsewhere by the relay's own last frame
+    let base = RadioConfig::DEFAULT;
+    relay.radio.config = Some(base.with_channel(base.region(), base.channel() + 1).unwrap());
+
+    let mut frame = [0u8; 255];
+    frame[..ACK_LEN].copy_from_slice(&ack_frame(&[1, 2, 3, 4, 0, 0, 0, 7, 0, 0, 0, 9]));
+    let len = wrap_relay(&mut frame, ACK_LEN).unwrap();
+    relay.radio.incoming.push_back(frame[..len].to_vec());
+    for _ in 0..3 {
+        clock.advance(10);
+        relay.node.poll(&mut relay.radio, &mut relay.store, &mut relay.log, &clock);
+    }
+    assert_eq!(relay.radio.sent.len(), 1);
+    // on the base channel, which the duty cycle was charged for
+    assert_eq!(relay.radio.config.unwrap().frequency_hz(), base.frequency_hz());
+}
+
+#[test]
+fn lorawan_uplinks_reach_the_network_server() {
+    let mut bench = Bench::with_ack(false);
+    let activation = Activation::Otaa { keys: OTAA_KEYS, dev_nonce: 7 << 4 };
+    bench.beacon.set_lorawan(Some(Mac::new(LorawanConfig::default(), activation).unwrap()));
+    bench.beacon.set_lorawan_store(Some(0));
+    bench.lorawan = Some((MockNetworkServer::new(OTAA_KEYS), 0));
+    assert_eq!(bench.feed(DGPS, 0), None);
+    // the next boot starts after the DevNonce of the join request
+    assert_eq!(boot_count::current(&mut bench.store, 0), Ok(Some((7 << 4) + 1)));
+
+    let text = bench.log.sink().text();
+    assert_eq!(text.matches("sent LoRaWAN join request\r\n").count(), 1);
+    assert_eq!(text.matches("joined the LoRaWAN network\r\n").count(), 1);
+    assert_eq!(text.matches("LoRa delivery acknowledged\r\n").count(), 3);
+    assert_eq!(bench.radio.sent.len(), 4);
+    assert!(bench.radio.sent[1..].iter().all(|f| f[0] == CONFIRMED_UP));
+    assert_eq!(bench.beacon.lorawan().unwrap().session().unwrap().fcnt_up, 3);
+
+    // the application decodes the payloads like the receiver
+    let (server, _) = bench.lorawan.as_ref().unwrap();
+    assert_eq!(server.rejected, 0);
+    assert_eq!(server.uplinks.len(), 3);
+    let mut rx = RxSide::default();
+    rx.receiver.handle_packet(&server.uplinks[0], &mut rx.radio, &mut rx.log, &bench.clock);
+    assert_eq!(rx.log.sink().text(), "{\"beacon\":\"01020304\",\"lat\":-337520067,\"long\":-1183926183}\r\n");
+}
+
+#[test]
+fn abp_uplink_counters_rise_across_resets() {
+    let mut fcnts = Vec::new();
+    let mut store = MockStore::new(3 * 4096);
+    for _ in 0..2 {
+        let mut bench = Bench::new();
+        bench.store = store;
+        let fcnt_up = boot_count::current(&mut bench.store, 0).unwrap().unwrap_or(0);
+        let session = Session { fcnt_up, ..ABP_SESSION };
+        bench.beacon.set_lorawan(Some(Mac::new(LorawanConfig::default(), Activation::Abp(session)).unwrap()));
+        bench.beacon.set_lorawan_store(Some(0));
+        bench.feed(DGPS, 0);
+        assert!(!bench.radio.sent.is_empty());
+        fcnts.extend(bench.radio.sent.iter().map(|f| parse_data(f).unwrap().fcnt));
+        store = bench.store;
+    }
+    // each boot stored the uplink counter ahead before its first uplink
+    assert_eq!(boot_count::current(&mut store, 0), Ok(Some(2 * FCNT_RESERVE)));
+    assert!(fcnts.windows(2).all(|w| w[0] < w[1]), "{fcnts:?}");
+    assert_eq!(fcnts.last(), Some(&(FCNT_RESERVE as u16)));
+}
+
+#[test]
+fn scanning_receiver_serves_beacons_on_either_profile() {
+    // the 8-symbol preambles of the fast profiles are shorter than a scan round
+    let pairs = [Profile::STANDARD, Profile::LONG_RANGE].map(|p| RadioConfig::DEFAULT.with_profile(p));
+    for (i, config) in pairs.into_iter().enumerate() {
+        let mut bench = Bench::with_ack(true);
+        bench.radio.configure(&config).unwrap();
+        bench.beacon.set_radio_config(config);
+        bench.rx.as_mut().unwrap().receiver.set_scan(Scanner::new(&pairs));
+        bench.feed(DGPS, 3);
+        bench.idle(3_000);
+
+        // every fix was caught on the beacon's profile and acknowledged on it
+        assert_eq!(bench.log.sink().text().matches("LoRa delivery acknowledged\r\n").count(), 3, "{}", config);
+        let rx = bench.rx.as_mut().unwrap();
+        assert_eq!(rx.log.sink().text().lines().count(), 3);
+        // each position names the beacon it came from
+        assert!(rx.log.sink().text().lines().all(|l| l.starts_with("{\"beacon\":\"01020304\",\"lat\":")));
+        let stats: Vec<PairStats> = rx.receiver.scanner().unwrap().stats().map(|(_, s)| s).collect();
+        assert_eq!(stats[i], PairStats { preambles: 3, frames: 3 });
+        assert_eq!(stats[1 - i], PairStats::default());
+    }
+}
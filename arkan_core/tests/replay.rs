@@ -0,0 +1,132 @@
+//! Replays recorded NMEA captures of the NEO-6M, and of a multi-GNSS NEO-M8N,
+//! through the same line assembly and `gps_proccess` path the beacon runs,
+//! with USB output captured in memory.
+
+use arkan_core::encryption::KEY;
+use arkan_core::gps_proccess::{gps_proccess, NonceCounter};
+use arkan_core::log::{Level, Logger, Mode};
+use arkan_core::mock::MockLog;
+use arkan_core::nmea::LineAssembler;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+
+struct Replay {
+    packets: Vec<Vec<u8>>,
+    log: String,
+}
+
+fn replay(capture: &[u8]) -> Replay {
+    replay_in(capture, Mode::Debug)
+}
+
+fn replay_in(capture: &[u8], mode: Mode) -> Replay {
+    let mut assembler = LineAssembler::new();
+    let mut log = Logger::new(MockLog::default(), Level::Trace);
+    log.set_mode(mode);
+    let mut nonces = NonceCounter::new([0x01, 0x02, 0x03, 0x04], 0);
+    let mut lora_buf = [0u8; 255];
+    let mut packets = Vec::new();
+
+    for &b in capture {
+        if let Some(line) = assembler.push(b)
+            && let Some(len) = gps_proccess(line, &mut log, &mut nonces, 0, &mut lora_buf)
+        {
+            packets.push(lora_buf[..len].to_vec());
+        }
+    }
+    Replay { packets, log: log.sink().text() }
+}
+
+fn decrypt(packet: &[u8]) -> (i32, i32) {
+    assert_eq!(packet.len(), 21);
+    assert_eq!(packet[0], 0x10);
+    let (nonce, ct) = packet[1..].split_at(12);
+    let mut pt = [0u8; 8];
+    pt.copy_from_slice(ct);
+    ChaCha20::new_from_slices(KEY.secret_bytes(), nonce).unwrap().apply_keystream(&mut pt);
+    (
+        i32::from_le_bytes(pt[0..4].try_into().unwrap()),
+        i32::from_le_bytes(pt[4..8].try_into().unwrap()),
+    )
+}
+
+#[test]
+fn cold_start_produces_no_packets() {
+    let r = replay(include_bytes!("data/neo6m_cold_start.nmea"));
+    assert!(r.packets.is_empty());
+    assert_eq!(r.log.matches("GGA invalid\r\n").count(), 4);
+}
+
+#[test]
+fn no_fix_produces_no_packets() {
+    let r = replay(include_bytes!("data/neo6m_no_fix.nmea"));
+    assert!(r.packets.is_empty());
+    assert_eq!(r.log.matches("GGA invalid\r\n").count(), 4);
+    assert!(!r.log.contains("Satellites"));
+}
+
+#[test]
+fn fix_2d_produces_one_packet_per_gga() {
+    let r = replay(include_bytes!("data/neo6m_2d_fix.nmea"));
+    let coords: Vec<_> = r.packets.iter().map(|p| decrypt(p)).collect();
+    assert_eq!(coords, [
+        (504499450, 305207500),
+        (504499467, 305207517),
+        (504499483, 305207533),
+    ]);
+    assert_eq!(r.log.matches("Satellites: 03\r\n").count(), 3);
+    assert!(r.log.contains("RAW lat_e7=504499450, lon_e7=305207500\r\n"));
+}
+
+#[test]
+fn dgps_fix_applies_hemispheres() {
+    let r = replay(include_bytes!("data/neo6m_dgps.nmea"));
+    assert_eq!(r.packets.len(), 3);
+    for p in &r.packets {
+        assert_eq!(decrypt(p), (-337520067, -1183926183));
+    }
+    assert_eq!(r.log.matches("Satellites: 09\r\n").count(), 3);
+}
+
+#[test]
+fn multi_gnss_receivers_report_gga_from_the_gn_talker() {
+    let r = replay(include_bytes!("data/neo_m8n_gnss.nmea"));
+    let coords: Vec<_> = r.packets.iter().map(|p| decrypt(p)).collect();
+    assert_eq!(coords, [
+        (481173000, 115166667),
+        (481173017, 115166700),
+        (481173050, 115166717),
+    ]);
+    // the search before the first fix, but not the GSA, GSV or GLL sentences
+    assert_eq!(r.log.matches("GGA invalid\r\n").count(), 1);
+    assert_eq!(r.log.matches("Satellites: 11\r\n").count(), 3);
+}
+
+#[test]
+fn packets_use_fresh_nonces() {
+    let r = replay(include_bytes!("data/neo6m_dgps.nmea"));
+    let counters: Vec<_> = r.packets.iter().map(|p| &p[1..13]).collect();
+    assert_eq!(counters[0], [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(counters[2], [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 2]);
+    assert_eq!(r.log.matches("NONCE: ").count(), 3);
+}
+
+#[test]
+fn field_mode_hides_positions_and_packets() {
+    let r = replay_in(include_bytes!("data/neo6m_dgps.nmea"), Mode::Field);
+    assert_eq!(r.packets.len(), 3);
+    assert_eq!(r.log.matches("Satellites: 09\r\n").count(), 3);
+    assert!(!r.log.contains("RAW"));
+    assert!(!r.log.contains("NONCE"));
+    assert!(!r.log.contains("CIPHERTEXT"));
+}
+
+#[test]
+fn sentences_failing_their_checksum_are_dropped() {
+    // a bit error turning the second fix's latitude 5026.9968 into 5026.9969
+    let capture = String::from_utf8(include_bytes!("data/neo6m_2d_fix.nmea").to_vec()).unwrap();
+    let capture = capture.replacen("$GPGGA,120413.00,5026.9968", "$GPGGA,120413.00,5026.9969", 1);
+    let r = replay(capture.as_bytes());
+    let coords: Vec<_> = r.packets.iter().map(|p| decrypt(p)).collect();
+    assert_eq!(coords, [(504499450, 305207500), (504499483, 305207533)]);
+}
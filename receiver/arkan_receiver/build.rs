@@ -0,0 +1,6 @@
+fn main() {
+    // defmt needs its linker script when the defmt log backend is enabled
+    if std::env::var_os("CARGO_FEATURE_LOG_DEFMT").is_some() {
+        println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    }
+}
@@ -1,35 +1,93 @@
 #![no_std]
 #![no_main]
 
-use core::fmt::Write;
-use embedded_hal::serial::Read;
-use embedded_hal::blocking::delay::DelayMs;
+use core::cell::RefCell;
+
+use cortex_m::peripheral::NVIC;
 
-use embedded_hal::digital::v2::InputPin;
 use embedded_hal::digital::v2::OutputPin;
-use heapless::String;
+use arkan_core::airtime::Report;
+use arkan_core::console::Command;
+use arkan_core::log::{Level, Logger, Mode};
+use arkan_core::{debug, error, info, warn};
+use arkan_core::receiver::Receiver;
+use arkan_core::hal::Radio;
+use arkan_core::hopping::HopConfig;
+use arkan_core::lbt::{LbtConfig, ListenBeforeTalk};
+use arkan_core::radio_config::{Profile, RadioConfig};
+use arkan_core::radio_health::{Health, HealthConfig, Monitored};
+use arkan_core::relay::RelayConfig;
+use arkan_core::rp2040::{
+    check_in, pulse_reset, radio_down, report_reset, serve_usb, set_radio_down, show_radio, start_time_driver, supervise,
+    take_panic_record, take_reset_reason, SpiBus, CHECK_IN_MS, COMMANDS, RADIO_TASK, USB_IRQ,
+};
+use arkan_core::rx_queue::RxQueue;
+use arkan_core::scan::Scanner;
+use arkan_core::sx127x::Sx127x;
+use arkan_core::tdma::SlotPlan;
 
-use panic_halt as _;
-use rp_pico::entry;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Instant, Timer};
+#[cfg(feature = "log-defmt")]
+use defmt_rtt as _;
 use rp_pico::hal::fugit::HertzU32;
+use rp_pico::hal::gpio::{self, bank0, FunctionSioInput, FunctionSioOutput, FunctionSpi, Pin, PullDown, PullNone};
+use rp_pico::hal::pac::{interrupt, Interrupt};
 use rp_pico::hal::{
-    spi::Spi,
+    self as hal,
+    spi::{Enabled, Spi},
     clocks::{init_clocks_and_plls, Clock},
     pac,
-    uart::{DataBits, StopBits, UartConfig, UartPeripheral},
+    rosc::RingOscillator,
     watchdog::Watchdog,
     Sio
 };
-
-mod decryption;
-use decryption::{decrypt_packet, DecryptError};
+use static_cell::StaticCell;
 
 use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::device::UsbDevice;
 use usbd_serial::SerialPort;
 use usb_device::prelude::UsbVidPid;
 use usb_device::prelude::UsbDeviceBuilder;
-#[entry]
-fn main() -> ! {
+
+type UsbBus = hal::usb::UsbBus;
+
+type SpiPins = (
+    Pin<bank0::Gpio19, FunctionSpi, PullDown>,
+    Pin<bank0::Gpio16, FunctionSpi, PullDown>,
+    Pin<bank0::Gpio18, FunctionSpi, PullDown>,
+);
+type Nss = Pin<bank0::Gpio17, FunctionSioOutput, PullDown>;
+type Rst = Pin<bank0::Gpio20, FunctionSioOutput, PullDown>;
+type Chip = Sx127x<SpiBus<Spi<Enabled, pac::SPI0, SpiPins, 8>, Nss>>;
+type Lora = RxQueue<ListenBeforeTalk<Monitored<Chip, hal::Timer>, hal::Timer>>;
+type Led = Pin<bank0::Gpio25, FunctionSioOutput, PullDown>;
+type Dio0 = Pin<bank0::Gpio21, FunctionSioInput, PullNone>;
+
+// The radio and its DIO0 pin, shared with the DIO0 interrupt handler. Whoever
+// uses the radio takes it out, so critical sections only cover handing it over.
+static LORA: cortex_m::interrupt::Mutex<RefCell<Option<Lora>>> = cortex_m::interrupt::Mutex::new(RefCell::new(None));
+static DIO0: cortex_m::interrupt::Mutex<RefCell<Option<Dio0>>> = cortex_m::interrupt::Mutex::new(RefCell::new(None));
+// Raised by the DIO0 interrupt handler for the radio task.
+static DIO0_IRQ: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Log output is buffered in RAM and streamed to USB (`log-usb`) or kept
+// until `log dump` (`log-ram`); with `log-defmt` it goes out over RTT.
+#[cfg(not(feature = "log-defmt"))]
+type LogBackend = arkan_core::rp2040::Signalled<arkan_core::log::RingLog<2048>>;
+#[cfg(feature = "log-defmt")]
+type LogBackend = arkan_core::log::DefmtSink;
+type SharedLog = arkan_core::rp2040::SharedLog<LogBackend>;
+
+static LOG: StaticCell<SharedLog> = StaticCell::new();
+static USB_BUS: StaticCell<UsbBusAllocator<UsbBus>> = StaticCell::new();
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
     let mut pac = pac::Peripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let clocks = init_clocks_and_plls(
@@ -43,11 +101,12 @@ fn main() -> ! {
     )
     .ok()
     .unwrap();
-    let mut timer = rp_pico::hal::timer::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    let reset_reason = take_reset_reason(&mut watchdog);
+    let panic_record = take_panic_record();
+    let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    start_time_driver();
 
     let sio = Sio::new(pac.SIO);
-    let core = pac::CorePeripherals::take().unwrap();
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
     let pins = rp_pico::Pins::new(
         pac.IO_BANK0,
         pac.PADS_BANK0,
@@ -55,22 +114,35 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    let usb_bus = UsbBusAllocator::new(rp_pico::hal::usb::UsbBus::new(
+    let usb_bus = USB_BUS.init(UsbBusAllocator::new(UsbBus::new(
         pac.USBCTRL_REGS,
         pac.USBCTRL_DPRAM,
         clocks.usb_clock,
         true,
         &mut pac.RESETS,
-    ));
-
-    let mut serial = SerialPort::new(&usb_bus);
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+    )));
+    let serial = SerialPort::new(usb_bus);
+    let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
         .device_class(2)
         .build();
-    let spi_sck = pins.gpio18.into_function::<rp_pico::hal::gpio::FunctionSpi>();
-    let spi_mosi = pins.gpio19.into_function::<rp_pico::hal::gpio::FunctionSpi>();
-    let spi_miso = pins.gpio16.into_function::<rp_pico::hal::gpio::FunctionSpi>();
+    let mut log = Logger::new(LogBackend::default(), Level::Debug);
+    if cfg!(feature = "debug-log") {
+        log.set_mode(Mode::Debug);
+    }
+    report_reset(reset_reason, &mut log);
+    if let Some(record) = &panic_record {
+        error!(log, "panic", "{}", record);
+    }
+    let log = LOG.init(Mutex::new(RefCell::new(log)));
+    spawner.must_spawn(watchdog_task(watchdog));
+    // enumerates while the radio is set up
+    spawner.must_spawn(usb_task(usb_dev, serial, log));
+
+    let spi_sck = pins.gpio18.into_function::<FunctionSpi>();
+    let spi_mosi = pins.gpio19.into_function::<FunctionSpi>();
+    let spi_miso = pins.gpio16.into_function::<FunctionSpi>();
     let dio0 = pins.gpio21.into_floating_input();
+    dio0.set_interrupt_enabled(gpio::Interrupt::EdgeHigh, true);
     let spi= Spi::new(
         pac.SPI0,
         (
@@ -88,70 +160,226 @@ fn main() -> ! {
     let mut nss = pins.gpio17.into_push_pull_output();
     nss.set_high().unwrap();
     let mut rst = pins.gpio20.into_push_pull_output();
-    rst.set_low().unwrap();
-    timer.delay_ms(20);
-    rst.set_high().unwrap();
-    timer.delay_ms(50);
-    let mut lora = sx127x_lora::LoRa::new(
-        spi0,
-        nss,
-        rst,
-        433,
-        delay
-    ).expect("Could not connect to LoRa");
-    let _ = lora.set_mode(sx127x_lora::RadioMode::Sleep).unwrap();
-    timer.delay_ms(10);
-    let _ = lora.set_tx_power(17, 1);
-    let _ = lora.set_crc(true);
-    let _ = lora.set_preamble_length(12);
-    let _ = lora.set_ocp(120).unwrap();
-    let _ = lora.set_coding_rate_4(8).unwrap();
-    let _ = lora.set_spreading_factor(9);
-    for _ in 0..100 {
-        usb_dev.poll(&mut [&mut serial]);
-        timer.delay_ms(10);
+    let rosc = RingOscillator::new(pac.ROSC).initialize();
+    // seeds the random backoff of listen-before-talk
+    let seed = (0..32).fold(0u32, |s, _| s << 1 | rosc.get_random_bit() as u32);
+    let radio = RxQueue::new(ListenBeforeTalk::new(
+        Monitored::new(Sx127x::unchecked(SpiBus::new(spi0, nss)), timer, HealthConfig::default()),
+        timer,
+        LbtConfig::default(),
+        seed,
+    ));
+    let radio_config = RadioConfig::DEFAULT;
+    cortex_m::interrupt::free(|cs| {
+        LORA.borrow(cs).replace(Some(radio));
+        DIO0.borrow(cs).replace(Some(dio0));
+    });
+    // SAFETY: the handler only touches the statics above, inside critical sections
+    unsafe {
+        NVIC::unmask(Interrupt::IO_IRQ_BANK0);
     }
 
-    let mut led_pin = pins.led.into_push_pull_output();
-    let mut buf = [0u8; 128];
-    let mut i = 0;
-    let mut lora_buf = [0u8; 255];
-    let mut last_success_time: u64 = timer.get_counter().ticks();
-    let _ = lora.set_mode(sx127x_lora::RadioMode::RxContinuous).unwrap();
+    let led = pins.led.into_push_pull_output();
+    let mut receiver = Receiver::new();
+    if cfg!(feature = "tdma") {
+        receiver.set_tdma(Some(SlotPlan::default()));
+    }
+    if cfg!(feature = "hopping") {
+        receiver.set_hopping(Some(HopConfig::default()));
+    }
+    if cfg!(feature = "relay") {
+        receiver.set_relay(Some(RelayConfig::default()));
+    }
+    if cfg!(feature = "scan") {
+        receiver.set_scan(Scanner::new(&Profile::ALL.map(|p| radio_config.with_profile(p))));
+    }
+    if !reset_radio(&mut rst, &mut receiver, &radio_config).await {
+        log.lock(|log| error!(log.borrow_mut(), "radio", "LoRa module not found"));
+        set_radio_down(true);
+    }
+    spawner.must_spawn(led_task(led));
+    spawner.must_spawn(radio_task(receiver, rst, radio_config, timer, log));
+}
 
+/// Runs the receiver logic on the queued packets and carries out the console
+/// commands that concern the radio. Resets the radio when it fails.
+#[embassy_executor::task]
+async fn radio_task(
+    mut receiver: Receiver,
+    mut rst: Rst,
+    mut radio_config: RadioConfig,
+    timer: hal::Timer,
+    log: &'static SharedLog,
+) {
     loop {
-        if usb_dev.poll(&mut [&mut serial]) {
-            // todo
-        }
-        if let Ok(size) = lora.poll_irq(None) {
-            if let Ok(r_buf) = lora.read_packet() {
-                let packet = &r_buf[..size];
-
-                let _ = serial.write(b"RX RAW: ");
-                for b in packet {
-                    let mut hex = heapless::String::<4>::new();
-                    let _ = write!(hex, "{:02X} ", b);
-                    let _ = serial.write(hex.as_bytes());
+        check_in(RADIO_TASK, CHECK_IN_MS);
+        if with_lora(|radio| radio.radio().radio().needs_reset()) {
+            let failing = with_lora(|radio| radio.radio().radio().health() == Health::Failing);
+            if failing {
+                log.lock(|log| warn!(log.borrow_mut(), "radio", "LoRa module not responding, resetting it"));
+            }
+            let up = reset_radio(&mut rst, &mut receiver, &radio_config).await;
+            log.lock(|log| {
+                let log = &mut *log.borrow_mut();
+                match (up, failing) {
+                    (true, _) => info!(log, "radio", "LoRa module back"),
+                    (false, true) => error!(log, "radio", "LoRa module down"),
+                    (false, false) => debug!(log, "radio", "LoRa module still down"),
                 }
-                let _ = serial.write(b"\r\n");
-
-                match decrypt_packet(packet) {
-                    Ok(coord) => {
-                        let mut msg = heapless::String::<64>::new();
-                        let _ = write!(msg, "{{\"lat\":{},\"long\":{}}}\r\n", coord.lat_deg_e7, coord.lon_deg_e7);
-                        let _ = serial.write(msg.as_bytes());
+            });
+            set_radio_down(!up);
+        }
+        let command = if with_lora(|radio| radio.is_empty()) {
+            let wake = with_lora(|radio| wake_at(&receiver, radio, Instant::now().as_millis()));
+            match select3(DIO0_IRQ.wait(), COMMANDS.receive(), Timer::at(Instant::from_millis(wake))).await {
+                Either3::Second(command) => Some(command),
+                Either3::First(()) | Either3::Third(()) => None,
+            }
+        } else {
+            None
+        };
+        log.lock(|log| {
+            let log = &mut *log.borrow_mut();
+            let mut wanted = None;
+            match command {
+                Some(Command::Airtime) => log.print(format_args!("{}", Report(radio_config.params()))),
+                Some(Command::TrackDump) => warn!(log, "console", "the receiver keeps no track log"),
+                Some(Command::Gps) => warn!(log, "console", "the receiver has no GPS"),
+                Some(Command::Battery) => warn!(log, "console", "the receiver runs on USB power"),
+                Some(Command::Radio) => {
+                    with_lora(|radio| {
+                        let monitored = radio.radio().radio();
+                        log.print(format_args!(
+                            "{}\r\n{}, {} CRC errors\r\n{}\r\nLoRa module {}, {}\r\n",
+                            radio_config,
+                            radio.radio().stats(),
+                            monitored.radio().crc_errors(),
+                            radio.stats(),
+                            monitored.health(),
+                            monitored.stats()
+                        ))
+                    });
+                    if let Some(relay) = receiver.relay() {
+                        log.print(format_args!("{}\r\n", relay.stats()));
                     }
-                    Err(err) => {
-                        let mut msg = heapless::String::<64>::new();
-                        let _ = match err {
-                            DecryptError::PacketTooShort => write!(msg, "Decrypt error: packet too short\r\n"),
-                            DecryptError::CipherError => write!(msg, "Decrypt error: cipher init failed\r\n"),
-                            DecryptError::MalformedPlaintext => write!(msg, "Decrypt error: invalid plaintext\r\n"),
-                        };
-                        let _ = serial.write(msg.as_bytes());
+                    for (config, stats) in receiver.scanner().into_iter().flat_map(|s| s.stats()) {
+                        let (name, sf) = (config.profile().name, config.params().spreading_factor);
+                        log.print(format_args!("{} SF{}: {}\r\n", name, sf, stats));
                     }
                 }
+                Some(Command::Slots) => match receiver.slots() {
+                    Some(slots) => {
+                        log.print(format_args!("{}\r\n", slots.plan()));
+                        for stats in slots.stats() {
+                            log.print(format_args!("{}\r\n", stats));
+                        }
+                    }
+                    None => warn!(log, "console", "time slots need a tdma build"),
+                },
+                Some(Command::Profile(profile)) => wanted = Some(Ok(radio_config.with_profile(profile))),
+                Some(Command::Region(region, channel)) => wanted = Some(radio_config.with_channel(region, channel)),
+                // the USB task handles the log commands
+                Some(_) | None => {}
+            }
+            match wanted {
+                Some(Ok(config)) => match with_lora(|radio| receiver.configure(radio, &config)) {
+                    Ok(()) => {
+                        radio_config = config;
+                        log.print(format_args!("{}\r\n", radio_config));
+                    }
+                    Err(_) => warn!(log, "radio", "configuration failed"),
+                },
+                Some(Err(e)) => warn!(log, "console", "{}", e),
+                None => {}
             }
+            // nothing to receive while the radio is down
+            if !radio_down() {
+                with_lora(|radio| {
+                    if radio.radio_mut().poll().is_err() {
+                        error!(log, "radio", "LoRa channel check failed");
+                    }
+                    receiver.poll(radio, log, &timer);
+                });
+            }
+        });
+    }
+}
+
+/// Serves the USB serial port: reads console commands and sends the log.
+#[embassy_executor::task]
+async fn usb_task(usb_dev: UsbDevice<'static, UsbBus>, serial: SerialPort<'static, UsbBus>, log: &'static SharedLog) {
+    serve_usb(usb_dev, serial, log, cfg!(feature = "log-usb"), cfg!(feature = "debug-log")).await
+}
+
+/// Feeds the watchdog while the radio and USB tasks check in on time.
+#[embassy_executor::task]
+async fn watchdog_task(watchdog: Watchdog) {
+    supervise(watchdog).await
+}
+
+/// Lights the LED while the radio works and blinks it while the radio is down.
+#[embassy_executor::task]
+async fn led_task(mut led: Led) {
+    loop {
+        show_radio(&mut led, true).await;
+    }
+}
+
+/// Resets the radio, then initialises it and has `receiver` configure it;
+/// returns whether it came up.
+async fn reset_radio(rst: &mut Rst, receiver: &mut Receiver, config: &RadioConfig) -> bool {
+    let up = pulse_reset(rst, || {
+        with_lora(|radio| {
+            radio.radio_mut().radio_mut().radio_mut().init().is_ok() && receiver.configure(radio, config).is_ok()
+        })
+    })
+    .await;
+    with_lora(|radio| radio.radio_mut().radio_mut().reset_done(up));
+    up
+}
+
+/// When the radio task has to poll again without a DIO0 interrupt or a
+/// command: at the next timeout of listen-before-talk or of `receiver`, and
+/// in time to check in with the supervisor.
+fn wake_at(receiver: &Receiver, radio: &mut Lora, now: u64) -> u64 {
+    let check_in = now + CHECK_IN_MS / 2;
+    if radio_down() {
+        return check_in;
+    }
+    // the end of a transmission raises DIO0
+    let receiver = if radio.is_transmitting().unwrap_or(false) { None } else { receiver.wake_at() };
+    [radio.radio().wake_at(), receiver].into_iter().flatten().fold(check_in, u64::min)
+}
+
+/// Runs `f` on the radio with interrupts enabled. Meanwhile the DIO0
+/// interrupt leaves a received packet in the FIFO, and the radio task picks
+/// it up on the next `poll`.
+fn with_lora<T>(f: impl FnOnce(&mut Lora) -> T) -> T {
+    let mut radio = cortex_m::interrupt::free(|cs| LORA.borrow(cs).take()).unwrap();
+    let result = f(&mut radio);
+    cortex_m::interrupt::free(|cs| LORA.borrow(cs).replace(Some(radio)));
+    result
+}
+
+/// Copies a received packet out of the radio into the queue and wakes the
+/// radio task.
+#[interrupt]
+fn IO_IRQ_BANK0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(dio0) = DIO0.borrow(cs).borrow_mut().as_mut() {
+            dio0.clear_interrupt(gpio::Interrupt::EdgeHigh);
         }
+    });
+    // the radio task cannot run before the handler returns, so the radio is
+    // either here or with the radio task, which will find the packet itself
+    if let Some(mut radio) = cortex_m::interrupt::free(|cs| LORA.borrow(cs).take()) {
+        radio.on_dio0();
+        cortex_m::interrupt::free(|cs| LORA.borrow(cs).replace(Some(radio)));
     }
+    DIO0_IRQ.signal(());
+}
+
+#[interrupt]
+fn USBCTRL_IRQ() {
+    USB_IRQ.on_interrupt();
 }
@@ -1,35 +1,118 @@
 #![no_std]
 #![no_main]
 
-use embedded_hal::serial::Read;
-use embedded_hal::blocking::delay::DelayMs;
-mod encryption;
-// use crate::encryption::{CoordinateEncryptor, EncryptConfig, GpsCoord, MyCipher};
+use core::cell::RefCell;
+use core::sync::atomic::Ordering;
 
+use arkan_core::beacon::{AckConfig, Beacon, TELEMETRY_MS};
+use arkan_core::boot_count;
+use arkan_core::breadcrumb::BatchConfig;
+use arkan_core::compact::{Encoding, Precision};
+use arkan_core::airtime::Report;
+use arkan_core::console::Command;
+use arkan_core::log::{Level, Logger, Mode};
+use arkan_core::{debug, error, info, warn};
+use arkan_core::gps_proccess::NonceCounter;
+use arkan_core::hal::Clock as _;
+use arkan_core::hal::Radio as _;
+use arkan_core::lbt::{LbtConfig, ListenBeforeTalk};
+use arkan_core::lorawan::{Activation, LorawanConfig, Mac, Session, ABP_SESSION, OTAA_KEYS};
+use arkan_core::radio_config::RadioConfig;
+use arkan_core::radio_health::{Health, HealthConfig, Monitored};
+use arkan_core::relay::RelayConfig;
+use arkan_core::rp2040::{
+    check_in, drain_uart, pulse_reset, report_reset, serve_usb, set_radio_down, start_time_driver, supervise,
+    take_panic_record, take_reset_reason, FlashStore, SpiBus, CHECK_IN_MS, COMMANDS, DUMPING, RADIO_TASK, USB_IRQ,
+};
+use arkan_core::sx127x::Sx127x;
+use arkan_core::tdma::SlotPlan;
+use arkan_core::telemetry::Version;
+use arkan_core::uart_rx::RxRing;
+use arkan_core::track_log::TrackLog;
+
+use cortex_m::peripheral::NVIC;
+use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
 use embedded_hal::digital::v2::OutputPin;
-use panic_halt as _;
-use sx127x_lora::RadioMode;
-mod gps_proccess;
-use rp_pico::entry;
+#[cfg(feature = "log-defmt")]
+use defmt_rtt as _;
 use rp_pico::hal::fugit::HertzU32;
+use rp_pico::hal::gpio::{bank0, FunctionSioOutput, FunctionSpi, FunctionUart, Pin, PullDown};
+use rp_pico::hal::pac::{self, interrupt, Interrupt};
 use rp_pico::hal::{
-    spi::Spi,
+    self as hal,
+    adc::{Adc, AdcPin},
+    spi::{self, Spi},
     clocks::{init_clocks_and_plls, Clock},
-    pac,
-    uart::{DataBits, StopBits, UartConfig, UartPeripheral},
+    rosc::RingOscillator,
+    uart::{self, DataBits, StopBits, UartConfig, UartPeripheral},
     watchdog::Watchdog,
     Sio
 };
+use static_cell::StaticCell;
 
-mod sleep;
-use sleep::{disable_uart1, enable_uart1, sleep_ms};
+mod power;
+use power::{power_task, sensor_task, AWAKE, BATTERY, SLEEP, TEMPERATURE};
 
 use usb_device::class_prelude::UsbBusAllocator;
+use usb_device::device::UsbDevice;
 use usbd_serial::SerialPort;
 use usb_device::prelude::UsbVidPid;
 use usb_device::prelude::UsbDeviceBuilder;
-#[entry]
-fn main() -> ! {
+
+type UsbBus = hal::usb::UsbBus;
+
+// Log output is buffered in RAM and streamed to USB (`log-usb`) or kept
+// until `log dump` (`log-ram`); with `log-defmt` it goes out over RTT.
+#[cfg(not(feature = "log-defmt"))]
+type LogBackend = arkan_core::rp2040::Signalled<arkan_core::log::RingLog<2048>>;
+#[cfg(feature = "log-defmt")]
+type LogBackend = arkan_core::log::DefmtSink;
+type SharedLog = arkan_core::rp2040::SharedLog<LogBackend>;
+
+type SpiPins = (
+    Pin<bank0::Gpio19, FunctionSpi, PullDown>,
+    Pin<bank0::Gpio16, FunctionSpi, PullDown>,
+    Pin<bank0::Gpio18, FunctionSpi, PullDown>,
+);
+type Nss = Pin<bank0::Gpio17, FunctionSioOutput, PullDown>;
+type Rst = Pin<bank0::Gpio20, FunctionSioOutput, PullDown>;
+type Chip = Sx127x<SpiBus<Spi<spi::Enabled, pac::SPI0, SpiPins, 8>, Nss>>;
+type Lora = ListenBeforeTalk<Monitored<Chip, hal::Timer>, hal::Timer>;
+type UartPins = (Pin<bank0::Gpio8, FunctionUart, PullDown>, Pin<bank0::Gpio9, FunctionUart, PullDown>);
+type Gps = UartPeripheral<uart::Enabled, pac::UART1, UartPins>;
+
+// Persistent store at the end of the 2 MiB flash, excluded from FLASH in memory.x.
+const STORE_OFFSET: u32 = 2048 * 1024 - STORE_LEN;
+const STORE_LEN: u32 = 128 * 1024;
+// Compact frames at about 1 m resolution; deltas once fixes are acknowledged (`ack`).
+const ENCODING: Encoding = Encoding::Delta(Precision::E5);
+// First erase blocks of the store hold the boot counter and the LoRaWAN
+// counter, the rest the track log.
+const BOOT_COUNT_OFFSET: u32 = 0;
+const LORAWAN_COUNT_OFFSET: u32 = boot_count::BLOCKS * 4096;
+const TRACK_LOG_OFFSET: u32 = 2 * boot_count::BLOCKS * 4096;
+/// The radio task checks the beacon's timeouts at least this often.
+const TICK_MS: u64 = 10;
+/// GPS bytes the UART interrupt keeps for the radio task, about 0.5 s at 9600 baud.
+const GPS_RX_LEN: usize = 512;
+
+// The GPS UART and the bytes its interrupt handler took from it.
+static GPS: cortex_m::interrupt::Mutex<RefCell<Option<Gps>>> = cortex_m::interrupt::Mutex::new(RefCell::new(None));
+static GPS_RX: cortex_m::interrupt::Mutex<RefCell<RxRing<GPS_RX_LEN>>> =
+    cortex_m::interrupt::Mutex::new(RefCell::new(RxRing::new()));
+// Raised by the UART interrupt handler for the radio task.
+static GPS_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static LOG: StaticCell<SharedLog> = StaticCell::new();
+static USB_BUS: StaticCell<UsbBusAllocator<UsbBus>> = StaticCell::new();
+static BEACON: StaticCell<Beacon> = StaticCell::new();
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
     let mut pac = pac::Peripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let clocks = init_clocks_and_plls(
@@ -43,11 +126,12 @@ fn main() -> ! {
     )
     .ok()
     .unwrap();
-    let mut timer = rp_pico::hal::timer::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    let reset_reason = take_reset_reason(&mut watchdog);
+    let panic_record = take_panic_record();
+    let timer = hal::Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
+    start_time_driver();
 
     let sio = Sio::new(pac.SIO);
-    let core = pac::CorePeripherals::take().unwrap();
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
     let pins = rp_pico::Pins::new(
         pac.IO_BANK0,
         pac.PADS_BANK0,
@@ -55,20 +139,33 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    let usb_bus = UsbBusAllocator::new(rp_pico::hal::usb::UsbBus::new(
+    let usb_bus = USB_BUS.init(UsbBusAllocator::new(UsbBus::new(
         pac.USBCTRL_REGS,
         pac.USBCTRL_DPRAM,
         clocks.usb_clock,
         true,
         &mut pac.RESETS,
-    ));
-    let mut serial = SerialPort::new(&usb_bus);
-    let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16c0, 0x27dd))
+    )));
+    let serial = SerialPort::new(usb_bus);
+    let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
         .device_class(2)
         .build();
-    let spi_sck = pins.gpio18.into_function::<rp_pico::hal::gpio::FunctionSpi>();
-    let spi_mosi = pins.gpio19.into_function::<rp_pico::hal::gpio::FunctionSpi>();
-    let spi_miso = pins.gpio16.into_function::<rp_pico::hal::gpio::FunctionSpi>();
+    let mut log = Logger::new(LogBackend::default(), Level::Debug);
+    if cfg!(feature = "debug-log") {
+        log.set_mode(Mode::Debug);
+    }
+    report_reset(reset_reason, &mut log);
+    if let Some(record) = &panic_record {
+        error!(log, "panic", "{}", record);
+    }
+    let log = LOG.init(Mutex::new(RefCell::new(log)));
+    spawner.must_spawn(watchdog_task(watchdog));
+    // enumerates while the radio is set up
+    spawner.must_spawn(usb_task(usb_dev, serial, log));
+
+    let spi_sck = pins.gpio18.into_function::<FunctionSpi>();
+    let spi_mosi = pins.gpio19.into_function::<FunctionSpi>();
+    let spi_miso = pins.gpio16.into_function::<FunctionSpi>();
     let spi= Spi::new(
         pac.SPI0,
         (
@@ -83,145 +180,266 @@ fn main() -> ! {
         HertzU32::Hz(2_000_000),
         embedded_hal::spi::MODE_0,
     );
-    
 
     let mut nss = pins.gpio17.into_push_pull_output();
     nss.set_high().unwrap();
     let mut rst = pins.gpio20.into_push_pull_output();
-    rst.set_low().unwrap();
-    timer.delay_ms(20);
-    rst.set_high().unwrap();
-    timer.delay_ms(50);
-    let mut lora = sx127x_lora::LoRa::new(
-        spi0,
-        nss,
-        rst,
-        433,
-        delay
-    ).expect("Could not connect to LoRa");
-    let _ = lora.set_mode(sx127x_lora::RadioMode::Sleep).unwrap();
-    timer.delay_ms(10);
-    let _ = lora.set_tx_power(17, 1);
-    let _ = lora.set_crc(true);
-    let _ = lora.set_preamble_length(12);
-    let _ = lora.set_ocp(120).unwrap();
-    let _ = lora.set_coding_rate_4(8).unwrap();
-    let _ = lora.set_spreading_factor(9);
-    for _ in 0..100 {
-        usb_dev.poll(&mut [&mut serial]);
-        timer.delay_ms(10);
+    let rosc = RingOscillator::new(pac.ROSC).initialize();
+    // seeds the random backoff of listen-before-talk
+    let seed = (0..32).fold(0u32, |s, _| s << 1 | rosc.get_random_bit() as u32);
+    let mut radio = ListenBeforeTalk::new(
+        Monitored::new(Sx127x::unchecked(SpiBus::new(spi0, nss)), timer, HealthConfig::default()),
+        timer,
+        LbtConfig::default(),
+        seed,
+    );
+    let radio_config = RadioConfig::DEFAULT;
+    let radio_up = reset_radio(&mut radio, &mut rst, &radio_config).await;
+    if !radio_up {
+        log.lock(|log| error!(log.borrow_mut(), "radio", "LoRa module not found, logging fixes only"));
+        set_radio_down(true);
     }
 
-
-    let mut led_pin = pins.led.into_push_pull_output();
+    let led_pin = pins.led.into_push_pull_output();
+    let mut adc = Adc::new(pac.ADC, &mut pac.RESETS);
+    let temp = adc.take_temp_sensor().unwrap();
+    let vsys = AdcPin::new(pins.voltage_monitor.into_floating_input()).unwrap();
+    let vbus = pins.vbus_detect.into_floating_input();
     let uart_pins = (
-        pins.gpio8.into_function::<rp_pico::hal::gpio::FunctionUart>(),//tx
-        pins.gpio9.into_function::<rp_pico::hal::gpio::FunctionUart>()//rx
+        pins.gpio8.into_function::<FunctionUart>(),//tx
+        pins.gpio9.into_function::<FunctionUart>()//rx
     );
     let mut uart = UartPeripheral::new(
-        pac.UART1, 
-        uart_pins, 
+        pac.UART1,
+        uart_pins,
         &mut pac.RESETS)
         .enable(
             UartConfig::new(HertzU32::Hz(9600), DataBits::Eight, None, StopBits::One),
             clocks.peripheral_clock.freq()
         )
         .unwrap();
-    let mut buf = [0u8; 128];
-    let mut i = 0;
-    let mut lora_buf = [0u8; 255];
-    let mut last_lora_success: u64 = timer.get_counter().ticks();
-    let mut first_lora_success: u64 = 0;
-    let mut last_gps_success: u64 = 0;
-    let mut last_lora_packet_len: usize = 0;
+    // SAFETY: the store region is cut out of FLASH in memory.x and nothing else uses it.
+    let mut store = unsafe { FlashStore::new(STORE_OFFSET, STORE_LEN) };
+    let boot_count = boot_count::increment(&mut store, BOOT_COUNT_OFFSET).unwrap_or(0);
+    let nonces = NonceCounter::new([0x01, 0x02, 0x03, 0x04], boot_count); // device id, for example
+    let beacon = BEACON.init(Beacon::new(nonces, timer.now_ms()));
+    beacon.set_encoding(ENCODING);
+    beacon.set_radio_config(radio_config);
+    beacon.set_track_log(TrackLog::open(&mut store, TRACK_LOG_OFFSET, STORE_LEN - TRACK_LOG_OFFSET).ok());
+    if cfg!(feature = "breadcrumb") {
+        beacon.set_batch(Some(BatchConfig::default()));
+    }
+    if cfg!(feature = "ack") {
+        let ack = AckConfig::default();
+        // relayed ACKs take two more frames to come back
+        let window_ms = if cfg!(feature = "mesh") { 3 * ack.window_ms } else { ack.window_ms };
+        beacon.set_ack(Some(AckConfig { window_ms, ..ack }));
+    }
+    beacon.set_mesh(cfg!(feature = "mesh"));
+    if cfg!(feature = "relay") {
+        beacon.set_relay(Some(RelayConfig::default()));
+    }
+    if cfg!(feature = "tdma") {
+        beacon.set_tdma(Some(SlotPlan::default()));
+    }
+    beacon.set_hopping(cfg!(feature = "hopping"));
+    beacon.set_panic_report(panic_record);
+    beacon.set_log_only(!radio_up);
+    beacon.set_telemetry(Some(TELEMETRY_MS));
+    beacon.set_firmware(Version::parse(env!("CARGO_PKG_VERSION")), reset_reason);
+    if cfg!(feature = "lorawan") {
+        // the store keeps the count above every DevNonce or uplink counter sent
+        // so far, so network servers never see one again after a reset
+        let count = boot_count::current(&mut store, LORAWAN_COUNT_OFFSET).ok().flatten().unwrap_or(0);
+        let activation = if cfg!(feature = "lorawan-abp") {
+            Activation::Abp(Session { fcnt_up: count, ..ABP_SESSION })
+        } else {
+            Activation::Otaa { keys: OTAA_KEYS, dev_nonce: count as u16 }
+        };
+        match Mac::new(LorawanConfig::default(), activation) {
+            Ok(mac) => {
+                beacon.set_lorawan(Some(mac));
+                beacon.set_lorawan_store(Some(LORAWAN_COUNT_OFFSET));
+            }
+            Err(e) => log.lock(|log| error!(log.borrow_mut(), "lorawan", "{}", e)),
+        }
+    }
+
+    uart.enable_rx_interrupt();
+    cortex_m::interrupt::free(|cs| GPS.borrow(cs).replace(Some(uart)));
+    // SAFETY: the handler only touches the GPS statics, inside a critical section
+    unsafe {
+        NVIC::unmask(Interrupt::UART1_IRQ);
+    }
+
+    spawner.must_spawn(power_task(led_pin));
+    spawner.must_spawn(sensor_task(adc, vsys, vbus, temp));
+    spawner.must_spawn(radio_task(radio, rst, radio_config, beacon, store, timer, log));
+}
+
+/// Runs the beacon: hands it the GPS bytes, moves its frames on and carries
+/// out the console commands that concern the radio, the beacon or the store.
+/// Resets the radio when it fails, and keeps the beacon to the track log
+/// while it is down.
+#[embassy_executor::task]
+async fn radio_task(
+    mut radio: Lora,
+    mut rst: Rst,
+    mut radio_config: RadioConfig,
+    beacon: &'static mut Beacon,
+    mut store: FlashStore,
+    timer: hal::Timer,
+    log: &'static SharedLog,
+) {
+    // next record `track dump` prints
+    let mut track_dump = None;
     loop {
-        if usb_dev.poll(&mut [&mut serial]) {
-            // todo
+        let event = select3(GPS_READY.wait(), COMMANDS.receive(), Timer::after_millis(TICK_MS)).await;
+        check_in(RADIO_TASK, CHECK_IN_MS);
+        if radio.radio().needs_reset() {
+            let failing = radio.radio().health() == Health::Failing;
+            if failing {
+                log.lock(|log| warn!(log.borrow_mut(), "radio", "LoRa module not responding, resetting it"));
+            }
+            let up = reset_radio(&mut radio, &mut rst, &radio_config).await;
+            log.lock(|log| {
+                let log = &mut *log.borrow_mut();
+                match (up, failing) {
+                    (true, _) => info!(log, "radio", "LoRa module back"),
+                    (false, true) => error!(log, "radio", "LoRa module down, logging fixes only"),
+                    (false, false) => debug!(log, "radio", "LoRa module still down"),
+                }
+            });
+            beacon.set_log_only(!up);
+            set_radio_down(!up);
         }
-        
-        if let Ok(b) = uart.read() {
-            if b == b'\n' {
-                let line = &buf[..i];
-
-                // if we found GPS signals, process and send to LoRa
-                if let Some(len) = gps_proccess::gps_proccess(line, &mut serial, &mut lora_buf) {
-                    last_gps_success = timer.get_counter().ticks();
-                    last_lora_packet_len = len;
-
-                    match lora.transmit_payload(lora_buf, last_lora_packet_len) {
-                        Ok(_) => {
-                            let _ = serial.write(b"sent data to LoRa\r\n");
-                            last_lora_success = timer.get_counter().ticks();
-                            if first_lora_success == 0 {
-                                first_lora_success = last_lora_success;
-                            }
-                        },
-                        Err(_) => { 
-                            let _ = serial.write(b"ERR\r\n");
-                        }
+        let sleep = log.lock(|log| {
+            let log = &mut *log.borrow_mut();
+            let mut wanted = None;
+            let mut sleep = None;
+            if let Some(status) = BATTERY.try_take() {
+                beacon.update_battery(status, log);
+            }
+            if let Some(temp_c) = TEMPERATURE.try_take() {
+                beacon.update_temperature(temp_c);
+            }
+            // bytes after a sleep request are discarded on wake-up
+            while sleep.is_none()
+                && let Some(b) = cortex_m::interrupt::free(|cs| GPS_RX.borrow(cs).borrow_mut().pop())
+            {
+                sleep = beacon.handle_byte(b, &mut radio, &mut store, log, &timer);
+            }
+            match event {
+                Either3::Second(Command::Airtime) => log.print(format_args!("{}", Report(radio_config.params()))),
+                Either3::Second(Command::TrackDump) if beacon.track_log().is_none() => {
+                    warn!(log, "console", "track log unavailable")
+                }
+                Either3::Second(Command::TrackDump) => track_dump = Some(0),
+                Either3::Second(Command::Radio) => {
+                    log.print(format_args!(
+                        "{}\r\n{}, {} CRC errors\r\nLoRa module {}, {}\r\n",
+                        radio_config,
+                        radio.stats(),
+                        radio.radio().radio().crc_errors(),
+                        radio.radio().health(),
+                        radio.radio().stats()
+                    ));
+                    if let Some(relay) = beacon.relay() {
+                        log.print(format_args!("{}\r\n", relay.stats()));
                     }
-
-                // if GPS fix failed now, but we have valid data from before, resend it
-                } else if last_lora_packet_len > 0 {
-                    match lora.transmit_payload(lora_buf, last_lora_packet_len) {
-                        Ok(_) => {
-                            let _ = serial.write(b"sent data to LoRa\r\n");
-                            last_lora_success = timer.get_counter().ticks();
-                        },
-                        Err(_) => { 
-                            let _ = serial.write(b"ERR\r\n");
-                        }
+                    if let Some(mac) = beacon.lorawan() {
+                        log.print(format_args!("{}\r\n", mac));
                     }
                 }
-                
-                i = 0;
-            } else if b == b'\r' {
-                // ignore CR from CRLF
-            } else if i < buf.len() {
-                buf[i] = b;
-                i += 1;
-            } else {
-                // buffer full: reset and store current byte as first byte
-                i = 0;
-                if !buf.is_empty() {
-                    buf[0] = b;
-                    i = 1;
+                Either3::Second(Command::Slots) => match beacon.slot() {
+                    Some(slot) => log.print(format_args!("slot {}: {}\r\n", slot, SlotPlan::default())),
+                    None => warn!(log, "console", "time slots need a tdma build"),
+                },
+                Either3::Second(Command::Battery) => match beacon.battery() {
+                    Some(status) => log.print(format_args!("{}\r\n", status)),
+                    None => warn!(log, "console", "battery not measured yet"),
+                },
+                Either3::Second(Command::Gps) => {
+                    let stats = cortex_m::interrupt::free(|cs| GPS_RX.borrow(cs).borrow().stats());
+                    log.print(format_args!("{}, {} lines dropped\r\n", stats, beacon.discarded_lines()));
                 }
+                Either3::Second(Command::Profile(profile)) => wanted = Some(Ok(radio_config.with_profile(profile))),
+                Either3::Second(Command::Region(region, channel)) => {
+                    wanted = Some(radio_config.with_channel(region, channel))
+                }
+                // the USB task handles the log commands
+                Either3::Second(_) | Either3::First(()) | Either3::Third(()) => {}
             }
-
-            let now = timer.get_counter().ticks();
-
-            // if we have been successfully sending data for 20 seconds, go to sleep
-            if now.wrapping_sub(first_lora_success) > 20_000 && first_lora_success != 0 {
-                let _ = serial.write(b"Been sending data for 20 seconds, going to sleep...\r\n");
-                disable_uart1();
-                let _ = led_pin.set_low();
-                sleep_ms(&mut timer, 30_000); // sleep 30 seconds (testing, change later)
-                enable_uart1();
-                let _ = led_pin.set_high();
-
-                let _ = serial.write(b"Woke up from sleep, retrying GPS connection...\r\n");
-                first_lora_success = 0;
-                last_lora_success = timer.get_counter().ticks();
+            match wanted {
+                Some(Ok(config)) => match radio.configure(&config) {
+                    Ok(()) => {
+                        radio_config = config;
+                        beacon.set_radio_config(config);
+                        log.print(format_args!("{}\r\n", radio_config));
+                    }
+                    Err(_) => warn!(log, "radio", "configuration failed"),
+                },
+                Some(Err(e)) => warn!(log, "console", "{}", e),
+                None => {}
             }
-
-            // if we don't have any valid GPS data and did not send any packets, go to sleep and retry after
-            if now.wrapping_sub(last_gps_success) > 30_000 && now.wrapping_sub(last_lora_success) > 30_000 {
-                let _ = serial.write(b"No valid GPS data for 30 sec, going to sleep...\r\n");
-                disable_uart1();
-                let _ = led_pin.set_low();
-                sleep_ms(&mut timer, 30_000); // sleep 30 s
-                enable_uart1    ();
-                let _ = led_pin.set_high();
-
-                let _ = serial.write(b"Woke up from sleep, retrying GPS connection...\r\n");
-                last_lora_success = timer.get_counter().ticks(); // reset this to avoid immediate sleep
+            // one record at a time, once the USB task sent the previous one
+            if let Some(seq) = track_dump
+                && !DUMPING.load(Ordering::Relaxed)
+                && let Some(track) = beacon.track_log()
+            {
+                track_dump = track.dump(&mut store, seq, log).unwrap_or(None);
+                DUMPING.store(true, Ordering::Relaxed);
             }
-
-            // if we did not have GPS fix for 1 day, prevent resending irrelevant data
-            if now.wrapping_sub(last_gps_success) > 86_400_000 && last_gps_success != 0 {
-                last_lora_packet_len = 0;
+            if radio.poll().is_err() {
+                error!(log, "radio", "LoRa channel check failed");
             }
+            let uart = cortex_m::interrupt::free(|cs| GPS_RX.borrow(cs).borrow().stats());
+            beacon.update_faults(radio.radio().stats(), uart);
+            beacon.poll(&mut radio, &mut store, log, &timer);
+            sleep
+        });
+        if let Some(ms) = sleep {
+            check_in(RADIO_TASK, ms as u64 + CHECK_IN_MS);
+            SLEEP.signal(ms);
+            AWAKE.wait().await;
+            // the UART was off, so the line it was in is incomplete
+            cortex_m::interrupt::free(|cs| GPS_RX.borrow(cs).borrow_mut().discard());
+            log.lock(|log| beacon.wake(&mut log.borrow_mut(), &timer));
         }
     }
 }
+
+/// Serves the USB serial port: reads console commands and sends the log.
+#[embassy_executor::task]
+async fn usb_task(usb_dev: UsbDevice<'static, UsbBus>, serial: SerialPort<'static, UsbBus>, log: &'static SharedLog) {
+    serve_usb(usb_dev, serial, log, cfg!(feature = "log-usb"), cfg!(feature = "debug-log")).await
+}
+
+/// Feeds the watchdog while the radio and USB tasks check in on time.
+#[embassy_executor::task]
+async fn watchdog_task(watchdog: Watchdog) {
+    supervise(watchdog).await
+}
+
+/// Resets the radio, then initialises and configures it; returns whether it came up.
+async fn reset_radio(radio: &mut Lora, rst: &mut Rst, config: &RadioConfig) -> bool {
+    let up = pulse_reset(rst, || radio.radio_mut().radio_mut().init().is_ok() && radio.configure(config).is_ok()).await;
+    radio.radio_mut().reset_done(up);
+    up
+}
+
+/// Takes the received GPS bytes out of the UART's FIFO and wakes the radio task.
+#[interrupt]
+fn UART1_IRQ() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(uart) = GPS.borrow(cs).borrow().as_ref() {
+            drain_uart(uart, &mut GPS_RX.borrow(cs).borrow_mut());
+        }
+    });
+    GPS_READY.signal(());
+}
+
+#[interrupt]
+fn USBCTRL_IRQ() {
+    USB_IRQ.on_interrupt();
+}
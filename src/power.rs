@@ -0,0 +1,83 @@
+use arkan_core::battery::{vsys_mv, Battery, BatteryConfig, BatteryStatus};
+use arkan_core::rp2040::show_radio;
+use arkan_core::telemetry::chip_temp_c;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+use embedded_hal::adc::OneShot;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rp_pico::hal::adc::{Adc, AdcPin, TempSense};
+use rp_pico::hal::gpio::{bank0, FunctionSioInput, FunctionSioOutput, Pin, PullDown, PullNone};
+use rp_pico::hal::pac;
+
+pub type LedPin = Pin<bank0::Gpio25, FunctionSioOutput, PullDown>;
+/// VSYS / 3; a pull resistor would load the divider.
+pub type VsysPin = AdcPin<Pin<bank0::Gpio29, FunctionSioInput, PullNone>>;
+/// High while the Pico's USB port is powered.
+pub type VbusPin = Pin<bank0::Gpio24, FunctionSioInput, PullNone>;
+
+// Sleep requests from the radio task (in ms), and the answer once the board is awake again.
+pub static SLEEP: Signal<ThreadModeRawMutex, u32> = Signal::new();
+pub static AWAKE: Signal<ThreadModeRawMutex, ()> = Signal::new();
+// The latest battery state and chip temperature (°C), for the radio task.
+pub static BATTERY: Signal<ThreadModeRawMutex, BatteryStatus> = Signal::new();
+pub static TEMPERATURE: Signal<ThreadModeRawMutex, i8> = Signal::new();
+
+/// The battery and the temperature are sampled this often, also while the beacon sleeps.
+const SENSOR_MS: u64 = 10_000;
+
+/// Power manager: turns the GPS UART and the LED off for the requested time.
+/// The executor sleeps in WFE while no task has work; USB stays up. While the
+/// radio is down the LED blinks.
+#[embassy_executor::task]
+pub async fn power_task(mut led: LedPin) {
+    // the LED as sleeping and waking left it
+    let mut lit = false;
+    loop {
+        if let Either::First(ms) = select(SLEEP.wait(), show_radio(&mut led, lit)).await {
+            disable_uart1();
+            let _ = led.set_low();
+            Timer::after_millis(ms as u64).await;
+            enable_uart1();
+            let _ = led.set_high();
+            lit = true;
+            AWAKE.signal(());
+        }
+    }
+}
+
+/// Samples the battery voltage and the chip temperature and hands them to
+/// the radio task, the battery state averaged.
+#[embassy_executor::task]
+pub async fn sensor_task(mut adc: Adc, mut vsys: VsysPin, vbus: VbusPin, mut temp: TempSense) {
+    let mut battery = Battery::new(BatteryConfig::default());
+    loop {
+        let raw: u16 = adc.read(&mut vsys).unwrap_or(0);
+        let usb_power = vbus.is_high().unwrap_or(false);
+        BATTERY.signal(battery.sample(vsys_mv(raw), usb_power));
+        let raw: u16 = adc.read(&mut temp).unwrap_or(0);
+        TEMPERATURE.signal(chip_temp_c(raw));
+        Timer::after_millis(SENSOR_MS).await;
+    }
+}
+
+/// Disable UART1 transmit/receive paths to reduce power before sleep.
+pub fn disable_uart1() {
+    unsafe {
+        let uart1 = &*pac::UART1::ptr();
+        uart1
+            .uartcr()
+            .modify(|_, w| w.uarten().clear_bit().txe().clear_bit().rxe().clear_bit());
+    }
+}
+
+/// Re-enable UART1 after waking from sleep.
+pub fn enable_uart1() {
+    unsafe {
+        let uart1 = &*pac::UART1::ptr();
+        uart1
+            .uartcr()
+            .modify(|_, w| w.uarten().set_bit().txe().set_bit().rxe().set_bit());
+    }
+}